@@ -5,11 +5,29 @@ pub mod x86_64;
 pub use self::x86_64::{exec_elf, init, serial, structures::paging::OffsetPageTable};
 
 pub static mut SYSCALL_PHYS_ADDR: u64 = 0;
+pub static mut SYSCALL_PAGES: u64 = 0;
 pub static mut SYSCALL_TRIGGER_PORT: u16 = 0;
+pub static mut NR_CPUS: usize = 1;
 
 pub fn init_syscall(boot_info: &vmsyscall::bootinfo::BootInfo) {
     unsafe {
         SYSCALL_PHYS_ADDR = boot_info as *const vmsyscall::bootinfo::BootInfo as _;
+        SYSCALL_PAGES = boot_info.syscall_pages;
         SYSCALL_TRIGGER_PORT = boot_info.syscall_trigger_port;
+        NR_CPUS = core::cmp::min(
+            core::cmp::max(boot_info.nr_cpus as usize, 1),
+            vmsyscall::bootinfo::MAX_CPUS,
+        );
+    }
+}
+
+/// The physical address of the syscall page of the current CPU
+pub fn syscall_page() -> u64 {
+    unsafe {
+        if SYSCALL_PAGES == 0 {
+            SYSCALL_PHYS_ADDR
+        } else {
+            SYSCALL_PAGES + self::x86_64::percpu::cpu_id() as u64 * 4096
+        }
     }
 }
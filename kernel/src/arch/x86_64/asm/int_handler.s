@@ -18,9 +18,20 @@ _isr_\num:
     pushq   %r10
     pushq   %r11
     pushq   %rbx
+    # the callee saved registers only for the crash report
+    pushq   %rbp
+    pushq   %r12
+    pushq   %r13
+    pushq   %r14
+    pushq   %r15
+
+    # with SMAP, the kernel has no access to user pages, even if userspace set AC
+    pushfq
+    andq    $~0x40000, (%rsp)
+    popfq
 
     movq    %rsp, %rbx
-    movq    80(%rsp), %rsi
+    movq    120(%rsp), %rsi
 
     # rsp is first argument
     movq    %rsp, %rdi
@@ -28,9 +39,9 @@ _isr_\num:
 
     # add xsave area and align stack
 .if \has_error
-    addq    $(11*8), %rdi
+    addq    $(16*8), %rdi
 .else
-    addq    $(10*8), %rdi
+    addq    $(15*8), %rdi
 .endif
 
     # align stack
@@ -51,6 +62,10 @@ _isr_\num:
     # xsave end
 
     movq   $\num, %rdx
+    # the saved registers are the fourth argument
+    movq   %rbx, %rcx
+    # the xsave area is the fifth argument
+    movq   %rsp, %r8
 
     callq  run_interrupt_fn
 
@@ -62,6 +77,11 @@ _isr_\num:
     # xrstor end
     movq    %rbx, %rsp
 
+    popq    %r15
+    popq    %r14
+    popq    %r13
+    popq    %r12
+    popq    %rbp
     popq    %rbx
     popq    %r11
     popq    %r10
@@ -73,6 +93,11 @@ _isr_\num:
     popq    %rsi
     popq    %rdi
 
+.if \has_error
+    # drop the error code
+    addq    $8, %rsp
+.endif
+
     iretq
     .p2align 4
 .endm
@@ -104,6 +129,6 @@ ISR 30 has_error=1
 #ISR 32 has_error=0
 #ISR 33 has_error=0
 
-#ISR 100 has_error=0
-#ISR 101 has_error=0
-#ISR 102 has_error=0
+ISR 100 has_error=0
+ISR 101 has_error=0
+ISR 102 has_error=0
@@ -1,9 +1,14 @@
 .section .text, "ax"
 .global _syscall_enter
 .type _syscall_enter, @function
+.global _syscall_return
+.type _syscall_return, @function
+.global _sigreturn
+.type _sigreturn, @function
 .code64
 
 XSAVE_STACK_OFFSET = (16*64 + 3 * 8)
+SYSCALL_FRAME_RAX = (12 * 8)
 
 .p2align 4
 _syscall_enter:
@@ -43,29 +48,43 @@ _syscall_enter:
 
     sti
 
-    # SYSV:    rdi, rsi, rdx, rcx, r8, r9
-    # SYSCALL: rdi, rsi, rdx, r10, r8, r9
-    mov    %r10, %rcx
-
-    pushq   %rdi
+    # struct SyscallFrame
+    pushq   $0             # Padding
+    pushq   %rax
     pushq   %rdi
     pushq   %rsi
     pushq   %rdx
     pushq   %r10
     pushq   %r8
     pushq   %r9
-    pushq   %rax
-
-    callq  syscall_rust
-
-    popq    %rcx
+    pushq   %rbx
+    pushq   %rbp
+    pushq   %r12
+    pushq   %r13
+    pushq   %r14
+    pushq   %r15
+
+    movq    %rsp, %rdi
+    callq   syscall_rust
+    movq    %rax, SYSCALL_FRAME_RAX(%rsp)
+
+# Return to userspace with the `SyscallFrame` on the stack.
+# New threads start here.
+_syscall_return:
+    popq    %r15
+    popq    %r14
+    popq    %r13
+    popq    %r12
+    popq    %rbp
+    popq    %rbx
     popq    %r9
     popq    %r8
     popq    %r10
     popq    %rdx
     popq    %rsi
     popq    %rdi
-    popq    %rdi
+    popq    %rax
+    addq    $8, %rsp       # Padding
 
     cli
 
@@ -98,3 +117,43 @@ _syscall_enter:
     swapgs
     sti
     sysretq
+
+# _sigreturn(ctx: *const SigContext, xsave: *const u8) -> !
+#
+# Return to userspace with the registers of the `struct sigcontext` at `ctx` and the
+# FPU state in the xsave area at `xsave`. `cs`, `ss` and `eflags` are already checked.
+.p2align 4
+_sigreturn:
+    cli
+    movq    %rdi, %r15
+
+    movl    $-1, %edx
+    movl    $-1, %eax
+    xrstor  (%rsi)
+
+    # the interrupt stack frame for iretq
+    movzwq  150(%r15), %rax   # ss
+    pushq   %rax
+    pushq   120(%r15)         # rsp
+    pushq   136(%r15)         # eflags
+    movzwq  144(%r15), %rax   # cs
+    pushq   %rax
+    pushq   128(%r15)         # rip
+
+    movq    0(%r15), %r8
+    movq    8(%r15), %r9
+    movq    16(%r15), %r10
+    movq    24(%r15), %r11
+    movq    32(%r15), %r12
+    movq    40(%r15), %r13
+    movq    48(%r15), %r14
+    movq    64(%r15), %rdi
+    movq    72(%r15), %rsi
+    movq    80(%r15), %rbp
+    movq    88(%r15), %rbx
+    movq    96(%r15), %rdx
+    movq    104(%r15), %rax
+    movq    112(%r15), %rcx
+    movq    56(%r15), %r15
+
+    iretq
@@ -0,0 +1,35 @@
+# _stac(), _clac(), _read_cr4() and _write_cr4()
+#
+# Allow and forbid the access to user pages with SMAP enabled.
+# Both fault on CPUs without SMAP.
+.section .text, "ax"
+.global _stac
+.type _stac, @function
+.p2align 4
+_stac:
+    stac
+    retq
+
+.section .text, "ax"
+.global _clac
+.type _clac, @function
+.p2align 4
+_clac:
+    clac
+    retq
+
+.section .text, "ax"
+.global _read_cr4
+.type _read_cr4, @function
+.p2align 4
+_read_cr4:
+    movq  %cr4, %rax
+    retq
+
+.section .text, "ax"
+.global _write_cr4
+.type _write_cr4, @function
+.p2align 4
+_write_cr4:
+    movq  %rdi, %cr4
+    retq
@@ -15,3 +15,43 @@ _context_switch:
 _read_rsp:
     movq %rsp, %rax
     retq
+
+# _switch_stack(old_sp: *mut u64, new_sp: u64, old_on_cpu: *mut bool)
+#
+# Save the callee saved registers, store the stack pointer in `old_sp`,
+# clear `old_on_cpu` and continue with the registers saved on `new_sp`.
+.section .text, "ax"
+.global _switch_stack
+.type _switch_stack, @function
+.p2align 4
+_switch_stack:
+    pushq %rbp
+    pushq %rbx
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    movq  %rsp, (%rdi)
+    movb  $0, (%rdx)
+    movq  %rsi, %rsp
+    popq  %r15
+    popq  %r14
+    popq  %r13
+    popq  %r12
+    popq  %rbx
+    popq  %rbp
+    retq
+
+# _read_segments(out: *mut [u16; 4])
+#
+# Store the selectors of ds, es, fs and gs in `out`.
+.section .text, "ax"
+.global _read_segments
+.type _read_segments, @function
+.p2align 4
+_read_segments:
+    movw  %ds, (%rdi)
+    movw  %es, 2(%rdi)
+    movw  %fs, 4(%rdi)
+    movw  %gs, 6(%rdi)
+    retq
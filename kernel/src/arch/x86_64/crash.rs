@@ -0,0 +1,248 @@
+//! Crash reports for fatal exceptions
+//!
+//! The report holds the complete CPU state and the return addresses found by following
+//! the frame pointers. It is handed to vmrun, which symbolizes it. Without vmrun, it is
+//! printed as is.
+
+use super::idt::InterruptStackFrame;
+use super::percpu::cpu_id;
+use super::uaccess;
+use super::PHYSICAL_MEMORY_OFFSET;
+use crate::{eprintln, exit_hypervisor, hlt_loop, HyperVisorExitCode};
+use vmsyscall::crash::{CrashReport, Registers, Segments, CRASH_FRAMES_LEN};
+use x86_64::registers::control::{Cr2, Cr3};
+use x86_64::registers::model_specific::{FsBase, GsBase};
+use x86_64::structures::paging::PageTableFlags;
+
+extern "C" {
+    fn _read_segments(out: *mut [u16; 4]);
+}
+
+/// The registers pushed by the interrupt entry in `int_handler.s`
+#[derive(Debug)]
+#[repr(C)]
+pub struct SavedRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+}
+
+/// Report the fatal exception `vector` and stop the VM
+pub fn crash(
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    error_code: u64,
+    regs: &SavedRegisters,
+) -> ! {
+    let report = report(vector, stack_frame, error_code, regs);
+
+    #[cfg(not(feature = "qemu"))]
+    let taken = crate::libc::crash(&report).is_ok();
+    #[cfg(feature = "qemu")]
+    let taken = false;
+
+    if !taken {
+        print(&report);
+    }
+
+    exit_hypervisor(HyperVisorExitCode::Exception(vector));
+    hlt_loop();
+}
+
+/// Collect the state of the current CPU at the exception `vector`
+pub fn report(
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    error_code: u64,
+    regs: &SavedRegisters,
+) -> CrashReport {
+    let mut selectors = [0u16; 4];
+    unsafe { _read_segments(&mut selectors) };
+
+    let mut report = CrashReport {
+        vector: vector as u32,
+        cpu: cpu_id() as u32,
+        user: stack_frame.code_segment & 3 == 3,
+        error_code,
+        cr2: if vector == 14 {
+            Cr2::read().as_u64()
+        } else {
+            0
+        },
+        app_bias: unsafe { super::APP_BIAS },
+        regs: Registers {
+            rax: regs.rax,
+            rbx: regs.rbx,
+            rcx: regs.rcx,
+            rdx: regs.rdx,
+            rsi: regs.rsi,
+            rdi: regs.rdi,
+            rbp: regs.rbp,
+            rsp: stack_frame.stack_pointer.as_u64(),
+            r8: regs.r8,
+            r9: regs.r9,
+            r10: regs.r10,
+            r11: regs.r11,
+            r12: regs.r12,
+            r13: regs.r13,
+            r14: regs.r14,
+            r15: regs.r15,
+            rip: stack_frame.instruction_pointer.as_u64(),
+            rflags: stack_frame.cpu_flags,
+        },
+        segments: Segments {
+            cs: stack_frame.code_segment as u16,
+            ss: stack_frame.stack_segment as u16,
+            ds: selectors[0],
+            es: selectors[1],
+            fs: selectors[2],
+            gs: selectors[3],
+            fs_base: FsBase::read().as_u64(),
+            gs_base: GsBase::read().as_u64(),
+        },
+        ..CrashReport::default()
+    };
+    report.frames_len = walk_stack(regs.rbp, report.user, &mut report.frames);
+    report
+}
+
+/// Follow the frame pointers from `rbp` and store the return addresses in `frames`
+///
+/// Every frame is checked to be mapped, so a corrupted chain ends the walk instead of
+/// faulting again.
+fn walk_stack(mut rbp: u64, user: bool, frames: &mut [u64; CRASH_FRAMES_LEN]) -> usize {
+    let mut len = 0;
+    while len < frames.len() {
+        if rbp == 0 || rbp % 8 != 0 || !is_mapped(rbp, user) || !is_mapped(rbp + 8, user) {
+            break;
+        }
+        // a user stack is only readable with the user access of SMAP
+        let (next, ret) = unsafe {
+            uaccess::with_user_access(|| (*(rbp as *const u64), *((rbp + 8) as *const u64)))
+        };
+        if ret == 0 {
+            break;
+        }
+        frames[len] = ret;
+        len += 1;
+        // the frames of the callers are above
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    len
+}
+
+/// Whether `addr` is mapped in the current page tables, for `user` with user access
+fn is_mapped(addr: u64, user: bool) -> bool {
+    match page_flags(addr) {
+        Some(flags) => !user || flags.contains(PageTableFlags::USER_ACCESSIBLE),
+        None => false,
+    }
+}
+
+/// The flags of the present page mapping `addr` in the current page tables
+///
+/// `USER_ACCESSIBLE` and `WRITABLE` are only set, if all levels of the page tables
+/// allow the access.
+pub fn page_flags(addr: u64) -> Option<PageTableFlags> {
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+    let inherited = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+
+    // canonical addresses only
+    if (0x0000_8000_0000_0000..0xffff_8000_0000_0000).contains(&addr) {
+        return None;
+    }
+
+    let mut allowed = inherited;
+    let mut table = Cr3::read().0.start_address().as_u64();
+    for level in (0..4).rev() {
+        let index = (addr >> (12 + 9 * level)) & 0x1ff;
+        let entry = unsafe { *((PHYSICAL_MEMORY_OFFSET + table + index * 8) as *const u64) };
+        let flags = PageTableFlags::from_bits_truncate(entry);
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        allowed &= flags;
+        if level == 0 || (level < 3 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            return Some((flags - inherited) | allowed);
+        }
+        table = entry & ADDR_MASK;
+    }
+    None
+}
+
+/// Print the `report` without symbols
+pub fn print(report: &CrashReport) {
+    eprintln!(
+        "EXCEPTION: {} ({}) in {} mode on CPU {}",
+        report.exception_name(),
+        report.vector,
+        if report.user { "user" } else { "kernel" },
+        report.cpu
+    );
+    eprintln!(
+        "error code: {:#x}, cr2: {:#018x}",
+        report.error_code, report.cr2
+    );
+    for regs in report.regs.named().chunks(3) {
+        eprintln!(
+            "{:>6}: {:#018x} {:>6}: {:#018x} {:>6}: {:#018x}",
+            regs[0].0, regs[0].1, regs[1].0, regs[1].1, regs[2].0, regs[2].1
+        );
+    }
+    let seg = &report.segments;
+    eprintln!(
+        "    cs: {:#06x} ss: {:#06x} ds: {:#06x} es: {:#06x} fs: {:#06x} gs: {:#06x}",
+        seg.cs, seg.ss, seg.ds, seg.es, seg.fs, seg.gs
+    );
+    eprintln!(
+        "fs_base: {:#018x} gs_base: {:#018x}",
+        seg.fs_base, seg.gs_base
+    );
+    eprintln!("stack:");
+    eprintln!("  #0 {:#018x}", report.regs.rip);
+    for (i, frame) in report.frames().iter().enumerate() {
+        eprintln!("  #{} {:#018x}", i + 1, frame);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_walk_stack() {
+        serial_print!("test_walk_stack...");
+        // two frames on the stack of the test, the outer one ends the chain
+        let mut stack = [0u64; 4];
+        let base = stack.as_ptr() as u64;
+        stack[0] = base + 16;
+        stack[1] = 0x1234;
+        stack[2] = 0;
+        stack[3] = 0x5678;
+
+        let mut frames = [0u64; CRASH_FRAMES_LEN];
+        assert_eq!(walk_stack(base, false, &mut frames), 2);
+        assert_eq!(&frames[..2], &[0x1234, 0x5678]);
+
+        assert_eq!(walk_stack(0, false, &mut frames), 0);
+        assert_eq!(walk_stack(base + 1, false, &mut frames), 0);
+        assert!(!is_mapped(0x0000_8000_0000_0000, false));
+        serial_println!("[ok]");
+    }
+}
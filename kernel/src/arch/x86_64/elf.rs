@@ -0,0 +1,580 @@
+//! Loader of position independent ring3 executables and of the dynamic linker
+//!
+//! vmrun hands over the unchanged ELF files. Their segments are copied to fresh frames at a
+//! random base, the `R_X86_64_RELATIVE` relocations of a static-pie are applied and the
+//! pages are mapped with the permissions of their program headers. The relocations of a
+//! dynamically linked executable are left to its dynamic linker.
+
+use super::mmap::{self, with_mm};
+use super::vma::Prot;
+use super::{PAGESIZE, USER_INTERP_END, USER_INTERP_START, USER_PIE_END, USER_PIE_START};
+use crate::arch::x86_64::structures::paging::{Mapper, OffsetPageTable, Page, PageTableFlags};
+use core::mem::size_of;
+use core::ops::Range;
+use linux_errno::ErrNo;
+use vmsyscall::bootinfo::AppSegment;
+use x86_64::VirtAddr;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_RELAENT: i64 = 9;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// `Elf64_Ehdr`
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct Header {
+    ident: [u8; 16],
+    type_: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+/// `Elf64_Phdr`
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct ProgramHeader {
+    type_: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// The protection of the pages of a segment with the program header flags `flags`
+fn segment_prot(flags: u32) -> Prot {
+    let mut prot = Prot::empty();
+    if flags & PF_R != 0 {
+        prot |= Prot::READ;
+    }
+    if flags & PF_W != 0 {
+        prot |= Prot::WRITE;
+    }
+    if flags & PF_X != 0 {
+        prot |= Prot::EXEC;
+    }
+    prot
+}
+
+impl ProgramHeader {
+    /// The protection of the pages of the segment
+    fn prot(&self) -> Prot {
+        segment_prot(self.flags)
+    }
+
+    /// Whether `[vaddr, vaddr + len)` is part of the segment in memory
+    fn contains(&self, vaddr: u64, len: u64) -> bool {
+        match vaddr.checked_add(len) {
+            Some(end) => self.vaddr <= vaddr && end <= self.vaddr + self.memsz,
+            None => false,
+        }
+    }
+}
+
+/// `Elf64_Dyn`
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Dyn {
+    tag: i64,
+    val: u64,
+}
+
+/// `Elf64_Rela`
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+/// Read the entry `index` of the table of `T` at `table` in `data`
+fn read<T: Copy>(data: &[u8], table: u64, index: u64) -> Result<T, ErrNo> {
+    let start = index
+        .checked_mul(size_of::<T>() as u64)
+        .and_then(|offset| offset.checked_add(table))
+        .ok_or(ErrNo::ENOEXEC)?;
+    let end = start
+        .checked_add(size_of::<T>() as u64)
+        .ok_or(ErrNo::ENOEXEC)?;
+    if end > data.len() as u64 {
+        return Err(ErrNo::ENOEXEC);
+    }
+    Ok(unsafe { (data.as_ptr().add(start as usize) as *const T).read_unaligned() })
+}
+
+/// An ELF file with validated program headers
+struct Elf<'a> {
+    data: &'a [u8],
+    header: Header,
+}
+
+impl<'a> Elf<'a> {
+    /// Parse the position independent x86_64 executable `data`
+    fn parse(data: &'a [u8]) -> Result<Self, ErrNo> {
+        let header: Header = read(data, 0, 0)?;
+        if header.ident[..4] != ELF_MAGIC
+            || header.ident[4] != ELFCLASS64
+            || header.ident[5] != ELFDATA2LSB
+            || header.type_ != ET_DYN
+            || header.machine != EM_X86_64
+            || header.phentsize as usize != size_of::<ProgramHeader>()
+        {
+            return Err(ErrNo::ENOEXEC);
+        }
+
+        let elf = Elf { data, header };
+        for i in 0..header.phnum as u64 {
+            let ph: ProgramHeader = read(data, header.phoff, i)?;
+            match ph.type_ {
+                PT_LOAD | PT_DYNAMIC | PT_INTERP => {}
+                _ => continue,
+            }
+            let file_end = ph.offset.checked_add(ph.filesz);
+            let mem_end = ph.vaddr.checked_add(ph.memsz);
+            match (file_end, mem_end) {
+                (Some(file_end), Some(_))
+                    if ph.filesz <= ph.memsz && file_end <= data.len() as u64 => {}
+                _ => return Err(ErrNo::ENOEXEC),
+            }
+        }
+        Ok(elf)
+    }
+
+    /// All program headers
+    fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.header.phnum as u64)
+            .filter_map(move |i| read(self.data, self.header.phoff, i).ok())
+    }
+
+    /// Whether the executable is dynamically linked and names its dynamic linker
+    fn has_interp(&self) -> bool {
+        self.program_headers().any(|ph| ph.type_ == PT_INTERP)
+    }
+
+    /// The `PT_LOAD` program headers
+    fn loads(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        self.program_headers().filter(|ph| ph.type_ == PT_LOAD)
+    }
+
+    /// The page aligned start and end of all segments and their largest alignment
+    fn load_range(&self) -> Result<(u64, u64, u64), ErrNo> {
+        let mut range: Option<(u64, u64, u64)> = None;
+        for ph in self.loads() {
+            let start = ph.vaddr & !(PAGESIZE as u64 - 1);
+            let end = ph.vaddr + ph.memsz;
+            let align = core::cmp::max(ph.align, PAGESIZE as u64);
+            range = Some(match range {
+                Some((s, e, a)) => (s.min(start), e.max(end), a.max(align)),
+                None => (start, end, align),
+            });
+        }
+        match range {
+            Some((start, end, align)) if align.is_power_of_two() => {
+                let end = end.checked_add(PAGESIZE as u64 - 1).ok_or(ErrNo::ENOEXEC)?
+                    & !(PAGESIZE as u64 - 1);
+                Ok((start & !(align - 1), end, align))
+            }
+            _ => Err(ErrNo::ENOEXEC),
+        }
+    }
+
+    /// The file offset of `[vaddr, vaddr + len)`, which has to be part of the file
+    /// contents of a segment
+    fn file_offset(&self, vaddr: u64, len: u64) -> Result<u64, ErrNo> {
+        let end = vaddr.checked_add(len).ok_or(ErrNo::ENOEXEC)?;
+        self.loads()
+            .find(|ph| ph.vaddr <= vaddr && end <= ph.vaddr + ph.filesz)
+            .map(|ph| ph.offset + (vaddr - ph.vaddr))
+            .ok_or(ErrNo::ENOEXEC)
+    }
+
+    /// The linked address of the program headers in memory, for `AT_PHDR`
+    ///
+    /// Without a `PT_PHDR` header, they are found in the file contents of a segment.
+    fn phdr_vaddr(&self) -> Option<u64> {
+        if let Some(ph) = self.program_headers().find(|ph| ph.type_ == PT_PHDR) {
+            return Some(ph.vaddr);
+        }
+        let phoff = self.header.phoff;
+        let len = u64::from(self.header.phnum) * size_of::<ProgramHeader>() as u64;
+        self.loads()
+            .find(|ph| ph.offset <= phoff && phoff + len <= ph.offset + ph.filesz)
+            .map(|ph| ph.vaddr + (phoff - ph.offset))
+    }
+
+    /// The protection of the last segment covering the page at `vaddr`
+    ///
+    /// Like Linux, a later segment is mapped over a page shared with the previous one,
+    /// so the end of the text and the start of the data never make a page writable and
+    /// executable.
+    fn page_prot(&self, vaddr: u64) -> Prot {
+        self.loads()
+            .filter(|ph| ph.vaddr < vaddr + PAGESIZE as u64 && vaddr < ph.vaddr + ph.memsz)
+            .last()
+            .map_or(Prot::empty(), |ph| ph.prot())
+    }
+}
+
+/// A ring3 executable loaded by [`load_pie`] or a dynamic linker loaded by [`load_interp`]
+#[derive(Clone, Copy, Debug)]
+pub struct LoadedApp {
+    /// The address of the entry point
+    pub entry: usize,
+    /// The address of the program headers
+    pub phdr: usize,
+    /// The number of program headers
+    pub phnum: usize,
+    /// The offset of the load addresses to the linked addresses
+    pub bias: usize,
+}
+
+/// The base for a random load address `random` of the segments at `[start, end)`
+/// with alignment `align` in the address range `area`
+fn pick_base(random: u64, area: Range<u64>, start: u64, end: u64, align: u64) -> Option<u64> {
+    let len = end - start;
+    let range = area.end - area.start;
+    if len > range {
+        return None;
+    }
+    let slots = (range - len) / align + 1;
+    let base = area.start + (random % slots) * align;
+    Some(base)
+}
+
+/// Apply the relocations of the dynamic section
+fn relocate(mapper: &OffsetPageTable, elf: &Elf, bias: u64) -> Result<(), ErrNo> {
+    let dynamic = match elf.program_headers().find(|ph| ph.type_ == PT_DYNAMIC) {
+        Some(dynamic) => dynamic,
+        None => return Ok(()),
+    };
+
+    let (mut rela, mut relasz, mut relaent) = (None, 0, size_of::<Rela>() as u64);
+    for i in 0..dynamic.filesz / size_of::<Dyn>() as u64 {
+        let entry: Dyn = read(elf.data, dynamic.offset, i)?;
+        match entry.tag {
+            DT_NULL => break,
+            DT_RELA => rela = Some(entry.val),
+            DT_RELASZ => relasz = entry.val,
+            DT_RELAENT => relaent = entry.val,
+            _ => {}
+        }
+    }
+    let rela = match rela {
+        Some(rela) => rela,
+        None => return Ok(()),
+    };
+    if relaent != size_of::<Rela>() as u64 {
+        return Err(ErrNo::ENOEXEC);
+    }
+
+    let table = elf.file_offset(rela, relasz)?;
+    for i in 0..relasz / relaent {
+        let rela: Rela = read(elf.data, table, i)?;
+        match rela.info as u32 {
+            R_X86_64_NONE => {}
+            R_X86_64_RELATIVE => {
+                if !elf.loads().any(|ph| ph.contains(rela.offset, 8)) {
+                    return Err(ErrNo::ENOEXEC);
+                }
+                let value = bias.wrapping_add(rela.addend as u64);
+                mmap::write_frames(mapper, bias + rela.offset, &value.to_ne_bytes())?;
+            }
+            // symbol lookups need a dynamic linker
+            _ => return Err(ErrNo::ENOEXEC),
+        }
+    }
+    Ok(())
+}
+
+/// Load `elf` at a random base in `area` and apply its relocations, if `apply_relocations`
+fn load(elf: &Elf, area: Range<u64>, apply_relocations: bool) -> Result<LoadedApp, ErrNo> {
+    let (start, end, align) = elf.load_range()?;
+
+    let mut random = [0u8; 8];
+    if super::random::fill(&mut random) != Ok(random.len()) {
+        return Err(ErrNo::EIO);
+    }
+    let base =
+        pick_base(u64::from_ne_bytes(random), area, start, end, align).ok_or(ErrNo::ENOMEM)?;
+    let bias = base.checked_sub(start).ok_or(ErrNo::ENOEXEC)?;
+
+    with_mm(|mapper, frame_allocator, _| {
+        // writable for the kernel only until the relocations are done
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for ph in elf.loads() {
+            let seg_start = (bias + ph.vaddr) & !(PAGESIZE as u64 - 1);
+            for page in (seg_start..bias + ph.vaddr + ph.memsz).step_by(PAGESIZE) {
+                let page = Page::containing_address(VirtAddr::new(page));
+                if mapper.translate_page(page).is_err() {
+                    mmap::map_zeroed_page(mapper, frame_allocator, page, flags)?;
+                }
+            }
+            let bytes = &elf.data[ph.offset as usize..(ph.offset + ph.filesz) as usize];
+            mmap::write_frames(mapper, bias + ph.vaddr, bytes)?;
+        }
+
+        if apply_relocations {
+            relocate(mapper, elf, bias)?;
+        }
+
+        // the gaps between the segments stay unmapped
+        for addr in (bias + start..bias + end).step_by(PAGESIZE) {
+            let page: Page = Page::containing_address(VirtAddr::new(addr));
+            if mapper.translate_page(page).is_ok() {
+                mmap::protect_image_page(mapper, addr as usize, elf.page_prot(addr - bias))?;
+            }
+        }
+        Ok(())
+    })?;
+
+    let phdr = elf.phdr_vaddr().ok_or(ErrNo::ENOEXEC)?;
+    Ok(LoadedApp {
+        entry: (bias + elf.header.entry) as usize,
+        phdr: (bias + phdr) as usize,
+        phnum: elf.header.phnum as usize,
+        bias: bias as usize,
+    })
+}
+
+/// The protection of the last of the `segments` covering the page at `vaddr`, like
+/// `Elf::page_prot`
+fn fixed_page_prot(segments: &[AppSegment], vaddr: u64) -> Option<Prot> {
+    segments
+        .iter()
+        .filter(|s| s.vaddr < vaddr + PAGESIZE as u64 && vaddr < s.vaddr + u64::from(s.memsz))
+        .last()
+        .map(|s| segment_prot(s.flags))
+}
+
+/// Map the pages of the `segments` of a fixed-address executable, which vmrun loaded,
+/// with the permissions of their program headers.
+///
+/// Nothing else of the identity mapping of the RAM stays executable for userspace.
+pub fn protect_fixed(segments: &[AppSegment]) -> Result<(), ErrNo> {
+    mmap::protect_identity_map(|vaddr| fixed_page_prot(segments, vaddr))
+}
+
+/// Load the position independent executable `data` at a random base
+///
+/// A dynamically linked executable is relocated by its dynamic linker,
+/// which has to be loaded with [`load_interp`].
+pub fn load_pie(data: &[u8]) -> Result<LoadedApp, ErrNo> {
+    let elf = Elf::parse(data)?;
+    let apply_relocations = !elf.has_interp();
+    load(
+        &elf,
+        USER_PIE_START as u64..USER_PIE_END as u64,
+        apply_relocations,
+    )
+}
+
+/// Load the dynamic linker `data` of a dynamically linked executable at a random base
+///
+/// The dynamic linker relocates itself.
+pub fn load_interp(data: &[u8]) -> Result<LoadedApp, ErrNo> {
+    let elf = Elf::parse(data)?;
+    if elf.has_interp() {
+        return Err(ErrNo::ENOEXEC);
+    }
+    load(
+        &elf,
+        USER_INTERP_START as u64..USER_INTERP_END as u64,
+        false,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    const PHOFF: usize = size_of::<Header>();
+
+    /// A static-pie with a text and a data segment, which share no page
+    fn image(extra_type: u32) -> [u8; 0x200] {
+        let mut data = [0u8; 0x200];
+        let header = Header {
+            ident: [
+                0x7f,
+                b'E',
+                b'L',
+                b'F',
+                ELFCLASS64,
+                ELFDATA2LSB,
+                1,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+            type_: ET_DYN,
+            machine: EM_X86_64,
+            version: 1,
+            entry: 0x40,
+            phoff: PHOFF as u64,
+            phentsize: size_of::<ProgramHeader>() as u16,
+            phnum: 3,
+            ..Header::default()
+        };
+        let phdrs = [
+            ProgramHeader {
+                type_: PT_LOAD,
+                flags: PF_R | PF_X,
+                offset: 0,
+                vaddr: 0,
+                filesz: 0x100,
+                memsz: 0x100,
+                align: 0x1000,
+                ..ProgramHeader::default()
+            },
+            ProgramHeader {
+                type_: PT_LOAD,
+                flags: PF_R | PF_W,
+                offset: 0x100,
+                vaddr: 0x1100,
+                filesz: 0x100,
+                memsz: 0x2000,
+                align: 0x1000,
+                ..ProgramHeader::default()
+            },
+            ProgramHeader {
+                type_: extra_type,
+                ..ProgramHeader::default()
+            },
+        ];
+        unsafe {
+            (data.as_mut_ptr() as *mut Header).write_unaligned(header);
+            for (i, ph) in phdrs.iter().enumerate() {
+                let dst = data
+                    .as_mut_ptr()
+                    .add(PHOFF + i * size_of::<ProgramHeader>());
+                (dst as *mut ProgramHeader).write_unaligned(*ph);
+            }
+        }
+        data
+    }
+
+    #[test_case]
+    fn test_elf_parse() {
+        serial_print!("test_elf_parse...");
+        let data = image(0);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.loads().count(), 2);
+        assert_eq!(elf.load_range(), Ok((0, 0x4000, 0x1000)));
+        assert_eq!(elf.file_offset(0x1108, 8), Ok(0x108));
+        assert_eq!(elf.file_offset(0x1200, 8), Err(ErrNo::ENOEXEC));
+        assert_eq!(elf.phdr_vaddr(), Some(PHOFF as u64));
+        assert_eq!(elf.page_prot(0), Prot::READ | Prot::EXEC);
+        assert_eq!(elf.page_prot(0x1000), Prot::READ | Prot::WRITE);
+        assert_eq!(elf.page_prot(0x3000), Prot::READ | Prot::WRITE);
+        assert_eq!(elf.page_prot(0x4000), Prot::empty());
+        assert!((0..0x4000)
+            .step_by(PAGESIZE)
+            .all(|vaddr| !elf.page_prot(vaddr).contains(Prot::WRITE | Prot::EXEC)));
+        assert_eq!(segment_prot(PF_R | PF_X), Prot::READ | Prot::EXEC);
+
+        assert!(!elf.has_interp());
+        assert!(Elf::parse(&image(PT_INTERP)).unwrap().has_interp());
+        assert!(Elf::parse(&data[..0x100]).is_err());
+        let mut data = data;
+        data[16] = 2; // ET_EXEC
+        assert!(Elf::parse(&data).is_err());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_elf_fixed_page_prot() {
+        serial_print!("test_elf_fixed_page_prot...");
+        let segments = [
+            AppSegment {
+                vaddr: 0x40_0000,
+                memsz: 0x1100,
+                flags: PF_R | PF_X,
+            },
+            AppSegment {
+                vaddr: 0x40_1100,
+                memsz: 0x2000,
+                flags: PF_R | PF_W,
+            },
+        ];
+        let rx = Prot::READ | Prot::EXEC;
+        assert_eq!(fixed_page_prot(&segments, 0x3f_f000), None);
+        assert_eq!(fixed_page_prot(&segments, 0x40_0000), Some(rx));
+        // the page shared by the text and the data gets the protection of the data
+        assert_eq!(
+            fixed_page_prot(&segments, 0x40_1000),
+            Some(Prot::READ | Prot::WRITE)
+        );
+        assert_eq!(
+            fixed_page_prot(&segments, 0x40_3000),
+            Some(Prot::READ | Prot::WRITE)
+        );
+        assert_eq!(fixed_page_prot(&segments, 0x40_4000), None);
+        assert_eq!(fixed_page_prot(&[], 0x40_0000), None);
+        let wx = Prot::WRITE | Prot::EXEC;
+        assert!((0x40_0000..0x40_4000)
+            .step_by(PAGESIZE)
+            .filter_map(|vaddr| fixed_page_prot(&segments, vaddr))
+            .all(|prot| !prot.contains(wx)));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_elf_pick_base() {
+        serial_print!("test_elf_pick_base...");
+        let area = USER_PIE_START as u64..USER_PIE_END as u64;
+        let range = area.end - area.start;
+        assert_eq!(
+            pick_base(0, area.clone(), 0, 0x4000, 0x1000),
+            Some(USER_PIE_START as u64)
+        );
+        for &random in &[1, 0x1234_5678_9abc, u64::max_value()] {
+            let base = pick_base(random, area.clone(), 0, 0x20_0000, 0x20_0000).unwrap();
+            assert_eq!(base % 0x20_0000, 0);
+            assert!(base + 0x20_0000 <= USER_PIE_END as u64);
+        }
+        assert_eq!(pick_base(0, area, 0, range + 0x1000, 0x1000), None);
+
+        let area = USER_INTERP_START as u64..USER_INTERP_END as u64;
+        let base = pick_base(u64::max_value(), area, 0, 0x4000, 0x1000).unwrap();
+        assert!(base >= USER_INTERP_START as u64 && base + 0x4000 <= USER_INTERP_END as u64);
+        serial_println!("[ok]");
+    }
+}
@@ -1,110 +1,141 @@
+use super::elf;
+use super::mmap;
 use super::syscall;
-use crate::arch::x86_64::structures::paging::{
-    mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
-};
+use super::uaccess;
+use super::APP_ARGS;
+use super::USER_STACK_END;
+use super::{APP_BIAS, APP_IMAGE, APP_INTERP, APP_SEGMENTS};
+use crate::arch::x86_64::structures::paging::OffsetPageTable;
 use crate::memory::BootInfoFrameAllocator;
 use crate::{exit_hypervisor, HyperVisorExitCode};
 use crt0stack::{self, Builder, Entry};
-use x86_64::instructions::random::RdRand;
-use x86_64::VirtAddr;
+use linux_errno::ErrNo;
 
-const PML4_SIZE: usize = 0x0000_0080_0000_0000;
-const USER_STACK_SIZE: usize = 8 * 1024 * 1024; // 8 MB
-const USER_STACK_OFFSET: usize = PML4_SIZE * 4;
+/// Part of the user stack for the arguments, the environment and the auxiliary vector,
+/// which is mapped upfront. The rest is mapped on the first access.
+const USER_STACK_INITIAL_SIZE: usize = 64 * 1024;
 //const USER_HEAP_OFFSET: usize = PML4_SIZE;
 
+/// Exit like a shell, which cannot execute the file
+fn load_failed(what: &str, e: ErrNo) -> ! {
+    eprintln!("Failed to load the {}: {:?}", what, e);
+    exit_hypervisor(HyperVisorExitCode::Exit(126));
+    crate::hlt_loop()
+}
+
+/// The path of the ring3 executable for `/proc/self/exe`, its `argv[0]` like `AT_EXECFN`
+pub fn exe_path() -> &'static str {
+    unsafe { APP_ARGS.as_ref() }
+        .and_then(|app_args| app_args.argv().next())
+        .unwrap_or("")
+}
+
 pub fn exec_elf(
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut BootInfoFrameAllocator,
+    _mapper: &mut OffsetPageTable,
+    _frame_allocator: &mut BootInfoFrameAllocator,
     app_entry_point: *const u8,
     app_load_addr: *const u8,
     app_phnum: usize,
 ) -> ! {
-    let virt_start_addr = VirtAddr::new(USER_STACK_OFFSET as u64);
-    let start_page: Page = Page::containing_address(virt_start_addr);
-    let end_page: Page = Page::containing_address(virt_start_addr + USER_STACK_SIZE - 256u64);
-    let page_range = Page::range_inclusive(start_page, end_page);
+    // the program headers of a fixed-address executable from vmrun follow its ELF header
+    const ELF64_HDR_SIZE: usize = 0x40;
+    let (app_entry_point, app_phdr, app_phnum) = match unsafe { APP_IMAGE.take() } {
+        Some(image) => match elf::load_pie(image) {
+            Ok(app) => {
+                unsafe { APP_BIAS = app.bias as u64 };
+                (app.entry as *const u8, app.phdr, app.phnum)
+            }
+            Err(e) => load_failed("app", e),
+        },
+        None => (
+            app_entry_point,
+            app_load_addr as usize + ELF64_HDR_SIZE,
+            app_phnum,
+        ),
+    };
+    if let Err(e) = elf::protect_fixed(unsafe { APP_SEGMENTS.as_slice() }) {
+        load_failed("app", e);
+    }
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::<Size4KiB>::FrameAllocationFailed)
-            .unwrap();
-        mapper
-            .map_to(
-                page,
-                frame,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::USER_ACCESSIBLE,
-                PageTableFlags::USER_ACCESSIBLE,
-                frame_allocator,
-            )
-            .unwrap()
-            .flush();
+    // a dynamically linked app starts in its dynamic linker
+    let interp = match unsafe { APP_INTERP.take() } {
+        Some(image) => match elf::load_interp(image) {
+            Ok(interp) => Some(interp),
+            Err(e) => load_failed("dynamic linker", e),
+        },
+        None => None,
+    };
+    let entry_point = interp.map_or(app_entry_point as usize, |interp| interp.entry);
+
+    let stack_start = USER_STACK_END - USER_STACK_INITIAL_SIZE;
+    if let Err(e) = mmap::init_user_stack(USER_STACK_INITIAL_SIZE) {
+        panic!("Failed to map the user stack: {:?}", e);
     }
 
-    const ELF64_HDR_SIZE: u64 = 0x40;
     const ELF64_PHDR_SIZE: u64 = 56;
 
     let hwcap = unsafe { core::arch::x86_64::__cpuid(1) }.edx;
-    let rdrand = RdRand::new();
-    let (r1, r2) = match rdrand {
-        None => {
-            if cfg!(debug_assertions) {
-                eprintln!("!!! No RDRAND. Using pseudo random numbers!!!");
-                (0xAFFE_AFFE_AFFE_AFFE_u64, 0xC0FF_EEC0_FFEE_C0FF_u64)
-            } else {
-                panic!("No rdrand supported by CPU")
-            }
-        }
-        Some(rdrand) => (rdrand.get_u64().unwrap(), rdrand.get_u64().unwrap()),
-    };
-
     let mut ra = [0u8; 16];
-    let r1u8 = unsafe { core::slice::from_raw_parts(&r1 as *const u64 as *const u8, 8) };
-    let r2u8 = unsafe { core::slice::from_raw_parts(&r2 as *const u64 as *const u8, 8) };
-    ra[0..8].copy_from_slice(r1u8);
-    ra[8..16].copy_from_slice(r2u8);
+    if super::random::fill(&mut ra) != Ok(ra.len()) {
+        panic!("No random numbers for AT_RANDOM");
+    }
 
-    let mut sp_slice =
-        unsafe { core::slice::from_raw_parts_mut((USER_STACK_OFFSET) as *mut u8, USER_STACK_SIZE) };
+    let app_args = unsafe { APP_ARGS.as_ref().unwrap() };
+    let exec_filename = app_args.argv().next().unwrap_or("");
 
-    let mut builder = Builder::new(&mut sp_slice);
-    builder.push("/init").unwrap();
-    let mut builder = builder.done().unwrap();
-    builder.push("LANG=C").unwrap();
-    let mut builder = builder.done().unwrap();
-    for aux in &[
-        Entry::ExecFilename("/init"),
-        Entry::Platform("x86_64"),
-        Entry::Uid(1000),
-        Entry::EUid(1000),
-        Entry::Gid(1000),
-        Entry::EGid(1000),
-        Entry::PageSize(4096),
-        Entry::Secure(false),
-        Entry::ClockTick(100),
-        Entry::Flags(0),
-        Entry::PHdr((app_load_addr as u64 + ELF64_HDR_SIZE) as _),
-        Entry::PHent(ELF64_PHDR_SIZE as _),
-        Entry::PHnum(app_phnum),
-        Entry::HwCap(hwcap as _),
-        Entry::HwCap2(0),
-        Entry::Random(ra),
-    ] {
-        builder.push(aux).unwrap();
-    }
-    let handle = builder.done().unwrap();
-    let sp = handle.start_ptr() as *const () as usize;
+    // the initial stack is written in place, where the pointers in it point to
+    let sp = unsafe {
+        uaccess::with_user_access(|| {
+            let mut sp_slice =
+                core::slice::from_raw_parts_mut(stack_start as *mut u8, USER_STACK_INITIAL_SIZE);
+
+            let mut builder = Builder::new(&mut sp_slice);
+            for arg in app_args.argv() {
+                builder.push(arg).unwrap();
+            }
+            let mut builder = builder.done().unwrap();
+            for env in app_args.envp() {
+                builder.push(env).unwrap();
+            }
+            let mut builder = builder.done().unwrap();
+            for aux in &[
+                Entry::ExecFilename(exec_filename),
+                Entry::Platform("x86_64"),
+                Entry::Uid(app_args.auxv.uid as _),
+                Entry::EUid(app_args.auxv.euid as _),
+                Entry::Gid(app_args.auxv.gid as _),
+                Entry::EGid(app_args.auxv.egid as _),
+                Entry::PageSize(4096),
+                Entry::Secure(
+                    app_args.auxv.uid != app_args.auxv.euid
+                        || app_args.auxv.gid != app_args.auxv.egid,
+                ),
+                Entry::ClockTick(app_args.auxv.clock_tick as _),
+                Entry::Flags(0),
+                Entry::PHdr(app_phdr),
+                Entry::PHent(ELF64_PHDR_SIZE as _),
+                Entry::PHnum(app_phnum),
+                Entry::Base(interp.map_or(0, |interp| interp.bias)),
+                Entry::Entry(app_entry_point as usize),
+                Entry::HwCap(hwcap as _),
+                Entry::HwCap2(0),
+                Entry::Random(ra),
+            ] {
+                builder.push(aux).unwrap();
+            }
+            let handle = builder.done().unwrap();
+            handle.start_ptr() as *const () as usize
+        })
+    };
 
     #[cfg(debug_assertions)]
     {
         eprintln!("app_entry_point={:#X}", app_entry_point as u64);
-        eprintln!("app_load_addr={:#X}", app_load_addr as u64);
+        eprintln!("entry_point={:#X}", entry_point);
+        eprintln!("app_phdr={:#X}", app_phdr);
         eprintln!("app_phnum={}", app_phnum);
         eprintln!("stackpointer={:#X}", sp);
-        eprintln!("USER_STACK_OFFSET={:#X}", USER_STACK_OFFSET);
+        eprintln!("USER_STACK_END={:#X}", USER_STACK_END);
         eprintln!("\n========= APP START =============\n");
     }
 
@@ -113,8 +144,9 @@ pub fn exec_elf(
         exit_hypervisor(HyperVisorExitCode::Success);
         crate::hlt_loop()
     } else {
+        super::sched::init_main_thread();
         unsafe {
-            syscall::usermode(app_entry_point as usize, sp, 0);
+            syscall::usermode(entry_point, sp, 0);
         }
     }
 }
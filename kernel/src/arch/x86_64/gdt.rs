@@ -68,8 +68,6 @@ pub fn init() {
     #[cfg(debug_assertions)]
     eprintln!("init_gdt");
 
-    use x86_64::instructions::segmentation::set_cs;
-
     unsafe {
         TSS = Some({
             let mut tss = TaskStateSegment::new();
@@ -136,36 +134,44 @@ pub fn init() {
     }
 
     unsafe {
-        GDT = Some({
-            let mut gdt = GlobalDescriptorTable::new();
-            let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-            let data_selector = gdt.add_entry(Descriptor::UserSegment(
-                (DescriptorFlags::USER_SEGMENT
-                    | DescriptorFlags::PRESENT
-                    | DescriptorFlags::WRITABLE
-                    | DescriptorFlags::LONG_MODE)
-                    .bits(),
-            ));
-
-            let mut user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-            user_data_selector.set_rpl(PrivilegeLevel::Ring3);
-            let mut user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
-            user_code_selector.set_rpl(PrivilegeLevel::Ring3);
-            let tss_selector = gdt.add_entry(Descriptor::tss_segment(TSS.as_ref().unwrap()));
-            (
-                gdt,
-                Selectors {
-                    code_selector,
-                    data_selector,
-                    user_data_selector,
-                    user_code_selector,
-                    tss_selector,
-                },
-            )
-        });
+        GDT = Some(new_gdt(TSS.as_ref().unwrap()));
+        load(GDT.as_ref().unwrap());
     }
+}
+
+/// Create a GDT with the kernel and user segments and a descriptor for `tss`
+pub fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::UserSegment(
+        (DescriptorFlags::USER_SEGMENT
+            | DescriptorFlags::PRESENT
+            | DescriptorFlags::WRITABLE
+            | DescriptorFlags::LONG_MODE)
+            .bits(),
+    ));
+
+    let mut user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    user_data_selector.set_rpl(PrivilegeLevel::Ring3);
+    let mut user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    user_code_selector.set_rpl(PrivilegeLevel::Ring3);
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_data_selector,
+            user_code_selector,
+            tss_selector,
+        },
+    )
+}
+
+/// Load `gdt`, the segment registers and the task register on the current CPU
+pub fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::set_cs;
 
-    let gdt = unsafe { GDT.as_ref().unwrap() };
     unsafe {
         load_ss(SegmentSelector(0));
         load_ds(SegmentSelector(0));
@@ -1,10 +1,10 @@
 use super::gdt;
 use super::interrupts;
 use super::syscall;
+use super::uaccess;
 use super::xcr0::{XCr0, XCr0Flags};
 use crate::memory::BootInfoFrameAllocator;
 use vmsyscall::bootinfo::BootInfo;
-use vmsyscall::memory_map::MemoryRegionType;
 
 use crate::arch::x86_64::structures::paging::{
     mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
@@ -12,18 +12,20 @@ use crate::arch::x86_64::structures::paging::{
 
 pub use x86_64::{PhysAddr, VirtAddr};
 
+use super::APP_ARGS;
 use super::APP_ENTRY_POINT;
+use super::APP_IMAGE;
+use super::APP_INTERP;
 use super::APP_LOAD_ADDR;
 use super::APP_PH_NUM;
+use super::APP_SEGMENTS;
 use super::FRAME_ALLOCATOR;
 use super::MAPPER;
-use super::NEXT_MMAP;
+use super::PHYSICAL_MEMORY_OFFSET;
 use super::STACK_SIZE;
 use super::STACK_START;
 use crate::arch::x86_64::PAGESIZE;
 
-const PHYSICAL_MEMORY_OFFSET: u64 = 0x800_0000_0000;
-
 static mut ENTRY_POINT: Option<
     fn(
         mapper: &mut OffsetPageTable,
@@ -51,26 +53,14 @@ pub fn init(
     // NO println! before this point!!
     // *********************************
 
-    unsafe {
-        let xsave_supported = (core::arch::x86_64::__cpuid(1).ecx & (1 << 26)) != 0;
-        assert!(xsave_supported);
-
-        let xsaveopt_supported = (core::arch::x86_64::__cpuid_count(0xD, 1).eax & 1) == 1;
-        assert!(xsaveopt_supported);
-
-        let sse_extended_supported =
-            (core::arch::x86_64::__cpuid_count(0xd, 0).eax & 0b111) == 0b111;
-        if sse_extended_supported {
-            XCr0::update(|xcr0| xcr0.insert(XCr0Flags::YMM));
-        } else {
-            XCr0::update(|xcr0| xcr0.insert(XCr0Flags::SSE));
-        }
-
-        let xsave_size = core::arch::x86_64::__cpuid(0xD).ebx;
-        assert!(xsave_size < (16 * 64 - 64));
-    }
+    init_xsave();
     gdt::init();
     unsafe { syscall::init() };
+    uaccess::init();
+
+    super::time::init(&boot_info);
+    crate::syscall_policy::init(&boot_info.syscall_policy);
+    crate::trace::init(boot_info.trace_level);
 
     //    #[cfg(feature = "nightly")]
     interrupts::init();
@@ -85,21 +75,28 @@ pub fn init(
         APP_ENTRY_POINT = boot_info.entry_point;
         APP_LOAD_ADDR = boot_info.load_addr;
         APP_PH_NUM = boot_info.elf_phnum;
-    }
-
-    unsafe {
-        let e = boot_info
-            .memory_map
-            .iter()
-            .filter(|e| e.region_type == MemoryRegionType::Usable)
-            .last()
-            .unwrap();
-        assert!(e.region_type == MemoryRegionType::Usable);
-        NEXT_MMAP = e.range.start_addr();
-        eprintln!("NEXT_MMAP = {:#X}", NEXT_MMAP);
+        APP_SEGMENTS = boot_info.app_segments;
+        if boot_info.app_image != 0 {
+            APP_IMAGE.replace(core::slice::from_raw_parts(
+                (PHYSICAL_MEMORY_OFFSET + boot_info.app_image) as *const u8,
+                boot_info.app_image_len as usize,
+            ));
+        }
+        if boot_info.interp_image != 0 {
+            APP_INTERP.replace(core::slice::from_raw_parts(
+                (PHYSICAL_MEMORY_OFFSET + boot_info.interp_image) as *const u8,
+                boot_info.interp_image_len as usize,
+            ));
+        }
+        if let Err(e) = boot_info.app_args.validate() {
+            panic!("Invalid application arguments in BootInfo: {:?}", e);
+        }
+        APP_ARGS.replace(boot_info.app_args.clone());
     }
 
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(boot_info.memory_map) };
+    #[cfg(debug_assertions)]
+    eprintln!("{:?}", frame_allocator.stats());
 
     #[cfg(feature = "allocator")]
     init_heap(unsafe { MAPPER.as_mut().unwrap() }, &mut frame_allocator)
@@ -116,6 +113,28 @@ pub fn init(
     unsafe { crate::_context_switch(init_after_stack_swap, stack_pointer.as_u64() as _) }
 }
 
+/// Enable the xsave features of the current CPU, which `syscall.s` saves and restores
+pub fn init_xsave() {
+    unsafe {
+        let xsave_supported = (core::arch::x86_64::__cpuid(1).ecx & (1 << 26)) != 0;
+        assert!(xsave_supported);
+
+        let xsaveopt_supported = (core::arch::x86_64::__cpuid_count(0xD, 1).eax & 1) == 1;
+        assert!(xsaveopt_supported);
+
+        let sse_extended_supported =
+            (core::arch::x86_64::__cpuid_count(0xd, 0).eax & 0b111) == 0b111;
+        if sse_extended_supported {
+            XCr0::update(|xcr0| xcr0.insert(XCr0Flags::YMM));
+        } else {
+            XCr0::update(|xcr0| xcr0.insert(XCr0Flags::SSE));
+        }
+
+        let xsave_size = core::arch::x86_64::__cpuid(0xD).ebx;
+        assert!(xsave_size < (16 * 64 - 64));
+    }
+}
+
 #[cfg(feature = "allocator")]
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -158,8 +177,25 @@ pub fn init_stack(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<VirtAddr, MapToError<Size4KiB>> {
-    let stack_start = VirtAddr::new(STACK_START as u64);
-    let stack_end = stack_start + STACK_SIZE - 1u64;
+    let stack_pointer = map_stack(STACK_START, STACK_SIZE, mapper, frame_allocator)?;
+
+    unsafe {
+        gdt::TSS.as_mut().unwrap().privilege_stack_table[0] = stack_pointer;
+    }
+
+    Ok(stack_pointer)
+}
+
+/// Map a kernel stack of `stack_size` bytes with guard pages at `stack_start`
+/// and return the initial stack pointer
+pub fn map_stack(
+    stack_start: usize,
+    stack_size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let stack_start = VirtAddr::new(stack_start as u64);
+    let stack_end = stack_start + stack_size - 1u64;
     let stack_start_page = Page::containing_address(stack_start);
     let stack_end_page = Page::containing_address(stack_end);
 
@@ -208,13 +244,7 @@ pub fn init_stack(
 
     use core::ops::Sub;
 
-    let stack_pointer = stack_end.sub(PAGESIZE).align_down(64u64);
-
-    unsafe {
-        gdt::TSS.as_mut().unwrap().privilege_stack_table[0] = stack_pointer;
-    }
-
-    Ok(stack_pointer)
+    Ok(stack_end.sub(PAGESIZE).align_down(64u64))
 }
 
 extern "C" fn init_after_stack_swap() -> ! {
@@ -222,6 +252,9 @@ extern "C" fn init_after_stack_swap() -> ! {
     let mapper = unsafe { MAPPER.as_mut().unwrap() };
     let entry_point = unsafe { ENTRY_POINT.as_ref().unwrap() };
 
+    #[cfg(not(feature = "qemu"))]
+    super::percpu::start_aps(mapper, frame_allocator);
+
     unsafe {
         entry_point(
             mapper,
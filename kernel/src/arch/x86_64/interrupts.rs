@@ -1,6 +1,12 @@
+use super::crash::{crash, SavedRegisters};
 use super::gdt;
 use super::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{eprintln, exit_hypervisor, hlt_loop, HyperVisorExitCode};
+#[cfg(not(feature = "timer"))]
+use super::lapic;
+use super::mmap;
+use super::signal;
+use crate::eprintln;
+use x86_64::registers::control::Cr2;
 
 extern "C" {
     pub fn _isr_0(vars: &mut InterruptStackFrame);
@@ -26,12 +32,15 @@ extern "C" {
 /*
     pub fn _isr_32(vars: &mut InterruptStackFrame);
     pub fn _isr_33(vars: &mut InterruptStackFrame);
+*/
     pub fn _isr_100(vars: &mut InterruptStackFrame);
     pub fn _isr_101(vars: &mut InterruptStackFrame);
     pub fn _isr_102(vars: &mut InterruptStackFrame);
-*/
 }
 
+/// Size of the xsave area of the interrupt entry in `int_handler.s`
+pub const XSAVE_AREA_SIZE: usize = 16 * 64;
+
 pub static mut IDT: Option<InterruptDescriptorTable> = None;
 
 #[no_mangle]
@@ -39,33 +48,30 @@ pub unsafe extern "C" fn run_interrupt_fn(
     vars: &mut InterruptStackFrame,
     error_code: u64,
     irq: u64,
+    regs: &mut SavedRegisters,
+    xsave: &mut [u8; XSAVE_AREA_SIZE],
 ) {
-    println!("IRQ starts {}", irq);
+    // the APIC interrupts are frequent and must not take the print lock
+    #[cfg(not(feature = "timer"))]
+    match irq as u8 {
+        lapic::TIMER_VECTOR => return lapic::timer_interrupt(vars),
+        lapic::WAKEUP_VECTOR => return lapic::wakeup_interrupt(),
+        lapic::SPURIOUS_VECTOR => return,
+        _ => {}
+    }
+
+    // demand paging is frequent and must not take the print lock either
+    if irq == 14 && mmap::handle_page_fault(Cr2::read().as_u64() as usize, error_code) {
+        return;
+    }
+
     match irq {
-        0 => divide_error_handler(vars),
-        1 => debug_handler(vars),
         2 => non_maskable_interrupt_handler(vars),
         3 => breakpoint_handler(vars),
-        4 => overflow_handler(vars),
-        5 => bound_range_exceeded_handler(vars),
-        6 => invalid_opcode_handler(vars),
-        7 => device_not_available_handler(vars),
-        8 => double_fault_handler(vars, error_code),
-        9 => {}
-        10 => invalid_tss_handler(vars, error_code),
-        11 => segment_not_present_handler(vars, error_code),
-        12 => stack_segment_fault(vars, error_code),
-        13 => general_protection_fault(vars, error_code),
-        14 => page_fault_handler(vars, PageFaultErrorCode::from_bits_unchecked(error_code)),
-        15 => {}
-        16 => x87_floating_point_handler(vars),
-        17 => alignment_check_handler(vars, error_code),
-        18 => machine_check_handler(vars),
-        19 => simd_floating_point_handler(vars),
-        20 => virtualization_handler(vars),
-        21..=29 => {}
-        30 => security_exception_handler(vars, error_code),
-        31 => {}
+        9 | 15 | 21..=29 | 31 => {}
+        // only these exceptions push an error code
+        8 | 10..=14 | 17 | 30 => fault(irq as u8, vars, error_code, regs, xsave),
+        0..=31 => fault(irq as u8, vars, 0, regs, xsave),
         _ => panic!("Unknown int {}", irq),
     }
 
@@ -125,6 +131,14 @@ pub fn init() {
 
             #[cfg(feature = "timer")]
             crate::arch::x86_64::timer::timer_set_idt(core::mem::transmute(&mut idt));
+
+            // No interrupt stack, so the scheduler can switch threads in the timer interrupt
+            #[cfg(not(feature = "timer"))]
+            {
+                idt[lapic::TIMER_VECTOR as usize].set_handler_fn(_isr_100);
+                idt[lapic::WAKEUP_VECTOR as usize].set_handler_fn(_isr_101);
+                idt[lapic::SPURIOUS_VECTOR as usize].set_handler_fn(_isr_102);
+            }
             /*
             for i in 32..256 {
                 idt[i].set_handler_fn(unknown_interrupt_handler);
@@ -132,128 +146,38 @@ pub fn init() {
             */
             idt
         });
-        IDT.as_ref().unwrap().load();
     }
+    load();
 
     #[cfg(feature = "timer")]
     super::timer::timer_init();
 
+    // interrupts are enabled on the return to userspace
+    #[cfg(not(feature = "timer"))]
+    lapic::init();
+
     #[cfg(feature = "timer")]
     x86_64::instructions::interrupts::enable();
 }
 
-fn stack_segment_fault(stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    eprintln!("stack_segment_fault {}", error_code);
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn general_protection_fault(stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    eprintln!("general_protection_fault {:#b}", error_code);
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn segment_not_present_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    eprintln!("segment_not_present_handler {}", error_code);
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("invalid_opcode_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn divide_error_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("divide_error_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn debug_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("debug_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn overflow_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("overflow_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn bound_range_exceeded_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("bound_range_exceeded_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn device_not_available_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("device_not_available_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn x87_floating_point_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("x87_floating_point_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn alignment_check_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    eprintln!("alignment_check_handler");
-    eprintln!("Error Code: {:?}", error_code);
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn machine_check_handler(stack_frame: &mut InterruptStackFrame) -> ! {
-    eprintln!("machine_check_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn simd_floating_point_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("simd_floating_point_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn virtualization_handler(stack_frame: &mut InterruptStackFrame) {
-    eprintln!("virtualization_handler");
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
+/// Load the IDT on the current CPU
+pub fn load() {
+    unsafe { IDT.as_ref().unwrap().load() };
 }
 
-fn security_exception_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    eprintln!("security_exception_handler");
-    eprintln!("Error Code: {:?}", error_code);
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn invalid_tss_handler(stack_frame: &mut InterruptStackFrame, error_code: u64) {
-    eprintln!("invalid_tss_handler {}", error_code);
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
+/// A fault in userspace goes to the signal handler of the app, everything else is fatal
+fn fault(
+    vector: u8,
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+    regs: &mut SavedRegisters,
+    xsave: &mut [u8; XSAVE_AREA_SIZE],
+) {
+    if !signal::deliver_fault(vector, error_code, stack_frame, regs, xsave) {
+        #[cfg(debug_assertions)]
+        eprintln!("IRQ starts {}", vector);
+        crash(vector, stack_frame, error_code, regs)
+    }
 }
 
 fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
@@ -266,25 +190,6 @@ fn non_maskable_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
     eprintln!("{:#?}", stack_frame);
 }
 
-fn page_fault_handler(stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
-    use x86_64::registers::control::Cr2;
-
-    eprintln!("EXCEPTION: PAGE FAULT");
-    eprintln!("Accessed Address: {:?}", Cr2::read());
-    eprintln!("Error Code: {:?}", error_code);
-    eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
-
-fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64, // Always 0
-) -> ! {
-    eprintln!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
-    hlt_loop();
-}
 /*
 fn unknown_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
     eprintln!("EXCEPTION: unknown interrupt");
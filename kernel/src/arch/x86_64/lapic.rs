@@ -0,0 +1,188 @@
+//! The local APIC timer in x2APIC mode, which drives the scheduler and the tick count
+//!
+//! The timer of every CPU fires `TICK_HZ` times per second. Its frequency is measured
+//! against channel 2 of the PIT, which the in-kernel irqchip of KVM emulates.
+//! The clocks are kept by the TSC, see the `time` module.
+
+use super::idt::InterruptStackFrame;
+use super::percpu::cpu_id;
+use super::time::{pit_calibrate, CALIBRATION_MS};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+/// Vector of the periodic timer interrupt
+pub const TIMER_VECTOR: u8 = 100;
+/// Vector of the IPI, which wakes up halted CPUs
+pub const WAKEUP_VECTOR: u8 = 101;
+/// Vector of the spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 102;
+
+/// Timer interrupts per second
+pub const TICK_HZ: u64 = 100;
+
+const MSR_APIC_BASE: u32 = 0x1B;
+const MSR_X2APIC_TPR: u32 = 0x808;
+const MSR_X2APIC_EOI: u32 = 0x80B;
+const MSR_X2APIC_SVR: u32 = 0x80F;
+const MSR_X2APIC_ICR: u32 = 0x830;
+const MSR_X2APIC_LVT_TIMER: u32 = 0x832;
+const MSR_X2APIC_LVT_LINT0: u32 = 0x835;
+const MSR_X2APIC_LVT_LINT1: u32 = 0x836;
+const MSR_X2APIC_LVT_ERROR: u32 = 0x837;
+const MSR_X2APIC_TIMER_INIT: u32 = 0x838;
+const MSR_X2APIC_TIMER_CUR: u32 = 0x839;
+const MSR_X2APIC_TIMER_DIV: u32 = 0x83E;
+
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const SVR_APIC_ENABLE: u64 = 1 << 8;
+const LVT_MASKED: u64 = 1 << 16;
+const LVT_TIMER_PERIODIC: u64 = 1 << 17;
+const TIMER_DIV_16: u64 = 0b0011;
+const ICR_ALL_EXCLUDING_SELF: u64 = 0b11 << 18;
+
+/// KVM runs the APIC bus with 1 GHz
+const DEFAULT_BUS_HZ: u64 = 1_000_000_000;
+
+/// Whether the timer runs on the CPUs
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Initial count of the timer for `TICK_HZ`
+static INITIAL_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Timer ticks of the first CPU since the start of the timer
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn rdmsr(msr: u32) -> u64 {
+    Msr::new(msr).read()
+}
+
+unsafe fn wrmsr(msr: u32, value: u64) {
+    Msr::new(msr).write(value)
+}
+
+fn has_x2apic() -> bool {
+    unsafe { (core::arch::x86_64::__cpuid(1).ecx & (1 << 21)) != 0 }
+}
+
+/// Whether the timer interrupts and wakeup IPIs are running
+#[inline]
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Timer ticks since the start of the timer
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Switch the local APIC of the current CPU to x2APIC mode
+unsafe fn enable_x2apic() {
+    let base = rdmsr(MSR_APIC_BASE);
+    wrmsr(
+        MSR_APIC_BASE,
+        base | APIC_BASE_ENABLE | APIC_BASE_X2APIC_ENABLE,
+    );
+    wrmsr(MSR_X2APIC_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u64);
+    wrmsr(MSR_X2APIC_TPR, 0);
+
+    // only the timer and the IPIs are used, no legacy PIC
+    wrmsr(MSR_X2APIC_LVT_LINT0, LVT_MASKED);
+    wrmsr(MSR_X2APIC_LVT_LINT1, LVT_MASKED);
+    wrmsr(MSR_X2APIC_LVT_ERROR, LVT_MASKED);
+}
+
+/// Count the APIC timer cycles per second with the PIT channel 2.
+///
+/// Returns `None`, if the PIT does not count.
+unsafe fn calibrate() -> Option<u64> {
+    wrmsr(MSR_X2APIC_TIMER_DIV, TIMER_DIV_16);
+    wrmsr(MSR_X2APIC_LVT_TIMER, LVT_MASKED);
+
+    let ret = pit_calibrate(|| wrmsr(MSR_X2APIC_TIMER_INIT, u32::max_value() as u64));
+
+    let elapsed = u32::max_value() as u64 - rdmsr(MSR_X2APIC_TIMER_CUR);
+    wrmsr(MSR_X2APIC_TIMER_INIT, 0);
+
+    ret.map(|_| elapsed * 1000 / CALIBRATION_MS)
+}
+
+/// Calibrate and start the timer on the bootstrap processor
+pub fn init() {
+    if !has_x2apic() {
+        eprintln!("No x2APIC, threads are not preempted");
+        return;
+    }
+
+    let timer_hz = unsafe {
+        enable_x2apic();
+        calibrate()
+    }
+    .unwrap_or_else(|| {
+        eprintln!("PIT calibration failed, assuming the default APIC bus frequency");
+        DEFAULT_BUS_HZ / 16
+    });
+
+    #[cfg(debug_assertions)]
+    eprintln!("APIC timer: {} Hz", timer_hz);
+
+    INITIAL_COUNT.store((timer_hz / TICK_HZ).max(1), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+
+    start_timer();
+}
+
+/// Start the timer on an application processor
+pub fn init_ap() {
+    if !is_enabled() {
+        return;
+    }
+    unsafe { enable_x2apic() };
+    start_timer();
+}
+
+fn start_timer() {
+    unsafe {
+        wrmsr(MSR_X2APIC_TIMER_DIV, TIMER_DIV_16);
+        wrmsr(
+            MSR_X2APIC_LVT_TIMER,
+            LVT_TIMER_PERIODIC | TIMER_VECTOR as u64,
+        );
+        wrmsr(MSR_X2APIC_TIMER_INIT, INITIAL_COUNT.load(Ordering::Relaxed));
+    }
+}
+
+fn end_of_interrupt() {
+    unsafe { wrmsr(MSR_X2APIC_EOI, 0) };
+}
+
+/// Wake up the other CPUs, which might halt waiting for a runnable thread
+pub fn wake_other_cpus() {
+    if !is_enabled() {
+        return;
+    }
+    unsafe {
+        wrmsr(
+            MSR_X2APIC_ICR,
+            ICR_ALL_EXCLUDING_SELF | WAKEUP_VECTOR as u64,
+        )
+    };
+}
+
+/// The periodic timer interrupt.
+///
+/// Threads interrupted in userspace are preempted, when their time slice is used up.
+pub fn timer_interrupt(stack_frame: &InterruptStackFrame) {
+    if cpu_id() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // acknowledge before switching to another thread, which could run for a long time
+    end_of_interrupt();
+
+    let user_mode = stack_frame.code_segment & 3 == 3;
+    super::sched::tick(user_mode);
+}
+
+/// The wakeup IPI, which only ends the `hlt` of an idle CPU
+pub fn wakeup_interrupt() {
+    end_of_interrupt();
+}
@@ -1,133 +1,686 @@
 use crate::arch::x86_64::structures::paging::{
-    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+    mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame,
+    Size2MiB,
 };
+use crate::memory::BootInfoFrameAllocator;
+use linux_errno::ErrNo;
+use spin::Mutex;
 
-use x86_64::structures::paging::FrameDeallocator;
+use x86_64::structures::paging::{FrameDeallocator, UnusedPhysFrame};
 use x86_64::VirtAddr;
 
+use super::idt::PageFaultErrorCode;
+use super::vma::{Prot, VmaTracker};
 use super::FRAME_ALLOCATOR;
 use super::MAPPER;
-use super::NEXT_MMAP;
 use super::PAGESIZE;
+use super::PHYSICAL_MEMORY_OFFSET;
+use super::{USER_BRK_END, USER_BRK_START, USER_INTERP_END};
+use super::{USER_MMAP_END, USER_MMAP_START};
+use super::{USER_STACK_END, USER_STACK_GUARD_GAP, USER_STACK_START};
 
-// TODO: multi-thread or syscall-proxy
-pub fn mmap_user(len: usize) -> *mut u8 {
-    let virt_start_addr;
+const MAP_SHARED: i32 = 0x01;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_FIXED: i32 = 0x10;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FIXED_NOREPLACE: i32 = 0x10_0000;
+
+/// End of the identity mapping of the RAM with 2 MiB pages, see `pml2ident.s`
+const IDENTITY_MAP_END: u64 = 0x4000_0000;
+
+static mut VMAS: VmaTracker = VmaTracker::new(USER_MMAP_START, USER_MMAP_END);
+
+/// The areas outside of the `mmap` areas and the stack: the segments of the loaded ELF files
+/// and the `brk` heap
+static mut IMAGE_VMAS: VmaTracker = VmaTracker::new(0, USER_INTERP_END);
+
+/// The end of the `brk` heap
+static mut BRK: usize = USER_BRK_START;
+
+/// The stack area, which grows down on page faults, but never into the guard gap
+static mut STACK_VMAS: VmaTracker =
+    VmaTracker::new(USER_STACK_START + USER_STACK_GUARD_GAP, USER_STACK_END);
+
+/// Serializes the access to the page table and the frame allocator of all threads
+///
+/// It may be taken with the lock of the scheduler held to read a populated futex word,
+/// but the scheduler is never entered with it held.
+static MM_LOCK: Mutex<()> = Mutex::new(());
+
+/// Run `f` with the page table, the frame allocator and the `mmap` areas
+pub(super) fn with_mm<R>(
+    f: impl FnOnce(&mut OffsetPageTable, &mut BootInfoFrameAllocator, &mut VmaTracker) -> R,
+) -> R {
+    let _guard = MM_LOCK.lock();
     unsafe {
-        virt_start_addr = VirtAddr::new(NEXT_MMAP as u64);
+        let mut frame_allocator = FRAME_ALLOCATOR.take().unwrap();
+        let mut mapper = MAPPER.take().unwrap();
+        let ret = f(&mut mapper, &mut frame_allocator, &mut VMAS);
+        FRAME_ALLOCATOR.replace(frame_allocator);
+        MAPPER.replace(mapper);
+        ret
+    }
+}
+
+pub(super) fn page_flags(prot: Prot) -> PageTableFlags {
+    // PROT_NONE pages keep their frame, but are not present
+    if prot.is_empty() {
+        return PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
     }
-    let start_page: Page = Page::containing_address(virt_start_addr);
-    let end_page: Page = Page::containing_address(virt_start_addr + len - 1u64);
-    let page_range = Page::range_inclusive(start_page, end_page);
 
-    let mut frame_allocator;
-    let mut mapper;
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if prot.contains(Prot::WRITE) {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !prot.contains(Prot::EXEC) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn pages(start: usize, end: usize) -> impl Iterator<Item = Page> {
+    (start..end)
+        .step_by(PAGESIZE)
+        .map(|addr| Page::containing_address(VirtAddr::new(addr as u64)))
+}
+
+pub(super) fn map_zeroed_page(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), ErrNo> {
+    let frame = frame_allocator.allocate_frame().ok_or(ErrNo::ENOMEM)?;
+
     unsafe {
-        frame_allocator = FRAME_ALLOCATOR.take().unwrap();
-        mapper = MAPPER.take().unwrap();
+        let frame_ptr = (PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64()) as *mut u8;
+        frame_ptr.write_bytes(0u8, PAGESIZE);
     }
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::<Size4KiB>::FrameAllocationFailed)
-            .unwrap();
 
-        //println!("page {:#?} frame {:#?}", page, frame);
+    match mapper.map_to(
+        page,
+        frame,
+        flags,
+        PageTableFlags::USER_ACCESSIBLE,
+        frame_allocator,
+    ) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(MapToError::PageAlreadyMapped(frame)) => {
+            frame_allocator.deallocate_frame(frame);
+            Err(ErrNo::EEXIST)
+        }
+        Err(_) => Err(ErrNo::ENOMEM),
+    }
+}
+
+/// Copy `bytes` to the user address `addr` via the physical memory mapping,
+/// so the pages do not have to be writable
+pub(super) fn write_frames(mapper: &OffsetPageTable, addr: u64, bytes: &[u8]) -> Result<(), ErrNo> {
+    let mut done = 0;
+    while done < bytes.len() {
+        let addr = addr + done as u64;
+        let page: Page = Page::containing_address(VirtAddr::new(addr));
+        let frame = mapper.translate_page(page).map_err(|_| ErrNo::EFAULT)?;
+        let offset = addr as usize % PAGESIZE;
+        let len = core::cmp::min(PAGESIZE - offset, bytes.len() - done);
+        unsafe {
+            let dst = (PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64()) as *mut u8;
+            core::ptr::copy_nonoverlapping(bytes[done..].as_ptr(), dst.add(offset), len);
+        }
+        done += len;
+    }
+    Ok(())
+}
+
+/// Unmap `[start, end)` and return the frames to the frame allocator
+fn unmap_pages(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    start: usize,
+    end: usize,
+) {
+    for page in pages(start, end) {
+        if mapper.translate_page(page).is_err() {
+            continue;
+        }
 
+        // `unmap()` refuses the not present entries of PROT_NONE pages
         mapper
-            .map_to(
-                page,
-                frame,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::USER_ACCESSIBLE,
-                PageTableFlags::USER_ACCESSIBLE,
-                &mut frame_allocator,
-            )
-            .and_then(|f| {
-                f.flush();
-                Ok(())
-            })
-            .or_else(|e| match e {
-                MapToError::PageAlreadyMapped(f) => {
-                    frame_allocator.deallocate_frame(f);
-                    Ok(())
+            .update_flags(page, PageTableFlags::PRESENT)
+            .unwrap()
+            .ignore();
+
+        let (frame, flush) = mapper.unmap(page).unwrap();
+        flush.flush();
+        frame_allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(frame) });
+    }
+}
+
+/// Rewrite the page table flags of `[start, end)` for `prot`
+///
+/// Pages without a frame get theirs on the first access.
+fn protect_pages(mapper: &mut OffsetPageTable, start: usize, end: usize, prot: Prot) {
+    let flags = page_flags(prot);
+    for page in pages(start, end) {
+        if let Ok(flush) = mapper.update_flags(page, flags) {
+            flush.flush();
+        }
+    }
+}
+
+/// Track the page at `addr` of a loaded ELF file with `prot` for `mprotect()`
+fn track_image_page(addr: usize, prot: Prot) -> Result<(), ErrNo> {
+    let images = unsafe { &mut IMAGE_VMAS };
+    images.replace(addr, addr + PAGESIZE, prot, |_, _, _| {})
+}
+
+/// Map the page at `addr` of a loaded ELF file with `prot` and track it for `mprotect()`
+pub(super) fn protect_image_page(
+    mapper: &mut OffsetPageTable,
+    addr: usize,
+    prot: Prot,
+) -> Result<(), ErrNo> {
+    let page: Page = Page::containing_address(VirtAddr::new(addr as u64));
+    mapper
+        .update_flags(page, page_flags(prot))
+        .map_err(|_| ErrNo::EFAULT)?
+        .flush();
+    track_image_page(addr, prot)
+}
+
+/// Take the user access from the 2 MiB pages of the identity mapping of the RAM, which
+/// holds a fixed-address executable loaded by vmrun.
+///
+/// The 2 MiB pages with a page, for which `prot_of` returns a protection, are split into
+/// 4 KiB pages. Only these pages stay user accessible, with that protection, and are
+/// tracked like the segments of a loaded ELF file.
+pub(super) fn protect_identity_map(prot_of: impl Fn(u64) -> Option<Prot>) -> Result<(), ErrNo> {
+    const HUGE_PAGE_SIZE: u64 = 0x20_0000;
+    let user =
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::HUGE_PAGE;
+    let data = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    with_mm(|mapper, frame_allocator, _| {
+        for start in (0..IDENTITY_MAP_END).step_by(HUGE_PAGE_SIZE as usize) {
+            match super::crash::page_flags(start) {
+                Some(flags) if flags.contains(user) => {}
+                _ => continue,
+            }
+            let huge: Page<Size2MiB> = Page::containing_address(VirtAddr::new(start));
+
+            let split = (start..start + HUGE_PAGE_SIZE)
+                .step_by(PAGESIZE)
+                .any(|addr| prot_of(addr).is_some());
+            if !split {
+                Mapper::<Size2MiB>::update_flags(mapper, huge, data)
+                    .map_err(|_| ErrNo::EFAULT)?
+                    .flush();
+                continue;
+            }
+
+            let (frame, flush) =
+                Mapper::<Size2MiB>::unmap(mapper, huge).map_err(|_| ErrNo::EFAULT)?;
+            flush.flush();
+            for offset in (0..HUGE_PAGE_SIZE).step_by(PAGESIZE) {
+                let page: Page = Page::containing_address(VirtAddr::new(start + offset));
+                let frame: PhysFrame =
+                    PhysFrame::containing_address(frame.start_address() + offset);
+                let prot = prot_of(start + offset);
+                mapper
+                    .map_to(
+                        page,
+                        unsafe { UnusedPhysFrame::new(frame) },
+                        prot.map_or(data, page_flags),
+                        PageTableFlags::USER_ACCESSIBLE,
+                        frame_allocator,
+                    )
+                    .map_err(|_| ErrNo::ENOMEM)?
+                    .flush();
+                if let Some(prot) = prot {
+                    track_image_page((start + offset) as usize, prot)?;
                 }
-                MapToError::ParentEntryHugePage => Ok(()),
-                _ => Err(e),
-            })
-            .unwrap();
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Whether an access described by the page fault `error_code` is allowed by `prot`
+fn access_allowed(prot: Prot, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        prot.contains(Prot::WRITE)
+    } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        prot.contains(Prot::EXEC)
+    } else {
+        !prot.is_empty()
     }
+}
 
-    let ret;
-    unsafe {
-        ret = NEXT_MMAP as *mut u8;
-        ret.write_bytes(0u8, len);
+/// The protection of the area containing `addr` and the stack range to grow for it
+fn fault_area(
+    vmas: &VmaTracker,
+    images: &VmaTracker,
+    stack: &VmaTracker,
+    addr: usize,
+) -> Option<(Prot, Option<(usize, usize)>)> {
+    let vma = vmas
+        .find(addr)
+        .or_else(|| images.find(addr))
+        .or_else(|| stack.find(addr));
+    if let Some(vma) = vma {
+        return Some((vma.prot, None));
+    }
 
-        NEXT_MMAP = (virt_start_addr + len).align_up(PAGESIZE as u64).as_u64();
+    // the stack grows down to the faulting page, if it stays above the guard gap
+    let top = stack.iter().next()?;
+    let page = addr & !(PAGESIZE - 1);
+    if stack.contains_range(page, top.start) {
+        Some((top.prot, Some((page, top.start))))
+    } else {
+        None
+    }
+}
 
-        FRAME_ALLOCATOR.replace(frame_allocator);
-        MAPPER.replace(mapper);
+/// Map a zeroed frame to the page at `addr` of a lazily populated area.
+///
+/// Returns `false`, if `addr` is not part of an area or the access
+/// described by `error_code` violates its protection.
+fn populate_page(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BootInfoFrameAllocator,
+    vmas: &mut VmaTracker,
+    stack: &mut VmaTracker,
+    addr: usize,
+    error_code: PageFaultErrorCode,
+) -> bool {
+    let images = unsafe { &IMAGE_VMAS };
+    let (prot, grow) = match fault_area(vmas, images, stack, addr) {
+        Some(area) => area,
+        None => return false,
+    };
+    if !access_allowed(prot, error_code) {
+        return false;
+    }
+    if let Some((start, end)) = grow {
+        if stack.replace(start, end, prot, |_, _, _| {}).is_err() {
+            return false;
+        }
+    }
+
+    let page = Page::containing_address(VirtAddr::new(addr as u64));
+    match map_zeroed_page(mapper, frame_allocator, page, page_flags(prot)) {
+        Ok(()) => true,
+        // another thread was faster
+        Err(e) => e == ErrNo::EEXIST,
     }
-    ret
 }
 
-// TODO: muti-thread or syscall-proxy
-pub fn brk_user(len: usize) -> *mut u8 {
-    let virt_start_addr;
-    unsafe {
-        virt_start_addr = VirtAddr::new(NEXT_MMAP as u64);
+/// Handle a page fault at `addr` in the lazily populated `mmap`, heap and stack areas
+///
+/// Returns `false`, if the fault is not resolved by mapping a zeroed frame.
+/// Only faults of userspace are resolved. The kernel populates a user range before it
+/// accesses it, so a fault of the kernel is a bug, which may have happened with the page
+/// table locked.
+pub fn handle_page_fault(addr: usize, error_code: u64) -> bool {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    if !error_code.contains(PageFaultErrorCode::USER_MODE)
+        || error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        return false;
     }
-    let start_page: Page = Page::containing_address(virt_start_addr);
-    let end_page: Page = Page::containing_address(virt_start_addr + len - 1u64);
-    let page_range = Page::range_inclusive(start_page, end_page);
 
-    let mut frame_allocator;
-    let mut mapper;
-    unsafe {
-        frame_allocator = FRAME_ALLOCATOR.take().unwrap();
-        mapper = MAPPER.take().unwrap();
+    with_mm(|mapper, frame_allocator, vmas| {
+        let stack = unsafe { &mut STACK_VMAS };
+        populate_page(mapper, frame_allocator, vmas, stack, addr, error_code)
+    })
+}
+
+/// Map all pages of `[start, end)`, which have no frame yet, as if they were
+/// accessed by userspace with or without `write`.
+///
+/// Returns `false`, if a page is not part of an area or the access violates its protection,
+/// even if it is mapped, e.g. by the identity mapping of the RAM.
+pub fn populate_user(start: usize, end: usize, write: bool) -> bool {
+    with_user_range(start, end, write, || ()).is_some()
+}
+
+/// Populate `[start, end)` like `populate_user` and run `f` with the page table locked,
+/// so no other thread can unmap the range or change its protection until `f` returns.
+///
+/// `f` must not fault on the range or lock the page table itself.
+/// Returns `None` without running `f`, if `populate_user` would fail.
+pub fn with_user_range<R>(
+    start: usize,
+    end: usize,
+    write: bool,
+    f: impl FnOnce() -> R,
+) -> Option<R> {
+    // the lower half of the canonical addresses
+    if end > 0x0000_8000_0000_0000 {
+        return None;
     }
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::<Size4KiB>::FrameAllocationFailed)
-            .unwrap();
-        //println!("page {:#?} frame {:#?}", page, frame);
-        mapper
-            .map_to(
-                page,
-                frame,
-                PageTableFlags::PRESENT
-                    | PageTableFlags::WRITABLE
-                    | PageTableFlags::USER_ACCESSIBLE,
-                PageTableFlags::USER_ACCESSIBLE,
-                &mut frame_allocator,
-            )
-            .and_then(|f| {
-                f.flush();
-                Ok(())
-            })
-            .or_else(|e| match e {
-                MapToError::PageAlreadyMapped(f) => {
-                    frame_allocator.deallocate_frame(f);
-                    Ok(())
+    let mut error_code = PageFaultErrorCode::USER_MODE;
+    if write {
+        error_code |= PageFaultErrorCode::CAUSED_BY_WRITE;
+    }
+
+    with_mm(|mapper, frame_allocator, vmas| {
+        let stack = unsafe { &mut STACK_VMAS };
+        let images = unsafe { &IMAGE_VMAS };
+        let populated = pages(start & !(PAGESIZE - 1), end).all(|page| {
+            let addr = page.start_address().as_u64() as usize;
+            match fault_area(vmas, images, stack, addr) {
+                Some((prot, _)) if access_allowed(prot, error_code) => {}
+                _ => return false,
+            }
+            mapper.translate_page(page).is_ok()
+                || populate_page(mapper, frame_allocator, vmas, stack, addr, error_code)
+        });
+        if populated {
+            Some(f())
+        } else {
+            None
+        }
+    })
+}
+
+/// Reserve the stack area of the ring3 executable with its top `len` bytes populated
+pub fn init_user_stack(len: usize) -> Result<(), ErrNo> {
+    let start = USER_STACK_END - len;
+    with_mm(|_, _, _| {
+        let stack = unsafe { &mut STACK_VMAS };
+        stack.replace(
+            start,
+            USER_STACK_END,
+            Prot::READ | Prot::WRITE,
+            |_, _, _| {},
+        )
+    })?;
+
+    if populate_user(start, USER_STACK_END, true) {
+        Ok(())
+    } else {
+        Err(ErrNo::ENOMEM)
+    }
+}
+
+fn page_align_up(len: usize) -> Option<usize> {
+    len.checked_add(PAGESIZE - 1).map(|l| l & !(PAGESIZE - 1))
+}
+
+fn prot_from_raw(prot: i32) -> Result<Prot, ErrNo> {
+    Prot::from_bits(prot as u32).ok_or(ErrNo::EINVAL)
+}
+
+/// Fill the pages of the new file mapping `[start, start + len)` with the file
+/// contents, which `read` returns for an offset of the file
+///
+/// The pages after the end of the file are left to be mapped on the first access.
+fn read_pages(
+    start: usize,
+    len: usize,
+    prot: Prot,
+    offset: usize,
+    mut read: impl FnMut(usize, &mut [u8]) -> Result<usize, ErrNo>,
+) -> Result<(), ErrNo> {
+    let mut buf = [0u8; PAGESIZE];
+    for pos in (0..len).step_by(PAGESIZE) {
+        let mut filled = 0;
+        while filled < PAGESIZE {
+            match read(offset + pos + filled, &mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+
+        with_mm(|mapper, frame_allocator, _| {
+            // not accessible by userspace until the contents are copied
+            let page = Page::containing_address(VirtAddr::new((start + pos) as u64));
+            let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+            if let Err(e) = map_zeroed_page(mapper, frame_allocator, page, flags) {
+                // another thread was faster
+                if e != ErrNo::EEXIST {
+                    return Err(e);
                 }
-                MapToError::ParentEntryHugePage => Ok(()),
-                _ => Err(e),
+            }
+            write_frames(mapper, (start + pos) as u64, &buf[..filled])?;
+            protect_pages(mapper, start + pos, start + pos + PAGESIZE, prot);
+            Ok(())
+        })?;
+
+        if filled < PAGESIZE {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Map memory for the ring3 executable.
+///
+/// With `MAP_FIXED` existing mappings in the range are replaced,
+/// otherwise `addr` is only a hint. Anonymous frames are mapped on the first access.
+/// File mappings are private copies, which are filled upfront by `read` with the
+/// contents of the file at a file offset.
+pub fn mmap_user(
+    addr: usize,
+    len: usize,
+    prot: i32,
+    flags: i32,
+    offset: usize,
+    read: impl FnMut(usize, &mut [u8]) -> Result<usize, ErrNo>,
+) -> Result<usize, ErrNo> {
+    if len == 0 || flags & (MAP_SHARED | MAP_PRIVATE) == 0 {
+        return Err(ErrNo::EINVAL);
+    }
+
+    let file = flags & MAP_ANONYMOUS == 0;
+    if file && offset % PAGESIZE != 0 {
+        return Err(ErrNo::EINVAL);
+    }
+    // changes cannot be written back to the file of the host
+    if file && flags & MAP_SHARED != 0 {
+        return Err(ErrNo::ENODEV);
+    }
+
+    let prot = prot_from_raw(prot)?;
+    let len = page_align_up(len).ok_or(ErrNo::ENOMEM)?;
+
+    let start = with_mm(|mapper, frame_allocator, vmas| {
+        let start = if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+            if addr % PAGESIZE != 0 {
+                return Err(ErrNo::EINVAL);
+            }
+            let end = addr.checked_add(len).ok_or(ErrNo::ENOMEM)?;
+            if !vmas.contains_range(addr, end) {
+                return Err(ErrNo::ENOMEM);
+            }
+            if flags & MAP_FIXED == 0 && !vmas.is_free(addr, end) {
+                return Err(ErrNo::EEXIST);
+            }
+            addr
+        } else {
+            vmas.find_free(addr & !(PAGESIZE - 1), len)
+                .ok_or(ErrNo::ENOMEM)?
+        };
+        let end = start + len;
+
+        vmas.replace(start, end, prot, |s, e, _| {
+            unmap_pages(mapper, frame_allocator, s, e)
+        })?;
+
+        Ok(start)
+    })?;
+
+    if file {
+        if let Err(e) = read_pages(start, len, prot, offset, read) {
+            let _ = munmap_user(start, len);
+            return Err(e);
+        }
+    }
+    Ok(start)
+}
+
+/// Unmap memory of the ring3 executable and free its frames
+pub fn munmap_user(addr: usize, len: usize) -> Result<(), ErrNo> {
+    if addr % PAGESIZE != 0 || len == 0 {
+        return Err(ErrNo::EINVAL);
+    }
+    let end = page_align_up(len)
+        .and_then(|len| addr.checked_add(len))
+        .ok_or(ErrNo::EINVAL)?;
+
+    with_mm(|mapper, frame_allocator, vmas| {
+        vmas.remove(addr, end, |s, e, _| {
+            unmap_pages(mapper, frame_allocator, s, e)
+        })
+    })
+}
+
+/// Change the protection of memory of the ring3 executable
+///
+/// The range has to be part of the `mmap` areas, the stack or the tracked areas of the
+/// loaded ELF files and the heap, otherwise `ENOMEM` is returned like for unmapped memory.
+pub fn mprotect_user(addr: usize, len: usize, prot: i32) -> Result<(), ErrNo> {
+    if addr % PAGESIZE != 0 {
+        return Err(ErrNo::EINVAL);
+    }
+    let prot = prot_from_raw(prot)?;
+    let end = page_align_up(len)
+        .and_then(|len| addr.checked_add(len))
+        .ok_or(ErrNo::ENOMEM)?;
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    with_mm(|mapper, _, vmas| {
+        let stack = unsafe { &mut STACK_VMAS };
+        let images = unsafe { &mut IMAGE_VMAS };
+        let area = if addr >= USER_MMAP_START && end <= USER_MMAP_END {
+            vmas
+        } else if addr >= USER_STACK_START && end <= USER_STACK_END {
+            stack
+        } else if end <= USER_INTERP_END {
+            images
+        } else {
+            return Err(ErrNo::ENOMEM);
+        };
+        area.protect(addr, end, prot, |s, e, _| protect_pages(mapper, s, e, prot))
+    })
+}
+
+/// `brk()`: move the end of the heap of the ring3 executable to `addr` and return the end
+///
+/// The heap starts at `USER_BRK_START`, its zeroed pages are mapped on the first access.
+/// Like Linux, the current end is returned, if `addr` is out of range or the heap can't grow.
+pub fn brk_user(addr: usize) -> usize {
+    with_mm(|mapper, frame_allocator, _| {
+        let images = unsafe { &mut IMAGE_VMAS };
+        let brk = unsafe { &mut BRK };
+        if addr < USER_BRK_START || addr > USER_BRK_END {
+            return *brk;
+        }
+
+        // the page aligned ends are at most `USER_BRK_END`
+        let old_end = page_align_up(*brk).unwrap();
+        let new_end = page_align_up(addr).unwrap();
+        let resized = if new_end > old_end {
+            images.replace(old_end, new_end, Prot::READ | Prot::WRITE, |_, _, _| {})
+        } else {
+            images.remove(new_end, old_end, |s, e, _| {
+                unmap_pages(mapper, frame_allocator, s, e)
             })
-            .unwrap();
+        };
+        if resized.is_ok() {
+            *brk = addr;
+        }
+        *brk
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    const STACK_LIMIT: usize = USER_STACK_START + USER_STACK_GUARD_GAP;
+
+    #[test_case]
+    fn test_mmap_access_allowed() {
+        serial_print!("test_mmap_access_allowed...");
+        let rw = Prot::READ | Prot::WRITE;
+        let write = PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE;
+        let fetch = PageFaultErrorCode::INSTRUCTION_FETCH;
+        assert!(access_allowed(Prot::READ, PageFaultErrorCode::empty()));
+        assert!(!access_allowed(Prot::READ, write));
+        assert!(access_allowed(rw, write));
+        assert!(!access_allowed(rw, fetch));
+        assert!(access_allowed(Prot::READ | Prot::EXEC, fetch));
+        assert!(!access_allowed(Prot::empty(), PageFaultErrorCode::empty()));
+        serial_println!("[ok]");
     }
 
-    let ret;
-    unsafe {
-        ret = NEXT_MMAP as *mut u8;
-        NEXT_MMAP += len as u64;
-        ret.write_bytes(0u8, len);
+    #[test_case]
+    fn test_mmap_fault_area() {
+        serial_print!("test_mmap_fault_area...");
+        let mut vmas = VmaTracker::new(USER_MMAP_START, USER_MMAP_END);
+        vmas.replace(
+            USER_MMAP_START,
+            USER_MMAP_START + 0x2000,
+            Prot::READ,
+            |_, _, _| {},
+        )
+        .unwrap();
+        let rw = Prot::READ | Prot::WRITE;
+        let mut stack = VmaTracker::new(STACK_LIMIT, USER_STACK_END);
+        let top = USER_STACK_END - 0x1000;
+        stack
+            .replace(top, USER_STACK_END, rw, |_, _, _| {})
+            .unwrap();
+        let mut images = VmaTracker::new(0, USER_INTERP_END);
+        images
+            .replace(USER_BRK_START, USER_BRK_START + 0x1000, rw, |_, _, _| {})
+            .unwrap();
 
-        FRAME_ALLOCATOR.replace(frame_allocator);
-        MAPPER.replace(mapper);
+        assert_eq!(
+            fault_area(&vmas, &images, &stack, USER_MMAP_START + 0x1008),
+            Some((Prot::READ, None))
+        );
+        assert_eq!(
+            fault_area(&vmas, &images, &stack, USER_MMAP_START + 0x2000),
+            None
+        );
+        assert_eq!(
+            fault_area(&vmas, &images, &stack, top + 8),
+            Some((rw, None))
+        );
+        assert_eq!(
+            fault_area(&vmas, &images, &stack, USER_BRK_START + 8),
+            Some((rw, None))
+        );
+        assert_eq!(
+            fault_area(&vmas, &images, &stack, USER_BRK_START + 0x1000),
+            None
+        );
+
+        // growth down to the faulting page, but not into the guard gap
+        assert_eq!(
+            fault_area(&vmas, &images, &stack, top - 0x1ff8),
+            Some((rw, Some((top - 0x2000, top))))
+        );
+        assert_eq!(
+            fault_area(&vmas, &images, &stack, STACK_LIMIT),
+            Some((rw, Some((STACK_LIMIT, top))))
+        );
+        assert_eq!(fault_area(&vmas, &images, &stack, STACK_LIMIT - 8), None);
+        assert_eq!(fault_area(&vmas, &images, &stack, USER_STACK_END), None);
+        serial_println!("[ok]");
     }
-    ret
 }
@@ -1,6 +1,7 @@
 #[macro_use]
 pub mod serial;
 
+pub mod crash;
 pub mod gdt;
 
 pub mod idt;
@@ -13,19 +14,32 @@ pub mod syscall;
 #[cfg(feature = "timer")]
 pub mod timer;
 
+mod elf;
 mod exec;
-pub use exec::exec_elf;
+pub use exec::{exe_path, exec_elf};
 
 mod init;
 pub use init::init;
 
+pub mod lapic;
+pub mod time;
+
+pub mod percpu;
+pub mod random;
+pub mod sched;
+pub mod signal;
+pub mod uaccess;
+
 mod mmap;
-pub use mmap::{brk_user, mmap_user};
+pub use mmap::{brk_user, mmap_user, mprotect_user, munmap_user};
+
+pub mod vma;
 
 mod xcr0;
 
 use crate::arch::x86_64::structures::paging::OffsetPageTable;
 use crate::memory::BootInfoFrameAllocator;
+use vmsyscall::bootinfo::{AppArgs, AppSegments};
 pub use x86_64::{PhysAddr, VirtAddr};
 
 /// Defines the entry point function.
@@ -51,15 +65,52 @@ pub const PAGESIZE: usize = 4096;
 pub const STACK_START: usize = 0x7F48_4800_0000;
 pub const STACK_SIZE: usize = 1 * 1024 * 1024; // 100 KiB
 
+/// The complete physical memory is mapped at this offset
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0x800_0000_0000;
+
+/// Size of the address range covered by one PML4 entry
+pub const PML4_SIZE: usize = 0x0000_0080_0000_0000;
+/// Start of the address range for the `brk` heap of the ring3 executable,
+/// above the identity mapping of the RAM
+pub const USER_BRK_START: usize = PML4_SIZE / 2;
+/// End of the address range for the `brk` heap of the ring3 executable
+pub const USER_BRK_END: usize = PML4_SIZE;
+/// Start of the address range for a position independent ring3 executable
+pub const USER_PIE_START: usize = PML4_SIZE;
+/// End of the address range for a position independent ring3 executable
+pub const USER_PIE_END: usize = PML4_SIZE * 2;
+/// Start of the address range for the `mmap` areas of the ring3 executable
+pub const USER_MMAP_START: usize = PML4_SIZE * 2;
+/// End of the address range for the `mmap` areas of the ring3 executable
+pub const USER_MMAP_END: usize = PML4_SIZE * 3;
+/// Start of the address range for the dynamic linker of the ring3 executable
+pub const USER_INTERP_START: usize = PML4_SIZE * 3;
+/// End of the address range for the dynamic linker of the ring3 executable
+pub const USER_INTERP_END: usize = PML4_SIZE * 4;
+/// Start of the address range for the stack of the ring3 executable
+pub const USER_STACK_START: usize = PML4_SIZE * 4;
+/// Gap at `USER_STACK_START`, which the stack never grows into
+pub const USER_STACK_GUARD_GAP: usize = 256 * PAGESIZE;
+/// Maximum size of the stack of the ring3 executable
+pub const USER_STACK_SIZE: usize = 8 * 1024 * 1024; // 8 MiB
+/// End of the stack of the ring3 executable, which grows down from here
+pub const USER_STACK_END: usize = USER_STACK_START + USER_STACK_GUARD_GAP + USER_STACK_SIZE;
+
 static mut APP_ENTRY_POINT: *const u8 = core::ptr::null();
 static mut APP_LOAD_ADDR: *const u8 = core::ptr::null();
 static mut APP_PH_NUM: usize = 0;
+/// The `PT_LOAD` segments of a fixed-address ring3 executable
+static mut APP_SEGMENTS: AppSegments = AppSegments::new();
+/// The ELF file of a position independent ring3 executable, which is not loaded yet
+static mut APP_IMAGE: Option<&'static [u8]> = None;
+/// The ELF file of the dynamic linker of the ring3 executable, which is not loaded yet
+static mut APP_INTERP: Option<&'static [u8]> = None;
+/// The offset of the load addresses of the ring3 executable to its linked addresses
+static mut APP_BIAS: u64 = 0;
+static mut APP_ARGS: Option<AppArgs> = None;
 static mut FRAME_ALLOCATOR: Option<BootInfoFrameAllocator> = None;
 static mut MAPPER: Option<OffsetPageTable> = None;
 
-// TODO: multi-thread or syscall-proxy
-pub static mut NEXT_MMAP: u64 = 0;
-
 #[cfg(feature = "allocator")]
 pub const HEAP_START: usize = 0x7F4E_4300_0000;
 #[cfg(feature = "allocator")]
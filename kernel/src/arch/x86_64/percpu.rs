@@ -0,0 +1,158 @@
+//! Per CPU state and the start of the application processors
+//!
+//! The number of the current CPU is stored in the `TSC_AUX` MSR and read with `rdtscp`.
+//! Every application processor gets its own GDT, TSS and kernel stack.
+
+use super::gdt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::tss::TaskStateSegment;
+
+pub use vmsyscall::bootinfo::MAX_CPUS;
+
+/// `IA32_TSC_AUX`
+const MSR_TSC_AUX: u32 = 0xC000_0103;
+
+/// Whether `TSC_AUX` holds the CPU number
+static HAS_CPU_ID: AtomicBool = AtomicBool::new(false);
+
+/// Number of CPUs, which finished their initialization
+pub static CPUS_ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// The number of the current CPU
+#[inline]
+pub fn cpu_id() -> usize {
+    if !HAS_CPU_ID.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let mut aux = 0u32;
+    unsafe { core::arch::x86_64::__rdtscp(&mut aux) };
+    aux as usize
+}
+
+fn has_rdtscp() -> bool {
+    unsafe { (core::arch::x86_64::__cpuid(0x8000_0001).edx & (1 << 27)) != 0 }
+}
+
+/// Store the number of the current CPU in `TSC_AUX`
+fn set_cpu_id(id: usize) {
+    unsafe { Msr::new(MSR_TSC_AUX).write(id as u64) };
+}
+
+/// The descriptor tables of an application processor
+struct CpuTables {
+    id: usize,
+    tss: TaskStateSegment,
+    gdt: Option<(GlobalDescriptorTable, gdt::Selectors)>,
+}
+
+#[cfg(not(feature = "qemu"))]
+mod start {
+    use super::*;
+    use crate::arch::x86_64::init::{init_xsave, map_stack};
+    use crate::arch::x86_64::structures::paging::OffsetPageTable;
+    use crate::arch::x86_64::{
+        interrupts, lapic, syscall, uaccess, PHYSICAL_MEMORY_OFFSET, STACK_SIZE, STACK_START,
+    };
+    use crate::eprintln;
+    use crate::memory::BootInfoFrameAllocator;
+    use x86_64::structures::paging::{FrameAllocator, Size4KiB};
+    use x86_64::VirtAddr;
+
+    /// Number of pages of every interrupt stack
+    const IST_PAGES: usize = 5;
+
+    /// Start all application processors one after another and wait until they are online
+    pub fn start_aps(mapper: &mut OffsetPageTable, frame_allocator: &mut BootInfoFrameAllocator) {
+        let nr_cpus = unsafe { crate::arch::NR_CPUS };
+        if nr_cpus < 2 {
+            return;
+        }
+
+        if !has_rdtscp() {
+            eprintln!("No RDTSCP support, using only one CPU");
+            return;
+        }
+
+        set_cpu_id(0);
+        HAS_CPU_ID.store(true, Ordering::Relaxed);
+
+        for cpu in 1..nr_cpus {
+            let stack_pointer = map_stack(
+                STACK_START + cpu * STACK_SIZE,
+                STACK_SIZE,
+                mapper,
+                frame_allocator,
+            )
+            .expect("AP stack initialization failed");
+            let tables = new_cpu_tables(cpu, stack_pointer, frame_allocator);
+
+            // enter `ap_main()` as if it was called
+            if let Err(e) = crate::libc::start_cpu(
+                cpu as u32,
+                ap_main as usize as u64,
+                stack_pointer.as_u64() - 8,
+                tables as *const CpuTables as u64,
+            ) {
+                eprintln!("Failed to start CPU {}: {:?}", cpu, e);
+                return;
+            }
+
+            while CPUS_ONLINE.load(Ordering::Acquire) <= cpu {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+    }
+
+    /// Allocate and fill the descriptor tables of CPU `id` with its kernel `stack_pointer`
+    fn new_cpu_tables(
+        id: usize,
+        stack_pointer: VirtAddr,
+        frame_allocator: &mut BootInfoFrameAllocator,
+    ) -> &'static CpuTables {
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+            .expect("no frame for the CPU tables");
+
+        let mut tss = TaskStateSegment::new();
+        tss.privilege_stack_table[0] = stack_pointer;
+        for ist in tss.interrupt_stack_table.iter_mut() {
+            let stack = frame_allocator
+                .allocate_contiguous(IST_PAGES)
+                .expect("no frames for the interrupt stacks");
+            *ist = VirtAddr::new(PHYSICAL_MEMORY_OFFSET + stack.end.start_address().as_u64())
+                .align_down(64u64);
+        }
+
+        let tables = (PHYSICAL_MEMORY_OFFSET + frame.start_address().as_u64()) as *mut CpuTables;
+        unsafe {
+            tables.write(CpuTables { id, tss, gdt: None });
+            let tss: &'static TaskStateSegment = &*(&(*tables).tss as *const _);
+            (*tables).gdt = Some(gdt::new_gdt(tss));
+            &*tables
+        }
+    }
+
+    /// The first Rust function of an application processor
+    extern "C" fn ap_main(tables: &'static CpuTables) -> ! {
+        set_cpu_id(tables.id);
+        init_xsave();
+
+        let gdt = tables.gdt.as_ref().unwrap();
+        gdt::load(gdt);
+        unsafe { syscall::init_cpu(gdt, &tables.tss) };
+        uaccess::init();
+        interrupts::load();
+        lapic::init_ap();
+
+        CPUS_ONLINE.fetch_add(1, Ordering::Release);
+
+        #[cfg(debug_assertions)]
+        eprintln!("CPU {} online", tables.id);
+
+        super::super::sched::run_idle()
+    }
+}
+
+#[cfg(not(feature = "qemu"))]
+pub use start::start_aps;
@@ -0,0 +1,134 @@
+//! Random numbers from the CPU with the host as fallback
+//!
+//! `RDRAND` is tried first, then `RDSEED`. Both can fail transiently, when the entropy
+//! source of the CPU is drained, so every instruction is retried a few times.
+//! If the CPU has neither or they keep failing, the host fills the rest.
+
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdrand64_step, _rdseed64_step};
+use core::sync::atomic::{spin_loop_hint, AtomicU8, Ordering};
+
+/// Retries of a failing `RDRAND` or `RDSEED`, as recommended by Intel
+const RETRIES: usize = 10;
+
+const FEATURES_UNKNOWN: u8 = 1 << 7;
+const FEATURE_RDRAND: u8 = 1 << 0;
+const FEATURE_RDSEED: u8 = 1 << 1;
+
+/// The random instructions of the CPU, probed on first use
+static FEATURES: AtomicU8 = AtomicU8::new(FEATURES_UNKNOWN);
+
+fn features() -> u8 {
+    let features = FEATURES.load(Ordering::Relaxed);
+    if features & FEATURES_UNKNOWN == 0 {
+        return features;
+    }
+
+    let mut features = 0;
+    unsafe {
+        if __cpuid(1).ecx & (1 << 30) != 0 {
+            features |= FEATURE_RDRAND;
+        }
+        if __cpuid(0).eax >= 7 && __cpuid_count(7, 0).ebx & (1 << 18) != 0 {
+            features |= FEATURE_RDSEED;
+        }
+    }
+    FEATURES.store(features, Ordering::Relaxed);
+    features
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand64() -> Option<u64> {
+    let mut val = 0;
+    for _ in 0..RETRIES {
+        if _rdrand64_step(&mut val) == 1 {
+            return Some(val);
+        }
+        spin_loop_hint();
+    }
+    None
+}
+
+#[target_feature(enable = "rdseed")]
+unsafe fn rdseed64() -> Option<u64> {
+    let mut val = 0;
+    for _ in 0..RETRIES {
+        if _rdseed64_step(&mut val) == 1 {
+            return Some(val);
+        }
+        spin_loop_hint();
+    }
+    None
+}
+
+/// A random `u64` from the CPU, if it has `RDRAND` or `RDSEED` and they deliver
+pub fn cpu_u64() -> Option<u64> {
+    let features = features();
+    let mut val = None;
+    if features & FEATURE_RDRAND != 0 {
+        val = unsafe { rdrand64() };
+    }
+    if val.is_none() && features & FEATURE_RDSEED != 0 {
+        val = unsafe { rdseed64() };
+    }
+    val
+}
+
+/// Fill `buf` from the CPU and return the number of bytes filled.
+///
+/// Less than `buf.len()` bytes are filled, if the CPU runs out of entropy.
+pub fn fill_cpu(buf: &mut [u8]) -> usize {
+    let mut done = 0;
+    for chunk in buf.chunks_mut(8) {
+        match cpu_u64() {
+            Some(val) => {
+                chunk.copy_from_slice(&val.to_ne_bytes()[..chunk.len()]);
+                done += chunk.len();
+            }
+            None => break,
+        }
+    }
+    done
+}
+
+/// Fill `buf` from the CPU and the rest from the host.
+///
+/// Returns the number of bytes filled, which is only less than `buf.len()`,
+/// if the host fails after some bytes.
+#[cfg(not(feature = "qemu"))]
+pub fn fill(buf: &mut [u8]) -> Result<usize, vmsyscall::Error> {
+    let mut done = fill_cpu(buf);
+    while done < buf.len() {
+        match crate::libc::getrandom(&mut buf[done..]) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(e) if done == 0 => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(done)
+}
+
+/// Fill `buf` from the CPU, there is no host to fall back to.
+#[cfg(feature = "qemu")]
+pub fn fill(buf: &mut [u8]) -> Result<usize, vmsyscall::Error> {
+    match fill_cpu(buf) {
+        0 if !buf.is_empty() => Err(vmsyscall::Error::Errno(linux_errno::ErrNo::EIO.into())),
+        n => Ok(n),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_fill() {
+        serial_print!("test_fill...");
+        let mut buf = [0u8; 61];
+        assert_eq!(fill(&mut buf), Ok(61));
+        // 488 random bits are never all zero
+        assert!(buf.iter().any(|b| *b != 0));
+        serial_println!("[ok]");
+    }
+}
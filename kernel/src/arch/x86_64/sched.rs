@@ -0,0 +1,720 @@
+//! Threads of the ring3 executable and a round robin scheduler
+//!
+//! Every thread has its own kernel stack, on which `_syscall_enter` saves the user
+//! registers in a `SyscallFrame`. Threads are only switched in the kernel by exchanging
+//! the kernel stacks with `_switch_stack`, so a thread always returns to userspace
+//! through the normal syscall return path or the return path of the timer interrupt.
+//!
+//! The timer interrupt preempts a thread in userspace after `TIME_SLICE_TICKS` ticks.
+//! A CPU without a runnable thread halts on the kernel stack of its last thread until
+//! the next interrupt. Sleeping threads and futex waits with a timeout become runnable
+//! on the first scheduling decision after their deadline, so their resolution is a tick.
+
+use super::init::map_stack;
+use super::lapic;
+use super::mmap::with_mm;
+use super::percpu::{cpu_id, MAX_CPUS};
+use super::signal::ThreadSignals;
+use super::syscall::SyscallFrame;
+use super::time::monotonic_ns;
+use super::uaccess::{check_user, UserPtr};
+use super::{STACK_SIZE, STACK_START};
+use crate::{exit_hypervisor, HyperVisorExitCode};
+use core::mem::size_of;
+use core::sync::atomic::spin_loop_hint;
+use linux_errno::ErrNo;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::registers::model_specific::KernelGsBase;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+extern "C" {
+    fn _switch_stack(old_sp: *mut u64, new_sp: u64, old_on_cpu: *mut bool);
+    fn _syscall_return();
+    fn _rdfsbase() -> u64;
+    fn _wrfsbase(val: u64);
+}
+
+/// Maximum number of threads
+pub const MAX_THREADS: usize = 128;
+
+/// Thread id of the initial thread, which is also the process id
+pub const MAIN_TID: u32 = 1;
+
+/// Timer ticks a thread runs in userspace, before it is preempted
+const TIME_SLICE_TICKS: u32 = 2;
+
+/// Size of the kernel stack of a thread including the guard pages
+const THREAD_STACK_SIZE: usize = 128 * 1024;
+/// The kernel stacks of the threads follow the stacks of the CPUs
+const THREAD_STACKS_START: usize = STACK_START + MAX_CPUS * STACK_SIZE;
+
+const CLONE_VM: usize = 0x0000_0100;
+const CLONE_SETTLS: usize = 0x0008_0000;
+const CLONE_PARENT_SETTID: usize = 0x0010_0000;
+const CLONE_CHILD_CLEARTID: usize = 0x0020_0000;
+const CLONE_THREAD: usize = 0x0001_0000;
+const CLONE_CHILD_SETTID: usize = 0x0100_0000;
+
+/// The bitset of `FUTEX_WAIT` and `FUTEX_WAKE`, which matches every waiter
+pub const FUTEX_BITSET_MATCH_ANY: u32 = u32::max_value();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Free,
+    /// Reserved by `clone_thread()`, which sets up the thread without the lock
+    Created,
+    Runnable,
+    Running,
+    /// Waiting on the futex at the address for a wakeup with a bit of the bitset
+    FutexWait(usize, u32),
+    /// Waiting for `wake_at`
+    Sleeping,
+    Exited,
+}
+
+#[derive(Clone, Copy)]
+struct Thread {
+    tid: u32,
+    state: State,
+    /// Whether a CPU still uses the kernel stack of this thread.
+    ///
+    /// Cleared by `_switch_stack` after the stack pointer is saved.
+    on_cpu: bool,
+    kernel_sp: u64,
+    /// `0`, if no kernel stack is mapped for this slot yet
+    kernel_stack_top: u64,
+    fs_base: u64,
+    clear_child_tid: usize,
+    /// Timer ticks since the thread got the CPU
+    ticks: u32,
+    /// `CLOCK_MONOTONIC` deadline of a sleep or futex wait in nanoseconds, `0` for none
+    wake_at: u64,
+    /// Whether the last wait ended by reaching `wake_at`
+    timed_out: bool,
+    /// The signal mask and the alternate signal stack
+    signals: ThreadSignals,
+}
+
+impl Thread {
+    const fn empty() -> Self {
+        Thread {
+            tid: 0,
+            state: State::Free,
+            on_cpu: false,
+            kernel_sp: 0,
+            kernel_stack_top: 0,
+            fs_base: 0,
+            clear_child_tid: 0,
+            ticks: 0,
+            wake_at: 0,
+            timed_out: false,
+            signals: ThreadSignals::new(),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self.state {
+            State::Free | State::Exited => false,
+            _ => true,
+        }
+    }
+}
+
+struct Scheduler {
+    threads: [Thread; MAX_THREADS],
+    /// The thread slot running on each CPU
+    current: [Option<usize>; MAX_CPUS],
+    next_tid: u32,
+    /// Slot of the last picked thread
+    last: usize,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Scheduler {
+            threads: [Thread::empty(); MAX_THREADS],
+            current: [None; MAX_CPUS],
+            next_tid: MAIN_TID,
+            last: 0,
+        }
+    }
+
+    /// The slot of the thread on the current CPU
+    fn current(&self) -> usize {
+        self.current[cpu_id()].expect("no thread on this CPU")
+    }
+
+    /// A free slot, whose kernel stack is not in use anymore
+    fn alloc(&self) -> Option<usize> {
+        self.threads.iter().position(|t| !t.is_alive() && !t.on_cpu)
+    }
+
+    /// Make the waiting threads runnable, whose deadline is before `now`
+    fn expire(&mut self, now: u64) {
+        for t in self.threads.iter_mut() {
+            if t.wake_at == 0 || t.wake_at > now {
+                continue;
+            }
+            match t.state {
+                State::FutexWait(..) | State::Sleeping => {
+                    t.state = State::Runnable;
+                    t.wake_at = 0;
+                    t.timed_out = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// The next runnable thread after the last picked one, which no CPU uses,
+    /// at the `CLOCK_MONOTONIC` time `now`
+    fn pick(&mut self, now: u64) -> Option<usize> {
+        self.expire(now);
+        let next = (1..=MAX_THREADS)
+            .map(|i| (self.last + i) % MAX_THREADS)
+            .find(|&i| self.threads[i].state == State::Runnable && !self.threads[i].on_cpu)?;
+        self.last = next;
+        Some(next)
+    }
+
+    /// Make up to `count` threads waiting on `uaddr` runnable, whose bitset has a bit
+    /// of `bitset`
+    fn wake(&mut self, uaddr: usize, count: usize, bitset: u32) -> usize {
+        let mut woken = 0;
+        for t in self.threads.iter_mut() {
+            if woken == count {
+                break;
+            }
+            match t.state {
+                State::FutexWait(addr, bits) if addr == uaddr && bits & bitset != 0 => {
+                    t.state = State::Runnable;
+                    t.wake_at = 0;
+                    woken += 1;
+                }
+                _ => {}
+            }
+        }
+        woken
+    }
+
+    /// Account a timer tick to the thread on `cpu` and return its slot, if the thread
+    /// is in `user_mode` and its time slice is used up
+    fn tick(&mut self, cpu: usize, user_mode: bool) -> Option<usize> {
+        let cur = self.current[cpu]?;
+        let t = &mut self.threads[cur];
+        t.ticks = t.ticks.saturating_add(1);
+        if !user_mode || t.ticks < TIME_SLICE_TICKS {
+            return None;
+        }
+        t.ticks = 0;
+        Some(cur)
+    }
+}
+
+static SCHED: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+
+/// The top of the kernel stack of the current CPU, which `_syscall_enter` uses
+fn kernel_stack() -> u64 {
+    // KernelGsBase points to the TSS of the current CPU, see `syscall::init_cpu()`
+    unsafe {
+        let tss = KernelGsBase::read().as_ptr::<TaskStateSegment>();
+        (*tss).privilege_stack_table[0].as_u64()
+    }
+}
+
+fn set_kernel_stack(top: u64) {
+    unsafe {
+        let tss = KernelGsBase::read().as_mut_ptr::<TaskStateSegment>();
+        (*tss).privilege_stack_table[0] = VirtAddr::new(top);
+    }
+}
+
+/// Register the initial thread of the executable, which runs on the current CPU
+pub fn init_main_thread() {
+    let mut sched = SCHED.lock();
+    sched.threads[0] = Thread {
+        tid: MAIN_TID,
+        state: State::Running,
+        on_cpu: true,
+        kernel_sp: 0,
+        kernel_stack_top: kernel_stack(),
+        fs_base: 0,
+        clear_child_tid: 0,
+        ticks: 0,
+        wake_at: 0,
+        timed_out: false,
+        signals: ThreadSignals::new(),
+    };
+    sched.current[cpu_id()] = Some(0);
+    sched.next_tid = MAIN_TID + 1;
+}
+
+/// Continue with thread `next` on the current CPU.
+///
+/// Returns, when `cur` runs again. Without `cur`, the current context is abandoned.
+fn switch_to(mut sched: MutexGuard<Scheduler>, cur: Option<usize>, next: usize) {
+    let mut idle_sp = 0u64;
+    let mut idle_on_cpu = true;
+
+    sched.threads[next].state = State::Running;
+    sched.threads[next].on_cpu = true;
+    sched.threads[next].ticks = 0;
+    sched.current[cpu_id()] = Some(next);
+
+    let (old_sp, old_on_cpu) = match cur {
+        Some(cur) => {
+            sched.threads[cur].fs_base = unsafe { _rdfsbase() };
+            let t = &mut sched.threads[cur];
+            (&mut t.kernel_sp as *mut u64, &mut t.on_cpu as *mut bool)
+        }
+        None => (&mut idle_sp as *mut u64, &mut idle_on_cpu as *mut bool),
+    };
+
+    let new_sp = sched.threads[next].kernel_sp;
+    unsafe { _wrfsbase(sched.threads[next].fs_base) };
+    set_kernel_stack(sched.threads[next].kernel_stack_top);
+
+    // No other CPU touches `kernel_sp` of the old thread, until `_switch_stack`
+    // cleared its `on_cpu`.
+    drop(sched);
+    unsafe { _switch_stack(old_sp, new_sp, old_on_cpu) };
+}
+
+/// Give up the CPU, after the caller changed the state of the current thread.
+///
+/// Returns, when the current thread is running again.
+fn schedule(mut sched: MutexGuard<Scheduler>) {
+    let cur = sched.current();
+    loop {
+        if let Some(next) = sched.pick(monotonic_ns()) {
+            switch_to(sched, Some(cur), next);
+            return;
+        }
+
+        if sched.threads[cur].state == State::Runnable {
+            sched.threads[cur].state = State::Running;
+            return;
+        }
+
+        // nothing to run, wait on the stack of the current thread
+        drop(sched);
+        wait_for_interrupt();
+        sched = SCHED.lock();
+    }
+}
+
+/// Wait for a timer tick or a wakeup IPI with interrupts disabled before and after.
+///
+/// Without the APIC timer, only spin.
+fn wait_for_interrupt() {
+    if lapic::is_enabled() {
+        interrupts::enable_interrupts_and_hlt();
+        interrupts::disable();
+    } else {
+        spin_loop_hint();
+    }
+}
+
+/// Run the threads of the executable on a CPU, which has not run any thread yet
+pub fn run_idle() -> ! {
+    loop {
+        without_interrupts(|| {
+            let mut sched = SCHED.lock();
+            if let Some(next) = sched.pick(monotonic_ns()) {
+                switch_to(sched, None, next);
+            }
+        });
+        wait_for_interrupt();
+    }
+}
+
+/// A timer tick on the current CPU, called with interrupts disabled.
+///
+/// A thread interrupted in `user_mode` is preempted, if its time slice is used up.
+/// In the kernel a thread keeps the CPU, until it returns to userspace.
+pub fn tick(user_mode: bool) {
+    let mut sched = SCHED.lock();
+    if let Some(cur) = sched.tick(cpu_id(), user_mode) {
+        sched.threads[cur].state = State::Runnable;
+        schedule(sched);
+    }
+}
+
+/// The thread id of the current thread
+pub fn current_tid() -> u32 {
+    without_interrupts(|| {
+        let sched = SCHED.lock();
+        sched.threads[sched.current()].tid
+    })
+}
+
+/// `set_tid_address()`: clear `*tidptr` and wake it, when the current thread exits
+pub fn set_clear_child_tid(tidptr: UserPtr<u32>) -> u32 {
+    without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        let cur = sched.current();
+        sched.threads[cur].clear_child_tid = tidptr.addr();
+        sched.threads[cur].tid
+    })
+}
+
+/// Run `f` with the signal state of the current thread
+pub fn with_signals<R>(f: impl FnOnce(&mut ThreadSignals) -> R) -> R {
+    without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        let cur = sched.current();
+        f(&mut sched.threads[cur].signals)
+    })
+}
+
+/// `clone()` a new thread, which returns to userspace with the registers of `frame`,
+/// `0` in `rax` and the stack pointer `stack`.
+///
+/// Only threads sharing the address space are supported.
+pub fn clone_thread(
+    frame: &SyscallFrame,
+    flags: usize,
+    stack: usize,
+    ptid: UserPtr<u32>,
+    ctid: UserPtr<u32>,
+    tls: usize,
+) -> Result<u32, ErrNo> {
+    if flags & (CLONE_VM | CLONE_THREAD) != CLONE_VM | CLONE_THREAD {
+        return Err(ErrNo::ENOSYS);
+    }
+
+    // The kernel stack and the thread IDs are set up without the lock,
+    // so the other CPUs don't wait for the page table or a user page.
+    let (slot, tid, mut kernel_stack_top) = without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        let slot = sched.alloc().ok_or(ErrNo::EAGAIN)?;
+        sched.threads[slot].state = State::Created;
+        let tid = sched.next_tid;
+        sched.next_tid += 1;
+        Ok((slot, tid, sched.threads[slot].kernel_stack_top))
+    })?;
+
+    if kernel_stack_top == 0 {
+        let top = with_mm(|mapper, frame_allocator, _| {
+            map_stack(
+                THREAD_STACKS_START + slot * THREAD_STACK_SIZE,
+                THREAD_STACK_SIZE,
+                mapper,
+                frame_allocator,
+            )
+        });
+        match top {
+            Ok(top) => kernel_stack_top = top.as_u64(),
+            Err(_) => {
+                without_interrupts(|| SCHED.lock().threads[slot].state = State::Free);
+                return Err(ErrNo::ENOMEM);
+            }
+        }
+    }
+
+    // like Linux, a bad address for the thread ID is ignored
+    if flags & CLONE_PARENT_SETTID != 0 {
+        let _ = ptid.write(&tid);
+    }
+    if flags & CLONE_CHILD_SETTID != 0 {
+        let _ = ctid.write(&tid);
+    }
+
+    let kernel_sp = unsafe {
+        let child_frame = (kernel_stack_top as *mut SyscallFrame).sub(1);
+        child_frame.copy_from_nonoverlapping(frame, 1);
+        (*child_frame).rax = 0;
+        if stack != 0 {
+            (*child_frame).rsp = stack;
+        }
+
+        // the callee saved registers for `_switch_stack`, which returns to userspace
+        let sp = (child_frame as *mut u64).sub(7);
+        sp.write_bytes(0, 6);
+        sp.add(6).write(_syscall_return as usize as u64);
+        sp as u64
+    };
+
+    without_interrupts(|| {
+        let mut sched = SCHED.lock();
+
+        // the new thread inherits the signal mask, but not the alternate signal stack
+        let cur = sched.current();
+        let signals = ThreadSignals {
+            mask: sched.threads[cur].signals.mask,
+            ..ThreadSignals::new()
+        };
+
+        sched.threads[slot] = Thread {
+            tid,
+            state: State::Runnable,
+            on_cpu: false,
+            kernel_sp,
+            kernel_stack_top,
+            fs_base: if flags & CLONE_SETTLS != 0 {
+                tls as u64
+            } else {
+                unsafe { _rdfsbase() }
+            },
+            clear_child_tid: if flags & CLONE_CHILD_CLEARTID != 0 {
+                ctid.addr()
+            } else {
+                0
+            },
+            ticks: 0,
+            wake_at: 0,
+            timed_out: false,
+            signals,
+        };
+
+        lapic::wake_other_cpus();
+        Ok(tid)
+    })
+}
+
+/// Terminate the current thread. The last thread ends the VM with `status`.
+pub fn exit_thread(status: usize) -> ! {
+    // The thread ID is cleared without the lock. A waiter, which still sees it,
+    // is woken below.
+    let ctid = without_interrupts(|| {
+        let sched = SCHED.lock();
+        sched.threads[sched.current()].clear_child_tid
+    });
+    if ctid != 0 {
+        let _ = UserPtr::<u32>::new(ctid).write(&0);
+    }
+
+    without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        let cur = sched.current();
+
+        if ctid != 0 && sched.wake(ctid, 1, FUTEX_BITSET_MATCH_ANY) > 0 {
+            lapic::wake_other_cpus();
+        }
+
+        sched.threads[cur].state = State::Exited;
+
+        if !sched.threads.iter().any(Thread::is_alive) {
+            drop(sched);
+            crate::trace::finish();
+            crate::syscall_policy::print_summary();
+            exit_hypervisor(HyperVisorExitCode::Exit(status as u8));
+            crate::hlt_loop();
+        }
+
+        schedule(sched);
+    });
+    unreachable!("exited thread scheduled again")
+}
+
+/// Let other threads run
+pub fn yield_now() {
+    without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        let cur = sched.current();
+        sched.threads[cur].state = State::Runnable;
+        schedule(sched);
+    })
+}
+
+/// Sleep until the `CLOCK_MONOTONIC` time `deadline` in nanoseconds
+pub fn sleep_until(deadline: u64) {
+    without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        if deadline <= monotonic_ns() {
+            return;
+        }
+        let cur = sched.current();
+        sched.threads[cur].state = State::Sleeping;
+        sched.threads[cur].wake_at = deadline;
+        schedule(sched);
+    })
+}
+
+/// `FUTEX_WAIT_BITSET`: sleep until woken on `uaddr` with a bit of `bitset`, if it still
+/// contains `val`.
+///
+/// Fails with `ETIMEDOUT`, when the `CLOCK_MONOTONIC` time `deadline` passes first.
+pub fn futex_wait(
+    uaddr: UserPtr<u32>,
+    val: u32,
+    bitset: u32,
+    deadline: Option<u64>,
+) -> Result<(), ErrNo> {
+    if uaddr.is_null() || uaddr.addr() % 4 != 0 || bitset == 0 {
+        return Err(ErrNo::EINVAL);
+    }
+    // the page is populated, so the read with the lock held doesn't map a frame
+    check_user(uaddr.addr(), size_of::<u32>(), false)?;
+
+    without_interrupts(|| {
+        // The value is checked with the lock held, so a wakeup can't be missed.
+        let mut sched = SCHED.lock();
+        if uaddr.read()? != val {
+            return Err(ErrNo::EAGAIN);
+        }
+
+        let cur = sched.current();
+        sched.threads[cur].state = State::FutexWait(uaddr.addr(), bitset);
+        sched.threads[cur].wake_at = deadline.map(|d| d.max(1)).unwrap_or(0);
+        sched.threads[cur].timed_out = false;
+        schedule(sched);
+
+        let mut sched = SCHED.lock();
+        if core::mem::replace(&mut sched.threads[cur].timed_out, false) {
+            return Err(ErrNo::ETIMEDOUT);
+        }
+        Ok(())
+    })
+}
+
+/// `FUTEX_WAKE_BITSET`: wake up to `count` threads waiting on `uaddr` with a bit of `bitset`
+pub fn futex_wake(uaddr: UserPtr<u32>, count: usize, bitset: u32) -> Result<usize, ErrNo> {
+    if bitset == 0 {
+        return Err(ErrNo::EINVAL);
+    }
+    let woken = without_interrupts(|| SCHED.lock().wake(uaddr.addr(), count, bitset));
+    if woken > 0 {
+        lapic::wake_other_cpus();
+    }
+    Ok(woken)
+}
+
+/// `FUTEX_REQUEUE` and `FUTEX_CMP_REQUEUE`: wake up to `count` threads waiting on `uaddr`
+/// and move up to `requeue` of the remaining waiters to `uaddr2`.
+///
+/// With `cmp`, `uaddr` has to contain the value.
+pub fn futex_requeue(
+    uaddr: UserPtr<u32>,
+    count: usize,
+    uaddr2: UserPtr<u32>,
+    requeue: usize,
+    cmp: Option<u32>,
+) -> Result<usize, ErrNo> {
+    if cmp.is_some() {
+        check_user(uaddr.addr(), size_of::<u32>(), false)?;
+    }
+
+    without_interrupts(|| {
+        let mut sched = SCHED.lock();
+        if let Some(val) = cmp {
+            if uaddr.read()? != val {
+                return Err(ErrNo::EAGAIN);
+            }
+        }
+
+        let woken = sched.wake(uaddr.addr(), count, FUTEX_BITSET_MATCH_ANY);
+        if woken > 0 {
+            lapic::wake_other_cpus();
+        }
+        let mut moved = 0;
+        for t in sched.threads.iter_mut() {
+            if moved == requeue {
+                break;
+            }
+            if let State::FutexWait(addr, bitset) = t.state {
+                if addr == uaddr.addr() {
+                    t.state = State::FutexWait(uaddr2.addr(), bitset);
+                    moved += 1;
+                }
+            }
+        }
+        Ok(woken + moved)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_sched_pick_round_robin() {
+        serial_print!("test_sched_pick_round_robin...");
+        let mut sched = Scheduler::new();
+        assert_eq!(sched.pick(0), None);
+
+        sched.threads[1].state = State::Runnable;
+        sched.threads[3].state = State::Runnable;
+        sched.threads[3].on_cpu = true;
+        sched.threads[5].state = State::Runnable;
+
+        assert_eq!(sched.pick(0), Some(1));
+        assert_eq!(sched.pick(0), Some(5));
+        assert_eq!(sched.pick(0), Some(1));
+
+        sched.threads[3].on_cpu = false;
+        assert_eq!(sched.pick(0), Some(3));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_sched_futex_wake() {
+        serial_print!("test_sched_futex_wake...");
+        let mut sched = Scheduler::new();
+        for t in sched.threads[..3].iter_mut() {
+            t.state = State::FutexWait(0x1000, FUTEX_BITSET_MATCH_ANY);
+        }
+        sched.threads[3].state = State::FutexWait(0x2000, FUTEX_BITSET_MATCH_ANY);
+
+        sched.threads[4].state = State::FutexWait(0x1000, 0b10);
+
+        assert_eq!(sched.wake(0x1000, 2, 0b01), 2);
+        assert_eq!(sched.wake(0x1000, 2, 0b01), 1);
+        assert_eq!(sched.wake(0x1000, 2, 0b01), 0);
+        assert_eq!(sched.wake(0x1000, 2, FUTEX_BITSET_MATCH_ANY), 1);
+        assert_eq!(
+            sched.threads[3].state,
+            State::FutexWait(0x2000, FUTEX_BITSET_MATCH_ANY)
+        );
+
+        assert_eq!(sched.alloc(), Some(5));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_sched_expire() {
+        serial_print!("test_sched_expire...");
+        let mut sched = Scheduler::new();
+        sched.threads[0].state = State::Sleeping;
+        sched.threads[0].wake_at = 2000;
+        sched.threads[1].state = State::FutexWait(0x1000, FUTEX_BITSET_MATCH_ANY);
+        sched.threads[1].wake_at = 1000;
+        sched.threads[2].state = State::FutexWait(0x1000, FUTEX_BITSET_MATCH_ANY);
+
+        assert_eq!(sched.pick(999), None);
+        assert_eq!(sched.pick(1000), Some(1));
+        assert!(sched.threads[1].timed_out);
+        assert_eq!(
+            sched.threads[2].state,
+            State::FutexWait(0x1000, FUTEX_BITSET_MATCH_ANY)
+        );
+
+        assert_eq!(sched.pick(5000), Some(0));
+        assert_eq!(sched.threads[0].wake_at, 0);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_sched_time_slice() {
+        serial_print!("test_sched_time_slice...");
+        let mut sched = Scheduler::new();
+        assert_eq!(sched.tick(0, true), None);
+
+        sched.current[0] = Some(2);
+        for _ in 1..TIME_SLICE_TICKS {
+            assert_eq!(sched.tick(0, true), None);
+        }
+        assert_eq!(sched.tick(0, true), Some(2));
+        assert_eq!(sched.threads[2].ticks, 0);
+
+        // a thread in the kernel is preempted on its next tick in userspace
+        for _ in 0..TIME_SLICE_TICKS {
+            assert_eq!(sched.tick(0, false), None);
+        }
+        assert_eq!(sched.tick(0, true), Some(2));
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,752 @@
+//! POSIX signals for the faults of the ring3 executable
+//!
+//! The actions registered with `rt_sigaction()` belong to the process and are shared by
+//! all threads, the signal mask and the alternate signal stack belong to a thread.
+//!
+//! A fault in userspace is delivered as `SIGSEGV`, `SIGFPE`, `SIGILL` or `SIGBUS`: the
+//! interrupt returns to the handler with an `rt_sigframe` laid out like the one of Linux
+//! on the user or the alternate signal stack. The restorer of the libc calls
+//! `rt_sigreturn()`, which continues with the context saved in the frame. Without a
+//! handler or if the frame can't be written, the fault stays fatal.
+//!
+//! Nothing sends other signals yet.
+
+use super::crash::SavedRegisters;
+use super::idt::{InterruptStackFrame, PageFaultErrorCode};
+use super::interrupts::XSAVE_AREA_SIZE;
+use super::sched;
+use super::syscall::SyscallFrame;
+use super::uaccess::{self, UserPtr, UserSlice};
+use super::xcr0::XCr0;
+use crate::{eprintln, exit_hypervisor, hlt_loop, HyperVisorExitCode};
+use core::mem::size_of;
+use linux_errno::ErrNo;
+use spin::Mutex;
+use x86_64::registers::control::Cr2;
+use x86_64::VirtAddr;
+
+extern "C" {
+    fn _sigreturn(ctx: *const SigContext, xsave: *const u8) -> !;
+}
+
+/// Number of signals
+pub const NSIG: usize = 64;
+/// `sizeof(sigset_t)` of the kernel
+pub const SIGSET_SIZE: usize = 8;
+
+pub const SIGILL: usize = 4;
+pub const SIGBUS: usize = 7;
+pub const SIGFPE: usize = 8;
+pub const SIGKILL: usize = 9;
+pub const SIGSEGV: usize = 11;
+pub const SIGSTOP: usize = 19;
+
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+const SA_RESTORER: usize = 0x0400_0000;
+const SA_ONSTACK: usize = 0x0800_0000;
+const SA_NODEFER: usize = 0x4000_0000;
+const SA_RESETHAND: usize = 0x8000_0000;
+
+const SIG_BLOCK: usize = 0;
+const SIG_UNBLOCK: usize = 1;
+const SIG_SETMASK: usize = 2;
+
+const SS_ONSTACK: i32 = 1;
+const SS_DISABLE: i32 = 2;
+const SS_AUTODISARM: i32 = i32::min_value();
+const MINSIGSTKSZ: usize = 2048;
+
+const SI_KERNEL: i32 = 0x80;
+const ILL_ILLOPN: i32 = 2;
+const FPE_INTDIV: i32 = 1;
+const FPE_FLTDIV: i32 = 3;
+const FPE_FLTOVF: i32 = 4;
+const FPE_FLTUND: i32 = 5;
+const FPE_FLTRES: i32 = 6;
+const FPE_FLTINV: i32 = 7;
+const SEGV_MAPERR: i32 = 1;
+const SEGV_ACCERR: i32 = 2;
+const BUS_ADRALN: i32 = 1;
+
+const UC_SIGCONTEXT_SS: u64 = 0x2;
+const UC_STRICT_RESTORE_SS: u64 = 0x4;
+
+/// The flags of `rflags`, which `rt_sigreturn()` takes from the frame
+const FIX_EFLAGS: u64 = 0x5_0dd5;
+/// `RF`, `DF` and `TF` are cleared for the handler
+const HANDLER_CLEAR_EFLAGS: u64 = 0x1_0500;
+
+/// The red zone below the stack pointer of the interrupted code
+const RED_ZONE: u64 = 128;
+
+/// Offsets in the xsave area
+const XSAVE_FCW: usize = 0;
+const XSAVE_FSW: usize = 2;
+const XSAVE_MXCSR: usize = 24;
+const XSAVE_MXCSR_MASK: usize = 28;
+const XSAVE_XSTATE_BV: usize = 512;
+const XSAVE_HEADER_END: usize = 576;
+
+const MXCSR_DEFAULT: u32 = 0x1f80;
+
+/// The signals, which can't be blocked
+const UNBLOCKABLE: u64 = sigmask(SIGKILL) | sigmask(SIGSTOP);
+
+/// The bit of `signo` in a signal mask
+const fn sigmask(signo: usize) -> u64 {
+    1 << (signo - 1)
+}
+
+/// `struct sigaction` of the kernel
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct SigAction {
+    pub handler: usize,
+    pub flags: usize,
+    pub restorer: usize,
+    pub mask: u64,
+}
+
+impl SigAction {
+    const DEFAULT: SigAction = SigAction {
+        handler: SIG_DFL,
+        flags: 0,
+        restorer: 0,
+        mask: 0,
+    };
+}
+
+/// `stack_t`, an alternate signal stack
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct SigStack {
+    pub sp: usize,
+    pub flags: i32,
+    pub size: usize,
+}
+
+impl SigStack {
+    const DISABLED: SigStack = SigStack {
+        sp: 0,
+        flags: 0,
+        size: 0,
+    };
+}
+
+/// The signal state of a thread
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThreadSignals {
+    /// The blocked signals
+    pub mask: u64,
+    /// The alternate signal stack, disabled with a `size` of `0`.
+    ///
+    /// Only `SS_AUTODISARM` is kept in its `flags`.
+    pub alt_stack: SigStack,
+}
+
+impl ThreadSignals {
+    pub const fn new() -> Self {
+        ThreadSignals {
+            mask: 0,
+            alt_stack: SigStack::DISABLED,
+        }
+    }
+
+    /// Whether `sp` is on the alternate signal stack
+    fn on_alt_stack(&self, sp: u64) -> bool {
+        let ss = &self.alt_stack;
+        // with `SS_AUTODISARM` the stack is disabled, while it is in use
+        ss.flags & SS_AUTODISARM == 0 && sp > ss.sp as u64 && sp - ss.sp as u64 <= ss.size as u64
+    }
+
+    /// The alternate signal stack as `sigaltstack()` reports it for the stack pointer `sp`
+    fn alt_stack(&self, sp: u64) -> SigStack {
+        let flags = if self.alt_stack.size == 0 {
+            SS_DISABLE
+        } else if self.on_alt_stack(sp) {
+            SS_ONSTACK
+        } else {
+            0
+        };
+        SigStack {
+            flags: flags | self.alt_stack.flags,
+            ..self.alt_stack
+        }
+    }
+
+    /// Replace the alternate signal stack with `ss` for the stack pointer `sp`
+    fn set_alt_stack(&mut self, ss: &SigStack, sp: u64) -> Result<(), ErrNo> {
+        if self.on_alt_stack(sp) {
+            return Err(ErrNo::EPERM);
+        }
+        match ss.flags & !SS_AUTODISARM {
+            SS_DISABLE => self.alt_stack = SigStack::DISABLED,
+            0 | SS_ONSTACK => {
+                if ss.size < MINSIGSTKSZ {
+                    return Err(ErrNo::ENOMEM);
+                }
+                self.alt_stack = SigStack {
+                    sp: ss.sp,
+                    flags: ss.flags & SS_AUTODISARM,
+                    size: ss.size,
+                };
+            }
+            _ => return Err(ErrNo::EINVAL),
+        }
+        Ok(())
+    }
+
+    /// Change the signal mask with `set` according to `how`
+    fn change_mask(&mut self, how: usize, set: u64) -> Result<(), ErrNo> {
+        let set = set & !UNBLOCKABLE;
+        self.mask = match how {
+            SIG_BLOCK => self.mask | set,
+            SIG_UNBLOCK => self.mask & !set,
+            SIG_SETMASK => set,
+            _ => return Err(ErrNo::EINVAL),
+        };
+        Ok(())
+    }
+}
+
+/// `siginfo_t` of a fault
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct SigInfo {
+    signo: i32,
+    errno: i32,
+    code: i32,
+    _pad: i32,
+    addr: u64,
+    _fields: [u64; 13],
+}
+
+/// `struct sigcontext`, the registers of the interrupted code
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct SigContext {
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub eflags: u64,
+    pub cs: u16,
+    pub gs: u16,
+    pub fs: u16,
+    pub ss: u16,
+    pub err: u64,
+    pub trapno: u64,
+    pub oldmask: u64,
+    pub cr2: u64,
+    /// The xsave area, `0` for none
+    pub fpstate: u64,
+    pub reserved: [u64; 8],
+}
+
+/// `struct ucontext` of the kernel
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct UContext {
+    flags: u64,
+    link: u64,
+    stack: SigStack,
+    mcontext: SigContext,
+    sigmask: u64,
+}
+
+/// `struct rt_sigframe`, which the handler finds on its stack
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RtSigFrame {
+    /// The return address of the handler, the restorer of the libc
+    pretcode: u64,
+    uc: UContext,
+    info: SigInfo,
+}
+
+static ACTIONS: Mutex<[SigAction; NSIG]> = Mutex::new([SigAction::DEFAULT; NSIG]);
+
+/// `rt_sigaction()`: replace the action of `signo` with `act` and return the old one
+pub fn sigaction(signo: usize, act: Option<SigAction>) -> Result<SigAction, ErrNo> {
+    if signo == 0 || signo > NSIG || (act.is_some() && (signo == SIGKILL || signo == SIGSTOP)) {
+        return Err(ErrNo::EINVAL);
+    }
+    let mut actions = ACTIONS.lock();
+    let old = actions[signo - 1];
+    if let Some(act) = act {
+        actions[signo - 1] = SigAction {
+            mask: act.mask & !UNBLOCKABLE,
+            ..act
+        };
+    }
+    Ok(old)
+}
+
+/// `rt_sigprocmask()`: change the signal mask of the current thread and return the old one
+pub fn sigprocmask(how: usize, set: Option<u64>) -> Result<u64, ErrNo> {
+    sched::with_signals(|signals| {
+        let old = signals.mask;
+        if let Some(set) = set {
+            signals.change_mask(how, set)?;
+        }
+        Ok(old)
+    })
+}
+
+/// `sigaltstack()`: replace the alternate signal stack of the current thread running on
+/// the user stack pointer `sp` and return the old one
+pub fn sigaltstack(ss: Option<SigStack>, sp: u64) -> Result<SigStack, ErrNo> {
+    sched::with_signals(|signals| {
+        let old = signals.alt_stack(sp);
+        if let Some(ss) = ss {
+            signals.set_alt_stack(&ss, sp)?;
+        }
+        Ok(old)
+    })
+}
+
+/// The signal, `si_code` and `si_addr` for the exception `vector` in userspace
+///
+/// `None` for the exceptions, which are always fatal.
+fn fault_info(
+    vector: u8,
+    error_code: u64,
+    rip: u64,
+    cr2: u64,
+    xsave: &[u8; XSAVE_AREA_SIZE],
+) -> Option<(usize, i32, u64)> {
+    Some(match vector {
+        0 => (SIGFPE, FPE_INTDIV, rip),
+        4 | 5 | 10 | 13 => (SIGSEGV, SI_KERNEL, 0),
+        6 => (SIGILL, ILL_ILLOPN, rip),
+        11 | 12 => (SIGBUS, SI_KERNEL, 0),
+        14 if PageFaultErrorCode::from_bits_truncate(error_code)
+            .contains(PageFaultErrorCode::PROTECTION_VIOLATION) =>
+        {
+            (SIGSEGV, SEGV_ACCERR, cr2)
+        }
+        14 => (SIGSEGV, SEGV_MAPERR, cr2),
+        16 => {
+            let fcw = read_u16(xsave, XSAVE_FCW);
+            let fsw = read_u16(xsave, XSAVE_FSW);
+            (SIGFPE, fpe_code(u32::from(fsw & !fcw)), rip)
+        }
+        17 => (SIGBUS, BUS_ADRALN, 0),
+        19 => {
+            let mxcsr = read_u32(xsave, XSAVE_MXCSR);
+            (SIGFPE, fpe_code(mxcsr & !(mxcsr >> 7) & 0x3f), rip)
+        }
+        _ => return None,
+    })
+}
+
+/// The `si_code` of `SIGFPE` for the unmasked floating point exceptions in `status`
+fn fpe_code(status: u32) -> i32 {
+    if status & 0x01 != 0 {
+        FPE_FLTINV
+    } else if status & 0x04 != 0 {
+        FPE_FLTDIV
+    } else if status & 0x08 != 0 {
+        FPE_FLTOVF
+    } else if status & 0x12 != 0 {
+        FPE_FLTUND
+    } else if status & 0x20 != 0 {
+        FPE_FLTRES
+    } else {
+        0
+    }
+}
+
+/// The address of the `rt_sigframe` and of the xsave area above it for a signal with the
+/// action `flags` at the user stack pointer `sp`, and whether the alternate signal stack
+/// is entered
+///
+/// `None`, if the frame does not fit.
+fn frame_addrs(sp: u64, flags: usize, signals: &ThreadSignals) -> Option<(u64, u64, bool)> {
+    let ss = &signals.alt_stack;
+    let mut top = sp.checked_sub(RED_ZONE)?;
+    let on_alt_stack = signals.on_alt_stack(top);
+    let enter = flags & SA_ONSTACK != 0 && ss.size != 0 && !on_alt_stack;
+    if enter {
+        top = (ss.sp as u64).checked_add(ss.size as u64)?;
+    }
+
+    let fpstate = top.checked_sub(XSAVE_AREA_SIZE as u64)? & !63;
+    // the stack is aligned like after a call to the handler
+    let frame = (fpstate.checked_sub(size_of::<RtSigFrame>() as u64)? & !15).checked_sub(8)?;
+
+    // the frame must not overflow the alternate signal stack
+    if (enter || on_alt_stack) && frame <= ss.sp as u64 {
+        return None;
+    }
+    Some((frame, fpstate, enter))
+}
+
+/// Deliver the exception `vector` of the current thread in userspace to its handler
+///
+/// The interrupt returns to the handler with the changed `stack_frame` and `regs`. The FPU
+/// state in `xsave` is saved in the signal frame and reset for the handler.
+/// Returns `false`, if the exception is fatal.
+pub fn deliver_fault(
+    vector: u8,
+    error_code: u64,
+    stack_frame: &mut InterruptStackFrame,
+    regs: &mut SavedRegisters,
+    xsave: &mut [u8; XSAVE_AREA_SIZE],
+) -> bool {
+    if stack_frame.code_segment & 3 != 3 {
+        return false;
+    }
+
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let sp = stack_frame.stack_pointer.as_u64();
+    let cr2 = if vector == 14 {
+        Cr2::read().as_u64()
+    } else {
+        0
+    };
+    let (signo, code, addr) = match fault_info(vector, error_code, rip, cr2, xsave) {
+        Some(info) => info,
+        None => return false,
+    };
+
+    let mut actions = ACTIONS.lock();
+    let action = actions[signo - 1];
+    let handler = match VirtAddr::try_new(action.handler as u64) {
+        Ok(handler) => handler,
+        Err(_) => return false,
+    };
+
+    sched::with_signals(|signals| {
+        // like Linux, a blocked or ignored fault kills the process
+        if action.handler == SIG_DFL
+            || action.handler == SIG_IGN
+            || action.flags & SA_RESTORER == 0
+            || signals.mask & sigmask(signo) != 0
+        {
+            return false;
+        }
+
+        let (frame_addr, fpstate, enter) = match frame_addrs(sp, action.flags, signals) {
+            Some(addrs) => addrs,
+            None => return false,
+        };
+        let len = (fpstate - frame_addr) as usize + XSAVE_AREA_SIZE;
+        if uaccess::check_user(frame_addr as usize, len, true).is_err() {
+            return false;
+        }
+
+        let frame = RtSigFrame {
+            pretcode: action.restorer as u64,
+            uc: UContext {
+                flags: UC_SIGCONTEXT_SS | UC_STRICT_RESTORE_SS,
+                link: 0,
+                stack: signals.alt_stack(sp),
+                mcontext: SigContext {
+                    r8: regs.r8,
+                    r9: regs.r9,
+                    r10: regs.r10,
+                    r11: regs.r11,
+                    r12: regs.r12,
+                    r13: regs.r13,
+                    r14: regs.r14,
+                    r15: regs.r15,
+                    rdi: regs.rdi,
+                    rsi: regs.rsi,
+                    rbp: regs.rbp,
+                    rbx: regs.rbx,
+                    rdx: regs.rdx,
+                    rax: regs.rax,
+                    rcx: regs.rcx,
+                    rsp: sp,
+                    rip,
+                    eflags: stack_frame.cpu_flags,
+                    cs: stack_frame.code_segment as u16,
+                    ss: stack_frame.stack_segment as u16,
+                    err: error_code,
+                    trapno: vector as u64,
+                    oldmask: signals.mask,
+                    cr2,
+                    fpstate,
+                    ..SigContext::default()
+                },
+                sigmask: signals.mask,
+            },
+            info: SigInfo {
+                signo: signo as i32,
+                errno: 0,
+                code,
+                _pad: 0,
+                addr,
+                _fields: [0; 13],
+            },
+        };
+        let fpstate_buf = UserSlice::new(fpstate as usize, XSAVE_AREA_SIZE);
+        if fpstate_buf.and_then(|buf| buf.write(&xsave[..])).is_err()
+            || UserPtr::new(frame_addr as usize).write(&frame).is_err()
+        {
+            return false;
+        }
+
+        signals.mask |= action.mask;
+        if action.flags & SA_NODEFER == 0 {
+            signals.mask |= sigmask(signo);
+        }
+        if enter && signals.alt_stack.flags & SS_AUTODISARM != 0 {
+            signals.alt_stack = SigStack::DISABLED;
+        }
+        if action.flags & SA_RESETHAND != 0 {
+            actions[signo - 1] = SigAction::DEFAULT;
+        }
+
+        // handler(signo, &info, &uc)
+        unsafe {
+            let value = stack_frame.as_mut();
+            value.instruction_pointer = handler;
+            value.stack_pointer = VirtAddr::new(frame_addr);
+            value.cpu_flags &= !HANDLER_CLEAR_EFLAGS;
+        }
+        regs.rdi = signo as u64;
+        regs.rsi = frame_addr + (size_of::<u64>() + size_of::<UContext>()) as u64;
+        regs.rdx = frame_addr + size_of::<u64>() as u64;
+        regs.rax = 0;
+        reset_fpu(xsave);
+        true
+    })
+}
+
+/// `rt_sigreturn()`: continue with the context in the `rt_sigframe` of the handler, which
+/// returned to the restorer with the registers in `frame`
+///
+/// A corrupted frame kills the process with `SIGSEGV`.
+pub fn sigreturn(frame: &mut SyscallFrame) -> ! {
+    // `pretcode` was popped by the return of the handler
+    let addr = (frame.rsp as u64).wrapping_sub(size_of::<u64>() as u64);
+    let sigframe = match UserPtr::<RtSigFrame>::new(addr as usize).read() {
+        Ok(sigframe) => sigframe,
+        Err(_) => bad_frame(addr),
+    };
+    let uc = &sigframe.uc;
+    let mut ctx = uc.mcontext;
+
+    // only the flags userspace may change and the user segments
+    ctx.eflags = (ctx.eflags & FIX_EFLAGS) | (frame.rflags as u64 & !FIX_EFLAGS);
+    ctx.cs = frame.cs as u16;
+    ctx.ss = frame.ss as u16;
+    // `iretq` faults in the kernel on a non-canonical address
+    if ctx.rip >= 0x0000_8000_0000_0000 {
+        bad_frame(addr);
+    }
+
+    if ctx.fpstate == 0 {
+        reset_fpu(&mut frame.xsave);
+    } else {
+        let mxcsr_mask = match read_u32(&frame.xsave, XSAVE_MXCSR_MASK) {
+            0 => 0xffbf,
+            mask => mask,
+        };
+        let fpstate = UserSlice::new(ctx.fpstate as usize, XSAVE_AREA_SIZE);
+        if fpstate
+            .and_then(|buf| buf.read(&mut frame.xsave[..XSAVE_AREA_SIZE]))
+            .is_err()
+        {
+            bad_frame(addr);
+        }
+        sanitize_fpu(&mut frame.xsave, mxcsr_mask);
+    }
+
+    sched::with_signals(|signals| {
+        signals.mask = uc.sigmask & !UNBLOCKABLE;
+        // like Linux, an invalid alternate signal stack in the frame is ignored
+        let _ = signals.set_alt_stack(&uc.stack, frame.rsp as u64);
+    });
+
+    unsafe { _sigreturn(&ctx, frame.xsave.as_ptr()) }
+}
+
+/// Kill the process for the corrupted signal frame at `addr`
+fn bad_frame(addr: u64) -> ! {
+    eprintln!("rt_sigreturn: bad signal frame at {:#x}", addr);
+    crate::trace::finish();
+    crate::syscall_policy::print_summary();
+    exit_hypervisor(HyperVisorExitCode::Signal(SIGSEGV as u8));
+    hlt_loop();
+}
+
+/// Set the xsave area to the initial FPU state
+fn reset_fpu(xsave: &mut [u8]) {
+    xsave[XSAVE_MXCSR..XSAVE_MXCSR + 4].copy_from_slice(&MXCSR_DEFAULT.to_le_bytes());
+    xsave[XSAVE_XSTATE_BV..XSAVE_XSTATE_BV + 8].copy_from_slice(&0u64.to_le_bytes());
+}
+
+/// Make the xsave area from userspace safe for `xrstor`
+fn sanitize_fpu(xsave: &mut [u8], mxcsr_mask: u32) {
+    let mxcsr = read_u32(xsave, XSAVE_MXCSR) & mxcsr_mask;
+    xsave[XSAVE_MXCSR..XSAVE_MXCSR + 4].copy_from_slice(&mxcsr.to_le_bytes());
+
+    let mut xstate_bv = [0u8; 8];
+    xstate_bv.copy_from_slice(&xsave[XSAVE_XSTATE_BV..XSAVE_XSTATE_BV + 8]);
+    let xstate_bv = u64::from_le_bytes(xstate_bv) & XCr0::read_raw();
+    xsave[XSAVE_XSTATE_BV..XSAVE_XSTATE_BV + 8].copy_from_slice(&xstate_bv.to_le_bytes());
+
+    // `XCOMP_BV` and the rest of the header must be zero in the standard format
+    for byte in xsave[XSAVE_XSTATE_BV + 8..XSAVE_HEADER_END].iter_mut() {
+        *byte = 0;
+    }
+}
+
+fn read_u16(xsave: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([xsave[offset], xsave[offset + 1]])
+}
+
+fn read_u32(xsave: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&xsave[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_signal_frame_layout() {
+        serial_print!("test_signal_frame_layout...");
+        assert_eq!(size_of::<SigStack>(), 24);
+        assert_eq!(size_of::<SigContext>(), 256);
+        assert_eq!(size_of::<UContext>(), 304);
+        assert_eq!(size_of::<SigInfo>(), 128);
+        assert_eq!(size_of::<RtSigFrame>(), 440);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_signal_frame_addrs() {
+        serial_print!("test_signal_frame_addrs...");
+        let mut signals = ThreadSignals::new();
+
+        // below the red zone, the xsave area and the frame
+        let (frame, fpstate, enter) = frame_addrs(0x1000_0000, 0, &signals).unwrap();
+        assert_eq!(fpstate, 0x0fff_fb80);
+        assert_eq!(frame, 0x0fff_f9b8);
+        assert_eq!(frame % 16, 8);
+        assert!(!enter);
+
+        // without SA_ONSTACK the alternate signal stack is not used
+        signals.alt_stack = SigStack {
+            sp: 0x2000_0000,
+            flags: 0,
+            size: 0x2000,
+        };
+        assert!(!frame_addrs(0x1000_0000, 0, &signals).unwrap().2);
+
+        let (frame, fpstate, enter) = frame_addrs(0x1000_0000, SA_ONSTACK, &signals).unwrap();
+        assert_eq!(fpstate, 0x2000_1c00);
+        assert_eq!(frame, 0x2000_1a38);
+        assert!(enter);
+
+        // a nested signal stays below the current frame
+        let (nested, _, enter) = frame_addrs(frame, SA_ONSTACK, &signals).unwrap();
+        assert!(nested < frame && nested > 0x2000_0000);
+        assert!(!enter);
+
+        signals.alt_stack.size = 0x400;
+        assert_eq!(frame_addrs(0x1000_0000, SA_ONSTACK, &signals), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_signal_thread_state() {
+        serial_print!("test_signal_thread_state...");
+        let mut signals = ThreadSignals::new();
+        let all = u64::max_value();
+
+        assert_eq!(signals.change_mask(SIG_BLOCK, all), Ok(()));
+        assert_eq!(signals.mask, !UNBLOCKABLE);
+        assert_eq!(signals.change_mask(SIG_UNBLOCK, sigmask(SIGSEGV)), Ok(()));
+        assert_eq!(signals.mask & sigmask(SIGSEGV), 0);
+        assert_eq!(signals.change_mask(SIG_SETMASK, sigmask(SIGBUS)), Ok(()));
+        assert_eq!(signals.mask, sigmask(SIGBUS));
+        assert_eq!(signals.change_mask(3, 0), Err(ErrNo::EINVAL));
+
+        assert_eq!(signals.alt_stack(0).flags, SS_DISABLE);
+        let mut ss = SigStack {
+            sp: 0x2000_0000,
+            flags: 0,
+            size: MINSIGSTKSZ - 1,
+        };
+        assert_eq!(signals.set_alt_stack(&ss, 0), Err(ErrNo::ENOMEM));
+        ss.size = 0x2000;
+        ss.flags = 4;
+        assert_eq!(signals.set_alt_stack(&ss, 0), Err(ErrNo::EINVAL));
+        ss.flags = SS_ONSTACK;
+        assert_eq!(signals.set_alt_stack(&ss, 0), Ok(()));
+        assert_eq!(signals.alt_stack(0).flags, 0);
+        assert_eq!(signals.alt_stack(0x2000_1000).flags, SS_ONSTACK);
+        assert_eq!(
+            signals.set_alt_stack(&SigStack::DISABLED, 0x2000_1000),
+            Err(ErrNo::EPERM)
+        );
+
+        // with SS_AUTODISARM the stack can be replaced while in use
+        ss.flags = SS_AUTODISARM;
+        assert_eq!(signals.set_alt_stack(&ss, 0), Ok(()));
+        assert_eq!(signals.alt_stack(0x2000_1000).flags, SS_AUTODISARM);
+        ss.flags = SS_DISABLE;
+        assert_eq!(signals.set_alt_stack(&ss, 0x2000_1000), Ok(()));
+        assert_eq!(signals.alt_stack, SigStack::DISABLED);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_signal_fault_info() {
+        serial_print!("test_signal_fault_info...");
+        let mut xsave = [0u8; XSAVE_AREA_SIZE];
+        assert_eq!(
+            fault_info(14, 0x4, 0x1000, 0x10, &xsave),
+            Some((SIGSEGV, SEGV_MAPERR, 0x10))
+        );
+        assert_eq!(
+            fault_info(14, 0x7, 0x1000, 0x10, &xsave),
+            Some((SIGSEGV, SEGV_ACCERR, 0x10))
+        );
+        assert_eq!(
+            fault_info(0, 0, 0x1000, 0, &xsave),
+            Some((SIGFPE, FPE_INTDIV, 0x1000))
+        );
+        assert_eq!(
+            fault_info(6, 0, 0x1000, 0, &xsave),
+            Some((SIGILL, ILL_ILLOPN, 0x1000))
+        );
+        assert_eq!(
+            fault_info(13, 0, 0x1000, 0, &xsave),
+            Some((SIGSEGV, SI_KERNEL, 0))
+        );
+        assert_eq!(fault_info(8, 0, 0x1000, 0, &xsave), None);
+
+        // an unmasked SSE division by zero
+        let mxcsr: u32 = (MXCSR_DEFAULT & !(0x04 << 7)) | 0x04;
+        xsave[XSAVE_MXCSR..XSAVE_MXCSR + 4].copy_from_slice(&mxcsr.to_le_bytes());
+        assert_eq!(
+            fault_info(19, 0, 0x1000, 0, &xsave),
+            Some((SIGFPE, FPE_FLTDIV, 0x1000))
+        );
+        serial_println!("[ok]");
+    }
+}
@@ -1,133 +1,103 @@
 use crate::arch::x86_64::PAGESIZE;
-use vmsyscall::bootinfo::BootInfo;
+use core::mem::size_of;
+use vmsyscall::bootinfo::{AppArgs, AppSegments, BootInfo, SyscallPolicy};
 use vmsyscall::memory_map::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use vmsyscall::pvh::{HvmMemmapTableEntry, HvmMemmapTableEntryType, HvmModlistEntry, HvmStartInfo};
 use x86_64::PhysAddr;
 
 extern "C" {
     fn _start_main(bootinfo: *mut BootInfo) -> !;
 }
 
-#[repr(C)]
-pub struct HvmStartInfo {
-    magic: u32, /* Contains the magic value 0x336ec578       */
-    /* ("xEn3" with the 0x80 bit of the "E" set).*/
-    version: u32,       /* Version of this structure.                */
-    flags: u32,         /* SIF_xxx flags.                            */
-    nr_modules: u32,    /* Number of modules passed to the kernel.   */
-    modlist_paddr: u64, /* Physical address of an array of           */
-    /* hvm_modlist_entry.                        */
-    cmdline_paddr: u64, /* Physical address of the command line.     */
-    rsdp_paddr: u64,    /* Physical address of the RSDP ACPI data    */
-    /* structure.                                */
-    /* All following fields only present in version 1 and newer */
-    memmap_paddr: u64, /* Physical address of an array of           */
-    /* hvm_memmap_table_entry.                   */
-    memmap_entries: u32, /* Number of entries in the memmap table.    */
-    /* Value will be zero if there is no memory  */
-    /* map being provided.                       */
-    reserved: u32, /* Must be zero.                             */
+extern "C" {
+    static _kernel_start: usize;
+    static _kernel_end: usize;
 }
 
-/// https://github.com/Xilinx/xen/blob/master/xen/include/public/arch-x86/hvm/start_info.h#L105
-#[repr(C)]
-pub struct HvmMemmapTableEntry {
-    addr: u64,       /* Base address of the memory region         */
-    size: u64,       /* Size of the memory region in bytes        */
-    entry_type: u32, /* Mapping type                              */
-    reserved: u32,   /* Must be zero for Version 1.               */
-}
+/// Where the `BootInfo` is built, if the loader does not pass one
+const BOOTINFO_PHYS_ADDR: u64 = 0x8000;
 
-impl HvmMemmapTableEntry {
-    pub fn get_type(&self) -> HvmMemmapTableEntryType {
-        const XEN_HVM_MEMMAP_TYPE_RAM: u32 = 1;
-        const XEN_HVM_MEMMAP_TYPE_RESERVED: u32 = 2;
-        const XEN_HVM_MEMMAP_TYPE_ACPI: u32 = 3;
-        const XEN_HVM_MEMMAP_TYPE_NVS: u32 = 4;
-        const XEN_HVM_MEMMAP_TYPE_UNUSABLE: u32 = 5;
-        const XEN_HVM_MEMMAP_TYPE_DISABLED: u32 = 6;
-        const XEN_HVM_MEMMAP_TYPE_PMEM: u32 = 7;
-
-        match self.entry_type {
-            XEN_HVM_MEMMAP_TYPE_RAM => HvmMemmapTableEntryType::RAM,
-            XEN_HVM_MEMMAP_TYPE_RESERVED => HvmMemmapTableEntryType::Reserved,
-            XEN_HVM_MEMMAP_TYPE_ACPI => HvmMemmapTableEntryType::ACPI,
-            XEN_HVM_MEMMAP_TYPE_NVS => HvmMemmapTableEntryType::NVS,
-            XEN_HVM_MEMMAP_TYPE_UNUSABLE => HvmMemmapTableEntryType::Unusable,
-            XEN_HVM_MEMMAP_TYPE_DISABLED => HvmMemmapTableEntryType::Disabled,
-            XEN_HVM_MEMMAP_TYPE_PMEM => HvmMemmapTableEntryType::PMEM,
-            _ => HvmMemmapTableEntryType::Unknown,
-        }
-    }
-}
+/// The size of the pages reserved for the `BootInfo`
+const BOOTINFO_SIZE: u64 =
+    (size_of::<BootInfo>() as u64 + PAGESIZE as u64 - 1) & !(PAGESIZE as u64 - 1);
 
-//#[cfg(feature = "allocator")]
-impl core::fmt::Debug for HvmMemmapTableEntry {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
-        write!(
-            f,
-            "HvmMemmapTableEntry({:#?} {:#x}..{:#x})",
-            self.get_type(),
-            self.addr,
-            self.addr + self.size
-        )
-    }
-}
+/// End of the conventional memory, which is free for sure, below the EBDA
+const LOW_MEMORY_END: u64 = 0x8_0000;
 
-#[derive(Debug)]
-pub enum HvmMemmapTableEntryType {
-    RAM,
-    Reserved,
-    ACPI,
-    NVS,
-    Unusable,
-    Disabled,
-    PMEM,
-    Unknown,
-}
-
-extern "C" {
-    static _kernel_start: usize;
-    static _kernel_end: usize;
-}
+// the `BootInfo` built at `BOOTINFO_PHYS_ADDR` has to fit into the conventional memory
+const _: [(); 0] = [(); (BOOTINFO_PHYS_ADDR + BOOTINFO_SIZE > LOW_MEMORY_END) as usize];
 
-#[export_name = "_start_e820"]
-pub unsafe extern "C" fn rust_start_820(hvm_start_info: *const HvmStartInfo) -> ! {
-    eprintln!("rust_start_820, magic={:#X}", (*hvm_start_info).magic);
-    let kernel_start_ptr = &_kernel_start as *const _ as u64;
-    let kernel_end_ptr = &_kernel_end as *const _ as u64;
+/// The path of the app in `argv[0]`, if the loader does not pass the arguments
+const DEFAULT_ARGV0: &str = "/init";
 
-    let e820_count = (*hvm_start_info).memmap_entries;
-    let entry = (*hvm_start_info).memmap_paddr as *const HvmMemmapTableEntry;
-    eprintln!("addr={}", (*entry).addr);
-    let e820_table = core::slice::from_raw_parts(
-        (*hvm_start_info).memmap_paddr as *const HvmMemmapTableEntry,
-        e820_count as _,
-    );
-    eprintln!("e820_table={:#X}", (*hvm_start_info).memmap_paddr);
-    eprintln!("e820_count={}", e820_count);
-    eprintln!("{:#?}", e820_table);
+/// The arguments of an app booted without vmrun, only its path in `argv[0]`
+fn default_app_args() -> AppArgs {
+    let mut app_args = AppArgs::new();
+    app_args
+        .push_arg(DEFAULT_ARGV0)
+        .expect("argv[0] fits into the AppArgs");
+    app_args
+}
 
-    pub const BOOTINFO_PHYS_ADDR: u64 = 0x8000;
+/// The `BootInfo` passed by vmrun as the first module or a new one without an app
+unsafe fn boot_info(hvm_start_info: &HvmStartInfo) -> *mut BootInfo {
+    if hvm_start_info.nr_modules > 0 {
+        let module = &*(hvm_start_info.modlist_paddr as *const HvmModlistEntry);
+        if module.size == size_of::<BootInfo>() as u64 {
+            return module.paddr as *mut BootInfo;
+        }
+    }
 
+    let boot_info = BOOTINFO_PHYS_ADDR as *mut BootInfo;
     core::ptr::write(
-        BOOTINFO_PHYS_ADDR as *mut BootInfo,
+        boot_info,
         BootInfo {
             memory_map: MemoryMap::new(),
             entry_point: core::ptr::null(),
             load_addr: core::ptr::null(),
             elf_phnum: 0,
+            app_segments: AppSegments::new(),
+            app_image: 0,
+            app_image_len: 0,
+            interp_image: 0,
+            interp_image_len: 0,
             syscall_trigger_port: 0,
+            nr_cpus: 1,
+            syscall_pages: 0,
+            tsc_hz: 0,
+            realtime_ns: 0,
+            syscall_policy: SyscallPolicy::new(),
+            trace_level: 0,
+            app_args: default_app_args(),
         },
     );
+    boot_info
+}
 
-    let boot_info: *mut BootInfo = BOOTINFO_PHYS_ADDR as _;
+/// Entry of the PVH boot protocol, after `ram32_start` switched to long mode
+///
+/// The memory map is always built from the E820 table, so the kernel boots the same
+/// way with vmrun and qemu.
+#[export_name = "_start_e820"]
+pub unsafe extern "C" fn rust_start_820(hvm_start_info: *const HvmStartInfo) -> ! {
+    let hvm_start_info = &*hvm_start_info;
+    let kernel_start_ptr = &_kernel_start as *const _ as u64;
+    let kernel_end_ptr = &_kernel_end as *const _ as u64;
+
+    let e820_table = core::slice::from_raw_parts(
+        hvm_start_info.memmap_paddr as *const HvmMemmapTableEntry,
+        hvm_start_info.memmap_entries as _,
+    );
+
+    let boot_info = boot_info(hvm_start_info);
+    (*boot_info).memory_map = MemoryMap::new();
 
     for entry in e820_table {
         let end = entry.addr + entry.size;
         let start = entry.addr;
         #[allow(clippy::single_match)]
         match entry.get_type() {
-            HvmMemmapTableEntryType::RAM => {
+            HvmMemmapTableEntryType::Ram => {
                 (*boot_info).memory_map.add_region(MemoryRegion {
                     range: FrameRange::new(
                         PhysAddr::new(start).align_up(PAGESIZE as u64).as_u64(),
@@ -150,18 +120,15 @@ pub unsafe extern "C" fn rust_start_820(hvm_start_info: *const HvmStartInfo) ->
             _ => {}
         }
     }
-    //eprintln!("{:#?}", (*boot_info).memory_map);
 
     (*boot_info).memory_map.mark_allocated_region(MemoryRegion {
         range: FrameRange::new(0, 0x1000),
         region_type: MemoryRegionType::Reserved,
     });
-    /* FIXME
     (*boot_info).memory_map.mark_allocated_region(MemoryRegion {
-        range: FrameRange::new(SYSCALL_PHYS_ADDR, SYSCALL_PHYS_ADDR + 0x1000),
-        region_type: MemoryRegionType::InUse,
+        range: FrameRange::new(boot_info as u64, boot_info as u64 + BOOTINFO_SIZE),
+        region_type: MemoryRegionType::Reserved,
     });
-    */
     (*boot_info).memory_map.mark_allocated_region(MemoryRegion {
         range: FrameRange::new(kernel_start_ptr, kernel_end_ptr),
         region_type: MemoryRegionType::Kernel,
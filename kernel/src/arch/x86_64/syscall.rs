@@ -1,6 +1,8 @@
 use super::gdt;
 use x86_64::registers::model_specific::{KernelGsBase, LStar, SFMask, Star};
 use x86_64::registers::rflags::RFlags;
+use x86_64::structures::gdt::GlobalDescriptorTable;
+use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 extern "C" {
@@ -8,36 +10,72 @@ extern "C" {
     fn _usermode(ip: usize, sp: usize, arg: usize) -> !;
 }
 
+/// Size of the xsave area of `_syscall_enter`
+pub const XSAVE_AREA_SIZE: usize = 16 * 64 + 3 * 8;
+
+/// The user registers saved by `_syscall_enter` at the top of the kernel stack
+///
+/// `_syscall_return` restores them and returns to userspace.
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: usize,
+    pub r14: usize,
+    pub r13: usize,
+    pub r12: usize,
+    pub rbp: usize,
+    pub rbx: usize,
+    pub r9: usize,
+    pub r8: usize,
+    pub r10: usize,
+    pub rdx: usize,
+    pub rsi: usize,
+    pub rdi: usize,
+    /// The syscall number on entry and the return value on exit
+    pub rax: usize,
+    _padding: usize,
+    pub xsave: [u8; XSAVE_AREA_SIZE],
+    pub rip: usize,
+    pub cs: usize,
+    pub rflags: usize,
+    pub rsp: usize,
+    pub ss: usize,
+}
+
+impl SyscallFrame {
+    /// The six syscall arguments and the syscall number
+    pub fn args(&self) -> (usize, usize, usize, usize, usize, usize, usize) {
+        (
+            self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9, self.rax,
+        )
+    }
+}
+
 pub unsafe fn init() {
+    init_cpu(gdt::GDT.as_ref().unwrap(), gdt::TSS.as_ref().unwrap());
+}
+
+/// Setup the syscall MSRs of the current CPU with its `gdt` and `tss`
+pub unsafe fn init_cpu(gdt: &(GlobalDescriptorTable, gdt::Selectors), tss: &TaskStateSegment) {
     // FIXME: might (not) want to use sysret someday for performance
     Star::write(
-        gdt::GDT.as_ref().unwrap().1.user_code_selector,
-        gdt::GDT.as_ref().unwrap().1.user_data_selector,
-        gdt::GDT.as_ref().unwrap().1.code_selector,
-        gdt::GDT.as_ref().unwrap().1.data_selector,
+        gdt.1.user_code_selector,
+        gdt.1.user_data_selector,
+        gdt.1.code_selector,
+        gdt.1.data_selector,
     )
     .unwrap();
 
     LStar::write(VirtAddr::new(_syscall_enter as usize as u64));
 
-    // Clear trap flag and interrupt enable
-    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG);
+    // Clear trap flag, interrupt enable and the user access of SMAP
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::ALIGNMENT_CHECK);
 
-    KernelGsBase::write(VirtAddr::new(gdt::TSS.as_ref().unwrap() as *const _ as u64));
+    KernelGsBase::write(VirtAddr::new(tss as *const _ as u64));
 }
 
-#[allow(clippy::many_single_char_names)]
 #[no_mangle]
-pub unsafe extern "C" fn syscall_rust(
-    a: usize,
-    b: usize,
-    c: usize,
-    d: usize,
-    e: usize,
-    f: usize,
-    nr: usize,
-) -> usize {
-    crate::syscall::handle_syscall(a, b, c, d, e, f, nr)
+pub unsafe extern "C" fn syscall_rust(frame: &mut SyscallFrame) -> usize {
+    crate::syscall::handle_syscall(frame)
 }
 
 #[inline(always)]
@@ -0,0 +1,228 @@
+//! Timekeeping with the TSC
+//!
+//! `CLOCK_MONOTONIC` counts the TSC cycles since the first vCPU started. `CLOCK_REALTIME`
+//! adds the host time of that moment from the `BootInfo`. Without a TSC frequency from
+//! the host, the TSC is measured against channel 2 of the PIT.
+
+use core::sync::atomic::{spin_loop_hint, AtomicU64, Ordering};
+use linux_errno::ErrNo;
+use vmsyscall::bootinfo::BootInfo;
+use x86_64::instructions::port::Port;
+
+pub const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+pub const CLOCK_REALTIME: usize = 0;
+pub const CLOCK_MONOTONIC: usize = 1;
+pub const CLOCK_PROCESS_CPUTIME_ID: usize = 2;
+pub const CLOCK_THREAD_CPUTIME_ID: usize = 3;
+pub const CLOCK_MONOTONIC_RAW: usize = 4;
+pub const CLOCK_REALTIME_COARSE: usize = 5;
+pub const CLOCK_MONOTONIC_COARSE: usize = 6;
+pub const CLOCK_BOOTTIME: usize = 7;
+
+const PIT_HZ: u64 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PIT_GATE_PORT: u16 = 0x61;
+const PIT_GATE2: u8 = 1 << 0;
+const PIT_SPEAKER: u8 = 1 << 1;
+const PIT_OUT2: u8 = 1 << 5;
+/// Length of a PIT calibration in milliseconds
+pub const CALIBRATION_MS: u64 = 10;
+/// Assumed TSC frequency, if neither the host nor the PIT tell
+const DEFAULT_TSC_HZ: u64 = 2_000_000_000;
+
+static TSC_HZ: AtomicU64 = AtomicU64::new(DEFAULT_TSC_HZ);
+/// `CLOCK_REALTIME` minus `CLOCK_MONOTONIC` in nanoseconds
+static REALTIME_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
+/// A `struct timespec`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+impl Timespec {
+    pub fn from_ns(ns: u64) -> Self {
+        Timespec {
+            tv_sec: (ns / NSEC_PER_SEC) as i64,
+            tv_nsec: (ns % NSEC_PER_SEC) as i64,
+        }
+    }
+
+    /// The nanoseconds of a valid timespec
+    pub fn as_ns(&self) -> Result<u64, ErrNo> {
+        if self.tv_sec < 0 || self.tv_nsec < 0 || self.tv_nsec >= NSEC_PER_SEC as i64 {
+            return Err(ErrNo::EINVAL);
+        }
+        Ok((self.tv_sec as u64)
+            .saturating_mul(NSEC_PER_SEC)
+            .saturating_add(self.tv_nsec as u64))
+    }
+}
+
+/// A `struct timeval`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct Timeval {
+    pub tv_sec: i64,
+    pub tv_usec: i64,
+}
+
+impl Timeval {
+    pub fn from_ns(ns: u64) -> Self {
+        Timeval {
+            tv_sec: (ns / NSEC_PER_SEC) as i64,
+            tv_usec: ((ns % NSEC_PER_SEC) / 1000) as i64,
+        }
+    }
+}
+
+/// Busy wait `CALIBRATION_MS` on the PIT channel 2 after calling `start`
+/// and return the passed TSC cycles.
+///
+/// Returns `None`, if the PIT does not count.
+pub unsafe fn pit_calibrate(start: impl FnOnce()) -> Option<u64> {
+    let mut gate: Port<u8> = Port::new(PIT_GATE_PORT);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2);
+
+    // gate the channel 2 on, but not the speaker
+    let val = gate.read();
+    gate.write((val & !PIT_SPEAKER) | PIT_GATE2);
+
+    // channel 2, low and high byte, mode 0: OUT2 goes high after `count` PIT cycles
+    let count = PIT_HZ * CALIBRATION_MS / 1000;
+    command.write(0b1011_0000);
+
+    start();
+    channel2.write(count as u8);
+    channel2.write((count >> 8) as u8);
+    let tsc_start = rdtsc();
+
+    let mut tries: u64 = 0;
+    while gate.read() & PIT_OUT2 == 0 {
+        tries += 1;
+        if tries == 10_000_000 {
+            return None;
+        }
+        spin_loop_hint();
+    }
+
+    Some(rdtsc() - tsc_start)
+}
+
+/// Set up the clocks with the TSC frequency and the host time of the `boot_info`
+pub fn init(boot_info: &BootInfo) {
+    let tsc_hz = if boot_info.tsc_hz != 0 {
+        boot_info.tsc_hz
+    } else {
+        match unsafe { pit_calibrate(|| {}) } {
+            Some(cycles) => cycles * 1000 / CALIBRATION_MS,
+            None => {
+                eprintln!("TSC calibration failed, assuming {} Hz", DEFAULT_TSC_HZ);
+                DEFAULT_TSC_HZ
+            }
+        }
+    };
+
+    #[cfg(debug_assertions)]
+    eprintln!("TSC: {} Hz", tsc_hz);
+
+    TSC_HZ.store(tsc_hz, Ordering::Relaxed);
+    REALTIME_OFFSET_NS.store(boot_info.realtime_ns, Ordering::Relaxed);
+}
+
+#[inline]
+fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Convert TSC cycles to nanoseconds without overflowing
+fn cycles_to_ns(cycles: u64, hz: u64) -> u64 {
+    let secs = cycles / hz;
+    let rest = cycles % hz;
+    secs * NSEC_PER_SEC + rest * NSEC_PER_SEC / hz
+}
+
+/// Nanoseconds since the start of the first vCPU
+pub fn monotonic_ns() -> u64 {
+    cycles_to_ns(rdtsc(), TSC_HZ.load(Ordering::Relaxed))
+}
+
+/// Nanoseconds since the epoch
+pub fn realtime_ns() -> u64 {
+    monotonic_ns() + REALTIME_OFFSET_NS.load(Ordering::Relaxed)
+}
+
+fn is_realtime(clockid: usize) -> bool {
+    clockid == CLOCK_REALTIME || clockid == CLOCK_REALTIME_COARSE
+}
+
+/// The current time of the clock `clockid` in nanoseconds
+///
+/// The CPU time clocks are approximated by the monotonic clock.
+pub fn clock_ns(clockid: usize) -> Result<u64, ErrNo> {
+    match clockid {
+        CLOCK_REALTIME | CLOCK_REALTIME_COARSE => Ok(realtime_ns()),
+        CLOCK_MONOTONIC
+        | CLOCK_MONOTONIC_RAW
+        | CLOCK_MONOTONIC_COARSE
+        | CLOCK_BOOTTIME
+        | CLOCK_PROCESS_CPUTIME_ID
+        | CLOCK_THREAD_CPUTIME_ID => Ok(monotonic_ns()),
+        _ => Err(ErrNo::EINVAL),
+    }
+}
+
+/// Convert the absolute time `ns` of the clock `clockid` to `CLOCK_MONOTONIC`
+pub fn to_monotonic(clockid: usize, ns: u64) -> u64 {
+    if is_realtime(clockid) {
+        ns.saturating_sub(REALTIME_OFFSET_NS.load(Ordering::Relaxed))
+    } else {
+        ns
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_cycles_to_ns() {
+        serial_print!("test_cycles_to_ns...");
+        assert_eq!(cycles_to_ns(3_000_000_000, 3_000_000_000), NSEC_PER_SEC);
+        assert_eq!(cycles_to_ns(1_500, 3_000_000_000), 500);
+        // would overflow with `cycles * NSEC_PER_SEC`
+        assert_eq!(
+            cycles_to_ns(u64::max_value() / 2, 4_000_000_000),
+            u64::max_value() / 2 / 4
+        );
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_timespec() {
+        serial_print!("test_timespec...");
+        let ts = Timespec::from_ns(1_500_000_001);
+        assert_eq!(
+            ts,
+            Timespec {
+                tv_sec: 1,
+                tv_nsec: 500_000_001
+            }
+        );
+        assert_eq!(ts.as_ns(), Ok(1_500_000_001));
+
+        let invalid = Timespec {
+            tv_sec: 0,
+            tv_nsec: NSEC_PER_SEC as i64,
+        };
+        assert!(invalid.as_ns().is_err());
+        assert_eq!(Timeval::from_ns(1_500_000_001).tv_usec, 500_000);
+        serial_println!("[ok]");
+    }
+}
@@ -82,14 +82,14 @@ pub fn timer_set_idt(idt: &mut InterruptDescriptorTable) {
 extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
     eprintln!("EXCEPTION: spurious interrupt");
     eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
+    exit_hypervisor(HyperVisorExitCode::Panic);
     hlt_loop();
 }
 
 extern "x86-interrupt" fn error_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
     eprintln!("EXCEPTION: error interrupt");
     eprintln!("{:#?}", stack_frame);
-    exit_hypervisor(HyperVisorExitCode::Failed);
+    exit_hypervisor(HyperVisorExitCode::Panic);
     hlt_loop();
 }
 
@@ -0,0 +1,366 @@
+//! Access to the memory of the ring3 executable
+//!
+//! With SMAP, the kernel faults on every access to a user page, unless `RFLAGS.AC` is set
+//! with `stac`. Every access is checked first: the range has to be in the lower half of
+//! the address space, every page has to be part of a tracked area of the executable (the
+//! `mmap` areas, the stack, the loaded segments and the heap), whose protection allows
+//! the access, and has to be accessible for userspace in the page tables. So the app
+//! can't make the kernel read or write kernel memory, like the identity mapping of the
+//! RAM, with a syscall.
+//!
+//! The pointer arguments of the syscalls are wrapped in a `UserPtr`, `UserSlice` or
+//! `UserCStr`, whose accesses fail with `EFAULT` instead of faulting in the kernel.
+
+use super::crash::page_flags;
+use super::mmap;
+use super::PAGESIZE;
+use core::cmp::min;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicBool, Ordering};
+use linux_errno::ErrNo;
+use x86_64::structures::paging::PageTableFlags;
+
+extern "C" {
+    fn _stac();
+    fn _clac();
+    fn _read_cr4() -> u64;
+    fn _write_cr4(cr4: u64);
+}
+
+/// `CR4.UMIP`, `CR4.SMEP` and `CR4.SMAP`
+const CR4_UMIP: u64 = 1 << 11;
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+// CPUID leaf 7 features of the same protections
+const CPUID_7_EBX_SMEP: u32 = 1 << 7;
+const CPUID_7_EBX_SMAP: u32 = 1 << 20;
+const CPUID_7_ECX_UMIP: u32 = 1 << 2;
+
+/// End of the lower half of the canonical addresses
+const USER_ADDR_END: usize = 0x0000_8000_0000_0000;
+
+/// Whether SMAP is enabled, so `stac` and `clac` are needed and available
+static SMAP: AtomicBool = AtomicBool::new(false);
+
+/// Enable SMEP, SMAP and UMIP on this CPU, as far as it supports them, and use `stac`
+/// and `clac`, if SMAP is enabled
+///
+/// Every CPU calls this, vmrun and the PVH boot leave the protections disabled.
+pub fn init() {
+    let cr4 = unsafe { _read_cr4() } | cr4_protections();
+    unsafe { _write_cr4(cr4) };
+    SMAP.store(cr4 & CR4_SMAP != 0, Ordering::Relaxed);
+}
+
+/// The CR4 bits of the protections, which the CPU supports
+fn cr4_protections() -> u64 {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    if unsafe { __cpuid(0) }.eax < 7 {
+        return 0;
+    }
+    let features = unsafe { __cpuid_count(7, 0) };
+    let mut cr4 = 0;
+    if features.ebx & CPUID_7_EBX_SMEP != 0 {
+        cr4 |= CR4_SMEP;
+    }
+    if features.ebx & CPUID_7_EBX_SMAP != 0 {
+        cr4 |= CR4_SMAP;
+    }
+    if features.ecx & CPUID_7_ECX_UMIP != 0 {
+        cr4 |= CR4_UMIP;
+    }
+    cr4
+}
+
+/// Run `f` with access to the user pages
+///
+/// # Safety
+///
+/// `f` may only access user memory, which was checked with `check_user` and which no
+/// other thread can unmap meanwhile.
+pub unsafe fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP.load(Ordering::Relaxed);
+    if smap {
+        _stac();
+    }
+    let ret = f();
+    if smap {
+        _clac();
+    }
+    ret
+}
+
+/// The end of `[addr, addr + len)`, if it is in the lower half of the address space
+fn user_range_end(addr: usize, len: usize) -> Option<usize> {
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_ADDR_END => Some(end),
+        _ => None,
+    }
+}
+
+/// Check that the page table entries of `[addr, end)` allow userspace to access it
+/// and to `write` it, if requested.
+fn check_pages(addr: usize, end: usize, write: bool) -> Result<(), ErrNo> {
+    let mut required = PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let mut page = addr & !(PAGESIZE - 1);
+    while page < end {
+        match page_flags(page as u64) {
+            Some(flags) if flags.contains(required) => {}
+            _ => return Err(ErrNo::EFAULT),
+        }
+        page += PAGESIZE;
+    }
+    Ok(())
+}
+
+/// Check that userspace may access `[addr, addr + len)` and may `write` it, if requested.
+///
+/// Lazily populated pages get their frames. Fails with `EFAULT` otherwise.
+/// Another thread may unmap the range afterwards, so the copies check it again.
+pub fn check_user(addr: usize, len: usize, write: bool) -> Result<(), ErrNo> {
+    with_checked_user(addr, len, write, || ())
+}
+
+/// Check `[addr, addr + len)` like `check_user` and run `f` with access to it, while
+/// no other thread can unmap it or change its protection.
+fn with_checked_user<R>(
+    addr: usize,
+    len: usize,
+    write: bool,
+    f: impl FnOnce() -> R,
+) -> Result<R, ErrNo> {
+    if len == 0 {
+        return Ok(f());
+    }
+    let end = user_range_end(addr, len).ok_or(ErrNo::EFAULT)?;
+    mmap::with_user_range(addr, end, write, || {
+        check_pages(addr, end, write)?;
+        Ok(unsafe { with_user_access(f) })
+    })
+    .unwrap_or(Err(ErrNo::EFAULT))
+}
+
+/// Copy `dst.len()` bytes from the user address `src` to `dst`
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), ErrNo> {
+    with_checked_user(src, dst.len(), false, || unsafe {
+        core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len())
+    })
+}
+
+/// Copy `src` to the user address `dst`
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), ErrNo> {
+    with_checked_user(dst, src.len(), true, || unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len())
+    })
+}
+
+/// A pointer to a `T` in the memory of the ring3 executable
+///
+/// `T` must be valid for any bit pattern. The pointer may be unaligned.
+pub struct UserPtr<T> {
+    addr: usize,
+    _type: PhantomData<T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T: Copy> UserPtr<T> {
+    /// The `T` at the user address `addr`, which is checked on every access
+    pub const fn new(addr: usize) -> Self {
+        UserPtr {
+            addr,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn addr(self) -> usize {
+        self.addr
+    }
+
+    pub fn is_null(self) -> bool {
+        self.addr == 0
+    }
+
+    /// The pointer to the `index`th element of an array of `T` starting here
+    pub fn offset(self, index: usize) -> Result<Self, ErrNo> {
+        index
+            .checked_mul(size_of::<T>())
+            .and_then(|offset| self.addr.checked_add(offset))
+            .map(Self::new)
+            .ok_or(ErrNo::EFAULT)
+    }
+
+    /// Copy the `T` from userspace
+    pub fn read(self) -> Result<T, ErrNo> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        copy_from_user(bytes, self.addr)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Copy `value` to userspace
+    pub fn write(self, value: &T) -> Result<(), ErrNo> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(self.addr, bytes)
+    }
+
+    /// Like `read`, but `None` for a NULL pointer
+    pub fn read_opt(self) -> Result<Option<T>, ErrNo> {
+        if self.is_null() {
+            return Ok(None);
+        }
+        self.read().map(Some)
+    }
+
+    /// Like `write`, but nothing is written to a NULL pointer
+    pub fn write_opt(self, value: &T) -> Result<(), ErrNo> {
+        if self.is_null() {
+            return Ok(());
+        }
+        self.write(value)
+    }
+}
+
+/// A buffer of bytes in the memory of the ring3 executable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    /// The `len` bytes at the user address `addr`
+    ///
+    /// Fails with `EFAULT`, if they are not in the lower half of the address space.
+    /// The pages are checked on every access.
+    pub fn new(addr: usize, len: usize) -> Result<Self, ErrNo> {
+        user_range_end(addr, len).ok_or(ErrNo::EFAULT)?;
+        Ok(UserSlice { addr, len })
+    }
+
+    pub fn len(self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len == 0
+    }
+
+    /// The part of at most `len` bytes at `pos`, cut at the end of the buffer
+    pub fn slice(self, pos: usize, len: usize) -> Self {
+        let pos = min(pos, self.len);
+        UserSlice {
+            addr: self.addr + pos,
+            len: min(len, self.len - pos),
+        }
+    }
+
+    /// Copy the first `dst.len()` bytes from userspace to `dst`
+    pub fn read(self, dst: &mut [u8]) -> Result<(), ErrNo> {
+        if dst.len() > self.len {
+            return Err(ErrNo::EFAULT);
+        }
+        copy_from_user(dst, self.addr)
+    }
+
+    /// Copy `src` to the start of the buffer in userspace
+    pub fn write(self, src: &[u8]) -> Result<(), ErrNo> {
+        if src.len() > self.len {
+            return Err(ErrNo::EFAULT);
+        }
+        copy_to_user(self.addr, src)
+    }
+}
+
+/// A NUL terminated string in the memory of the ring3 executable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UserCStr {
+    addr: usize,
+}
+
+impl UserCStr {
+    /// The string at the user address `addr`, which is checked, when it is read
+    pub const fn new(addr: usize) -> Self {
+        UserCStr { addr }
+    }
+
+    /// Copy the string to `buf` and return it without the NUL terminator
+    ///
+    /// Fails with `ENAMETOOLONG`, if it does not fit. No page after the terminator is
+    /// accessed, so the string may end right before an unmapped page.
+    pub fn read(self, buf: &mut [u8]) -> Result<&[u8], ErrNo> {
+        let mut len = 0;
+        while len < buf.len() {
+            let addr = self.addr.checked_add(len).ok_or(ErrNo::EFAULT)?;
+            let chunk = min(PAGESIZE - addr % PAGESIZE, buf.len() - len);
+            copy_from_user(&mut buf[len..len + chunk], addr)?;
+            if let Some(nul) = buf[len..len + chunk].iter().position(|&b| b == 0) {
+                return Ok(&buf[..len + nul]);
+            }
+            len += chunk;
+        }
+        Err(ErrNo::ENAMETOOLONG)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_uaccess_user_range() {
+        serial_print!("test_uaccess_user_range...");
+        assert_eq!(user_range_end(0x1000, 0x1000), Some(0x2000));
+        assert_eq!(user_range_end(USER_ADDR_END - 8, 8), Some(USER_ADDR_END));
+        assert_eq!(user_range_end(USER_ADDR_END - 8, 9), None);
+        assert_eq!(user_range_end(usize::max_value(), 2), None);
+        // the kernel half is never user memory
+        assert_eq!(check_user(USER_ADDR_END, 8, false), Err(ErrNo::EFAULT));
+        assert_eq!(check_user(0, 0, true), Ok(()));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_uaccess_user_slice() {
+        serial_print!("test_uaccess_user_slice...");
+        let buf = UserSlice::new(0x1000, 0x100).unwrap();
+        assert_eq!(buf.slice(0x80, 0x10), UserSlice::new(0x1080, 0x10).unwrap());
+        assert_eq!(buf.slice(0xf8, 0x10), UserSlice::new(0x10f8, 8).unwrap());
+        assert!(buf.slice(0x200, 0x10).is_empty());
+        assert_eq!(buf.read(&mut [0u8; 0x101]), Err(ErrNo::EFAULT));
+        assert_eq!(UserSlice::new(USER_ADDR_END - 8, 9), Err(ErrNo::EFAULT));
+        assert_eq!(UserSlice::new(usize::max_value(), 2), Err(ErrNo::EFAULT));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_uaccess_user_ptr() {
+        serial_print!("test_uaccess_user_ptr...");
+        let iov = UserPtr::<[u64; 2]>::new(0x1000);
+        assert_eq!(iov.offset(3).map(UserPtr::addr), Ok(0x1030));
+        assert!(iov.offset(usize::max_value() / 8).is_err());
+        assert_eq!(UserPtr::<u32>::new(0).read_opt(), Ok(None));
+        assert_eq!(UserPtr::<u32>::new(0).write_opt(&1), Ok(()));
+        assert_eq!(
+            UserPtr::<u32>::new(USER_ADDR_END).read(),
+            Err(ErrNo::EFAULT)
+        );
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,309 @@
+//! Bookkeeping of the virtual memory areas of the ring3 executable
+//!
+//! The areas are kept in a fixed size array sorted by start address.
+//! Adjacent areas with the same protection are merged.
+
+use bitflags::bitflags;
+use linux_errno::ErrNo;
+
+/// Maximum number of distinct areas
+pub const MAX_VMAS: usize = 512;
+
+bitflags! {
+    /// Access protection of an area, as passed to `mmap()` and `mprotect()`
+    pub struct Prot: u32 {
+        /// `PROT_READ`
+        const READ = 0x1;
+        /// `PROT_WRITE`
+        const WRITE = 0x2;
+        /// `PROT_EXEC`
+        const EXEC = 0x4;
+    }
+}
+
+/// A virtual memory area `[start, end)`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub prot: Prot,
+}
+
+impl Vma {
+    const fn empty() -> Self {
+        Vma {
+            start: 0,
+            end: 0,
+            prot: Prot { bits: 0 },
+        }
+    }
+}
+
+/// The areas of an address range `[base, limit)`
+pub struct VmaTracker {
+    base: usize,
+    limit: usize,
+    len: usize,
+    vmas: [Vma; MAX_VMAS],
+}
+
+impl VmaTracker {
+    /// Create an empty tracker for the page aligned range `[base, limit)`
+    pub const fn new(base: usize, limit: usize) -> Self {
+        VmaTracker {
+            base,
+            limit,
+            len: 0,
+            vmas: [Vma::empty(); MAX_VMAS],
+        }
+    }
+
+    /// Iterator over all areas sorted by start address
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas[..self.len].iter()
+    }
+
+    /// The area containing `addr`
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.iter().find(|v| v.start <= addr && addr < v.end)
+    }
+
+    /// Whether `[start, end)` is a non-empty part of the tracked address range
+    pub fn contains_range(&self, start: usize, end: usize) -> bool {
+        self.base <= start && start < end && end <= self.limit
+    }
+
+    /// Whether no area overlaps `[start, end)`
+    pub fn is_free(&self, start: usize, end: usize) -> bool {
+        self.iter().all(|v| v.end <= start || end <= v.start)
+    }
+
+    /// Whether `[start, end)` is completely covered by areas
+    pub fn is_mapped(&self, start: usize, end: usize) -> bool {
+        let mut cur = start;
+        for v in self.iter().filter(|v| v.end > start && v.start < end) {
+            if v.start > cur {
+                return false;
+            }
+            cur = v.end;
+        }
+        cur >= end
+    }
+
+    /// Find a free range of `len` bytes.
+    ///
+    /// `hint` is used, if the range starting there is free.
+    /// Otherwise the lowest free range is returned.
+    pub fn find_free(&self, hint: usize, len: usize) -> Option<usize> {
+        if len == 0 {
+            return None;
+        }
+
+        if let Some(end) = hint.checked_add(len) {
+            if hint != 0 && self.contains_range(hint, end) && self.is_free(hint, end) {
+                return Some(hint);
+            }
+        }
+
+        let mut start = self.base;
+        for v in self.iter() {
+            if v.start - start >= len {
+                return Some(start);
+            }
+            start = v.end;
+        }
+
+        if self.limit - start >= len {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    /// Replace everything in `[start, end)` by one area with protection `prot`.
+    ///
+    /// `f` is called with every part of an old area, which is replaced, and its old protection.
+    pub fn replace<F>(&mut self, start: usize, end: usize, prot: Prot, f: F) -> Result<(), ErrNo>
+    where
+        F: FnMut(usize, usize, Prot),
+    {
+        if !self.contains_range(start, end) {
+            return Err(ErrNo::ENOMEM);
+        }
+
+        // worst case: one area is split in two and the new one does not merge
+        if self.len + 2 > MAX_VMAS {
+            return Err(ErrNo::ENOMEM);
+        }
+
+        self.remove(start, end, f)?;
+        self.insert(start, end, prot);
+        Ok(())
+    }
+
+    /// Change the protection of `[start, end)`, which has to be completely covered by areas.
+    ///
+    /// `f` is called with every part of an old area and its old protection.
+    pub fn protect<F>(&mut self, start: usize, end: usize, prot: Prot, f: F) -> Result<(), ErrNo>
+    where
+        F: FnMut(usize, usize, Prot),
+    {
+        if !self.is_mapped(start, end) {
+            return Err(ErrNo::ENOMEM);
+        }
+        self.replace(start, end, prot, f)
+    }
+
+    /// Remove everything in `[start, end)`.
+    ///
+    /// `f` is called with every removed part of an area and its protection.
+    pub fn remove<F>(&mut self, start: usize, end: usize, mut f: F) -> Result<(), ErrNo>
+    where
+        F: FnMut(usize, usize, Prot),
+    {
+        let splits = self.iter().any(|v| v.start < start && end < v.end);
+        if splits && self.len == MAX_VMAS {
+            return Err(ErrNo::ENOMEM);
+        }
+
+        let mut i = 0;
+        while i < self.len {
+            let v = self.vmas[i];
+            if v.end <= start || end <= v.start {
+                i += 1;
+                continue;
+            }
+
+            f(
+                core::cmp::max(v.start, start),
+                core::cmp::min(v.end, end),
+                v.prot,
+            );
+
+            match (v.start < start, end < v.end) {
+                (true, true) => {
+                    self.vmas[i].end = start;
+                    self.insert_at(
+                        i + 1,
+                        Vma {
+                            start: end,
+                            end: v.end,
+                            prot: v.prot,
+                        },
+                    );
+                    i += 2;
+                }
+                (true, false) => {
+                    self.vmas[i].end = start;
+                    i += 1;
+                }
+                (false, true) => {
+                    self.vmas[i].start = end;
+                    i += 1;
+                }
+                (false, false) => self.remove_at(i),
+            }
+        }
+        Ok(())
+    }
+
+    /// Insert a new area into a free range and merge it with its neighbours.
+    ///
+    /// The caller has to ensure that there is enough space left.
+    fn insert(&mut self, start: usize, end: usize, prot: Prot) {
+        let i = self.iter().position(|v| v.start >= end).unwrap_or(self.len);
+
+        let merge_left = i > 0 && self.vmas[i - 1].end == start && self.vmas[i - 1].prot == prot;
+        let merge_right = i < self.len && self.vmas[i].start == end && self.vmas[i].prot == prot;
+
+        match (merge_left, merge_right) {
+            (true, true) => {
+                self.vmas[i - 1].end = self.vmas[i].end;
+                self.remove_at(i);
+            }
+            (true, false) => self.vmas[i - 1].end = end,
+            (false, true) => self.vmas[i].start = start,
+            (false, false) => self.insert_at(i, Vma { start, end, prot }),
+        }
+    }
+
+    fn insert_at(&mut self, i: usize, vma: Vma) {
+        assert!(self.len < MAX_VMAS);
+        self.vmas.copy_within(i..self.len, i + 1);
+        self.vmas[i] = vma;
+        self.len += 1;
+    }
+
+    fn remove_at(&mut self, i: usize) {
+        self.vmas.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    const BASE: usize = 0x1000_0000;
+    const LIMIT: usize = 0x2000_0000;
+    const RW: Prot = Prot {
+        bits: Prot::READ.bits | Prot::WRITE.bits,
+    };
+
+    #[test_case]
+    fn test_vma_find_free() {
+        serial_print!("test_vma_find_free...");
+        let mut t = VmaTracker::new(BASE, LIMIT);
+        assert_eq!(t.find_free(0, 0x2000), Some(BASE));
+        t.replace(BASE, BASE + 0x2000, RW, |_, _, _| {}).unwrap();
+        assert_eq!(t.find_free(0, 0x1000), Some(BASE + 0x2000));
+        assert_eq!(t.find_free(BASE + 0x8000, 0x1000), Some(BASE + 0x8000));
+        assert_eq!(t.find_free(BASE + 0x1000, 0x1000), Some(BASE + 0x2000));
+        assert_eq!(t.find_free(0, LIMIT - BASE), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_vma_merge_split() {
+        serial_print!("test_vma_merge_split...");
+        let mut t = VmaTracker::new(BASE, LIMIT);
+        t.replace(BASE, BASE + 0x1000, RW, |_, _, _| {}).unwrap();
+        t.replace(BASE + 0x1000, BASE + 0x3000, RW, |_, _, _| {})
+            .unwrap();
+        assert_eq!(t.iter().count(), 1);
+
+        let mut removed = 0;
+        t.remove(BASE + 0x1000, BASE + 0x2000, |s, e, _| removed += e - s)
+            .unwrap();
+        assert_eq!(removed, 0x1000);
+        assert_eq!(t.iter().count(), 2);
+        assert!(t.find(BASE + 0x1000).is_none());
+        assert_eq!(t.find(BASE + 0x2000).unwrap().start, BASE + 0x2000);
+        assert!(!t.is_mapped(BASE, BASE + 0x3000));
+
+        t.remove(BASE, LIMIT, |_, _, _| {}).unwrap();
+        assert_eq!(t.iter().count(), 0);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_vma_protect() {
+        serial_print!("test_vma_protect...");
+        let mut t = VmaTracker::new(BASE, LIMIT);
+        t.replace(BASE, BASE + 0x4000, RW, |_, _, _| {}).unwrap();
+        t.protect(BASE + 0x1000, BASE + 0x2000, Prot::empty(), |s, e, p| {
+            assert_eq!((s, e, p), (BASE + 0x1000, BASE + 0x2000, RW))
+        })
+        .unwrap();
+        assert_eq!(t.iter().count(), 3);
+        assert_eq!(t.find(BASE + 0x1000).unwrap().prot, Prot::empty());
+
+        t.protect(BASE + 0x1000, BASE + 0x2000, RW, |_, _, _| {})
+            .unwrap();
+        assert_eq!(t.iter().count(), 1);
+
+        assert!(t.protect(BASE, BASE + 0x5000, RW, |_, _, _| {}).is_err());
+        serial_println!("[ok]");
+    }
+}
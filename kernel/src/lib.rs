@@ -21,6 +21,7 @@ fn foo() {
 extern crate alloc;
 
 use core::panic::PanicInfo;
+use vmsyscall::exit::{ExitCode, EXIT_PORT, EXIT_RECORD_PORT};
 
 #[cfg(feature = "allocator")]
 use linked_list_allocator::LockedHeap;
@@ -31,22 +32,24 @@ pub mod libc;
 pub mod memory;
 pub mod strlen;
 pub mod syscall;
+pub mod syscall_policy;
+pub mod trace;
 
 #[cfg(any(feature = "nightly", test))]
 #[lang = "eh_personality"]
 extern "C" fn eh_personality() {
-    exit_hypervisor(HyperVisorExitCode::Failed);
+    exit_hypervisor(HyperVisorExitCode::Panic);
 }
 
 #[cfg(not(any(feature = "nightly", test)))]
 #[no_mangle]
 pub extern "C" fn rust_eh_personality() {
-    exit_hypervisor(HyperVisorExitCode::Failed);
+    exit_hypervisor(HyperVisorExitCode::Panic);
 }
 
 #[no_mangle]
 extern "C" fn _Unwind_Resume() {
-    exit_hypervisor(HyperVisorExitCode::Failed);
+    exit_hypervisor(HyperVisorExitCode::Panic);
 }
 
 #[cfg(feature = "allocator")]
@@ -73,18 +76,47 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
 pub enum HyperVisorExitCode {
-    Success = 0x10,
-    Failed = 0x11,
+    /// The kernel tests succeeded
+    Success,
+    /// The kernel tests failed
+    Failed,
+    /// The app exited with the status
+    Exit(u8),
+    /// The kernel panicked
+    Panic,
+    /// A fatal CPU exception with the vector
+    Exception(u8),
+    /// The app was killed by the signal
+    Signal(u8),
+}
+
+impl From<HyperVisorExitCode> for ExitCode {
+    fn from(code: HyperVisorExitCode) -> Self {
+        match code {
+            HyperVisorExitCode::Success => ExitCode::Exit(0),
+            HyperVisorExitCode::Failed => ExitCode::Exit(1),
+            HyperVisorExitCode::Exit(status) => ExitCode::Exit(status),
+            HyperVisorExitCode::Panic => ExitCode::Panic,
+            HyperVisorExitCode::Exception(vector) => ExitCode::Exception(vector),
+            HyperVisorExitCode::Signal(signo) => ExitCode::Signal(signo),
+        }
+    }
 }
 
 pub fn exit_hypervisor(exit_code: HyperVisorExitCode) {
     use x86_64::instructions::port::PortWriteOnly;
 
+    let value = ExitCode::from(exit_code).encode();
+
     unsafe {
-        let mut port = PortWriteOnly::new(0xf4);
-        port.write(exit_code as u32);
+        // qemu truncates the value of the exit port, so record it for vmrun first
+        let mut record = PortWriteOnly::<u8>::new(EXIT_RECORD_PORT);
+        for byte in value.to_le_bytes().iter() {
+            record.write(*byte);
+        }
+        let mut port = PortWriteOnly::<u32>::new(EXIT_PORT);
+        port.write(value);
     }
 }
 
@@ -0,0 +1,109 @@
+use super::vm_syscall;
+pub use vmsyscall::Error;
+use vmsyscall::{Stat, VmSyscall, VmSyscallRet, PATH_BUF_LEN, READ_BUF_LEN, WRITE_BUF_LEN};
+
+/// Open only the path, without read or write access
+const O_PATH: i32 = 0o10_000_000;
+
+pub fn openat(dirfd: i32, path: &[u8], flags: i32, mode: u32) -> Result<i32, Error> {
+    if path.len() >= PATH_BUF_LEN {
+        return Err(Error::SerializeError);
+    }
+    let mut buf = [0u8; PATH_BUF_LEN];
+    buf[..path.len()].copy_from_slice(path);
+
+    let s = VmSyscall::Openat {
+        dirfd,
+        path: buf,
+        path_len: path.len(),
+        flags,
+        mode,
+    };
+    let ret = vm_syscall(s)?;
+    match ret {
+        VmSyscallRet::Openat(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+pub fn close(fd: i32) -> Result<i32, Error> {
+    let ret = vm_syscall(VmSyscall::Close { fd })?;
+    match ret {
+        VmSyscallRet::Close(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+/// Read up to `READ_BUF_LEN` bytes at `offset` or at the current file offset
+pub fn pread(fd: i32, buf: &mut [u8], offset: Option<i64>) -> Result<usize, Error> {
+    let count = core::cmp::min(buf.len(), READ_BUF_LEN);
+    let ret = vm_syscall(VmSyscall::Pread { fd, count, offset })?;
+    match ret {
+        VmSyscallRet::Pread(Ok((n, data))) => {
+            let n = core::cmp::min(n as usize, count);
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+        VmSyscallRet::Pread(Err(e)) => Err(e),
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+/// Write up to `WRITE_BUF_LEN` bytes at `offset` or at the current file offset
+pub fn pwrite(fd: i32, bytes: &[u8], offset: Option<i64>) -> Result<usize, Error> {
+    let count = core::cmp::min(bytes.len(), WRITE_BUF_LEN);
+    let mut data = [0u8; WRITE_BUF_LEN];
+    data[..count].copy_from_slice(&bytes[..count]);
+
+    let ret = vm_syscall(VmSyscall::Pwrite {
+        fd,
+        count,
+        offset,
+        data,
+    })?;
+    match ret {
+        VmSyscallRet::Pwrite(res) => res.map(|n| n as usize),
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+pub fn lseek(fd: i32, offset: i64, whence: i32) -> Result<i64, Error> {
+    let ret = vm_syscall(VmSyscall::Lseek { fd, offset, whence })?;
+    match ret {
+        VmSyscallRet::Lseek(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+pub fn fstat(fd: i32) -> Result<Stat, Error> {
+    let ret = vm_syscall(VmSyscall::Fstat { fd })?;
+    match ret {
+        VmSyscallRet::Fstat(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+/// `stat()` the `path` relative to `dirfd` by opening it with `O_PATH`
+///
+/// The passthrough resolves all symlinks below its root, so `AT_SYMLINK_NOFOLLOW` has no effect.
+pub fn fstatat(dirfd: i32, path: &[u8]) -> Result<Stat, Error> {
+    let fd = openat(dirfd, path, O_PATH, 0)?;
+    let stat = fstat(fd);
+    close(fd)?;
+    stat
+}
+
+/// Read up to `READ_BUF_LEN` bytes of `linux_dirent64` records
+pub fn getdents64(fd: i32, buf: &mut [u8]) -> Result<usize, Error> {
+    let count = core::cmp::min(buf.len(), READ_BUF_LEN);
+    let ret = vm_syscall(VmSyscall::Getdents64 { fd, count })?;
+    match ret {
+        VmSyscallRet::Getdents64(Ok((n, data))) => {
+            let n = core::cmp::min(n as usize, count);
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+        VmSyscallRet::Getdents64(Err(e)) => Err(e),
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
@@ -1,53 +1,146 @@
+use core::sync::atomic::{compiler_fence, AtomicU32, Ordering};
+use vmsyscall::crash::CrashReport;
+use vmsyscall::trace::{TraceRecord, TraceSummary, TRACE_BATCH_LEN, TRACE_SUMMARY_BATCH_LEN};
+use vmsyscall::wire::MAX_MESSAGE_LEN;
 pub use vmsyscall::Error;
-use vmsyscall::{VmSyscall, VmSyscallRet, WRITE_BUF_LEN};
+use vmsyscall::{VmSyscall, VmSyscallRet, READ_BUF_LEN, WRITE_BUF_LEN};
 use x86_64::instructions::port::Port;
 use x86_64::VirtAddr;
 
 mod mmap;
 pub use mmap::*;
 
-use crate::arch::{SYSCALL_PHYS_ADDR, SYSCALL_TRIGGER_PORT};
+pub mod fs;
+
+use crate::arch::{syscall_page, SYSCALL_TRIGGER_PORT};
 
 #[cfg(test)]
 mod test;
 
 #[inline(always)]
 pub fn write(fd: u32, bytes: &[u8]) -> Result<i32, Error> {
-    unsafe {
-        let syscall_page = VirtAddr::new(SYSCALL_PHYS_ADDR);
-        let request = syscall_page.as_u64() as *mut VmSyscall;
-        let mut data = [0u8; WRITE_BUF_LEN];
-        data[..bytes.len()].copy_from_slice(bytes);
+    let count = core::cmp::min(bytes.len(), WRITE_BUF_LEN);
+    let mut data = [0u8; WRITE_BUF_LEN];
+    data[..count].copy_from_slice(&bytes[..count]);
 
-        request.write_volatile(VmSyscall::Write {
-            fd,
-            count: bytes.len(),
-            data,
-        });
+    let ret = vm_syscall(VmSyscall::Write { fd, count, data })?;
+    match ret {
+        VmSyscallRet::Write(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
 
-        let mut port = Port::<u16>::new(SYSCALL_TRIGGER_PORT);
-        port.write(1 as u16);
-        let reply = syscall_page.as_u64() as *mut VmSyscallRet;
+/// Read up to `READ_BUF_LEN` bytes from the hypervisor
+pub fn read(fd: u32, buf: &mut [u8]) -> Result<usize, Error> {
+    let count = core::cmp::min(buf.len(), READ_BUF_LEN);
+    let ret = vm_syscall(VmSyscall::Read { fd, count })?;
+    match ret {
+        VmSyscallRet::Read(Ok((n, data))) => {
+            let n = core::cmp::min(n as usize, count);
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+        VmSyscallRet::Read(Err(e)) => Err(e),
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
 
-        match reply.read_volatile() {
-            VmSyscallRet::Write(res) => res,
-            _ => panic!("Unknown KvmSyscallRet"),
+/// Fill `buf` with entropy of the host, at most `READ_BUF_LEN` bytes per call
+pub fn getrandom(buf: &mut [u8]) -> Result<usize, Error> {
+    let count = core::cmp::min(buf.len(), READ_BUF_LEN);
+    let ret = vm_syscall(VmSyscall::GetRandom { count })?;
+    match ret {
+        VmSyscallRet::GetRandom(Ok((n, data))) => {
+            let n = core::cmp::min(n as usize, count);
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
         }
+        VmSyscallRet::GetRandom(Err(e)) => Err(e),
+        _ => panic!("Unknown KvmSyscallRet"),
     }
 }
 
-#[inline(always)]
+/// Hand at most `TRACE_BATCH_LEN` syscall trace `records` to the host
+pub fn trace(records: &[TraceRecord]) -> Result<i32, Error> {
+    let count = core::cmp::min(records.len(), TRACE_BATCH_LEN);
+    let mut batch = [TraceRecord::default(); TRACE_BATCH_LEN];
+    batch[..count].copy_from_slice(&records[..count]);
+    let ret = vm_syscall(VmSyscall::Trace {
+        count,
+        records: batch,
+    })?;
+    match ret {
+        VmSyscallRet::Trace(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+/// Hand at most `TRACE_SUMMARY_BATCH_LEN` syscall totals to the host
+pub fn trace_summary(entries: &[TraceSummary], last: bool) -> Result<i32, Error> {
+    let count = core::cmp::min(entries.len(), TRACE_SUMMARY_BATCH_LEN);
+    let mut batch = [TraceSummary::default(); TRACE_SUMMARY_BATCH_LEN];
+    batch[..count].copy_from_slice(&entries[..count]);
+    let ret = vm_syscall(VmSyscall::TraceSummary {
+        count,
+        last,
+        entries: batch,
+    })?;
+    match ret {
+        VmSyscallRet::TraceSummary(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+/// Hand the report of a fatal exception to the host
+pub fn crash(report: &CrashReport) -> Result<i32, Error> {
+    let ret = vm_syscall(VmSyscall::Crash { report: *report })?;
+    match ret {
+        VmSyscallRet::Crash(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+/// Start the application processor `cpu` at `entry` with `stack` and `arg` in `rdi`
+pub fn start_cpu(cpu: u32, entry: u64, stack: u64, arg: u64) -> Result<i32, Error> {
+    let ret = vm_syscall(VmSyscall::StartCpu {
+        cpu,
+        entry,
+        stack,
+        arg,
+    })?;
+    match ret {
+        VmSyscallRet::StartCpu(res) => res,
+        _ => panic!("Unknown KvmSyscallRet"),
+    }
+}
+
+static REQUEST_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Send a request to the hypervisor via the syscall page and wait for the reply
 pub fn vm_syscall(syscall: VmSyscall) -> Result<VmSyscallRet, Error> {
-    let syscall_page = VirtAddr::new(unsafe { SYSCALL_PHYS_ADDR });
-    let request = syscall_page.as_u64() as *mut VmSyscall;
-    let reply = syscall_page.as_u64() as *mut VmSyscallRet;
+    // every CPU has its own page
+    let syscall_page = VirtAddr::new(syscall_page());
+    let page = unsafe {
+        core::slice::from_raw_parts_mut(syscall_page.as_mut_ptr::<u8>(), MAX_MESSAGE_LEN)
+    };
+
+    let request_id = REQUEST_ID.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
 
+    syscall.encode(request_id, page)?;
+
+    // The hypervisor reads and writes the page, when the port is written.
+    compiler_fence(Ordering::SeqCst);
     unsafe {
-        request.write_volatile(syscall);
         let mut port = Port::<u16>::new(SYSCALL_TRIGGER_PORT);
         port.write(1 as u16);
-        Ok(reply.read_volatile())
     }
+    compiler_fence(Ordering::SeqCst);
+
+    let (reply_id, reply) = VmSyscallRet::decode(page)?;
+    if reply_id != request_id || reply.nr() != syscall.nr() {
+        return Err(Error::DeSerializeError);
+    }
+    Ok(reply)
 }
 
 #[allow(non_camel_case_types)]
@@ -1,4 +1,5 @@
 use super::mmap::*;
+use super::{getrandom, read};
 use crate::{serial_print, serial_println};
 use linux_errno::ErrNo;
 pub use vmsyscall::Error;
@@ -42,3 +43,37 @@ fn test_mprotect() {
     assert_eq!(ret, Error::Errno(ErrNo::ENOSYS.into()));
     serial_println!("[ok]");
 }
+
+#[test_case]
+fn test_getrandom() {
+    serial_print!("test_getrandom...");
+    let mut buf = [0u8; 32];
+    assert_eq!(getrandom(&mut buf), Ok(32));
+    assert!(buf.iter().any(|b| *b != 0));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_read_badfd() {
+    serial_print!("test_read_badfd...");
+    let mut buf = [0u8; 16];
+    let ret = read(3, &mut buf).unwrap_err();
+    assert_eq!(ret, Error::Errno(ErrNo::EBADF.into()));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_openat_noroot() {
+    serial_print!("test_openat_noroot...");
+    let ret = super::fs::openat(vmsyscall::AT_FDCWD, b"/init", 0, 0).unwrap_err();
+    assert_eq!(ret, Error::Errno(ErrNo::ENOENT.into()));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_close_badfd() {
+    serial_print!("test_close_badfd...");
+    let ret = super::fs::close(3).unwrap_err();
+    assert_eq!(ret, Error::Errno(ErrNo::EBADF.into()));
+    serial_println!("[ok]");
+}
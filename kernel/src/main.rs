@@ -60,7 +60,7 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
-    exit_hypervisor(HyperVisorExitCode::Failed);
+    exit_hypervisor(HyperVisorExitCode::Panic);
     kernel::hlt_loop()
 }
 
@@ -1,9 +1,11 @@
 use crate::arch::x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
-    PhysAddr, VirtAddr,
+    structures::paging::{
+        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr, PHYSICAL_MEMORY_OFFSET,
 };
-use vmsyscall::memory_map::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
-use x86_64::structures::paging::{FrameDeallocator, UnusedPhysFrame};
+use vmsyscall::memory_map::{MemoryMap, MemoryRegion, MemoryRegionType};
+use x86_64::structures::paging::{FrameDeallocator, PhysFrameRange, UnusedPhysFrame};
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -38,10 +40,126 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr // unsafe
 }
 
+/// Number of 4 KiB frames in a 2 MiB frame
+const FRAMES_PER_2MIB: usize = 512;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// One bit per physical frame, which is set, if the frame is in use.
+///
+/// Bits of frames beyond `frames` are always set.
+pub struct FrameBitmap<'a> {
+    words: &'a mut [u64],
+    frames: usize,
+    free: usize,
+    /// There is no free frame in the words below this index
+    hint: usize,
+}
+
+impl<'a> FrameBitmap<'a> {
+    /// Create a bitmap for `frames` frames with all frames in use
+    pub fn new(words: &'a mut [u64], frames: usize) -> Self {
+        assert!(words.len() * 64 >= frames);
+        words.iter_mut().for_each(|w| *w = !0);
+        FrameBitmap {
+            words,
+            frames,
+            free: 0,
+            hint: 0,
+        }
+    }
+
+    /// Number of free frames
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+
+    pub fn is_free(&self, n: usize) -> bool {
+        n < self.frames && self.words[n / 64] & (1 << (n % 64)) == 0
+    }
+
+    /// Mark the frames `[start, end)` as free
+    pub fn free_range(&mut self, start: usize, end: usize) {
+        let end = core::cmp::min(end, self.frames);
+        for n in start..end {
+            if !self.is_free(n) {
+                self.words[n / 64] &= !(1 << (n % 64));
+                self.free += 1;
+            }
+        }
+        self.hint = core::cmp::min(self.hint, start / 64);
+    }
+
+    /// Mark the frames `[start, end)` as used
+    pub fn mark_used(&mut self, start: usize, end: usize) {
+        let end = core::cmp::min(end, self.frames);
+        for n in start..end {
+            if self.is_free(n) {
+                self.words[n / 64] |= 1 << (n % 64);
+                self.free -= 1;
+            }
+        }
+    }
+
+    /// Allocate the lowest free frame
+    pub fn alloc(&mut self) -> Option<usize> {
+        for i in self.hint..self.words.len() {
+            let word = self.words[i];
+            if word != !0 {
+                let bit = (!word).trailing_zeros() as usize;
+                self.words[i] |= 1 << bit;
+                self.free -= 1;
+                self.hint = i;
+                return Some(i * 64 + bit);
+            }
+        }
+        self.hint = self.words.len();
+        None
+    }
+
+    /// Allocate `count` contiguous frames, the first one aligned to `align` frames
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        if count == 0 || !align.is_power_of_two() || count > self.free {
+            return None;
+        }
+
+        let align_up = |n: usize| (n + align - 1) & !(align - 1);
+
+        let mut start = align_up(self.hint * 64);
+        while start + count <= self.frames {
+            match (start..start + count).rev().find(|&n| !self.is_free(n)) {
+                None => {
+                    self.mark_used(start, start + count);
+                    return Some(start);
+                }
+                Some(used) => start = align_up(used + 1),
+            }
+        }
+        None
+    }
+}
+
+/// Statistics of the frame allocator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Number of frames managed by the allocator
+    pub total_frames: usize,
+    /// Number of frames currently free
+    pub free_frames: usize,
+}
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Free frames are tracked in a [`FrameBitmap`](FrameBitmap), which is stored
+/// in the first usable region big enough to hold it.
 pub struct BootInfoFrameAllocator {
     memory_map: MemoryMap,
-    next: usize,
+    bitmap: FrameBitmap<'static>,
+    total_frames: usize,
+}
+
+fn is_free_region(r: &MemoryRegion) -> bool {
+    r.region_type == MemoryRegionType::Usable || r.region_type == MemoryRegionType::Bootloader
 }
 
 impl BootInfoFrameAllocator {
@@ -54,55 +172,167 @@ impl BootInfoFrameAllocator {
     /// # Safety
     /// FIXME
     pub unsafe fn init(memory_map: MemoryMap) -> Self {
+        let frames = memory_map
+            .iter()
+            .filter(|r| is_free_region(r))
+            .map(|r| r.range.end_frame_number as usize)
+            .max()
+            .unwrap_or(0);
+        let words_len = (frames + 63) / 64;
+        let bitmap_frames = (words_len * 8 + FRAME_SIZE as usize - 1) / FRAME_SIZE as usize;
+
+        let bitmap_region = memory_map
+            .iter()
+            .filter(|r| is_free_region(r))
+            .find(|r| r.range.len() as usize >= bitmap_frames)
+            .expect("no memory for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_frame_number as usize;
+
+        let words = core::slice::from_raw_parts_mut(
+            (PHYSICAL_MEMORY_OFFSET + bitmap_region.range.start_addr()) as *mut u64,
+            words_len,
+        );
+
+        let mut bitmap = FrameBitmap::new(words, frames);
+        for r in memory_map.iter().filter(|r| is_free_region(r)) {
+            bitmap.free_range(
+                r.range.start_frame_number as usize,
+                r.range.end_frame_number as usize,
+            );
+        }
+        let total_frames = bitmap.free_frames();
+        bitmap.mark_used(bitmap_start, bitmap_start + bitmap_frames);
+
         BootInfoFrameAllocator {
             memory_map,
-            next: 0,
+            bitmap,
+            total_frames,
         }
     }
 
     pub fn set_region_type_usable(&mut self, region_type: MemoryRegionType) {
+        let bitmap = &mut self.bitmap;
+        let free_before = bitmap.free_frames();
         self.memory_map.iter_mut().for_each(|r| {
             if r.region_type == region_type {
-                r.region_type = MemoryRegionType::Usable
+                r.region_type = MemoryRegionType::Usable;
+                bitmap.free_range(
+                    r.range.start_frame_number as usize,
+                    r.range.end_frame_number as usize,
+                );
             }
         });
+        self.total_frames += bitmap.free_frames() - free_before;
     }
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = UnusedPhysFrame> + '_ {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| {
-            r.region_type == MemoryRegionType::Usable
-                || r.region_type == MemoryRegionType::Bootloader
-        });
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        let frames = frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
-        // we know that the frames are really unused
-        frames.map(|f| unsafe { UnusedPhysFrame::new(f) })
+    /// Current number of total and free frames
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            free_frames: self.bitmap.free_frames(),
+        }
     }
+
+    /// Allocate `count` physically contiguous frames
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        let start = self.bitmap.alloc_contiguous(count, 1)?;
+        Some(PhysFrame::range(
+            frame_from_number(start),
+            frame_from_number(start + count),
+        ))
+    }
+
+    /// Free frames allocated with [`allocate_contiguous`](Self::allocate_contiguous)
+    ///
+    /// # Safety
+    /// The frames must not be in use anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        self.bitmap
+            .free_range(frame_number(range.start), frame_number(range.end));
+    }
+}
+
+fn frame_number<S: x86_64::structures::paging::PageSize>(frame: PhysFrame<S>) -> usize {
+    (frame.start_address().as_u64() / FRAME_SIZE) as usize
+}
+
+fn frame_from_number<S: x86_64::structures::paging::PageSize>(n: usize) -> PhysFrame<S> {
+    PhysFrame::containing_address(PhysAddr::new(n as u64 * FRAME_SIZE))
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let n = self.bitmap.alloc()?;
+        // we know that the frame is really unused
+        Some(unsafe { UnusedPhysFrame::new(frame_from_number(n)) })
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame<Size2MiB>> {
+        let n = self
+            .bitmap
+            .alloc_contiguous(FRAMES_PER_2MIB, FRAMES_PER_2MIB)?;
+        // we know that the frames are really unused
+        Some(unsafe { UnusedPhysFrame::new(frame_from_number(n)) })
     }
 }
 
 impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
     fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size4KiB>) {
-        self.memory_map.add_region(MemoryRegion {
-            range: FrameRange::new(
-                frame.start_address().as_u64(),
-                frame.start_address().as_u64() + frame.size() - 1,
-            ),
-            region_type: MemoryRegionType::Usable,
-        })
+        let n = frame_number(*frame);
+        self.bitmap.free_range(n, n + 1);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BootInfoFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame<Size2MiB>) {
+        let n = frame_number(*frame);
+        self.bitmap.free_range(n, n + FRAMES_PER_2MIB);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_frame_bitmap_reuse() {
+        serial_print!("test_frame_bitmap_reuse...");
+        let mut words = [0u64; 4];
+        let mut bitmap = FrameBitmap::new(&mut words, 200);
+        bitmap.free_range(1, 200);
+        assert_eq!(bitmap.free_frames(), 199);
+
+        assert_eq!(bitmap.alloc(), Some(1));
+        assert_eq!(bitmap.alloc(), Some(2));
+        bitmap.free_range(1, 2);
+        assert_eq!(bitmap.alloc(), Some(1));
+        assert_eq!(bitmap.free_frames(), 197);
+
+        while bitmap.alloc().is_some() {}
+        assert_eq!(bitmap.free_frames(), 0);
+        bitmap.free_range(150, 151);
+        assert_eq!(bitmap.alloc(), Some(150));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_frame_bitmap_contiguous() {
+        serial_print!("test_frame_bitmap_contiguous...");
+        let mut words = [0u64; 4];
+        let mut bitmap = FrameBitmap::new(&mut words, 256);
+        bitmap.free_range(0, 256);
+        bitmap.mark_used(10, 11);
+
+        assert_eq!(bitmap.alloc_contiguous(64, 64), Some(64));
+        assert_eq!(bitmap.alloc_contiguous(8, 1), Some(0));
+        assert_eq!(bitmap.alloc_contiguous(60, 1), Some(128));
+        assert_eq!(bitmap.alloc_contiguous(64, 64), Some(192));
+        assert_eq!(bitmap.alloc_contiguous(64, 64), None);
+        assert_eq!(bitmap.alloc_contiguous(2, 1), Some(8));
+        assert_eq!(bitmap.free_frames(), 256 - 1 - 64 - 8 - 60 - 64 - 2);
+        serial_println!("[ok]");
     }
 }
@@ -1,9 +1,19 @@
-use crate::arch::x86_64::{brk_user, mmap_user, NEXT_MMAP};
+use crate::arch::x86_64::random;
+use crate::arch::x86_64::sched::{self, FUTEX_BITSET_MATCH_ANY, MAIN_TID};
+use crate::arch::x86_64::signal::{self, SigAction, SigStack, SIGSET_SIZE};
+use crate::arch::x86_64::syscall::SyscallFrame;
+use crate::arch::x86_64::time::{self, Timespec, Timeval};
+use crate::arch::x86_64::uaccess::{UserCStr, UserPtr, UserSlice};
+use crate::arch::x86_64::{brk_user, exe_path, mmap_user, mprotect_user, munmap_user};
 //use crate::arch::SyscallStack;
-use crate::{eprintln, exit_hypervisor, print, HyperVisorExitCode};
+use crate::libc::fs;
+use crate::syscall_policy;
+use crate::trace;
+use crate::{exit_hypervisor, print, HyperVisorExitCode};
 //use vmbootspec::layout::USER_HEAP_OFFSET;
 use linux_errno::ErrNo;
 use linux_syscall::SysCall;
+use vmsyscall::{Stat, AT_FDCWD, PATH_BUF_LEN, READ_BUF_LEN, WRITE_BUF_LEN};
 
 trait NegAsUsize {
     fn neg_as_usize(self) -> usize;
@@ -15,6 +25,245 @@ impl NegAsUsize for ErrNo {
     }
 }
 
+impl NegAsUsize for vmsyscall::Error {
+    fn neg_as_usize(self) -> usize {
+        match self {
+            vmsyscall::Error::Errno(e) => -e as _,
+            _ => ErrNo::EIO.neg_as_usize(),
+        }
+    }
+}
+
+/// Transfer `len` bytes in chunks of at most `chunk_len` bytes, which fit the syscall page.
+///
+/// `f` is called with the position and the length of the chunk and returns the number of
+/// bytes transferred. A short transfer (e.g. EOF) ends the loop.
+fn transfer_chunked(
+    len: usize,
+    chunk_len: usize,
+    mut f: impl FnMut(usize, usize) -> Result<usize, vmsyscall::Error>,
+) -> usize {
+    let mut done = 0;
+    while done < len {
+        let want = core::cmp::min(len - done, chunk_len);
+        match f(done, want) {
+            Ok(n) => {
+                done += n;
+                if n < want {
+                    break;
+                }
+            }
+            Err(e) if done == 0 => return e.neg_as_usize(),
+            Err(_) => break,
+        }
+    }
+    done
+}
+
+/// The `vmsyscall::Error` of the errno `e`
+fn to_error(e: ErrNo) -> vmsyscall::Error {
+    vmsyscall::Error::Errno(e.into())
+}
+
+/// Read up to `buf.len()` bytes with `read` into the user buffer `buf`
+///
+/// `read` is called with the position and a kernel buffer of at most `READ_BUF_LEN` bytes,
+/// which is copied to `buf`, like in `transfer_chunked`.
+fn read_chunked(
+    buf: UserSlice,
+    mut read: impl FnMut(usize, &mut [u8]) -> Result<usize, vmsyscall::Error>,
+) -> usize {
+    let mut chunk = [0u8; READ_BUF_LEN];
+    transfer_chunked(buf.len(), READ_BUF_LEN, |pos, want| {
+        let n = read(pos, &mut chunk[..want])?;
+        buf.slice(pos, n).write(&chunk[..n]).map_err(to_error)?;
+        Ok(n)
+    })
+}
+
+/// Write the user buffer `buf` with `write`
+///
+/// `write` is called with the position and a kernel copy of at most `WRITE_BUF_LEN` bytes,
+/// like in `transfer_chunked`.
+fn write_chunked(
+    buf: UserSlice,
+    mut write: impl FnMut(usize, &[u8]) -> Result<usize, vmsyscall::Error>,
+) -> usize {
+    let mut chunk = [0u8; WRITE_BUF_LEN];
+    transfer_chunked(buf.len(), WRITE_BUF_LEN, |pos, want| {
+        buf.slice(pos, want)
+            .read(&mut chunk[..want])
+            .map_err(to_error)?;
+        write(pos, &chunk[..want])
+    })
+}
+
+/// Print the UTF-8 `text` on the console
+///
+/// The text is copied in chunks, a character split by a chunk is completed by the next one.
+fn print_user(text: UserSlice) -> Result<usize, ErrNo> {
+    let mut chunk = [0u8; WRITE_BUF_LEN];
+    let mut carry = 0;
+    let mut pos = 0;
+    while pos < text.len() {
+        let want = core::cmp::min(text.len() - pos, WRITE_BUF_LEN - carry);
+        text.slice(pos, want)
+            .read(&mut chunk[carry..carry + want])?;
+        pos += want;
+        let filled = carry + want;
+        let valid = match core::str::from_utf8(&chunk[..filled]) {
+            Ok(_) => filled,
+            Err(e) if e.error_len().is_none() && pos < text.len() => e.valid_up_to(),
+            Err(_) => return Err(ErrNo::EINVAL),
+        };
+        if let Ok(s) = core::str::from_utf8(&chunk[..valid]) {
+            print!("{}", s);
+        }
+        chunk.copy_within(valid..filled, 0);
+        carry = filled - valid;
+    }
+    Ok(text.len())
+}
+
+/// `read()` the file descriptor `fd` into the user buffer `buf`
+fn read_fd(fd: usize, buf: UserSlice) -> usize {
+    match fd {
+        // like a pipe or a terminal, return what the first host read got
+        0 => read_chunked(buf.slice(0, READ_BUF_LEN), |_, chunk| {
+            crate::libc::read(0, chunk)
+        }),
+        1 | 2 => ErrNo::EBADF.neg_as_usize(),
+        _ => read_chunked(buf, |_, chunk| fs::pread(fd as _, chunk, None)),
+    }
+}
+
+/// `write()` the user buffer `buf` to the file descriptor `fd`
+fn write_fd(fd: usize, buf: UserSlice) -> usize {
+    match fd {
+        1 | 2 => print_user(buf).unwrap_or_else(NegAsUsize::neg_as_usize),
+        0 => ErrNo::EBADF.neg_as_usize(),
+        _ => write_chunked(buf, |_, chunk| fs::pwrite(fd as _, chunk, None)),
+    }
+}
+
+/// `struct iovec`
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Iovec {
+    iov_base: usize, /* Starting address */
+    iov_len: usize,  /* Number of bytes to transfer */
+}
+
+/// The flag of `newfstatat()` to `fstat()` the `dirfd` itself for an empty path
+const AT_EMPTY_PATH: usize = 0x1000;
+
+/// Maximum number of iovecs of `readv()` and `writev()`
+const IOV_MAX: usize = 1024;
+
+/// Transfer the buffers of the `iovcnt` iovecs at `iovs` in order with `f`, which returns
+/// the number of bytes transferred or a negative errno, like `read_fd` and `write_fd`.
+///
+/// A short transfer ends the loop. An error is returned, if nothing was transferred.
+fn transfer_iovecs(
+    iovs: UserPtr<Iovec>,
+    iovcnt: usize,
+    mut f: impl FnMut(UserSlice) -> usize,
+) -> usize {
+    if iovcnt > IOV_MAX {
+        return ErrNo::EINVAL.neg_as_usize();
+    }
+
+    let mut done: usize = 0;
+    for i in 0..iovcnt {
+        let buf = iovs
+            .offset(i)
+            .and_then(UserPtr::read)
+            .and_then(|iov| UserSlice::new(iov.iov_base, iov.iov_len));
+        let ret = match buf {
+            Ok(buf) => (f(buf), buf.len()),
+            Err(e) => (e.neg_as_usize(), 0),
+        };
+        match ret {
+            (n, _) if (n as isize) < 0 => return if done == 0 { n } else { done },
+            (n, len) => {
+                done += n;
+                if n < len {
+                    break;
+                }
+            }
+        }
+    }
+    done
+}
+
+/// `fstat()` the file descriptor `fd`
+fn fstat_fd(fd: usize) -> Result<Stat, vmsyscall::Error> {
+    match fd {
+        1 => {
+            fn makedev(x: u64, y: u64) -> u64 {
+                (((x) & 0xffff_f000u64) << 32)
+                    | (((x) & 0x0000_0fffu64) << 8)
+                    | (((y) & 0xffff_ff00u64) << 12)
+                    | ((y) & 0x0000_00ffu64)
+            }
+
+            // the terminal is in use right now
+            let now = Timespec::from_ns(time::realtime_ns());
+            let stat = Stat {
+                st_dev: makedev(0, 0x17),
+                st_ino: 3,
+                st_mode: 0o020_000 | 0o620, // S_IFCHR
+                st_nlink: 1,
+                st_uid: 1000,
+                st_gid: 5,
+                st_blksize: 1024,
+                st_blocks: 0,
+                st_rdev: makedev(0x88, 0),
+                st_atime: now.tv_sec,
+                st_atime_nsec: now.tv_nsec,
+                st_mtime: now.tv_sec,
+                st_mtime_nsec: now.tv_nsec,
+                st_ctime: now.tv_sec,
+                st_ctime_nsec: now.tv_nsec,
+                ..Default::default()
+            };
+            Ok(stat)
+        }
+        0 | 2 => Err(to_error(ErrNo::EBADF)),
+        fd => fs::fstat(fd as _),
+    }
+}
+
+/// The `path` copied to `buf`, without the NUL terminator
+fn user_path(path: UserCStr, buf: &mut [u8; PATH_BUF_LEN]) -> Result<&[u8], usize> {
+    path.read(buf).map_err(NegAsUsize::neg_as_usize)
+}
+
+/// The user buffer of `len` bytes at `addr`
+fn user_slice(addr: usize, len: usize) -> Result<UserSlice, usize> {
+    UserSlice::new(addr, len).map_err(NegAsUsize::neg_as_usize)
+}
+
+/// The `CLOCK_MONOTONIC` deadline of the `struct timespec` at `ptr`, which is
+/// relative to now or an `absolute` time of the clock `clockid`.
+///
+/// A NULL `ptr` is no deadline.
+fn user_deadline(
+    ptr: UserPtr<Timespec>,
+    clockid: usize,
+    absolute: bool,
+) -> Result<Option<u64>, ErrNo> {
+    let ns = match ptr.read_opt()? {
+        Some(ts) => ts.as_ns()?,
+        None => return Ok(None),
+    };
+    Ok(Some(if absolute {
+        time::to_monotonic(clockid, ns)
+    } else {
+        time::monotonic_ns().saturating_add(ns)
+    }))
+}
+
 extern "C" {
     fn _read_rsp() -> u64;
 }
@@ -31,107 +280,119 @@ extern "C" {
 
 #[allow(clippy::many_single_char_names)]
 #[inline(always)]
-pub extern "C" fn handle_syscall(
-    a: usize,
-    b: usize,
-    c: usize,
-    d: usize,
-    e: usize,
-    f: usize,
-    nr: usize,
-) -> usize {
-    #[cfg(debug_assertions)]
-    eprintln!(
-        "SC> raw: syscall({}, {:#X}, {:#X}, {:#X}, {}, {}, {:#X})",
-        nr, a, b, c, d, e, f
-    );
+pub extern "C" fn handle_syscall(frame: &mut SyscallFrame) -> usize {
+    let (a, b, c, d, e, f, nr) = frame.args();
+    let start = trace::start();
+    let ret = dispatch(frame, start);
+    trace::record(nr, [a, b, c, d, e, f], ret, start);
+    ret
+}
+
+#[allow(clippy::many_single_char_names)]
+fn dispatch(frame: &mut SyscallFrame, start: u64) -> usize {
+    let (a, b, c, d, e, f, nr) = frame.args();
 
     //eprintln!("stackpointer: {:#X}", read_rsp());
     //eprintln!("stackpointer initial: {:#X}", f);
 
     match SysCall::from(nr as u64) {
         SysCall::EXIT => {
-            eprintln!("SC> exit({})", a);
-            exit_hypervisor(if a == 0 {
-                HyperVisorExitCode::Success
-            } else {
-                HyperVisorExitCode::Failed
-            });
-            loop {}
+            trace::record_no_return(nr, [a, b, c, d, e, f], start);
+            sched::exit_thread(a)
         }
         SysCall::EXIT_GROUP => {
-            eprintln!("SC> exit_group({})", a);
-            exit_hypervisor(if a == 0 {
-                HyperVisorExitCode::Success
-            } else {
-                HyperVisorExitCode::Failed
-            });
+            trace::record_no_return(nr, [a, b, c, d, e, f], start);
+            trace::finish();
+            syscall_policy::print_summary();
+            exit_hypervisor(HyperVisorExitCode::Exit(a as u8));
             loop {}
         }
-        SysCall::WRITE => {
+        SysCall::READ => match user_slice(b, c) {
+            Ok(buf) => read_fd(a, buf),
+            Err(e) => e,
+        },
+        SysCall::READV => transfer_iovecs(UserPtr::new(b), c, |buf| read_fd(a, buf)),
+        SysCall::PREAD64 => {
             let fd = a;
-            let data = b as *const u8;
-            let len = c;
-            match fd {
-                1 | 2 => {
-                    let cstr = unsafe { core::slice::from_raw_parts(data, len) };
-                    match core::str::from_utf8(cstr) {
-                        Ok(s) => {
-                            eprintln!("SC> write({}, {:#?}) = {}", fd, s, len);
-                            print!("{}", s);
-                            len
-                        }
-                        Err(_) => {
-                            eprintln!("SC> write({}, …) = -EINVAL", fd);
-                            ErrNo::EINVAL.neg_as_usize()
-                        }
-                    }
-                }
-                _ => {
-                    eprintln!("SC> write({}, \"…\") = -EBADFD", a);
-                    ErrNo::EBADFD.neg_as_usize()
-                }
+            let offset = d as i64;
+            if offset < 0 {
+                return ErrNo::EINVAL.neg_as_usize();
             }
+            let buf = match user_slice(b, c) {
+                Ok(buf) => buf,
+                Err(e) => return e,
+            };
+            read_chunked(buf, |pos, chunk| {
+                fs::pread(fd as _, chunk, Some(offset + pos as i64))
+            })
         }
-        SysCall::WRITEV => {
-            struct Iovec {
-                iov_base: u64,  /* Starting address */
-                iov_len: usize, /* Number of bytes to transfer */
+        SysCall::PWRITE64 => {
+            let fd = a;
+            let offset = d as i64;
+            if offset < 0 {
+                return ErrNo::EINVAL.neg_as_usize();
+            }
+            let buf = match user_slice(b, c) {
+                Ok(buf) => buf,
+                Err(e) => return e,
             };
+            write_chunked(buf, |pos, chunk| {
+                fs::pwrite(fd as _, chunk, Some(offset + pos as i64))
+            })
+        }
+        SysCall::OPEN => {
+            let mut buf = [0u8; PATH_BUF_LEN];
+            let path = match user_path(UserCStr::new(a), &mut buf) {
+                Ok(path) => path,
+                Err(e) => return e,
+            };
+            fs::openat(AT_FDCWD, path, b as _, c as _)
+                .map(|fd| fd as usize)
+                .unwrap_or_else(NegAsUsize::neg_as_usize)
+        }
+        SysCall::OPENAT => {
+            let mut buf = [0u8; PATH_BUF_LEN];
+            let path = match user_path(UserCStr::new(b), &mut buf) {
+                Ok(path) => path,
+                Err(e) => return e,
+            };
+            fs::openat(a as _, path, c as _, d as _)
+                .map(|fd| fd as usize)
+                .unwrap_or_else(NegAsUsize::neg_as_usize)
+        }
+        SysCall::CLOSE => match a {
+            0..=2 => 0,
+            fd => fs::close(fd as _)
+                .map(|r| r as usize)
+                .unwrap_or_else(NegAsUsize::neg_as_usize),
+        },
+        SysCall::LSEEK => match a {
+            0..=2 => ErrNo::ESPIPE.neg_as_usize(),
+            fd => fs::lseek(fd as _, b as _, c as _)
+                .map(|r| r as usize)
+                .unwrap_or_else(NegAsUsize::neg_as_usize),
+        },
+        SysCall::GETDENTS64 => {
             let fd = a;
-            let iov = b as *const Iovec;
-            let iovcnt = c;
-            let iovec = unsafe { core::slice::from_raw_parts(iov, iovcnt) };
-            let mut written: usize = 0;
-            match fd {
-                1 | 2 => {
-                    for iov in iovec {
-                        let data = iov.iov_base as *const u8;
-                        let len = iov.iov_len;
-                        if len == 0 {
-                            continue;
-                        }
-                        let cstr = unsafe { core::slice::from_raw_parts(data, len) };
-                        match core::str::from_utf8(cstr) {
-                            Ok(s) => {
-                                eprintln!("SC> writev({}, {:#?}) = {}", fd, s, len);
-                                print!("{}", s);
-                                written += len;
-                            }
-                            Err(_) => {
-                                eprintln!("SC> writev({}, …) = -EINVAL", fd);
-                                return ErrNo::EINVAL.neg_as_usize();
-                            }
-                        }
-                    }
-                    written
-                }
-                _ => {
-                    eprintln!("SC> write({}, \"…\") = -EBADFD", a);
-                    ErrNo::EBADFD.neg_as_usize()
-                }
+            let dirp = match user_slice(b, c) {
+                Ok(dirp) => dirp,
+                Err(e) => return e,
+            };
+            let count = core::cmp::min(dirp.len(), READ_BUF_LEN);
+            let mut buf = [0u8; READ_BUF_LEN];
+            match fs::getdents64(fd as _, &mut buf[..count]) {
+                Ok(n) => dirp
+                    .write(&buf[..n])
+                    .map(|_| n)
+                    .unwrap_or_else(NegAsUsize::neg_as_usize),
+                Err(e) => e.neg_as_usize(),
             }
         }
+        SysCall::WRITE => match user_slice(b, c) {
+            Ok(buf) => write_fd(a, buf),
+            Err(e) => e,
+        },
+        SysCall::WRITEV => transfer_iovecs(UserPtr::new(b), c, |buf| write_fd(a, buf)),
         SysCall::ARCH_PRCTL => {
             const ARCH_SET_GS: usize = 0x1001;
             const ARCH_SET_FS: usize = 0x1002;
@@ -140,59 +401,44 @@ pub extern "C" fn handle_syscall(
 
             match a {
                 ARCH_SET_FS => {
-                    eprintln!("SC> arch_prctl(ARCH_SET_FS, {:#X}) = 0", b);
                     let value: u64 = b as _;
                     unsafe {
                         _wrfsbase(value);
                     }
                     0
                 }
-                ARCH_GET_FS => unimplemented!(),
-                ARCH_SET_GS => unimplemented!(),
-                ARCH_GET_GS => unimplemented!(),
-                x => {
-                    eprintln!("SC> arch_prctl({:#X}, {:#X}) = -EINVAL", x, b);
-                    ErrNo::EINVAL.neg_as_usize()
-                }
+                ARCH_GET_FS => UserPtr::<u64>::new(b)
+                    .write(&unsafe { _rdfsbase() })
+                    .map(|_| 0)
+                    .unwrap_or_else(NegAsUsize::neg_as_usize),
+                // the GS base of userspace is not switched with the threads
+                ARCH_SET_GS | ARCH_GET_GS => ErrNo::EINVAL.neg_as_usize(),
+                _ => ErrNo::EINVAL.neg_as_usize(),
             }
         }
-        SysCall::MUNMAP => {
-            let ret = 0;
-            eprintln!("SC> dummy munmap({:#X}, {}, …) = {:#?}", a, b, ret);
-            ret
-        }
+        SysCall::MUNMAP => munmap_user(a, b)
+            .map(|_| 0)
+            .unwrap_or_else(NegAsUsize::neg_as_usize),
         SysCall::MMAP => {
-            if a == 0 {
-                let ret = mmap_user(b);
-                eprintln!("SC> mmap({:#X}, {}, …) = {:#?}", a, b, ret);
-                ret as _
-            } else {
-                eprintln!("SC> mmap({:#X}, {}, …)", a, b);
-                todo!();
-            }
-        }
-        SysCall::BRK => unsafe {
-            match a {
-                0 => {
-                    eprintln!("SC> brk({:#X}) = {:#X}", a, NEXT_MMAP);
-                    NEXT_MMAP as _
-                }
-                n => {
-                    brk_user(n - NEXT_MMAP as usize);
-                    eprintln!("SC> brk({:#X}) = {:#X}", a, NEXT_MMAP);
-                    n as _
+            const MAP_ANONYMOUS: usize = 0x20;
+            let fd = e as i32;
+            // an invalid file descriptor fails before anything is mapped
+            if d & MAP_ANONYMOUS == 0 {
+                if let Err(e) = fs::fstat(fd) {
+                    return e.neg_as_usize();
                 }
             }
-        },
-        SysCall::MPROTECT => {
-            let ret = 0;
-            eprintln!("SC> mprotect({:#X}, {}, {}) = {:#?}", a, b, c, ret);
-            ret as _
+            mmap_user(a, b, c as _, d as _, f, |offset, buf| {
+                fs::pread(fd, buf, Some(offset as i64)).map_err(|_| ErrNo::EIO)
+            })
+            .unwrap_or_else(NegAsUsize::neg_as_usize)
         }
+        SysCall::BRK => brk_user(a),
+        SysCall::MPROTECT => mprotect_user(a, b, c as _)
+            .map(|_| 0)
+            .unwrap_or_else(NegAsUsize::neg_as_usize),
         SysCall::UNAME => {
-            eprintln!(
-                r##"SC> uname({{sysname="Linux", nodename="enarx", release="5.4.8", version="1", machine="x86_64", domainname="(none)"}}) = 0"##
-            );
+            #[derive(Clone, Copy)]
             #[repr(C)]
             struct NewUtsname {
                 sysname: [u8; 65],
@@ -202,60 +448,228 @@ pub extern "C" fn handle_syscall(
                 machine: [u8; 65],
                 domainname: [u8; 65],
             };
-            let uts_ptr: *mut NewUtsname = a as _;
-            unsafe {
-                (*uts_ptr).sysname[..6].copy_from_slice(b"Linux\0");
-                (*uts_ptr).nodename[..6].copy_from_slice(b"enarx\0");
-                (*uts_ptr).release[..6].copy_from_slice(b"5.4.8\0");
-                (*uts_ptr).version[..2].copy_from_slice(b"1\0");
-                (*uts_ptr).machine[..7].copy_from_slice(b"x86_64\0");
-                (*uts_ptr).domainname[..1].copy_from_slice(b"\0");
-            }
-            0
+            let mut uts = NewUtsname {
+                sysname: [0; 65],
+                nodename: [0; 65],
+                release: [0; 65],
+                version: [0; 65],
+                machine: [0; 65],
+                domainname: [0; 65],
+            };
+            uts.sysname[..6].copy_from_slice(b"Linux\0");
+            uts.nodename[..6].copy_from_slice(b"enarx\0");
+            uts.release[..6].copy_from_slice(b"5.4.8\0");
+            uts.version[..2].copy_from_slice(b"1\0");
+            uts.machine[..7].copy_from_slice(b"x86_64\0");
+            UserPtr::new(a)
+                .write(&uts)
+                .map(|_| 0)
+                .unwrap_or_else(NegAsUsize::neg_as_usize)
         }
         SysCall::READLINK => {
-            let pathname = unsafe {
-                let mut len = 0;
-                let ptr: *const u8 = a as _;
-                loop {
-                    if ptr.offset(len).read() == 0 {
-                        break;
-                    }
-                    len += 1;
-                }
-                core::str::from_utf8_unchecked(core::slice::from_raw_parts(a as _, len as _))
+            let mut buf = [0u8; PATH_BUF_LEN];
+            let pathname = match user_path(UserCStr::new(a), &mut buf) {
+                Ok(path) => path,
+                Err(e) => return e,
             };
 
-            if !pathname.eq("/proc/self/exe") {
+            let link = exe_path().as_bytes();
+            if pathname != b"/proc/self/exe" || link.is_empty() {
                 return ErrNo::ENOENT.neg_as_usize();
             }
 
-            let outbuf = unsafe { core::slice::from_raw_parts_mut(b as _, c as _) };
-            outbuf[..6].copy_from_slice(b"/init\0");
-            eprintln!("SC> readlink({:#?}, \"/init\", {}) = 5", pathname, c);
-            5
+            let outbuf = match user_slice(b, c) {
+                Ok(outbuf) => outbuf,
+                Err(e) => return e,
+            };
+
+            // the link is truncated to the buffer without a NUL terminator
+            let len = core::cmp::min(outbuf.len(), link.len());
+            outbuf
+                .write(&link[..len])
+                .map(|_| len)
+                .unwrap_or_else(NegAsUsize::neg_as_usize)
         }
 
         SysCall::RT_SIGACTION => {
-            eprintln!("SC> rt_sigaction(…) = 0");
-            0
+            if d != SIGSET_SIZE {
+                return ErrNo::EINVAL.neg_as_usize();
+            }
+            UserPtr::<SigAction>::new(b)
+                .read_opt()
+                .and_then(|act| signal::sigaction(a, act))
+                .and_then(|old| UserPtr::new(c).write_opt(&old))
+                .map(|_| 0)
+                .unwrap_or_else(NegAsUsize::neg_as_usize)
         }
         SysCall::RT_SIGPROCMASK => {
-            eprintln!("SC> rt_sigprocmask(…) = 0");
+            if d != SIGSET_SIZE {
+                return ErrNo::EINVAL.neg_as_usize();
+            }
+            UserPtr::<u64>::new(b)
+                .read_opt()
+                .and_then(|set| signal::sigprocmask(a, set))
+                .and_then(|old| UserPtr::new(c).write_opt(&old))
+                .map(|_| 0)
+                .unwrap_or_else(NegAsUsize::neg_as_usize)
+        }
+        SysCall::SIGALTSTACK => UserPtr::<SigStack>::new(a)
+            .read_opt()
+            .and_then(|ss| signal::sigaltstack(ss, frame.rsp as u64))
+            .and_then(|old| UserPtr::new(b).write_opt(&old))
+            .map(|_| 0)
+            .unwrap_or_else(NegAsUsize::neg_as_usize),
+        SysCall::RT_SIGRETURN => {
+            trace::record_no_return(nr, [a, b, c, d, e, f], start);
+            signal::sigreturn(frame)
+        }
+        SysCall::SET_TID_ADDRESS => {
+            let tid = sched::set_clear_child_tid(UserPtr::new(a));
+            tid as _
+        }
+        SysCall::SET_ROBUST_LIST => {
+            // robust futexes of exiting threads are not released
             0
         }
-        SysCall::SIGALTSTACK => {
-            eprintln!("SC> sigaltstack(…) = 0");
+        SysCall::GETTID => {
+            let tid = sched::current_tid();
+            tid as _
+        }
+        SysCall::GETRANDOM => {
+            const GRND_NONBLOCK: usize = 1;
+            const GRND_RANDOM: usize = 2;
+            const GRND_INSECURE: usize = 4;
+
+            // the entropy of the CPU and the host never blocks, so all flags are the same
+            if c & !(GRND_NONBLOCK | GRND_RANDOM | GRND_INSECURE) != 0
+                || c & (GRND_RANDOM | GRND_INSECURE) == (GRND_RANDOM | GRND_INSECURE)
+            {
+                ErrNo::EINVAL.neg_as_usize()
+            } else {
+                match user_slice(a, b) {
+                    Ok(buf) => read_chunked(buf, |_, chunk| random::fill(chunk)),
+                    Err(e) => e,
+                }
+            }
+        }
+        SysCall::GETPID => MAIN_TID as _,
+        SysCall::CLONE => sched::clone_thread(frame, a, b, UserPtr::new(c), UserPtr::new(d), e)
+            .map(|tid| tid as usize)
+            .unwrap_or_else(NegAsUsize::neg_as_usize),
+        SysCall::FUTEX => {
+            const FUTEX_WAIT: usize = 0;
+            const FUTEX_WAKE: usize = 1;
+            const FUTEX_REQUEUE: usize = 3;
+            const FUTEX_CMP_REQUEUE: usize = 4;
+            const FUTEX_WAIT_BITSET: usize = 9;
+            const FUTEX_WAKE_BITSET: usize = 10;
+            const FUTEX_PRIVATE_FLAG: usize = 128;
+            const FUTEX_CLOCK_REALTIME: usize = 256;
+
+            // All futexes are private to the one process.
+            // The timeout of FUTEX_WAIT is relative, the one of FUTEX_WAIT_BITSET absolute.
+            let clockid = if b & FUTEX_CLOCK_REALTIME != 0 {
+                time::CLOCK_REALTIME
+            } else {
+                time::CLOCK_MONOTONIC
+            };
+            let uaddr = UserPtr::<u32>::new(a);
+            let uaddr2 = UserPtr::<u32>::new(e);
+            let timeout = UserPtr::<Timespec>::new(d);
+            match b & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) {
+                FUTEX_WAIT => user_deadline(timeout, clockid, false)
+                    .and_then(|deadline| {
+                        sched::futex_wait(uaddr, c as u32, FUTEX_BITSET_MATCH_ANY, deadline)
+                    })
+                    .map(|_| 0),
+                FUTEX_WAIT_BITSET => user_deadline(timeout, clockid, true)
+                    .and_then(|deadline| sched::futex_wait(uaddr, c as u32, f as u32, deadline))
+                    .map(|_| 0),
+                FUTEX_WAKE => sched::futex_wake(uaddr, c, FUTEX_BITSET_MATCH_ANY),
+                FUTEX_WAKE_BITSET => sched::futex_wake(uaddr, c, f as u32),
+                FUTEX_REQUEUE => sched::futex_requeue(uaddr, c, uaddr2, d, None),
+                FUTEX_CMP_REQUEUE => sched::futex_requeue(uaddr, c, uaddr2, d, Some(f as u32)),
+                _ => Err(ErrNo::ENOSYS),
+            }
+            .unwrap_or_else(NegAsUsize::neg_as_usize)
+        }
+        SysCall::CLOCK_GETTIME => match time::clock_ns(a) {
+            Ok(ns) => UserPtr::new(b)
+                .write(&Timespec::from_ns(ns))
+                .map(|_| 0)
+                .unwrap_or_else(NegAsUsize::neg_as_usize),
+            Err(e) => e.neg_as_usize(),
+        },
+        SysCall::CLOCK_GETRES => match time::clock_ns(a) {
+            Ok(_) => UserPtr::new(b)
+                .write_opt(&Timespec::from_ns(1))
+                .map(|_| 0)
+                .unwrap_or_else(NegAsUsize::neg_as_usize),
+            Err(e) => e.neg_as_usize(),
+        },
+        SysCall::GETTIMEOFDAY => {
+            UserPtr::new(a)
+                .write_opt(&Timeval::from_ns(time::realtime_ns()))
+                // struct timezone: UTC without daylight saving time
+                .and_then(|_| UserPtr::new(b).write_opt(&[0i32; 2]))
+                .map(|_| 0)
+                .unwrap_or_else(NegAsUsize::neg_as_usize)
+        }
+        SysCall::TIME => {
+            let secs = time::realtime_ns() / time::NSEC_PER_SEC;
+            UserPtr::new(a)
+                .write_opt(&(secs as i64))
+                .map(|_| secs as usize)
+                .unwrap_or_else(NegAsUsize::neg_as_usize)
+        }
+        SysCall::NANOSLEEP => {
+            // no signal interrupts the sleep, so `rem` is not written
+            match user_deadline(UserPtr::new(a), time::CLOCK_MONOTONIC, false) {
+                Ok(Some(deadline)) => {
+                    sched::sleep_until(deadline);
+                    0
+                }
+                Ok(None) => ErrNo::EFAULT.neg_as_usize(),
+                Err(e) => e.neg_as_usize(),
+            }
+        }
+        SysCall::CLOCK_NANOSLEEP => {
+            const TIMER_ABSTIME: usize = 1;
+
+            let deadline = time::clock_ns(a)
+                .and_then(|_| user_deadline(UserPtr::new(c), a, b & TIMER_ABSTIME != 0));
+            match deadline {
+                Ok(Some(deadline)) => {
+                    sched::sleep_until(deadline);
+                    0
+                }
+                Ok(None) => ErrNo::EFAULT.neg_as_usize(),
+                Err(e) => e.neg_as_usize(),
+            }
+        }
+        SysCall::SCHED_YIELD => {
+            sched::yield_now();
             0
         }
-        SysCall::SET_TID_ADDRESS => {
-            eprintln!("SC> set_tid_address(…) = 63618");
-            63618
+        SysCall::SCHED_GETAFFINITY => {
+            let nr_cpus = unsafe { crate::arch::NR_CPUS };
+            let size = core::mem::size_of::<u64>();
+            if b < size {
+                ErrNo::EINVAL.neg_as_usize()
+            } else {
+                // one bit for each of the 1 to 64 CPUs
+                let mask = u64::max_value() >> (64 - nr_cpus.max(1).min(64));
+                UserPtr::new(c)
+                    .write(&mask)
+                    .map(|_| size)
+                    .unwrap_or_else(NegAsUsize::neg_as_usize)
+            }
         }
         SysCall::IOCTL => match a {
             1 => {
                 match b {
                     0x5413 /* TIOCGWINSZ */ => {
+                        #[derive(Clone, Copy)]
                         #[repr(C, packed)]
                         struct WinSize {
                             ws_row: u16,
@@ -263,87 +677,47 @@ pub extern "C" fn handle_syscall(
                             ws_xpixel: u16,
                             ws_ypixel: u16,
                         };
-                        let p: *mut WinSize = c as _;
                         let winsize = WinSize {
                             ws_row: 40,
                             ws_col: 80,
                             ws_xpixel: 0,
                             ws_ypixel: 0
                         };
-                        unsafe {
-                            p.write_volatile(winsize);
-                        }
-                        eprintln!("SC> ioctl(1, TIOCGWINSZ, {{ws_row=40, ws_col=80, ws_xpixel=0, ws_ypixel=0}}) = 0");
-                        0
+                        UserPtr::new(c).write(&winsize)
+                            .map(|_| 0)
+                            .unwrap_or_else(NegAsUsize::neg_as_usize)
                     },
                     _ => ErrNo::EINVAL.neg_as_usize(),
                 }
             }
             _ => ErrNo::EINVAL.neg_as_usize(),
         },
-        SysCall::FSTAT => match a {
-            1 => {
-                fn makedev(x: u64, y: u64) -> u64 {
-                    (((x) & 0xffff_f000u64) << 32)
-                        | (((x) & 0x0000_0fffu64) << 8)
-                        | (((y) & 0xffff_ff00u64) << 12)
-                        | ((y) & 0x0000_00ffu64)
-                }
-
-                #[repr(C)]
-                #[derive(Debug, Copy, Clone)]
-                pub struct timespec {
-                    pub tv_sec: i64,
-                    pub tv_nsec: i64,
-                };
-
-                #[repr(C)]
-                #[derive(Debug, Copy, Clone)]
-                pub struct Stat {
-                    pub st_dev: u64,
-                    pub st_ino: u64,
-                    pub st_nlink: u64,
-                    pub st_mode: u32,
-                    pub st_uid: u32,
-                    pub st_gid: u32,
-                    pub __pad0: i32,
-                    pub st_rdev: u64,
-                    pub st_size: i64,
-                    pub st_blksize: i64,
-                    pub st_blocks: i64,
-                    pub st_atime: timespec,
-                    pub st_mtime: timespec,
-                    pub st_ctime: timespec,
-                    pub __glibc_reserved: [i64; 3usize],
-                };
-
-                let p: &mut Stat = &mut unsafe { *(c as *mut Stat) };
-                p.st_dev = makedev(0, 0x17);
-                p.st_ino = 3;
-                p.st_mode = 0o020_000 | 0o620; // S_IFCHR
-                p.st_nlink = 1;
-                p.st_uid = 1000;
-                p.st_gid = 5;
-                p.st_blksize = 1024;
-                p.st_blocks = 0;
-                p.st_rdev = makedev(0x88, 0);
-                p.st_atime.tv_sec=1_579_507_218 /* 2020-01-21T11:45:08.467721685+0100 */;
-                p.st_atime.tv_nsec = 0;
-                p.st_mtime.tv_sec=1_579_507_218 /* 2020-01-21T11:45:07.467721685+0100 */;
-                p.st_mtime.tv_nsec = 0;
-                p.st_ctime.tv_sec=1_579_507_218 /* 2020-01-20T09:00:18.467721685+0100 */;
-                p.st_ctime.tv_nsec = 0;
-                eprintln!("SC> fstat(1, {{st_dev=makedev(0, 0x17), st_ino=3, st_mode=S_IFCHR|0620, st_nlink=1, st_uid=1000, st_gid=5, st_blksize=1024, st_blocks=0, st_rdev=makedev(0x88, 0), st_atime=1579507218 /* 2020-01-21T11:45:08.467721685+0100 */, st_atime_nsec=0, st_mtime=1579507218 /* 2020-01-21T11:45:08.467721685+0100 */, st_mtime_nsec=0, st_ctime=1579507218 /* 2020-01-21T11:45:08.467721685+0100 */, st_ctime_nsec=0}}) = 0
- = 0");
-                0
-            }
-            _ => ErrNo::EBADF.neg_as_usize(),
+        SysCall::FSTAT => match fstat_fd(a) {
+            Ok(stat) => UserPtr::new(b)
+                .write(&stat)
+                .map(|_| 0)
+                .unwrap_or_else(NegAsUsize::neg_as_usize),
+            Err(e) => e.neg_as_usize(),
         },
-        _ => {
-            eprintln!("syscall({}, {}, {}, {}, {}, {}, {})", nr, a, b, c, d, e, f);
-            //stack.dump();
-            panic!("syscall {} not yet implemented", nr)
-            // ENOSYS.neg_as_usize(),
+        SysCall::NEWFSTATAT => {
+            let mut buf = [0u8; PATH_BUF_LEN];
+            let path = match user_path(UserCStr::new(b), &mut buf) {
+                Ok(path) => path,
+                Err(e) => return e,
+            };
+            let stat = if path.is_empty() && d & AT_EMPTY_PATH != 0 {
+                fstat_fd(a)
+            } else {
+                fs::fstatat(a as _, path)
+            };
+            match stat {
+                Ok(stat) => UserPtr::new(c)
+                    .write(&stat)
+                    .map(|_| 0)
+                    .unwrap_or_else(NegAsUsize::neg_as_usize),
+                Err(e) => e.neg_as_usize(),
+            }
         }
+        _ => syscall_policy::handle(nr, [a, b, c, d, e, f]),
     }
 }
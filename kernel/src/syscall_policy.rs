@@ -0,0 +1,90 @@
+//! Syscalls, which the kernel does not implement
+//!
+//! vmrun chooses in the `BootInfo`, whether such a syscall returns `-ENOSYS`, returns a
+//! fixed value or aborts the VM. Every hit is counted, so that the missing syscalls
+//! of an app can be listed when it exits.
+
+use crate::eprintln;
+use linux_errno::ErrNo;
+use spin::Mutex;
+use vmsyscall::bootinfo::{SyscallAction, SyscallPolicy};
+
+/// Syscall numbers below this are counted, all others are counted as `MAX_NR`
+const MAX_NR: usize = 512;
+
+static POLICY: Mutex<SyscallPolicy> = Mutex::new(SyscallPolicy::new());
+static HITS: Mutex<[u32; MAX_NR + 1]> = Mutex::new([0; MAX_NR + 1]);
+
+/// Use the `policy` from the `BootInfo`
+pub fn init(policy: &SyscallPolicy) {
+    *POLICY.lock() = *policy;
+}
+
+/// Count the hit of the unimplemented syscall `nr` and return its action
+pub fn hit(nr: usize) -> SyscallAction {
+    let mut hits = HITS.lock();
+    let count = &mut hits[core::cmp::min(nr, MAX_NR)];
+    *count = count.saturating_add(1);
+    drop(hits);
+
+    POLICY.lock().action(nr as u32)
+}
+
+/// Handle the unimplemented syscall `nr` with `args` according to the policy
+pub fn handle(nr: usize, args: [usize; 6]) -> usize {
+    match hit(nr) {
+        SyscallAction::Enosys => (-Into::<i64>::into(ErrNo::ENOSYS)) as usize,
+        SyscallAction::Return(value) => value as usize,
+        SyscallAction::Abort => {
+            crate::trace::finish();
+            print_summary();
+            panic!(
+                "syscall({}, {:#X}, {:#X}, {:#X}, {:#X}, {:#X}, {:#X}) not implemented",
+                nr, args[0], args[1], args[2], args[3], args[4], args[5]
+            )
+        }
+    }
+}
+
+/// Print the unimplemented syscalls, which were called
+pub fn print_summary() {
+    let hits = HITS.lock();
+    if hits.iter().all(|count| *count == 0) {
+        return;
+    }
+    eprintln!("Unimplemented syscalls called:");
+    for (nr, count) in hits.iter().enumerate().filter(|(_, count)| **count != 0) {
+        if nr == MAX_NR {
+            eprintln!("  >= {}: {}", MAX_NR, count);
+        } else {
+            eprintln!("  {}: {}", nr, count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_syscall_policy() {
+        serial_print!("test_syscall_policy...");
+        // a syscall number no kernel will ever implement
+        let nr = 100_000;
+        let mut policy = SyscallPolicy::new();
+        policy.set(nr as u32, SyscallAction::Return(7)).unwrap();
+        init(&policy);
+
+        let before = HITS.lock()[MAX_NR];
+        assert_eq!(handle(nr, [0; 6]), 7);
+        assert_eq!(
+            handle(nr + 1, [0; 6]),
+            (-Into::<i64>::into(ErrNo::ENOSYS)) as usize
+        );
+        assert_eq!(HITS.lock()[MAX_NR], before + 2);
+
+        init(&SyscallPolicy::new());
+        serial_println!("[ok]");
+    }
+}
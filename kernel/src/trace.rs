@@ -0,0 +1,190 @@
+//! Tracing of the syscalls of the app
+//!
+//! vmrun chooses the [`TraceLevel`] in the `BootInfo`. With `Full` the records are
+//! collected in a buffer and handed to vmrun in batches, so that tracing costs one
+//! VM exit per `TRACE_BATCH_LEN` syscalls. With `Summary` and `Full` the totals per
+//! syscall number are kept and handed over, when the app exits. Nothing is recorded
+//! with `Off`, so vmrun raises the level to `Summary` for `--stats`.
+
+use crate::arch::x86_64::percpu::cpu_id;
+use crate::arch::x86_64::sched;
+use crate::arch::x86_64::time;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use vmsyscall::trace::{
+    TraceLevel, TraceRecord, TraceSummary, TRACE_BATCH_LEN, TRACE_NO_RETURN,
+    TRACE_SUMMARY_BATCH_LEN,
+};
+
+/// Syscall numbers below this are summed up, all others are summed up as `MAX_NR`
+const MAX_NR: usize = 512;
+
+static LEVEL: AtomicU32 = AtomicU32::new(TraceLevel::Off as u32);
+
+struct Buffer {
+    len: usize,
+    records: [TraceRecord; TRACE_BATCH_LEN],
+}
+
+static BUFFER: Mutex<Buffer> = Mutex::new(Buffer {
+    len: 0,
+    records: [TraceRecord {
+        nr: 0,
+        tid: 0,
+        cpu: 0,
+        flags: 0,
+        args: [0; 6],
+        ret: 0,
+        start_ns: 0,
+        duration_ns: 0,
+    }; TRACE_BATCH_LEN],
+});
+
+static SUMMARY: Mutex<[(u64, u64, u64); MAX_NR + 1]> = Mutex::new([(0, 0, 0); MAX_NR + 1]);
+
+/// Use the trace level from the `BootInfo`
+pub fn init(level: u32) {
+    LEVEL.store(TraceLevel::from_u32(level) as u32, Ordering::Relaxed);
+}
+
+#[inline]
+fn level() -> TraceLevel {
+    TraceLevel::from_u32(LEVEL.load(Ordering::Relaxed))
+}
+
+/// The start time of a syscall, `0` if tracing is off
+#[inline]
+pub fn start() -> u64 {
+    match level() {
+        TraceLevel::Off => 0,
+        _ => time::monotonic_ns(),
+    }
+}
+
+/// Record the syscall `nr` with `args`, which started at `start_ns` and returned `ret`
+pub fn record(nr: usize, args: [usize; 6], ret: usize, start_ns: u64) {
+    match level() {
+        TraceLevel::Off => {}
+        level => {
+            let duration_ns = time::monotonic_ns().saturating_sub(start_ns);
+            add(nr, args, ret as i64, 0, start_ns, duration_ns, level)
+        }
+    }
+}
+
+/// Record the syscall `nr` with `args`, which does not return, e.g. `exit_group`
+pub fn record_no_return(nr: usize, args: [usize; 6], start_ns: u64) {
+    match level() {
+        TraceLevel::Off => {}
+        level => add(nr, args, 0, TRACE_NO_RETURN, start_ns, 0, level),
+    }
+}
+
+fn add(
+    nr: usize,
+    args: [usize; 6],
+    ret: i64,
+    flags: u32,
+    start_ns: u64,
+    duration_ns: u64,
+    level: TraceLevel,
+) {
+    {
+        let mut summary = SUMMARY.lock();
+        let entry = &mut summary[core::cmp::min(nr, MAX_NR)];
+        entry.0 += 1;
+        if flags & TRACE_NO_RETURN == 0 && (-4095..0).contains(&ret) {
+            entry.1 += 1;
+        }
+        entry.2 += duration_ns;
+    }
+
+    if level != TraceLevel::Full {
+        return;
+    }
+
+    let mut rec = TraceRecord {
+        nr: nr as u32,
+        tid: sched::current_tid(),
+        cpu: cpu_id() as u32,
+        flags,
+        args: [0; 6],
+        ret,
+        start_ns,
+        duration_ns,
+    };
+    for (arg, val) in rec.args.iter_mut().zip(args.iter()) {
+        *arg = *val as u64;
+    }
+
+    let mut buffer = BUFFER.lock();
+    let len = buffer.len;
+    buffer.records[len] = rec;
+    buffer.len += 1;
+    if buffer.len == TRACE_BATCH_LEN {
+        flush_buffer(&mut buffer);
+    }
+}
+
+fn flush_buffer(buffer: &mut Buffer) {
+    if buffer.len != 0 {
+        // tracing must not disturb the app, lost records are not an error
+        let _ = crate::libc::trace(&buffer.records[..buffer.len]);
+        buffer.len = 0;
+    }
+}
+
+/// Hand the pending records and the totals to vmrun, because the app exits
+pub fn finish() {
+    let level = level();
+    if level == TraceLevel::Off {
+        return;
+    }
+
+    flush_buffer(&mut BUFFER.lock());
+
+    let summary = SUMMARY.lock();
+    let mut batch = [TraceSummary::default(); TRACE_SUMMARY_BATCH_LEN];
+    let mut len = 0;
+    for (nr, entry) in summary.iter().enumerate().filter(|(_, e)| e.0 != 0) {
+        if len == TRACE_SUMMARY_BATCH_LEN {
+            let _ = crate::libc::trace_summary(&batch[..len], false);
+            len = 0;
+        }
+        batch[len] = TraceSummary {
+            nr: nr as u32,
+            calls: entry.0,
+            errors: entry.1,
+            total_ns: entry.2,
+        };
+        len += 1;
+    }
+    let _ = crate::libc::trace_summary(&batch[..len], true);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_trace_summary() {
+        serial_print!("test_trace_summary...");
+        let before = SUMMARY.lock()[MAX_NR];
+
+        // syscall numbers beyond `MAX_NR` share the last entry
+        init(TraceLevel::Off as u32);
+        record(100_000, [0; 6], 0, start());
+        assert_eq!(SUMMARY.lock()[MAX_NR], before);
+
+        init(TraceLevel::Summary as u32);
+        record(100_000, [0; 6], 0, start());
+        record(100_001, [0; 6], -22i64 as usize, start());
+        let after = SUMMARY.lock()[MAX_NR];
+        assert_eq!(after.0, before.0 + 2);
+        assert_eq!(after.1, before.1 + 1);
+
+        init(TraceLevel::Off as u32);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,624 @@
+//! Command line parsing for vmrun
+
+use crate::crash::CrashFormat;
+use crate::pvh::BootProtocol;
+use crate::stats::StatsFormat;
+use crate::trace::TraceFormat;
+use std::fmt;
+use vmsyscall::bootinfo::{AppArgs, SyscallAction, SyscallPolicy};
+use vmsyscall::trace::TraceLevel;
+
+/// Default guest memory size
+pub const DEFAULT_GUEST_MEM: u64 = 2 * 1024 * 1024 * 1024; // 2GiB
+/// Minimum guest memory size, the kernel and the app have to fit in
+pub const MIN_GUEST_MEM: u64 = 64 * 1024 * 1024; // 64MiB
+/// Maximum guest memory size, which has to stay below the 32bit MMIO hole
+pub const MAX_GUEST_MEM: u64 = 3 * 1024 * 1024 * 1024; // 3GiB
+/// Maximum number of vCPUs
+pub const MAX_VCPUS: u8 = 64;
+/// Default file for the syscall trace
+pub const DEFAULT_TRACE_FILE: &str = "vmrun.trace";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Kvm,
+    ForceQemu,
+    FallbackQemu,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub mode: Mode,
+    /// How vmrun enters the kernel
+    pub boot: BootProtocol,
+    pub mem_size: u64,
+    pub vcpus: u8,
+    pub app: String,
+    pub kernel: String,
+    /// The arguments of the app, including `argv[0]`
+    pub argv: Vec<String>,
+    pub env: Vec<String>,
+    /// The host directory exported as the root directory of the app
+    pub root: Option<String>,
+    /// The host directory with the dynamic linker and the shared libraries of the app
+    pub sysroot: Option<String>,
+    pub qemu_args: Vec<String>,
+    /// What the kernel does on syscalls it does not implement
+    pub syscall_policy: SyscallPolicy,
+    pub trace_level: TraceLevel,
+    pub trace_file: String,
+    pub trace_format: TraceFormat,
+    /// Print the statistics of the VM on shutdown
+    pub stats: Option<StatsFormat>,
+    /// The file for the statistics, stderr if `None`
+    pub stats_file: Option<String>,
+    pub crash_format: CrashFormat,
+    /// The file for crash reports, stderr if `None`
+    pub crash_file: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    Help,
+    MissingValue(String),
+    InvalidValue(String, String),
+    UnknownOption(String),
+    MissingArgument(&'static str),
+    UnexpectedArgument(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Help => Ok(()),
+            ParseError::MissingValue(o) => write!(f, "option `{}` requires a value", o),
+            ParseError::InvalidValue(o, v) => write!(f, "invalid value `{}` for `{}`", v, o),
+            ParseError::UnknownOption(o) => write!(f, "unknown option `{}`", o),
+            ParseError::MissingArgument(a) => write!(f, "missing argument <{}>", a),
+            ParseError::UnexpectedArgument(a) => write!(f, "unexpected argument `{}`", a),
+        }
+    }
+}
+
+pub fn usage(program: &str) -> String {
+    format!(
+        "Usage: {} [OPTIONS] <elf binary> <kernelblob> [-- <app args>...]
+
+Options:
+  -m, --memory <size>     guest memory size, with optional K, M or G suffix [default: 2G]
+  -c, --cpus <n>          number of vCPUs [default: 1]
+  -e, --env <KEY=VALUE>   add an environment variable for the app (repeatable)
+  -r, --root <dir>        export a host directory as the root directory of the app
+      --sysroot <dir>     load the dynamic linker of a dynamically linked app from <dir>,
+                          which is also the root directory, unless `--root` is given
+      --force-qemu        run the kernel with qemu-system-x86_64
+      --fallback-qemu     use qemu-system-x86_64, if KVM is not available
+      --qemu-arg <arg>    pass an extra argument to qemu-system-x86_64 (repeatable)
+      --boot <protocol>   enter the kernel with KVM `direct`ly in 64-bit mode
+                          or via the `pvh` boot protocol like qemu [default: direct]
+      --syscall-policy <action>
+                          action for unimplemented syscalls: `enosys`, `abort`
+                          or a fixed return value [default: enosys]
+      --syscall <nr>=<action>
+                          action for the unimplemented syscall <nr> (repeatable)
+      --trace <level>     trace the syscalls of the app: `off`, `summary` or `full`
+                          [default: off]
+      --trace-file <path> file for the syscall trace [default: vmrun.trace]
+      --trace-format <format>
+                          format of the syscall trace: `strace` or `json`
+                          [default: strace]
+      --stats <format>    print statistics of VM exits and syscalls on shutdown:
+                          `table` or `json`
+      --stats-file <path> file for the statistics [default: stderr]
+      --crash-format <format>
+                          format of crash reports: `text` or `json` [default: text]
+      --crash-file <path> file for crash reports [default: stderr]
+  -h, --help              print this help",
+        program
+    )
+}
+
+/// Parse a memory size like `512M` or `2G`
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (num, shift) = match s.chars().last()? {
+        'k' | 'K' => (&s[..s.len() - 1], 10),
+        'm' | 'M' => (&s[..s.len() - 1], 20),
+        'g' | 'G' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    num.parse::<u64>().ok()?.checked_mul(1u64 << shift)
+}
+
+/// Parse a syscall action like `enosys`, `abort` or `-1`
+pub fn parse_syscall_action(s: &str) -> Option<SyscallAction> {
+    match s {
+        "enosys" => Some(SyscallAction::Enosys),
+        "abort" => Some(SyscallAction::Abort),
+        _ => s.parse::<i64>().ok().map(SyscallAction::Return),
+    }
+}
+
+impl Config {
+    /// Parse the command line arguments, excluding the program name
+    pub fn parse<I, S>(args: I) -> Result<Self, ParseError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut args = args.into_iter().map(Into::into);
+        let mut mode = Mode::Kvm;
+        let mut boot = BootProtocol::Direct;
+        let mut mem_size = DEFAULT_GUEST_MEM;
+        let mut vcpus = 1u8;
+        let mut env = Vec::new();
+        let mut root = None;
+        let mut sysroot = None;
+        let mut qemu_args = Vec::new();
+        let mut syscall_policy = SyscallPolicy::new();
+        let mut trace_level = TraceLevel::Off;
+        let mut trace_file = DEFAULT_TRACE_FILE.to_string();
+        let mut trace_format = TraceFormat::Strace;
+        let mut stats = None;
+        let mut stats_file = None;
+        let mut crash_format = CrashFormat::Text;
+        let mut crash_file = None;
+        let mut positional = Vec::new();
+        let mut app_args = Vec::new();
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                app_args.extend(args.by_ref());
+                break;
+            }
+
+            if !arg.starts_with('-') || arg == "-" {
+                positional.push(arg);
+                continue;
+            }
+
+            let (opt, inline_value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => {
+                    (arg[..i].to_string(), Some(arg[i + 1..].to_string()))
+                }
+                _ => (arg.clone(), None),
+            };
+
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| ParseError::MissingValue(opt.clone()))
+            };
+
+            match opt.as_str() {
+                "-h" | "--help" => return Err(ParseError::Help),
+                "--force-qemu" => mode = Mode::ForceQemu,
+                "--fallback-qemu" => mode = Mode::FallbackQemu,
+                "-m" | "--memory" => {
+                    let v = value()?;
+                    mem_size = match parse_size(&v) {
+                        Some(s)
+                            if (MIN_GUEST_MEM..=MAX_GUEST_MEM).contains(&s) && s % 4096 == 0 =>
+                        {
+                            s
+                        }
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    };
+                }
+                "-c" | "--cpus" => {
+                    let v = value()?;
+                    vcpus = match v.parse::<u8>() {
+                        Ok(n) if (1..=MAX_VCPUS).contains(&n) => n,
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    };
+                }
+                "-e" | "--env" => {
+                    let v = value()?;
+                    match v.find('=') {
+                        Some(i) if i > 0 => env.push(v),
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    }
+                }
+                "-r" | "--root" => root = Some(value()?),
+                "--sysroot" => sysroot = Some(value()?),
+                "--qemu-arg" => qemu_args.push(value()?),
+                "--boot" => {
+                    let v = value()?;
+                    boot = match v.as_str() {
+                        "direct" => BootProtocol::Direct,
+                        "pvh" => BootProtocol::Pvh,
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    };
+                }
+                "--syscall-policy" => {
+                    let v = value()?;
+                    match parse_syscall_action(&v) {
+                        Some(action) => syscall_policy.set_default(action),
+                        None => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    }
+                }
+                "--syscall" => {
+                    let v = value()?;
+                    let entry = v.find('=').and_then(|i| {
+                        Some((
+                            v[..i].parse::<u32>().ok()?,
+                            parse_syscall_action(&v[i + 1..])?,
+                        ))
+                    });
+                    match entry {
+                        Some((nr, action)) if syscall_policy.set(nr, action).is_ok() => {}
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    }
+                }
+                "--trace" => {
+                    let v = value()?;
+                    trace_level = match v.as_str() {
+                        "off" => TraceLevel::Off,
+                        "summary" => TraceLevel::Summary,
+                        "full" => TraceLevel::Full,
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    };
+                }
+                "--trace-file" => trace_file = value()?,
+                "--trace-format" => {
+                    let v = value()?;
+                    trace_format = match v.as_str() {
+                        "strace" => TraceFormat::Strace,
+                        "json" => TraceFormat::Json,
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    };
+                }
+                "--stats" => {
+                    let v = value()?;
+                    stats = match v.as_str() {
+                        "table" => Some(StatsFormat::Table),
+                        "json" => Some(StatsFormat::Json),
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    };
+                }
+                "--stats-file" => stats_file = Some(value()?),
+                "--crash-format" => {
+                    let v = value()?;
+                    crash_format = match v.as_str() {
+                        "text" => CrashFormat::Text,
+                        "json" => CrashFormat::Json,
+                        _ => return Err(ParseError::InvalidValue(opt.clone(), v)),
+                    };
+                }
+                "--crash-file" => crash_file = Some(value()?),
+                _ => return Err(ParseError::UnknownOption(arg)),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let app = positional
+            .next()
+            .ok_or(ParseError::MissingArgument("elf binary"))?;
+        let kernel = positional
+            .next()
+            .ok_or(ParseError::MissingArgument("kernelblob"))?;
+        if let Some(arg) = positional.next() {
+            return Err(ParseError::UnexpectedArgument(arg));
+        }
+
+        let mut argv = vec![app.clone()];
+        argv.extend(app_args);
+
+        Ok(Config {
+            mode,
+            boot,
+            mem_size,
+            vcpus,
+            app,
+            kernel,
+            argv,
+            env,
+            root,
+            sysroot,
+            qemu_args,
+            syscall_policy,
+            trace_level,
+            trace_file,
+            trace_format,
+            stats,
+            stats_file,
+            crash_format,
+            crash_file,
+        })
+    }
+
+    /// The host directory exported as the root directory of the app
+    pub fn root_dir(&self) -> Option<&str> {
+        self.root
+            .as_ref()
+            .or(self.sysroot.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Build the argument block for the app, which is handed to the kernel via `BootInfo`
+    pub fn app_args(&self) -> Result<AppArgs, vmsyscall::Error> {
+        let mut app_args = AppArgs::new();
+        for arg in &self.argv {
+            app_args.push_arg(arg)?;
+        }
+        for env in &self.env {
+            app_args.push_env(env)?;
+        }
+        Ok(app_args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("64k"), Some(64 * 1024));
+        assert_eq!(parse_size("512M"), Some(512 * 1024 * 1024));
+        assert_eq!(parse_size("2G"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("99999999999G"), None);
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let config = Config::parse(vec!["app", "kernel"]).unwrap();
+        assert_eq!(config.mode, Mode::Kvm);
+        assert_eq!(config.boot, BootProtocol::Direct);
+        assert_eq!(config.mem_size, DEFAULT_GUEST_MEM);
+        assert_eq!(config.vcpus, 1);
+        assert_eq!(config.app, "app");
+        assert_eq!(config.kernel, "kernel");
+        assert_eq!(config.argv, vec!["app"]);
+        assert!(config.env.is_empty());
+        assert_eq!(config.root, None);
+        assert_eq!(config.sysroot, None);
+        assert_eq!(config.root_dir(), None);
+        assert!(config.qemu_args.is_empty());
+        assert_eq!(config.syscall_policy, SyscallPolicy::new());
+        assert_eq!(config.trace_level, TraceLevel::Off);
+        assert_eq!(config.trace_file, DEFAULT_TRACE_FILE);
+        assert_eq!(config.trace_format, TraceFormat::Strace);
+        assert_eq!(config.stats, None);
+        assert_eq!(config.stats_file, None);
+        assert_eq!(config.crash_format, CrashFormat::Text);
+        assert_eq!(config.crash_file, None);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let config = Config::parse(vec![
+            "--fallback-qemu",
+            "-m",
+            "256M",
+            "--cpus=2",
+            "-e",
+            "LANG=C",
+            "--env",
+            "FOO=bar=baz",
+            "--root=/srv",
+            "--qemu-arg",
+            "-S",
+            "app",
+            "kernel",
+            "--",
+            "-v",
+            "--",
+            "file",
+        ])
+        .unwrap();
+        assert_eq!(config.mode, Mode::FallbackQemu);
+        assert_eq!(config.mem_size, 256 * 1024 * 1024);
+        assert_eq!(config.vcpus, 2);
+        assert_eq!(config.env, vec!["LANG=C", "FOO=bar=baz"]);
+        assert_eq!(config.root, Some("/srv".into()));
+        assert_eq!(config.root_dir(), Some("/srv"));
+        assert_eq!(config.qemu_args, vec!["-S"]);
+        assert_eq!(config.argv, vec!["app", "-v", "--", "file"]);
+    }
+
+    #[test]
+    fn test_parse_sysroot() {
+        let config = Config::parse(vec!["--sysroot", "/opt/sysroot", "app", "kernel"]).unwrap();
+        assert_eq!(config.sysroot, Some("/opt/sysroot".into()));
+        assert_eq!(config.root_dir(), Some("/opt/sysroot"));
+
+        let config = Config::parse(vec![
+            "--sysroot=/opt/sysroot",
+            "-r",
+            "/srv",
+            "app",
+            "kernel",
+        ])
+        .unwrap();
+        assert_eq!(config.sysroot, Some("/opt/sysroot".into()));
+        assert_eq!(config.root_dir(), Some("/srv"));
+
+        assert_eq!(
+            Config::parse(vec!["app", "kernel", "--sysroot"]),
+            Err(ParseError::MissingValue("--sysroot".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_syscall_policy() {
+        let config = Config::parse(vec![
+            "--syscall-policy",
+            "abort",
+            "--syscall=13=0",
+            "--syscall",
+            "302=enosys",
+            "--syscall",
+            "999=-1",
+            "app",
+            "kernel",
+        ])
+        .unwrap();
+        let policy = config.syscall_policy;
+        assert_eq!(policy.action(0), SyscallAction::Abort);
+        assert_eq!(policy.action(13), SyscallAction::Return(0));
+        assert_eq!(policy.action(302), SyscallAction::Enosys);
+        assert_eq!(policy.action(999), SyscallAction::Return(-1));
+
+        assert_eq!(
+            Config::parse(vec!["--syscall-policy", "ignore", "app", "kernel"]),
+            Err(ParseError::InvalidValue(
+                "--syscall-policy".into(),
+                "ignore".into()
+            ))
+        );
+        assert_eq!(
+            Config::parse(vec!["--syscall", "open=0", "app", "kernel"]),
+            Err(ParseError::InvalidValue(
+                "--syscall".into(),
+                "open=0".into()
+            ))
+        );
+        assert_eq!(
+            Config::parse(vec!["--syscall", "13", "app", "kernel"]),
+            Err(ParseError::InvalidValue("--syscall".into(), "13".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        let config = Config::parse(vec![
+            "--trace",
+            "full",
+            "--trace-file=/tmp/app.trace",
+            "--trace-format",
+            "json",
+            "app",
+            "kernel",
+        ])
+        .unwrap();
+        assert_eq!(config.trace_level, TraceLevel::Full);
+        assert_eq!(config.trace_file, "/tmp/app.trace");
+        assert_eq!(config.trace_format, TraceFormat::Json);
+
+        let config = Config::parse(vec!["--trace=summary", "app", "kernel"]).unwrap();
+        assert_eq!(config.trace_level, TraceLevel::Summary);
+
+        assert_eq!(
+            Config::parse(vec!["--trace", "all", "app", "kernel"]),
+            Err(ParseError::InvalidValue("--trace".into(), "all".into()))
+        );
+        assert_eq!(
+            Config::parse(vec!["--trace-format", "xml", "app", "kernel"]),
+            Err(ParseError::InvalidValue(
+                "--trace-format".into(),
+                "xml".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        let config = Config::parse(vec![
+            "--stats",
+            "json",
+            "--stats-file=/tmp/app.stats",
+            "app",
+            "kernel",
+        ])
+        .unwrap();
+        assert_eq!(config.stats, Some(StatsFormat::Json));
+        assert_eq!(config.stats_file, Some("/tmp/app.stats".into()));
+
+        let config = Config::parse(vec!["--stats=table", "app", "kernel"]).unwrap();
+        assert_eq!(config.stats, Some(StatsFormat::Table));
+
+        assert_eq!(
+            Config::parse(vec!["--stats", "csv", "app", "kernel"]),
+            Err(ParseError::InvalidValue("--stats".into(), "csv".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_crash() {
+        let config = Config::parse(vec![
+            "--crash-format=json",
+            "--crash-file",
+            "/tmp/app.crash",
+            "app",
+            "kernel",
+        ])
+        .unwrap();
+        assert_eq!(config.crash_format, CrashFormat::Json);
+        assert_eq!(config.crash_file, Some("/tmp/app.crash".into()));
+
+        assert_eq!(
+            Config::parse(vec!["--crash-format", "xml", "app", "kernel"]),
+            Err(ParseError::InvalidValue(
+                "--crash-format".into(),
+                "xml".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_boot() {
+        let config = Config::parse(vec!["--boot", "pvh", "app", "kernel"]).unwrap();
+        assert_eq!(config.boot, BootProtocol::Pvh);
+        let config = Config::parse(vec!["--boot=direct", "app", "kernel"]).unwrap();
+        assert_eq!(config.boot, BootProtocol::Direct);
+
+        assert_eq!(
+            Config::parse(vec!["--boot", "multiboot", "app", "kernel"]),
+            Err(ParseError::InvalidValue(
+                "--boot".into(),
+                "multiboot".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_app_args() {
+        let config = Config::parse(vec!["-e", "LANG=C", "app", "kernel", "--", "-v"]).unwrap();
+        let app_args = config.app_args().unwrap();
+        assert!(app_args.validate().is_ok());
+        assert!(app_args.argv().eq(vec!["app", "-v"]));
+        assert!(app_args.envp().eq(vec!["LANG=C"]));
+
+        let long = "x".repeat(vmsyscall::bootinfo::APP_ARGS_LEN);
+        let config = Config::parse(vec!["app", "kernel", "--", &long]).unwrap();
+        assert!(config.app_args().is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Config::parse(vec!["-h"]), Err(ParseError::Help));
+        assert_eq!(
+            Config::parse(vec!["app"]),
+            Err(ParseError::MissingArgument("kernelblob"))
+        );
+        assert_eq!(
+            Config::parse(vec!["app", "kernel", "extra"]),
+            Err(ParseError::UnexpectedArgument("extra".into()))
+        );
+        assert_eq!(
+            Config::parse(vec!["--bogus", "app", "kernel"]),
+            Err(ParseError::UnknownOption("--bogus".into()))
+        );
+        assert_eq!(
+            Config::parse(vec!["app", "kernel", "-m"]),
+            Err(ParseError::MissingValue("-m".into()))
+        );
+        assert_eq!(
+            Config::parse(vec!["-m", "1M", "app", "kernel"]),
+            Err(ParseError::InvalidValue("-m".into(), "1M".into()))
+        );
+        assert_eq!(
+            Config::parse(vec!["-c", "0", "app", "kernel"]),
+            Err(ParseError::InvalidValue("-c".into(), "0".into()))
+        );
+        assert_eq!(
+            Config::parse(vec!["-e", "NOVALUE", "app", "kernel"]),
+            Err(ParseError::InvalidValue("-e".into(), "NOVALUE".into()))
+        );
+    }
+}
@@ -0,0 +1,373 @@
+//! Symbolized crash reports of the guest
+//!
+//! The kernel sends a [`CrashReport`] for fatal exceptions. The addresses of the report
+//! are looked up in the symbol tables of the app and the kernel and the report is
+//! written as text or as JSON.
+
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write as _};
+use vmsyscall::crash::CrashReport;
+
+/// Format of crash reports
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CrashFormat {
+    Text,
+    Json,
+}
+
+/// A function or object of an ELF file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub start: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+/// The symbols of one ELF file
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    /// The name of the ELF file
+    pub image: String,
+    /// Whether the ELF file is loaded at the `app_bias` of the report instead of
+    /// its linked addresses
+    pub relocatable: bool,
+    symbols: Vec<Symbol>,
+}
+
+/// An address resolved to a symbol
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location<'a> {
+    pub image: &'a str,
+    pub symbol: &'a str,
+    pub offset: u64,
+}
+
+impl Symbols {
+    pub fn new(image: &str, mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|s| s.start);
+        Symbols {
+            image: image.to_string(),
+            relocatable: false,
+            symbols,
+        }
+    }
+
+    /// The symbol containing `addr`
+    pub fn lookup(&self, addr: u64) -> Option<Location<'_>> {
+        let i = match self.symbols.binary_search_by_key(&addr, |s| s.start) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let sym = &self.symbols[i];
+        // symbols without size cover everything up to the next symbol
+        let end = match sym.size {
+            0 => self.symbols.get(i + 1).map(|s| s.start),
+            size => Some(sym.start + size),
+        };
+        match end {
+            Some(end) if addr >= end => None,
+            _ => Some(Location {
+                image: &self.image,
+                symbol: &sym.name,
+                offset: addr - sym.start,
+            }),
+        }
+    }
+}
+
+/// Writes the crash reports of a VM
+#[derive(Clone, Debug)]
+pub struct Reporter {
+    pub format: CrashFormat,
+    /// The file the reports are appended to, stderr if `None`
+    pub file: Option<String>,
+    /// The symbols of the loaded ELF files
+    pub images: Vec<Symbols>,
+}
+
+impl Default for Reporter {
+    fn default() -> Self {
+        Reporter {
+            format: CrashFormat::Text,
+            file: None,
+            images: Vec::new(),
+        }
+    }
+}
+
+impl Reporter {
+    /// Write the `report` of the crash `reason`
+    pub fn write(&self, report: &CrashReport, reason: &str) -> io::Result<()> {
+        let mut out = format(report, reason, &self.images, self.format);
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+        match self.file.as_ref() {
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(out.as_bytes()),
+            None => io::stderr().write_all(out.as_bytes()),
+        }
+    }
+}
+
+/// Look up `addr` in all `images`, the relocatable ones are loaded at `bias`
+pub fn symbolize(images: &[Symbols], addr: u64, bias: u64) -> Option<Location<'_>> {
+    images.iter().find_map(|s| {
+        if s.relocatable {
+            addr.checked_sub(bias).and_then(|addr| s.lookup(addr))
+        } else {
+            s.lookup(addr)
+        }
+    })
+}
+
+fn location_text(images: &[Symbols], addr: u64, bias: u64) -> String {
+    match symbolize(images, addr, bias) {
+        Some(loc) => format!("{}+{:#x} [{}]", loc.symbol, loc.offset, loc.image),
+        None => "??".into(),
+    }
+}
+
+/// The `report` in `format`, with the addresses resolved against `images`
+pub fn format(
+    report: &CrashReport,
+    reason: &str,
+    images: &[Symbols],
+    format: CrashFormat,
+) -> String {
+    match format {
+        CrashFormat::Text => format_text(report, reason, images),
+        CrashFormat::Json => format_json(report, reason, images),
+    }
+}
+
+/// The `report` as human readable text
+pub fn format_text(report: &CrashReport, reason: &str, images: &[Symbols]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "Crash: {} in {} mode on CPU {}",
+        reason,
+        if report.user { "user" } else { "kernel" },
+        report.cpu
+    );
+    let _ = writeln!(
+        out,
+        "  vector: {}, error code: {:#x}, cr2: {:#018x}",
+        report.vector, report.error_code, report.cr2
+    );
+    for regs in report.regs.named().chunks(3) {
+        out.push(' ');
+        for (name, value) in regs {
+            let _ = write!(out, " {:>6}: {:#018x}", name, value);
+        }
+        out.push('\n');
+    }
+    let seg = &report.segments;
+    let _ = writeln!(
+        out,
+        "      cs: {:#06x}     ss: {:#06x}     ds: {:#06x}     es: {:#06x}     fs: {:#06x}     gs: {:#06x}",
+        seg.cs, seg.ss, seg.ds, seg.es, seg.fs, seg.gs
+    );
+    let _ = writeln!(
+        out,
+        "  fs_base: {:#018x} gs_base: {:#018x}",
+        seg.fs_base, seg.gs_base
+    );
+    out.push_str("  stack:\n");
+    let addrs = std::iter::once(report.regs.rip).chain(report.frames().iter().cloned());
+    for (i, addr) in addrs.enumerate() {
+        let _ = writeln!(
+            out,
+            "    #{:<2} {:#018x} {}",
+            i,
+            addr,
+            location_text(images, addr, report.app_bias)
+        );
+    }
+    out
+}
+
+/// The `report` as one JSON object
+pub fn format_json(report: &CrashReport, reason: &str, images: &[Symbols]) -> String {
+    let regs = report
+        .regs
+        .named()
+        .iter()
+        .map(|(name, value)| format!("\"{}\":{}", name, value))
+        .collect::<Vec<_>>()
+        .join(",");
+    let seg = &report.segments;
+    let segments = format!(
+        "\"cs\":{},\"ss\":{},\"ds\":{},\"es\":{},\"fs\":{},\"gs\":{},\"fs_base\":{},\"gs_base\":{}",
+        seg.cs, seg.ss, seg.ds, seg.es, seg.fs, seg.gs, seg.fs_base, seg.gs_base
+    );
+    let stack = std::iter::once(report.regs.rip)
+        .chain(report.frames().iter().cloned())
+        .map(|addr| match symbolize(images, addr, report.app_bias) {
+            Some(loc) => format!(
+                "{{\"addr\":{},\"symbol\":\"{}\",\"offset\":{},\"image\":\"{}\"}}",
+                addr,
+                json_escape(loc.symbol),
+                loc.offset,
+                json_escape(loc.image)
+            ),
+            None => format!(
+                "{{\"addr\":{},\"symbol\":null,\"offset\":null,\"image\":null}}",
+                addr
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        "{{\"reason\":\"{}\",\"vector\":{},\"cpu\":{},\"user\":{},\"error_code\":{},\"cr2\":{},\"regs\":{{{}}},\"segments\":{{{}}},\"stack\":[{}]}}",
+        json_escape(reason),
+        report.vector,
+        report.cpu,
+        report.user,
+        report.error_code,
+        report.cr2,
+        regs,
+        segments,
+        stack
+    )
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn images() -> Vec<Symbols> {
+        vec![
+            Symbols::new(
+                "app",
+                vec![
+                    Symbol {
+                        start: 0x40_1100,
+                        size: 0x20,
+                        name: "main".into(),
+                    },
+                    Symbol {
+                        start: 0x40_1000,
+                        size: 0,
+                        name: "_start".into(),
+                    },
+                ],
+            ),
+            Symbols::new(
+                "kernel",
+                vec![Symbol {
+                    start: 0xffff_8000_0000_0000,
+                    size: 0x100,
+                    name: "kernel_main".into(),
+                }],
+            ),
+        ]
+    }
+
+    fn report() -> CrashReport {
+        let mut report = CrashReport {
+            vector: 14,
+            cpu: 0,
+            user: true,
+            error_code: 6,
+            cr2: 0x10,
+            frames_len: 2,
+            ..CrashReport::default()
+        };
+        report.regs.rip = 0x40_1104;
+        report.frames[0] = 0x40_1010;
+        report.frames[1] = 0x1234;
+        report
+    }
+
+    #[test]
+    fn test_lookup() {
+        let images = images();
+        assert_eq!(
+            symbolize(&images, 0x40_1104, 0),
+            Some(Location {
+                image: "app",
+                symbol: "main",
+                offset: 4
+            })
+        );
+        // `_start` has no size and ends at `main`
+        assert_eq!(symbolize(&images, 0x40_10ff, 0).unwrap().symbol, "_start");
+        assert_eq!(symbolize(&images, 0x40_1120, 0), None);
+        assert_eq!(symbolize(&images, 0x40_0fff, 0), None);
+        assert_eq!(
+            symbolize(&images, 0xffff_8000_0000_00ff, 0).unwrap().image,
+            "kernel"
+        );
+    }
+
+    #[test]
+    fn test_lookup_relocatable() {
+        let mut images = images();
+        images[0].relocatable = true;
+        let bias = 0x7f00_0000_0000;
+        assert_eq!(
+            symbolize(&images, bias + 0x40_1104, bias).unwrap().symbol,
+            "main"
+        );
+        assert_eq!(symbolize(&images, 0x40_1104, bias), None);
+        // the kernel stays at its linked addresses
+        assert_eq!(
+            symbolize(&images, 0xffff_8000_0000_00ff, bias)
+                .unwrap()
+                .symbol,
+            "kernel_main"
+        );
+    }
+
+    #[test]
+    fn test_format_text() {
+        let text = format_text(&report(), "page fault", &images());
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "Crash: page fault in user mode on CPU 0");
+        assert_eq!(
+            lines[1],
+            "  vector: 14, error code: 0x6, cr2: 0x0000000000000010"
+        );
+        assert!(lines[2].starts_with("     rax: 0x0000000000000000"));
+        assert_eq!(lines[11], "    #0  0x0000000000401104 main+0x4 [app]");
+        assert_eq!(lines[12], "    #1  0x0000000000401010 _start+0x10 [app]");
+        assert_eq!(lines[13], "    #2  0x0000000000001234 ??");
+    }
+
+    #[test]
+    fn test_format_json() {
+        let json = format_json(&report(), "page \"fault\"", &images());
+        assert!(json.starts_with(
+            "{\"reason\":\"page \\\"fault\\\"\",\"vector\":14,\"cpu\":0,\"user\":true,\"error_code\":6,\"cr2\":16,\"regs\":{\"rax\":0,"
+        ));
+        assert!(json.contains("\"segments\":{\"cs\":0,"));
+        assert!(json.ends_with(
+            "\"stack\":[{\"addr\":4198660,\"symbol\":\"main\",\"offset\":4,\"image\":\"app\"},\
+             {\"addr\":4198416,\"symbol\":\"_start\",\"offset\":16,\"image\":\"app\"},\
+             {\"addr\":4660,\"symbol\":null,\"offset\":null,\"image\":null}]}"
+        ));
+    }
+}
@@ -12,7 +12,11 @@ pub enum ErrorKind {
     NoMappingForVirtualAddress,
     NoVirtualAddressAvailable,
     GuestCodeNotFound,
-    NotAStaticBinary,
+    NoSysroot,
+    NoPvhEntry,
+    InvalidSyscallRequest,
+    InvalidVcpuCount,
+    VcpuNotStarted,
     Errno(i32),
     Io(::std::io::ErrorKind),
     Str(&'static str),
@@ -40,7 +44,11 @@ impl ::std::fmt::Display for ErrorKind {
             ErrorKind::VMModeUnsupported => write!(f, "VM mode currently unsupported"),
             ErrorKind::NoMappingForVirtualAddress => write!(f, "no mapping for virtual address"),
             ErrorKind::GuestCodeNotFound => write!(f, "guest code not found"),
-            ErrorKind::NotAStaticBinary => write!(f, "not a static binary"),
+            ErrorKind::NoSysroot => write!(f, "dynamically linked app without a sysroot"),
+            ErrorKind::NoPvhEntry => write!(f, "kernel without a PVH entry point"),
+            ErrorKind::InvalidSyscallRequest => write!(f, "invalid syscall request"),
+            ErrorKind::InvalidVcpuCount => write!(f, "invalid number of vCPUs"),
+            ErrorKind::VcpuNotStarted => write!(f, "vCPU was not started"),
             ErrorKind::NoVirtualAddressAvailable => {
                 write!(f, "No vaddr of specified pages available")
             }
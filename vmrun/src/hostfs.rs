@@ -0,0 +1,422 @@
+//! File system passthrough of a host directory to the guest
+//!
+//! Guest paths are resolved relative to the exported root directory.
+//! `..` can not leave the root and symlinks resolving outside of the root
+//! are rejected.
+
+use linux_errno::ErrNo;
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr};
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+use vmsyscall::{Error, Stat, AT_FDCWD, READ_BUF_LEN, WRITE_BUF_LEN};
+
+/// First file descriptor handed out to the guest, 0-2 are stdin, stdout and stderr
+pub const FIRST_FD: i32 = 3;
+/// Maximum number of files the guest can have open at the same time
+pub const MAX_OPEN_FILES: usize = 1024;
+
+fn errno(e: ErrNo) -> Error {
+    Error::Errno(e.into())
+}
+
+fn last_os_error() -> Error {
+    Error::Errno(
+        io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO)
+            .into(),
+    )
+}
+
+struct OpenFile {
+    file: File,
+    /// The guest path relative to the root
+    path: PathBuf,
+}
+
+/// The host side file descriptor table of the guest
+pub struct HostFs {
+    root: PathBuf,
+    files: BTreeMap<i32, OpenFile>,
+}
+
+impl HostFs {
+    /// Export the host directory `root` as the root directory of the guest
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "root is not a directory",
+            ));
+        }
+        Ok(HostFs {
+            root,
+            files: BTreeMap::new(),
+        })
+    }
+
+    fn file(&self, fd: i32) -> Result<&OpenFile, Error> {
+        self.files.get(&fd).ok_or_else(|| errno(ErrNo::EBADF))
+    }
+
+    /// Lexically resolve a guest path to a path relative to the root
+    fn guest_path(&self, dirfd: i32, path: &[u8]) -> Result<PathBuf, Error> {
+        if path.is_empty() {
+            return Err(errno(ErrNo::ENOENT));
+        }
+
+        let path = Path::new(OsStr::from_bytes(path));
+
+        let mut resolved = if path.is_absolute() || dirfd == AT_FDCWD {
+            PathBuf::new()
+        } else {
+            let dir = self.file(dirfd)?;
+            let is_dir = dir.file.metadata().map_err(|_| last_os_error())?.is_dir();
+            if !is_dir {
+                return Err(errno(ErrNo::ENOTDIR));
+            }
+            dir.path.clone()
+        };
+
+        for component in path.components() {
+            match component {
+                Component::Normal(c) => resolved.push(c),
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Map a guest path relative to the root to a host path without symlinks,
+    /// which is guaranteed to be below the root.
+    fn host_path(&self, guest_path: &Path) -> Result<PathBuf, Error> {
+        let path = self.root.join(guest_path);
+
+        let canonical = match path.canonicalize() {
+            Ok(p) => p,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                // The file might be created, so only the parent has to exist.
+                let name = match path.file_name() {
+                    Some(name) => name,
+                    None => return Err(errno(ErrNo::ENOENT)),
+                };
+                let parent = path
+                    .parent()
+                    .ok_or_else(|| errno(ErrNo::ENOENT))?
+                    .canonicalize()
+                    .map_err(|e| Error::Errno(e.raw_os_error().unwrap_or(libc::ENOENT).into()))?;
+                parent.join(name)
+            }
+            Err(e) => return Err(Error::Errno(e.raw_os_error().unwrap_or(libc::EIO).into())),
+        };
+
+        if !canonical.starts_with(&self.root) {
+            return Err(errno(ErrNo::EACCES));
+        }
+
+        Ok(canonical)
+    }
+
+    fn next_fd(&self) -> Result<i32, Error> {
+        if self.files.len() >= MAX_OPEN_FILES {
+            return Err(errno(ErrNo::EMFILE));
+        }
+        let mut fd = FIRST_FD;
+        for &used in self.files.keys() {
+            if used != fd {
+                break;
+            }
+            fd += 1;
+        }
+        Ok(fd)
+    }
+
+    /// openat(2) relative to the root or to a directory opened by the guest
+    pub fn openat(&mut self, dirfd: i32, path: &[u8], flags: i32, mode: u32) -> Result<i32, Error> {
+        let guest_path = self.guest_path(dirfd, path)?;
+        let host_path = self.host_path(&guest_path)?;
+        let fd = self.next_fd()?;
+
+        let cpath =
+            CString::new(host_path.as_os_str().as_bytes()).map_err(|_| errno(ErrNo::EINVAL))?;
+
+        // `host_path` does not contain any symlinks anymore, so a symlink showing up
+        // in the last component can only be a dangling one, which might point outside.
+        let host_flags = flags | libc::O_CLOEXEC | libc::O_NOFOLLOW;
+
+        let raw_fd = unsafe { libc::open(cpath.as_ptr(), host_flags, mode as libc::c_uint) };
+        if raw_fd < 0 {
+            return Err(last_os_error());
+        }
+
+        let file = unsafe { File::from_raw_fd(raw_fd) };
+        self.files.insert(
+            fd,
+            OpenFile {
+                file,
+                path: guest_path,
+            },
+        );
+        Ok(fd)
+    }
+
+    /// close(2)
+    pub fn close(&mut self, fd: i32) -> Result<i32, Error> {
+        self.files
+            .remove(&fd)
+            .map(|_| 0)
+            .ok_or_else(|| errno(ErrNo::EBADF))
+    }
+
+    /// pread(2), or read(2) if `offset` is `None`
+    pub fn pread(
+        &mut self,
+        fd: i32,
+        count: usize,
+        offset: Option<i64>,
+    ) -> Result<(i32, [u8; READ_BUF_LEN]), Error> {
+        let raw_fd = self.file(fd)?.file.as_raw_fd();
+        let count = count.min(READ_BUF_LEN);
+        let mut data = [0u8; READ_BUF_LEN];
+        let ptr = data.as_mut_ptr() as *mut libc::c_void;
+
+        let ret = unsafe {
+            match offset {
+                Some(offset) => libc::pread(raw_fd, ptr, count, offset),
+                None => libc::read(raw_fd, ptr, count),
+            }
+        };
+        if ret < 0 {
+            return Err(last_os_error());
+        }
+        Ok((ret as _, data))
+    }
+
+    /// pwrite(2), or write(2) if `offset` is `None`
+    pub fn pwrite(
+        &mut self,
+        fd: i32,
+        data: &[u8; WRITE_BUF_LEN],
+        count: usize,
+        offset: Option<i64>,
+    ) -> Result<i32, Error> {
+        let raw_fd = self.file(fd)?.file.as_raw_fd();
+        let count = count.min(WRITE_BUF_LEN);
+        let ptr = data.as_ptr() as *const libc::c_void;
+
+        let ret = unsafe {
+            match offset {
+                Some(offset) => libc::pwrite(raw_fd, ptr, count, offset),
+                None => libc::write(raw_fd, ptr, count),
+            }
+        };
+        if ret < 0 {
+            return Err(last_os_error());
+        }
+        Ok(ret as _)
+    }
+
+    /// lseek(2)
+    pub fn lseek(&mut self, fd: i32, offset: i64, whence: i32) -> Result<i64, Error> {
+        let raw_fd = self.file(fd)?.file.as_raw_fd();
+        let ret = unsafe { libc::lseek(raw_fd, offset, whence) };
+        if ret < 0 {
+            return Err(last_os_error());
+        }
+        Ok(ret)
+    }
+
+    /// fstat(2)
+    pub fn fstat(&mut self, fd: i32) -> Result<Stat, Error> {
+        let raw_fd = self.file(fd)?.file.as_raw_fd();
+        let mut st: libc::stat = unsafe { core::mem::zeroed() };
+        if unsafe { libc::fstat(raw_fd, &mut st) } < 0 {
+            return Err(last_os_error());
+        }
+        Ok(Stat {
+            st_dev: st.st_dev as _,
+            st_ino: st.st_ino as _,
+            st_nlink: st.st_nlink as _,
+            st_mode: st.st_mode as _,
+            st_uid: st.st_uid as _,
+            st_gid: st.st_gid as _,
+            pad0: 0,
+            st_rdev: st.st_rdev as _,
+            st_size: st.st_size as _,
+            st_blksize: st.st_blksize as _,
+            st_blocks: st.st_blocks as _,
+            st_atime: st.st_atime as _,
+            st_atime_nsec: st.st_atime_nsec as _,
+            st_mtime: st.st_mtime as _,
+            st_mtime_nsec: st.st_mtime_nsec as _,
+            st_ctime: st.st_ctime as _,
+            st_ctime_nsec: st.st_ctime_nsec as _,
+            reserved: [0; 3],
+        })
+    }
+
+    /// getdents64(2)
+    ///
+    /// The `linux_dirent64` records of the host are passed through unchanged.
+    pub fn getdents64(
+        &mut self,
+        fd: i32,
+        count: usize,
+    ) -> Result<(i32, [u8; READ_BUF_LEN]), Error> {
+        let raw_fd = self.file(fd)?.file.as_raw_fd();
+        let count = count.min(READ_BUF_LEN);
+        let mut data = [0u8; READ_BUF_LEN];
+
+        let ret = unsafe { libc::syscall(libc::SYS_getdents64, raw_fd, data.as_mut_ptr(), count) };
+        if ret < 0 {
+            return Err(last_os_error());
+        }
+        Ok((ret as _, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("vmrun-hostfs-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_buf(s: &[u8]) -> [u8; WRITE_BUF_LEN] {
+        let mut data = [0u8; WRITE_BUF_LEN];
+        data[..s.len()].copy_from_slice(s);
+        data
+    }
+
+    #[test]
+    fn test_file_io() {
+        let tmp = TempDir::new("io");
+        let mut hostfs = HostFs::new(&tmp.0).unwrap();
+
+        let fd = hostfs
+            .openat(AT_FDCWD, b"/file", libc::O_RDWR | libc::O_CREAT, 0o644)
+            .unwrap();
+        assert_eq!(fd, FIRST_FD);
+        assert_eq!(hostfs.pwrite(fd, &write_buf(b"hello"), 5, None), Ok(5));
+        assert_eq!(hostfs.pwrite(fd, &write_buf(b"J"), 1, Some(0)), Ok(1));
+        assert_eq!(hostfs.fstat(fd).unwrap().st_size, 5);
+        assert_eq!(hostfs.lseek(fd, 1, libc::SEEK_SET), Ok(1));
+
+        let (n, data) = hostfs.pread(fd, 100, None).unwrap();
+        assert_eq!(&data[..n as usize], b"ello");
+        let (n, data) = hostfs.pread(fd, 2, Some(0)).unwrap();
+        assert_eq!(&data[..n as usize], b"Je");
+
+        assert_eq!(hostfs.close(fd), Ok(0));
+        assert_eq!(hostfs.close(fd), Err(errno(ErrNo::EBADF)));
+        assert_eq!(fs::read(tmp.0.join("file")).unwrap(), b"Jello");
+    }
+
+    #[test]
+    fn test_openat_dirfd() {
+        let tmp = TempDir::new("dirfd");
+        fs::create_dir(tmp.0.join("dir")).unwrap();
+        fs::write(tmp.0.join("dir/file"), b"x").unwrap();
+        let mut hostfs = HostFs::new(&tmp.0).unwrap();
+
+        let dirfd = hostfs
+            .openat(AT_FDCWD, b"dir", libc::O_RDONLY | libc::O_DIRECTORY, 0)
+            .unwrap();
+        let fd = hostfs.openat(dirfd, b"file", libc::O_RDONLY, 0).unwrap();
+        assert_eq!(
+            hostfs.openat(fd, b"file", libc::O_RDONLY, 0),
+            Err(errno(ErrNo::ENOTDIR))
+        );
+        assert_eq!(
+            hostfs.openat(42, b"file", libc::O_RDONLY, 0),
+            Err(errno(ErrNo::EBADF))
+        );
+        hostfs.close(fd).unwrap();
+
+        // the lowest free fd is reused
+        assert_eq!(hostfs.openat(dirfd, b"./file", libc::O_RDONLY, 0), Ok(fd));
+    }
+
+    #[test]
+    fn test_no_escape() {
+        let tmp = TempDir::new("escape");
+        fs::create_dir(tmp.0.join("root")).unwrap();
+        fs::write(tmp.0.join("secret"), b"secret").unwrap();
+        std::os::unix::fs::symlink("../secret", tmp.0.join("root/link")).unwrap();
+        std::os::unix::fs::symlink("/", tmp.0.join("root/rootlink")).unwrap();
+        std::os::unix::fs::symlink("../new", tmp.0.join("root/dangling")).unwrap();
+        let mut hostfs = HostFs::new(tmp.0.join("root")).unwrap();
+
+        assert_eq!(
+            hostfs.openat(AT_FDCWD, b"../secret", libc::O_RDONLY, 0),
+            Err(errno(ErrNo::ENOENT))
+        );
+        assert_eq!(
+            hostfs.openat(AT_FDCWD, b"/../../secret", libc::O_RDONLY, 0),
+            Err(errno(ErrNo::ENOENT))
+        );
+        assert_eq!(
+            hostfs.openat(AT_FDCWD, b"link", libc::O_RDONLY, 0),
+            Err(errno(ErrNo::EACCES))
+        );
+        assert_eq!(
+            hostfs.openat(AT_FDCWD, b"rootlink/etc/passwd", libc::O_RDONLY, 0),
+            Err(errno(ErrNo::EACCES))
+        );
+        assert!(hostfs
+            .openat(AT_FDCWD, b"dangling", libc::O_WRONLY | libc::O_CREAT, 0o644)
+            .is_err());
+        assert!(!tmp.0.join("new").exists());
+    }
+
+    #[test]
+    fn test_getdents64() {
+        let tmp = TempDir::new("getdents");
+        fs::write(tmp.0.join("a"), b"").unwrap();
+        fs::write(tmp.0.join("b"), b"").unwrap();
+        let mut hostfs = HostFs::new(&tmp.0).unwrap();
+
+        let fd = hostfs
+            .openat(AT_FDCWD, b"/", libc::O_RDONLY | libc::O_DIRECTORY, 0)
+            .unwrap();
+        let (n, data) = hostfs.getdents64(fd, READ_BUF_LEN).unwrap();
+
+        // struct linux_dirent64 { u64 d_ino; i64 d_off; u16 d_reclen; u8 d_type; char d_name[]; }
+        let mut names = Vec::new();
+        let mut pos = 0;
+        while pos < n as usize {
+            let reclen = u16::from_ne_bytes([data[pos + 16], data[pos + 17]]) as usize;
+            let name = &data[pos + 19..pos + reclen];
+            let len = name.iter().position(|&b| b == 0).unwrap();
+            names.push(String::from_utf8(name[..len].to_vec()).unwrap());
+            pos += reclen;
+        }
+        names.sort();
+        assert_eq!(names, vec![".", "..", "a", "b"]);
+    }
+}
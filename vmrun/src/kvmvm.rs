@@ -4,25 +4,46 @@ use crate::arch::x86_64::{
     structures::paging::{frame::PhysFrameRange, PhysFrame},
     HostVirtAddr, PhysAddr, VirtAddr,
 };
+use crate::crash::{Reporter, Symbol, Symbols};
 use crate::error::*;
+use crate::hostfs::HostFs;
+use crate::pvh::{self, BootProtocol};
+use crate::stats::Stats;
+use crate::trace::Tracer;
 use crate::{context, map_context};
 use kvm_bindings::{
-    kvm_mp_state, kvm_pit_config, kvm_segment, kvm_userspace_memory_region, KVM_MAX_CPUID_ENTRIES,
-    KVM_PIT_SPEAKER_DUMMY,
+    kvm_mp_state, kvm_pit_config, kvm_regs, kvm_segment, kvm_sregs, kvm_userspace_memory_region,
+    KVMIO, KVM_MAX_CPUID_ENTRIES, KVM_MP_STATE_RUNNABLE, KVM_PIT_SPEAKER_DUMMY,
 };
 use kvm_ioctls::{Kvm, VcpuFd, VmFd};
 use linux_errno::ErrNo;
-use std::io::Write;
-use vmsyscall::bootinfo::BootInfo;
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use vmm_sys_util::ioctl::ioctl;
+use vmm_sys_util::{errno, ioctl_io_nr};
+use vmsyscall::bootinfo::{AppArgs, AppSegment, AppSegments, BootInfo, SyscallPolicy, MAX_CPUS};
+use vmsyscall::crash::{CrashReport, Registers, Segments};
 use vmsyscall::memory_map::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
-use vmsyscall::{VmSyscall, VmSyscallRet};
+use vmsyscall::pvh::{
+    HvmMemmapTableEntry, HvmModlistEntry, HvmStartInfo, XEN_HVM_START_INFO_VERSION,
+    XEN_HVM_START_MAGIC_VALUE,
+};
+use vmsyscall::trace::TraceLevel;
+use vmsyscall::wire::{Header, MAX_MESSAGE_LEN};
+use vmsyscall::{VmSyscall, VmSyscallRet, PATH_BUF_LEN, READ_BUF_LEN};
 
-const DEFAULT_GUEST_MEM: u64 = 2 * 1024 * 1024 * 1024; // 2GiB
 const DEFAULT_GUEST_PAGE_SIZE: usize = 4096;
 
+// Not wrapped by kvm-ioctls yet
+ioctl_io_nr!(KVM_GET_TSC_KHZ, KVMIO, 0xa3);
+
 pub const HIMEM_START: usize = 0x0010_0000; //1 MB.
 
 pub const SYSCALL_PHYS_ADDR: u64 = 0x1000;
+/// The syscall pages of all vCPUs, one page per vCPU
+pub const SYSCALL_PAGES_PHYS_ADDR: u64 = 0x1_0000;
 pub const SYSCALL_TRIGGER_PORT: u16 = 0xFF;
 
 // Initial pagetables.
@@ -34,6 +55,11 @@ pub const PAGETABLE_LEN: u64 = core::mem::size_of::<PageTables>() as _;
 pub const BOOT_GDT_OFFSET: usize = 0x500;
 pub const BOOT_IDT_OFFSET: usize = 0x520;
 
+/// The `hvm_start_info` of the PVH boot, followed by the module list and the memory map
+pub const PVH_START_INFO_PHYS_ADDR: u64 = 0x6000;
+/// End of the page of the PVH boot
+pub const PVH_END_PHYS_ADDR: u64 = 0x7000;
+
 #[repr(C)]
 pub struct PageTables {
     pub pml4t: [u64; 512],
@@ -51,6 +77,12 @@ impl Default for PageTables {
     }
 }
 
+/// The register state an application processor is started with
+pub struct CpuStart {
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+}
+
 struct UserspaceMemRegion {
     region: kvm_userspace_memory_region,
     host_mem: HostVirtAddr,
@@ -58,6 +90,89 @@ struct UserspaceMemRegion {
     mmap_size: usize,
 }
 
+/// The function and object symbols of the ELF file `path`
+pub fn load_symbols(path: &str) -> Result<Symbols, Error> {
+    use xmas_elf::sections::SectionData;
+    use xmas_elf::symbol_table::{Entry, Type};
+    use xmas_elf::ElfFile;
+
+    let data = std::fs::read(path).map_err(map_context!())?;
+    let elf_file = ElfFile::new(&data).map_err(map_context!())?;
+
+    let mut symbols = Vec::new();
+    for section in elf_file.section_iter() {
+        let entries = match section.get_data(&elf_file) {
+            Ok(SectionData::SymbolTable64(entries)) => entries,
+            _ => continue,
+        };
+        for entry in entries.iter().filter(|e| e.value() != 0) {
+            match entry.get_type() {
+                Ok(Type::Func) | Ok(Type::Object) => {}
+                _ => continue,
+            }
+            if let Ok(name) = entry.get_name(&elf_file) {
+                symbols.push(Symbol {
+                    start: entry.value(),
+                    size: entry.size(),
+                    name: name.to_string(),
+                });
+            }
+        }
+    }
+
+    let mut symbols = Symbols::new(path, symbols);
+    symbols.relocatable = is_position_independent(&elf_file);
+    Ok(symbols)
+}
+
+/// Whether `elf_file` is a position independent executable, which the kernel loads
+fn is_position_independent(elf_file: &xmas_elf::ElfFile) -> bool {
+    elf_file.header.pt2.type_().as_type() == xmas_elf::header::Type::SharedObject
+}
+
+/// The path of the dynamic linker named in the `PT_INTERP` segment of `elf_file`
+fn interpreter<'a>(elf_file: &xmas_elf::ElfFile<'a>) -> Option<&'a str> {
+    use xmas_elf::program::{ProgramHeader, Type};
+
+    elf_file
+        .program_iter()
+        .find_map(|program_header| match program_header {
+            ProgramHeader::Ph64(header) if header.get_type() == Ok(Type::Interp) => {
+                let start = header.offset as usize;
+                let path = elf_file
+                    .input
+                    .get(start..start.checked_add(header.file_size as usize)?)?;
+                std::str::from_utf8(path)
+                    .ok()
+                    .map(|path| path.trim_end_matches('\0'))
+            }
+            _ => None,
+        })
+}
+
+/// The 32-bit entry point in the PVH ELF note of the kernel ELF file `path`
+fn pvh_entry(path: &str) -> Result<u32, Error> {
+    use xmas_elf::program::{ProgramHeader, Type};
+    use xmas_elf::ElfFile;
+
+    let data = std::fs::read(path).map_err(map_context!())?;
+    let elf_file = ElfFile::new(&data).map_err(map_context!())?;
+
+    elf_file
+        .program_iter()
+        .find_map(|program_header| match program_header {
+            ProgramHeader::Ph64(header) if header.get_type() == Ok(Type::Note) => {
+                let start = header.offset as usize;
+                elf_file
+                    .input
+                    .get(start..start.checked_add(header.file_size as usize)?)
+                    .and_then(pvh::phys32_entry)
+            }
+            _ => None,
+        })
+        .ok_or_else(|| context!(ErrorKind::NoPvhEntry))
+}
+
 pub struct KvmVm {
     pub kvm: Kvm,
     pub cpu_fd: Vec<VcpuFd>,
@@ -66,7 +181,19 @@ pub struct KvmVm {
     memory_map: MemoryMap,
     userspace_mem_regions: Vec<UserspaceMemRegion>,
     has_irqchip: bool,
-    pub syscall_hostvaddr: Option<HostVirtAddr>,
+    /// The syscall page of every vCPU
+    pub syscall_pages: Vec<HostVirtAddr>,
+    /// The host directory exported as the root of the guest
+    pub host_fs: Mutex<Option<HostFs>>,
+    /// The output of the syscall trace of the guest
+    pub tracer: Mutex<Option<Tracer>>,
+    /// The counters of VM exits and syscalls, if requested
+    pub stats: Option<Stats>,
+    /// Writes the crash reports of the guest
+    pub crash_reporter: Reporter,
+    /// Channels to the application processors, which have not been started yet
+    cpu_starts: Mutex<Vec<Option<Sender<CpuStart>>>>,
+    cpu_start_receivers: Vec<Option<Receiver<CpuStart>>>,
 }
 
 fn frame_range(range: PhysFrameRange) -> FrameRange {
@@ -90,7 +217,13 @@ impl KvmVm {
             memory_map: MemoryMap::new(),
             userspace_mem_regions: vec![],
             has_irqchip: false,
-            syscall_hostvaddr: None,
+            syscall_pages: vec![],
+            host_fs: Mutex::new(None),
+            tracer: Mutex::new(None),
+            stats: None,
+            crash_reporter: Reporter::default(),
+            cpu_starts: Mutex::new(vec![]),
+            cpu_start_receivers: vec![],
         };
 
         //FIXME: remove phy_pages
@@ -216,11 +349,15 @@ impl KvmVm {
         Ok(())
     }
 
+    /// Load the segments of the fixed-address ELF file `program_invocation_name`
+    ///
+    /// Returns the entry point, the address of the ELF header, the number of program
+    /// headers and the `PT_LOAD` segments.
     pub fn elf_load(
         &mut self,
         program_invocation_name: &str,
         region_type: MemoryRegionType,
-    ) -> Result<(VirtAddr, VirtAddr, usize), Error> {
+    ) -> Result<(VirtAddr, VirtAddr, usize, AppSegments), Error> {
         use std::fs::File;
         use std::os::unix::io::AsRawFd;
         use xmas_elf::program::{self, ProgramHeader};
@@ -246,6 +383,7 @@ impl KvmVm {
         let guest_code: VirtAddr = VirtAddr::new(elf_file.header.pt2.entry_point());
         let mut load_addr: Option<VirtAddr> = None;
         let phnum: usize = elf_file.program_iter().count();
+        let mut segments = AppSegments::new();
 
         for program_header in elf_file.program_iter() {
             match program_header {
@@ -253,9 +391,6 @@ impl KvmVm {
                     let segment = *header;
                     match segment.get_type().unwrap() {
                         program::Type::Load => {}
-                        program::Type::Interp => {
-                            return Err(ErrorKind::NotAStaticBinary.into());
-                        }
                         _ => continue,
                     }
 
@@ -269,6 +404,14 @@ impl KvmVm {
                         continue;
                     }
 
+                    segments
+                        .push(AppSegment {
+                            vaddr: segment.virtual_addr,
+                            memsz: segment.mem_size as u32,
+                            flags: segment.flags.0,
+                        })
+                        .map_err(|_| context!(ErrorKind::Str("too many PT_LOAD segments")))?;
+
                     let start_phys = PhysAddr::new(segment.physical_addr);
                     let start_frame: PhysFrame =
                         PhysFrame::from_start_address(start_phys.align_down(self.page_size as u64))
@@ -316,7 +459,94 @@ impl KvmVm {
             }
         }
 
-        Ok((guest_code, load_addr.unwrap(), phnum))
+        Ok((guest_code, load_addr.unwrap(), phnum, segments))
+    }
+
+    /// Copy the ELF file `program_invocation_name` unchanged to the end of the guest memory
+    ///
+    /// Returns the guest physical address and the size of the copy.
+    pub fn image_load(
+        &mut self,
+        program_invocation_name: &str,
+        region_type: MemoryRegionType,
+    ) -> Result<(PhysAddr, u64), Error> {
+        let data = std::fs::read(program_invocation_name).map_err(map_context!())?;
+        let len = data.len() as u64;
+        let size = PhysAddr::new(len).align_up(self.page_size as u64).as_u64();
+
+        let end = self
+            .memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable && r.range.len() >= size)
+            .map(|r| r.range.end_addr())
+            .max()
+            .ok_or_else(|| context!(ErrorKind::NoMemFree))?;
+        let start_phys = PhysAddr::new(end - size);
+
+        self.memory_map.mark_allocated_region(MemoryRegion {
+            range: FrameRange::new(start_phys.as_u64(), end),
+            region_type,
+        });
+
+        // FIXME: SEV LOAD
+        let host_slice = unsafe {
+            core::slice::from_raw_parts_mut(
+                self.addr_gpa2hva(start_phys)?.as_u64() as *mut u8,
+                data.len(),
+            )
+        };
+        host_slice.copy_from_slice(&data);
+
+        Ok((start_phys, len))
+    }
+
+    /// The state of `vcpu` for a crash report of an unexpected VM exit
+    ///
+    /// The stack is not walked, because the guest page tables are not followed here.
+    /// The load address of a position independent app is only known to the kernel,
+    /// so the addresses of such an app stay unresolved.
+    pub fn vcpu_report(&self, cpu: usize, vcpu: &VcpuFd) -> Result<CrashReport, Error> {
+        let regs = vcpu.get_regs().map_err(|e| ErrorKind::from(&e))?;
+        let sregs = vcpu.get_sregs().map_err(|e| ErrorKind::from(&e))?;
+        Ok(CrashReport {
+            vector: 0,
+            cpu: cpu as u32,
+            user: sregs.cs.selector & 3 == 3,
+            error_code: 0,
+            cr2: sregs.cr2,
+            app_bias: 0,
+            regs: Registers {
+                rax: regs.rax,
+                rbx: regs.rbx,
+                rcx: regs.rcx,
+                rdx: regs.rdx,
+                rsi: regs.rsi,
+                rdi: regs.rdi,
+                rbp: regs.rbp,
+                rsp: regs.rsp,
+                r8: regs.r8,
+                r9: regs.r9,
+                r10: regs.r10,
+                r11: regs.r11,
+                r12: regs.r12,
+                r13: regs.r13,
+                r14: regs.r14,
+                r15: regs.r15,
+                rip: regs.rip,
+                rflags: regs.rflags,
+            },
+            segments: Segments {
+                cs: sregs.cs.selector,
+                ss: sregs.ss.selector,
+                ds: sregs.ds.selector,
+                es: sregs.es.selector,
+                fs: sregs.fs.selector,
+                gs: sregs.gs.selector,
+                fs_base: sregs.fs.base,
+                gs_base: sregs.gs.base,
+            },
+            ..CrashReport::default()
+        })
     }
 
     fn write_gdt_table(&self, table: &[u64]) -> Result<(), Error> {
@@ -394,7 +624,7 @@ impl KvmVm {
         sregs.cr0 = (X86_CR0_PE | X86_CR0_NE | X86_CR0_PG | X86_CR0_ET | X86_CR0_MP) as u64;
         //sregs.cr0 &= !(X86_CR0_EM as u64);
         sregs.cr4 = (X86_CR4_PAE | X86_CR4_OSFXSR | X86_CR4_OSXMMEXCPT) as u64;
-        sregs.efer = (EFER_LME | EFER_LMA) as u64;
+        sregs.efer = (EFER_LME | EFER_LMA | EFER_NX) as u64;
 
         sregs.cr3 = PML4_START as _;
 
@@ -410,49 +640,222 @@ impl KvmVm {
         Ok(())
     }
 
+    /// Start vCPU `vcpuid` at the PVH `entry` of the kernel in 32-bit protected mode
+    ///
+    /// The `hvm_start_info` passes the E820 memory map of the guest and the `BootInfo` on
+    /// the syscall page as the first module, the app arguments are in the `BootInfo`, so
+    /// there is no command line. The page from `PVH_START_INFO_PHYS_ADDR` to
+    /// `PVH_END_PHYS_ADDR` has to be allocated already.
+    fn vcpu_setup_pvh(&mut self, vcpuid: u8, entry: u32) -> Result<(), Error> {
+        // at most one entry per region of the memory map, which fits in the page
+        let memmap = pvh::e820(&self.memory_map);
+        let modlist_paddr = PVH_START_INFO_PHYS_ADDR + core::mem::size_of::<HvmStartInfo>() as u64;
+        let memmap_paddr = modlist_paddr + core::mem::size_of::<HvmModlistEntry>() as u64;
+
+        let start_info = HvmStartInfo {
+            magic: XEN_HVM_START_MAGIC_VALUE,
+            version: XEN_HVM_START_INFO_VERSION,
+            nr_modules: 1,
+            modlist_paddr,
+            memmap_paddr,
+            memmap_entries: memmap.len() as u32,
+            ..Default::default()
+        };
+        let module = HvmModlistEntry {
+            paddr: SYSCALL_PHYS_ADDR,
+            size: core::mem::size_of::<BootInfo>() as u64,
+            ..Default::default()
+        };
+
+        // FIXME: SEV LOAD
+        unsafe {
+            self.addr_gpa2hva(PhysAddr::new(PVH_START_INFO_PHYS_ADDR))?
+                .as_mut_ptr::<HvmStartInfo>()
+                .write(start_info);
+            self.addr_gpa2hva(PhysAddr::new(modlist_paddr))?
+                .as_mut_ptr::<HvmModlistEntry>()
+                .write(module);
+            core::ptr::copy_nonoverlapping(
+                memmap.as_ptr(),
+                self.addr_gpa2hva(PhysAddr::new(memmap_paddr))?
+                    .as_mut_ptr::<HvmMemmapTableEntry>(),
+                memmap.len(),
+            );
+        }
+
+        let mut sregs = self.cpu_fd[vcpuid as usize]
+            .get_sregs()
+            .map_err(|e| ErrorKind::from(&e))?;
+
+        // flat 32-bit segments and a 32-bit TSS, as the PVH ABI requires
+        let gdt_table: [u64; 4] = [
+            gdt_entry(0, 0, 0),                // NULL
+            gdt_entry(0xc09b, 0, 0xffff_ffff), // CODE
+            gdt_entry(0xc093, 0, 0xffff_ffff), // DATA
+            gdt_entry(0x008b, 0, 0x67),        // TSS
+        ];
+
+        let code_seg = kvm_segment_from_gdt(gdt_table[1], 1);
+        let data_seg = kvm_segment_from_gdt(gdt_table[2], 2);
+        let tss_seg = kvm_segment_from_gdt(gdt_table[3], 3);
+
+        self.write_gdt_table(&gdt_table[..])?;
+        sregs.gdt.base = BOOT_GDT_OFFSET as u64;
+        sregs.gdt.limit = core::mem::size_of_val(&gdt_table) as u16 - 1;
+
+        sregs.cs = code_seg;
+        sregs.ds = data_seg;
+        sregs.es = data_seg;
+        sregs.fs = data_seg;
+        sregs.gs = data_seg;
+        sregs.ss = data_seg;
+        sregs.tr = tss_seg;
+
+        // the kernel sets up paging, long mode and its protections itself
+        sregs.cr0 = X86_CR0_PE as u64;
+        sregs.cr3 = 0;
+        sregs.cr4 = 0;
+        sregs.efer = 0;
+
+        self.cpu_fd[vcpuid as usize]
+            .set_sregs(&sregs)
+            .map_err(|e| ErrorKind::from(&e))?;
+
+        let regs = kvm_regs {
+            rflags: 0x2,
+            rip: entry.into(),
+            rbx: PVH_START_INFO_PHYS_ADDR,
+            ..Default::default()
+        };
+        self.cpu_fd[vcpuid as usize]
+            .set_regs(&regs)
+            .map_err(|e| ErrorKind::from(&e))?;
+
+        Ok(())
+    }
+
+    /// Set the CPUID of vCPU `vcpuid` to the one supported by KVM with its own APIC ID
+    fn vcpu_set_cpuid(&self, vcpuid: u8) -> Result<(), Error> {
+        let mut cpuid = self
+            .kvm
+            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map_err(|e| ErrorKind::from(&e))?;
+
+        for entry in cpuid.as_mut_slice().iter_mut() {
+            match entry.function {
+                // initial APIC ID
+                0x1 => entry.ebx = (entry.ebx & 0x00FF_FFFF) | ((vcpuid as u32) << 24),
+                // x2APIC ID
+                0xB => entry.edx = vcpuid as u32,
+                _ => {}
+            }
+        }
+        self.cpu_fd[vcpuid as usize]
+            .set_cpuid2(&cpuid)
+            .map_err(|e| ErrorKind::from(&e))?;
+
+        Ok(())
+    }
+
     fn vcpu_add(&mut self, vcpuid: u8) -> Result<(), Error> {
         let vcpu_fd = self
             .kvm_fd
             .create_vcpu(vcpuid)
             .map_err(|e| ErrorKind::from(&e))?;
         self.cpu_fd.insert(vcpuid as usize, vcpu_fd);
+        // the kernel enables SMEP, SMAP and UMIP, if they are in the CPUID of the vCPU
+        self.vcpu_set_cpuid(vcpuid)?;
         self.vcpu_setup(vcpuid)?;
 
         Ok(())
     }
 
+    /// The TSC frequency of vCPU `vcpuid` in kHz
+    fn tsc_khz(&self, vcpuid: u8) -> Result<u64, Error> {
+        let ret = unsafe { ioctl(&self.cpu_fd[vcpuid as usize], KVM_GET_TSC_KHZ()) };
+        if ret < 0 {
+            return Err(ErrorKind::from(&errno::Error::last()).into());
+        }
+        Ok(ret as u64)
+    }
+
+    /// Reserve one syscall page for each of the `nr_cpus` vCPUs
+    fn syscall_pages_add(&mut self, nr_cpus: u8) -> Result<(), Error> {
+        let start_frame: PhysFrame =
+            PhysFrame::from_start_address(PhysAddr::new(SYSCALL_PAGES_PHYS_ADDR)).unwrap();
+        self.memory_map.mark_allocated_region(MemoryRegion {
+            range: frame_range(PhysFrame::range(
+                start_frame,
+                start_frame + u64::from(nr_cpus),
+            )),
+            region_type: MemoryRegionType::InUse,
+        });
+
+        self.syscall_pages = (0..u64::from(nr_cpus))
+            .map(|cpu| {
+                self.addr_gpa2hva(PhysAddr::new(
+                    SYSCALL_PAGES_PHYS_ADDR + cpu * self.page_size as u64,
+                ))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn vcpu_add_default(
         &mut self,
         vcpuid: u8,
+        nr_cpus: u8,
         guest_code: VirtAddr,
         elf_code: VirtAddr,
         elf_phdr: VirtAddr,
         elf_phnum: usize,
+        app_segments: AppSegments,
+        app_image: (PhysAddr, u64),
+        interp_image: (PhysAddr, u64),
+        app_args: &AppArgs,
+        syscall_policy: &SyscallPolicy,
+        trace_level: TraceLevel,
     ) -> Result<(), Error> {
         let syscall_vaddr = PhysAddr::new(SYSCALL_PHYS_ADDR);
 
-        self.syscall_hostvaddr = Some(self.addr_gpa2hva(syscall_vaddr)?);
+        /* Create VCPU, which starts its TSC at 0 */
+        self.vcpu_add(vcpuid)?;
+        let realtime_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let tsc_hz = self.tsc_khz(vcpuid)? * 1000;
 
         let mut boot_info = BootInfo {
             memory_map: self.memory_map.clone(),
             entry_point: elf_code.as_ptr(),
             load_addr: elf_phdr.as_ptr(),
             elf_phnum: elf_phnum,
+            app_segments,
+            app_image: app_image.0.as_u64(),
+            app_image_len: app_image.1,
+            interp_image: interp_image.0.as_u64(),
+            interp_image_len: interp_image.1,
             syscall_trigger_port: SYSCALL_TRIGGER_PORT,
+            app_args: app_args.clone(),
+            nr_cpus: nr_cpus.into(),
+            syscall_pages: SYSCALL_PAGES_PHYS_ADDR,
+            tsc_hz,
+            realtime_ns,
+            syscall_policy: *syscall_policy,
+            trace_level: trace_level as u32,
         };
 
         boot_info.memory_map.sort();
         // Write boot info to syscall page.
         unsafe {
-            self.syscall_hostvaddr
-                .unwrap()
+            self.addr_gpa2hva(syscall_vaddr)?
                 .as_mut_ptr::<BootInfo>()
                 .write(boot_info)
         };
 
-        /* Create VCPU */
-        self.vcpu_add(vcpuid)?;
-
         /* Setup guest general purpose registers */
         let mut regs = self.cpu_fd[vcpuid as usize]
             .get_regs()
@@ -474,129 +877,355 @@ impl KvmVm {
         Ok(())
     }
 
-    pub fn handle_syscall(&mut self) -> Result<(), ()> {
-        unsafe {
-            let syscall_page = self.syscall_hostvaddr.unwrap();
-            let request: *mut VmSyscall = syscall_page.as_mut_ptr();
-            let reply: *mut VmSyscallRet = syscall_page.as_mut_ptr();
-
-            //eprintln!("vmsyscall in: {:#?}", &*request);
-
-            reply.write_volatile(match request.read_volatile() {
-                VmSyscall::Write { fd, count, data } => match fd {
-                    1 => {
-                        let mut count: usize = count;
-                        if count > 4000 {
-                            count = 4000;
-                        }
-                        VmSyscallRet::Write(
-                            std::io::stdout()
-                                .write_all(&data[..count])
-                                .map(|_| count as _)
-                                .map_err(|e| {
-                                    vmsyscall::Error::Errno(
-                                        e.raw_os_error()
-                                            .unwrap_or(Into::<i64>::into(ErrNo::EBADF) as _)
-                                            .into(),
-                                    )
-                                }),
-                        )
-                    }
-                    2 => {
-                        let mut count: usize = count;
-                        if count > 4000 {
-                            count = 4000;
-                        }
-                        VmSyscallRet::Write(
-                            std::io::stderr()
-                                .write_all(&data[..count])
-                                .map(|_| count as _)
-                                .map_err(|e| {
-                                    vmsyscall::Error::Errno(
-                                        e.raw_os_error()
-                                            .unwrap_or(Into::<i64>::into(ErrNo::EBADF) as _)
-                                            .into(),
-                                    )
-                                }),
-                        )
-                    }
-                    _ => VmSyscallRet::Write(Err(vmsyscall::Error::Errno(ErrNo::EBADF.into()))),
-                },
-                VmSyscall::Read { fd: _, count: _ } => {
-                    VmSyscallRet::Read(Err(vmsyscall::Error::Errno(ErrNo::EBADF.into())))
+    /// Move the vCPUs out of the VM, so every one can run on its own thread.
+    ///
+    /// Every application processor comes with the receiver for its `CpuStart`.
+    pub fn take_vcpus(&mut self) -> Vec<(VcpuFd, Option<Receiver<CpuStart>>)> {
+        let receivers = std::mem::replace(&mut self.cpu_start_receivers, vec![]);
+        std::mem::replace(&mut self.cpu_fd, vec![])
+            .into_iter()
+            .zip(receivers)
+            .collect()
+    }
+
+    /// Wait until the application processor `vcpu` is started by the guest and load its
+    /// initial state.
+    ///
+    /// Fails, if the VM is shut down before.
+    pub fn wait_for_start(&self, vcpu: &VcpuFd, start: Receiver<CpuStart>) -> Result<(), Error> {
+        let CpuStart { regs, mut sregs } = start
+            .recv()
+            .map_err(|_| context!(ErrorKind::VcpuNotStarted))?;
+
+        // keep the local APIC of this vCPU
+        let own_sregs = vcpu.get_sregs().map_err(|e| ErrorKind::from(&e))?;
+        sregs.apic_base = own_sregs.apic_base;
+        sregs.interrupt_bitmap = own_sregs.interrupt_bitmap;
+
+        vcpu.set_sregs(&sregs).map_err(|e| ErrorKind::from(&e))?;
+        vcpu.set_regs(&regs).map_err(|e| ErrorKind::from(&e))?;
+        vcpu.set_mp_state(kvm_mp_state {
+            mp_state: KVM_MP_STATE_RUNNABLE,
+        })
+        .map_err(|e| ErrorKind::from(&e))?;
+
+        Ok(())
+    }
+
+    /// Start the application processor `cpu` in the mode and address space of `caller`
+    fn start_cpu(
+        &self,
+        caller: &VcpuFd,
+        cpu: u32,
+        entry: u64,
+        stack: u64,
+        arg: u64,
+    ) -> Result<i32, vmsyscall::Error> {
+        let sregs = caller
+            .get_sregs()
+            .map_err(|e| vmsyscall::Error::Errno(e.errno().into()))?;
+
+        let regs = kvm_regs {
+            rflags: 0x2,
+            rip: entry,
+            rsp: stack,
+            rdi: arg,
+            ..Default::default()
+        };
+
+        // every application processor can only be started once
+        let sender = self
+            .cpu_starts
+            .lock()
+            .unwrap()
+            .get_mut(cpu as usize)
+            .and_then(Option::take)
+            .ok_or_else(|| vmsyscall::Error::Errno(ErrNo::EINVAL.into()))?;
+
+        sender
+            .send(CpuStart { regs, sregs })
+            .map(|_| 0)
+            .map_err(|_| vmsyscall::Error::Errno(ErrNo::EIO.into()))
+    }
+
+    /// Handle a request on the syscall page of vCPU `cpu` and write the reply back to it.
+    ///
+    /// The request is copied out of guest memory before it is validated, so the guest
+    /// can't modify it while it is handled. Malformed requests are answered with
+    /// `vmsyscall::Error::DeSerializeError`, if at least the header is valid.
+    pub fn handle_syscall(&self, cpu: usize, vcpu: &VcpuFd) -> Result<(), Error> {
+        let syscall_page: *mut u8 = self
+            .syscall_pages
+            .get(cpu)
+            .ok_or_else(|| context!(ErrorKind::InvalidSyscallRequest))?
+            .as_mut_ptr();
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+
+        unsafe { std::ptr::copy_nonoverlapping(syscall_page, buf.as_mut_ptr(), MAX_MESSAGE_LEN) };
+
+        let header =
+            Header::decode(&buf).map_err(|_| context!(ErrorKind::InvalidSyscallRequest))?;
+
+        let reply = match VmSyscall::decode(&buf) {
+            Ok((_, request)) => match self.stats.as_ref() {
+                Some(stats) => {
+                    let name = format!("{:?}", request.nr());
+                    let start = Instant::now();
+                    let reply = self.syscall_reply(vcpu, request);
+                    stats.vm_syscall(&name, start.elapsed());
+                    reply
                 }
-                VmSyscall::Mmap {
-                    addr: _,
-                    length: _,
-                    prot: _,
-                    flags: _,
-                } => {
-                    VmSyscallRet::Mmap(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into())))
-                    /*
-                    let ret = unsafe {
-                        mmap(
-                            null_mut(),
-                            len,
-                            ProtFlags::from_bits_truncate(prot),
-                            MapFlags::from_bits_truncate(flags),
-                            -1,
-                            0,
-                        )
-                    };
-                    let mmap_start = match ret {
-                        Err(nix::Error::Sys(e)) if e == nix::errno::Errno::ENOMEM => {
-                            return KvmSyscallRet::Mmap(Err(vmsyscall::Error::ENOMEM))
-                        }
-                        Err(_) => return KvmSyscallRet::Mmap(Err(vmsyscall::Error::OTHERERROR)),
-                        Ok(v) => v,
-                    };
-                    let mut region = UserspaceMemRegion {
-                        region: Default::default(),
-                        used_phy_pages: Default::default(),
-                        host_mem: PhysAddr::new(mmap_start as u64),
-                        mmap_start: PhysAddr::new(mmap_start as u64),
-                        mmap_size: len as _,
-                    };
+                None => self.syscall_reply(vcpu, request),
+            },
+            Err(e) => VmSyscallRet::error(header.nr, e),
+        };
 
-                    region.region.slot = 0;
-                    region.region.flags = flags as _;
-                    region.region.guest_phys_addr = addr as _;
-                    region.region.memory_size = len as _;
-                    region.region.userspace_addr = region.host_mem.as_u64();
+        let len = reply
+            .encode(header.request_id, &mut buf)
+            .map_err(|_| context!(ErrorKind::InvalidSyscallRequest))?;
 
-                    unsafe {
-                        self.kvm_fd
-                            .set_user_memory_region(region.region)
-                            .map_err(map_context!())?
-                    };
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), syscall_page, len) };
 
-                    //self.userspace_mem_regions.push(region);
+        Ok(())
+    }
 
-                    KvmSyscallRet::Mmap(Ok(region.mmap_start.as_u64() as _))
-                    */
+    fn syscall_reply(&self, vcpu: &VcpuFd, request: VmSyscall) -> VmSyscallRet {
+        match request {
+            VmSyscall::Write { fd, count, data } => match fd {
+                1 => {
+                    let mut count: usize = count;
+                    if count > 4000 {
+                        count = 4000;
+                    }
+                    VmSyscallRet::Write(
+                        std::io::stdout()
+                            .write_all(&data[..count])
+                            .map(|_| count as _)
+                            .map_err(|e| {
+                                vmsyscall::Error::Errno(
+                                    e.raw_os_error()
+                                        .unwrap_or(Into::<i64>::into(ErrNo::EBADF) as _)
+                                        .into(),
+                                )
+                            }),
+                    )
+                }
+                2 => {
+                    let mut count: usize = count;
+                    if count > 4000 {
+                        count = 4000;
+                    }
+                    VmSyscallRet::Write(
+                        std::io::stderr()
+                            .write_all(&data[..count])
+                            .map(|_| count as _)
+                            .map_err(|e| {
+                                vmsyscall::Error::Errno(
+                                    e.raw_os_error()
+                                        .unwrap_or(Into::<i64>::into(ErrNo::EBADF) as _)
+                                        .into(),
+                                )
+                            }),
+                    )
                 }
-                VmSyscall::Madvise {
-                    addr: _,
-                    length: _,
-                    advice: _,
-                } => VmSyscallRet::Madvise(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into()))),
-                VmSyscall::Mremap {
-                    old_address: _,
-                    old_size: _,
-                    new_size: _,
-                    flags: _,
-                } => VmSyscallRet::Mremap(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into()))),
-                VmSyscall::Munmap { addr: _, length: _ } => {
-                    VmSyscallRet::Munmap(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into())))
+                _ => VmSyscallRet::Write(Err(vmsyscall::Error::Errno(ErrNo::EBADF.into()))),
+            },
+            VmSyscall::Read { fd, count } => match fd {
+                0 => {
+                    let count = count.min(READ_BUF_LEN);
+                    let mut data = [0u8; READ_BUF_LEN];
+                    VmSyscallRet::Read(loop {
+                        match std::io::stdin().read(&mut data[..count]) {
+                            Ok(n) => break Ok((n as _, data)),
+                            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                            Err(e) => {
+                                break Err(vmsyscall::Error::Errno(
+                                    e.raw_os_error()
+                                        .unwrap_or(Into::<i64>::into(ErrNo::EBADF) as _)
+                                        .into(),
+                                ))
+                            }
+                        }
+                    })
                 }
-                VmSyscall::Mprotect {
-                    addr: _,
-                    length: _,
-                    prot: _,
-                } => VmSyscallRet::Mprotect(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into()))),
-            });
+                _ => VmSyscallRet::Read(Err(vmsyscall::Error::Errno(ErrNo::EBADF.into()))),
+            },
+            VmSyscall::Mmap {
+                addr: _,
+                length: _,
+                prot: _,
+                flags: _,
+            } => {
+                VmSyscallRet::Mmap(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into())))
+                /*
+                let ret = unsafe {
+                    mmap(
+                        null_mut(),
+                        len,
+                        ProtFlags::from_bits_truncate(prot),
+                        MapFlags::from_bits_truncate(flags),
+                        -1,
+                        0,
+                    )
+                };
+                let mmap_start = match ret {
+                    Err(nix::Error::Sys(e)) if e == nix::errno::Errno::ENOMEM => {
+                        return KvmSyscallRet::Mmap(Err(vmsyscall::Error::ENOMEM))
+                    }
+                    Err(_) => return KvmSyscallRet::Mmap(Err(vmsyscall::Error::OTHERERROR)),
+                    Ok(v) => v,
+                };
+                let mut region = UserspaceMemRegion {
+                    region: Default::default(),
+                    used_phy_pages: Default::default(),
+                    host_mem: PhysAddr::new(mmap_start as u64),
+                    mmap_start: PhysAddr::new(mmap_start as u64),
+                    mmap_size: len as _,
+                };
+
+                region.region.slot = 0;
+                region.region.flags = flags as _;
+                region.region.guest_phys_addr = addr as _;
+                region.region.memory_size = len as _;
+                region.region.userspace_addr = region.host_mem.as_u64();
+
+                unsafe {
+                    self.kvm_fd
+                        .set_user_memory_region(region.region)
+                        .map_err(map_context!())?
+                };
+
+                //self.userspace_mem_regions.push(region);
+
+                KvmSyscallRet::Mmap(Ok(region.mmap_start.as_u64() as _))
+                */
+            }
+            VmSyscall::Madvise {
+                addr: _,
+                length: _,
+                advice: _,
+            } => VmSyscallRet::Madvise(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into()))),
+            VmSyscall::Mremap {
+                old_address: _,
+                old_size: _,
+                new_size: _,
+                flags: _,
+            } => VmSyscallRet::Mremap(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into()))),
+            VmSyscall::Munmap { addr: _, length: _ } => {
+                VmSyscallRet::Munmap(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into())))
+            }
+            VmSyscall::Mprotect {
+                addr: _,
+                length: _,
+                prot: _,
+            } => VmSyscallRet::Mprotect(Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into()))),
+            VmSyscall::Openat {
+                dirfd,
+                path,
+                path_len,
+                flags,
+                mode,
+            } => VmSyscallRet::Openat(match self.host_fs.lock().unwrap().as_mut() {
+                Some(fs) => fs.openat(dirfd, &path[..path_len.min(PATH_BUF_LEN)], flags, mode),
+                None => Err(vmsyscall::Error::Errno(ErrNo::ENOENT.into())),
+            }),
+            VmSyscall::Close { fd } => VmSyscallRet::Close(self.with_host_fs(|fs| fs.close(fd))),
+            VmSyscall::Pread { fd, count, offset } => {
+                VmSyscallRet::Pread(self.with_host_fs(|fs| fs.pread(fd, count, offset)))
+            }
+            VmSyscall::Pwrite {
+                fd,
+                count,
+                offset,
+                data,
+            } => VmSyscallRet::Pwrite(self.with_host_fs(|fs| fs.pwrite(fd, &data, count, offset))),
+            VmSyscall::Lseek { fd, offset, whence } => {
+                VmSyscallRet::Lseek(self.with_host_fs(|fs| fs.lseek(fd, offset, whence)))
+            }
+            VmSyscall::Fstat { fd } => VmSyscallRet::Fstat(self.with_host_fs(|fs| fs.fstat(fd))),
+            VmSyscall::Getdents64 { fd, count } => {
+                VmSyscallRet::Getdents64(self.with_host_fs(|fs| fs.getdents64(fd, count)))
+            }
+            VmSyscall::StartCpu {
+                cpu,
+                entry,
+                stack,
+                arg,
+            } => VmSyscallRet::StartCpu(self.start_cpu(vcpu, cpu, entry, stack, arg)),
+            VmSyscall::GetRandom { count } => {
+                let count = count.min(READ_BUF_LEN);
+                let mut data = [0u8; READ_BUF_LEN];
+                VmSyscallRet::GetRandom(loop {
+                    let ret = unsafe { libc::getrandom(data.as_mut_ptr() as _, count, 0) };
+                    if ret >= 0 {
+                        break Ok((ret as _, data));
+                    }
+                    let e = std::io::Error::last_os_error();
+                    if e.kind() != std::io::ErrorKind::Interrupted {
+                        break Err(vmsyscall::Error::Errno(
+                            e.raw_os_error()
+                                .unwrap_or(Into::<i64>::into(ErrNo::EIO) as _)
+                                .into(),
+                        ));
+                    }
+                })
+            }
+            VmSyscall::Trace { count, records } => VmSyscallRet::Trace(
+                self.with_tracer(|tracer| tracer.records(&records[..count]))
+                    .map(|_| count as i32),
+            ),
+            VmSyscall::TraceSummary {
+                count,
+                last,
+                entries,
+            } => {
+                let entries = &entries[..count];
+                if let Some(stats) = self.stats.as_ref() {
+                    stats.guest_syscalls(entries);
+                }
+                let has_tracer = self.tracer.lock().unwrap().is_some();
+                let ret = if !has_tracer && self.stats.is_some() {
+                    // the summary was requested for the statistics only
+                    Ok(())
+                } else {
+                    self.with_tracer(|tracer| tracer.summary(entries, last))
+                };
+                VmSyscallRet::TraceSummary(ret.map(|_| count as i32))
+            }
+            VmSyscall::Crash { report } => VmSyscallRet::Crash(
+                self.crash_reporter
+                    .write(&report, report.exception_name())
+                    .map(|_| 0)
+                    .map_err(|e| {
+                        vmsyscall::Error::Errno(
+                            e.raw_os_error()
+                                .unwrap_or(Into::<i64>::into(ErrNo::EIO) as _)
+                                .into(),
+                        )
+                    }),
+            ),
+        }
+    }
+
+    fn with_tracer(
+        &self,
+        f: impl FnOnce(&mut Tracer) -> std::io::Result<()>,
+    ) -> Result<(), vmsyscall::Error> {
+        match self.tracer.lock().unwrap().as_mut() {
+            Some(tracer) => f(tracer)
+                .map_err(|e| vmsyscall::Error::Errno(e.raw_os_error().unwrap_or(libc::EIO).into())),
+            None => Err(vmsyscall::Error::Errno(ErrNo::ENOSYS.into())),
+        }
+    }
+
+    fn with_host_fs<T, F>(&self, f: F) -> Result<T, vmsyscall::Error>
+    where
+        F: FnOnce(&mut HostFs) -> Result<T, vmsyscall::Error>,
+    {
+        match self.host_fs.lock().unwrap().as_mut() {
+            Some(fs) => f(fs),
+            None => Err(vmsyscall::Error::Errno(ErrNo::EBADF.into())),
         }
-        Ok(())
     }
 
     fn create_irqchip(&mut self) -> Result<(), Error> {
@@ -616,31 +1245,109 @@ impl KvmVm {
         Ok(())
     }
 
-    pub fn vm_create_default(kernel_name: &str, elf_name: &str, vcpuid: u8) -> Result<Self, Error> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn vm_create_default(
+        kernel_name: &str,
+        elf_name: &str,
+        sysroot: Option<&str>,
+        mem_size: u64,
+        app_args: &AppArgs,
+        syscall_policy: &SyscallPolicy,
+        trace_level: TraceLevel,
+        nr_cpus: u8,
+        boot: BootProtocol,
+    ) -> Result<Self, Error> {
+        if nr_cpus == 0 || nr_cpus as usize > MAX_CPUS {
+            return Err(context!(ErrorKind::InvalidVcpuCount));
+        }
+
         /* Create VM */
-        let mut vm = KvmVm::vm_create((DEFAULT_GUEST_MEM / DEFAULT_GUEST_PAGE_SIZE as u64) as _)?;
+        let mut vm = KvmVm::vm_create((mem_size / DEFAULT_GUEST_PAGE_SIZE as u64) as _)?;
 
         /* Setup IRQ Chip */
         vm.create_irqchip()?;
 
-        /* Setup app guest code */
-        let (elf_code, elf_phdr, elf_phnum) = vm.elf_load(elf_name, MemoryRegionType::App)?;
+        /* Setup app guest code, position independent apps are loaded by the kernel */
+        let app_data = std::fs::read(elf_name).map_err(map_context!())?;
+        let app_elf = xmas_elf::ElfFile::new(&app_data).map_err(map_context!())?;
+        let (elf_code, elf_phdr, elf_phnum, app_segments, app_image) =
+            if is_position_independent(&app_elf) {
+                let image = vm.image_load(elf_name, MemoryRegionType::App)?;
+                (
+                    VirtAddr::new(0),
+                    VirtAddr::new(0),
+                    0,
+                    AppSegments::new(),
+                    image,
+                )
+            } else {
+                let (code, phdr, phnum, segments) = vm.elf_load(elf_name, MemoryRegionType::App)?;
+                (code, phdr, phnum, segments, (PhysAddr::new(0), 0))
+            };
+
+        /* The dynamic linker of a dynamically linked app is loaded by the kernel, too */
+        let interp_image = match (interpreter(&app_elf), sysroot) {
+            (Some(interp), Some(sysroot)) => {
+                let path = std::path::Path::new(sysroot).join(interp.trim_start_matches('/'));
+                vm.image_load(&path.to_string_lossy(), MemoryRegionType::App)?
+            }
+            (Some(_), None) => return Err(context!(ErrorKind::NoSysroot)),
+            (None, _) => (PhysAddr::new(0), 0),
+        };
 
         /* Setup kernel guest code */
-        let (guest_code, _, _) = vm.elf_load(kernel_name, MemoryRegionType::Kernel)?;
+        let (guest_code, _, _, _) = vm.elf_load(kernel_name, MemoryRegionType::Kernel)?;
+
+        /* The PVH entry of the kernel and the pages of its start info */
+        let pvh_entry = match boot {
+            BootProtocol::Direct => None,
+            BootProtocol::Pvh => {
+                let entry = pvh_entry(kernel_name)?;
+                vm.memory_map.mark_allocated_region(MemoryRegion {
+                    range: FrameRange::new(PVH_START_INFO_PHYS_ADDR, PVH_END_PHYS_ADDR),
+                    region_type: MemoryRegionType::InUse,
+                });
+                Some(entry)
+            }
+        };
 
-        /* Add the first vCPU. */
-        vm.vcpu_add_default(vcpuid, guest_code, elf_code, elf_phdr, elf_phnum)?;
+        /* Symbols for crash reports, only the app may be relocated */
+        vm.crash_reporter.images = vec![load_symbols(elf_name)?, load_symbols(kernel_name)?];
 
-        /* Set CPUID */
-        let cpuid = vm
-            .kvm
-            .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
-            .map_err(|e| ErrorKind::from(&e))?;
+        vm.syscall_pages_add(nr_cpus)?;
 
-        vm.cpu_fd[vcpuid as usize]
-            .set_cpuid2(&cpuid)
-            .map_err(|e| ErrorKind::from(&e))?;
+        /* Add the first vCPU. */
+        vm.vcpu_add_default(
+            0,
+            nr_cpus,
+            guest_code,
+            elf_code,
+            elf_phdr,
+            elf_phnum,
+            app_segments,
+            app_image,
+            interp_image,
+            app_args,
+            syscall_policy,
+            trace_level,
+        )?;
+
+        /* Add the application processors, which wait for `VmSyscall::StartCpu` */
+        let mut cpu_starts = vec![None];
+        vm.cpu_start_receivers.push(None);
+        for vcpuid in 1..nr_cpus {
+            vm.vcpu_add(vcpuid)?;
+            let (sender, receiver) = channel();
+            cpu_starts.push(Some(sender));
+            vm.cpu_start_receivers.push(Some(receiver));
+        }
+        vm.cpu_starts = Mutex::new(cpu_starts);
+
+        /* The first vCPU enters the kernel like with qemu. Its 32-bit GDT replaces the boot
+         * GDT, the application processors only run after the kernel loaded its own. */
+        if let Some(entry) = pvh_entry {
+            vm.vcpu_setup_pvh(0, entry)?;
+        }
 
         Ok(vm)
     }
@@ -2,4 +2,10 @@ pub mod error;
 pub mod kvmvm;
 pub use error::*;
 pub mod arch;
+pub mod cli;
+pub mod crash;
+pub mod hostfs;
+pub mod pvh;
+pub mod stats;
+pub mod trace;
 //pub mod device_manager;
@@ -1,35 +1,81 @@
-use kvm_ioctls::{Kvm, VcpuExit};
-use std::path::Path;
+use kvm_ioctls::{Kvm, VcpuExit, VcpuFd};
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Instant;
+use vmrun::cli::{self, Config, Mode, ParseError};
+use vmrun::hostfs::HostFs;
 use vmrun::kvmvm::{self, SYSCALL_TRIGGER_PORT};
-
-const PORT_QEMU_EXIT: u16 = 0xF4;
+use vmrun::stats::Stats;
+use vmrun::trace::Tracer;
+use vmsyscall::bootinfo::{AppAuxv, APP_ARGS_LEN};
+use vmsyscall::exit::{ExitCode, EXIT_PORT, EXIT_RECORD_PORT};
+use vmsyscall::trace::TraceLevel;
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let kvm = Kvm::new();
+    let mut args = std::env::args();
+    let program = args.next().unwrap_or_else(|| "vmrun".into());
 
-    match args.len() {
-        4..=std::usize::MAX if args[1].eq("--force-qemu") => {
-            main_qemu(&args[2], &args[3], &args[4..])
+    let config = match Config::parse(args) {
+        Ok(config) => config,
+        Err(ParseError::Help) => {
+            println!("{}", cli::usage(&program));
+            exit(0);
         }
-        4..=std::usize::MAX if args[1].eq("--fallback-qemu") => match kvm {
-            Ok(_) => main_kvm(&args[2], &args[3]),
-            Err(_) => main_qemu(&args[2], &args[3], &args[4..]),
-        },
-        3 => main_kvm(&args[1], &args[2]),
-        _ => {
-            eprintln!(
-                "Usage: {} [--fallback-qemu] <elf binary> <kernelblob>",
-                args[0],
-            );
+        Err(e) => {
+            eprintln!("Error: {}\n\n{}", e, cli::usage(&program));
             exit(1);
         }
+    };
+
+    match config.mode {
+        Mode::ForceQemu => main_qemu(&config),
+        Mode::FallbackQemu => match Kvm::new() {
+            Ok(_) => main_kvm(&config),
+            Err(_) => main_qemu(&config),
+        },
+        Mode::Kvm => main_kvm(&config),
+    }
+}
+
+/// Hand the credentials of the vmrun process to the app
+fn host_auxv() -> AppAuxv {
+    let clock_tick = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    unsafe {
+        AppAuxv {
+            uid: libc::getuid(),
+            euid: libc::geteuid(),
+            gid: libc::getgid(),
+            egid: libc::getegid(),
+            clock_tick: if clock_tick > 0 { clock_tick as _ } else { 100 },
+        }
     }
 }
 
-fn main_qemu(_elf_binary: &str, kernel_blob: &str, extra_args: &[String]) -> ! {
+/// Create a new directory only accessible by the user in the temporary directory
+///
+/// Creating the directory fails, if the name exists, so no other user can plant a file
+/// or a symlink in it.
+fn private_temp_dir() -> io::Result<PathBuf> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.mode(0o700);
+    let mut n = 0u32;
+    loop {
+        let dir = std::env::temp_dir().join(format!("vmrun-{}-{}", std::process::id(), n));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && n < 100 => n += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn main_qemu(config: &Config) -> ! {
+    let kernel_blob = config.kernel.as_str();
+
     if !Path::new(kernel_blob).exists() {
         eprintln!("Kernel image `{}` not found!", kernel_blob);
         exit(1);
@@ -39,13 +85,25 @@ fn main_qemu(_elf_binary: &str, kernel_blob: &str, extra_args: &[String]) -> ! {
 
     let start = Instant::now();
 
+    let smp = config.vcpus.to_string();
+    let mem = (config.mem_size >> 20).to_string();
+    let temp_dir = match private_temp_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Failed to create a temporary directory: {}", e);
+            exit(1);
+        }
+    };
+    let exit_record = temp_dir.join("exit");
+    let debugcon = format!("file:{}", exit_record.display());
+
     eprintln!("Starting QEMU {}", kernel_blob);
     let mut cmd = Command::new("qemu-system-x86_64");
     let mut args = vec![
         "-smp",
-        "1",
+        &smp,
         "-m",
-        "128",
+        &mem,
         "-nodefaults",
         "-vga",
         "none",
@@ -54,6 +112,8 @@ fn main_qemu(_elf_binary: &str, kernel_blob: &str, extra_args: &[String]) -> ! {
         "-no-reboot",
         "-device",
         "isa-debug-exit,iobase=0xf4,iosize=0x04",
+        "-debugcon",
+        &debugcon,
         "-chardev",
         "stdio,mux=on,id=char0",
         "-mon",
@@ -73,26 +133,63 @@ fn main_qemu(_elf_binary: &str, kernel_blob: &str, extra_args: &[String]) -> ! {
     }
     args.push("-kernel");
     args.push(kernel_blob);
-    if !extra_args.is_empty() && extra_args[0].eq("--") {
-        //eprintln!("Extra args! {:#?}", extra_args);
-        args.extend(extra_args.iter().skip(1).map(String::as_str));
-    }
+    args.extend(config.qemu_args.iter().map(String::as_str));
     cmd.args(args);
-    let mut child = cmd.spawn().expect("Unable to start qemu-system-x86_64");
-    let status = child.wait().expect("Failed to wait on qemu-system-x86_64");
+    let status = cmd.spawn().and_then(|mut child| child.wait());
     let elapsed = start.elapsed();
+
+    // The exit port only carries 7 bits through qemu, the record has the full value.
+    let record = std::fs::read(&exit_record).unwrap_or_default();
+    let _ = std::fs::remove_dir_all(&temp_dir);
+
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("Failed to run qemu-system-x86_64: {}", e);
+            exit(1);
+        }
+    };
     eprintln!("QEMU: Creating and running took {:?}", elapsed);
-    match status.code() {
-        Some(33) => exit(0),
-        Some(v) => exit(v),
-        None => {
+    let recorded = match record.len() {
+        len if len >= 4 => {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(&record[len - 4..]);
+            ExitCode::decode(u32::from_le_bytes(value))
+        }
+        _ => None,
+    };
+
+    match (recorded, status.code()) {
+        (Some(code), _) => exit(exit_status(code)),
+        // isa-debug-exit exits with `(value << 1) | 1`
+        (None, Some(v)) => match ExitCode::decode((v >> 1) as u32) {
+            Some(code) if v & 1 == 1 => exit(exit_status(code)),
+            _ => exit(v),
+        },
+        (None, None) => {
             eprintln!("qemu terminated by signal");
             exit(1);
         }
     }
 }
 
-fn main_kvm(elf_blob: &str, kernel_blob: &str) {
+/// The exit code of vmrun for the exit `code` of the VM
+fn exit_status(code: ExitCode) -> i32 {
+    match code {
+        ExitCode::Exit(_) => {}
+        ExitCode::Panic => eprintln!("Hypervisor: kernel panic"),
+        ExitCode::Exception(vector) => {
+            eprintln!("Hypervisor: fatal exception {}", vector)
+        }
+        ExitCode::Signal(signo) => eprintln!("Hypervisor: app killed by signal {}", signo),
+    }
+    code.process_exit_code()
+}
+
+fn main_kvm(config: &Config) {
+    let elf_blob = config.app.as_str();
+    let kernel_blob = config.kernel.as_str();
+
     let start = Instant::now();
 
     if !Path::new(kernel_blob).exists() {
@@ -105,57 +202,207 @@ fn main_kvm(elf_blob: &str, kernel_blob: &str) {
         exit(1);
     }
 
+    let mut app_args = match config.app_args() {
+        Ok(app_args) => app_args,
+        Err(_) => {
+            eprintln!(
+                "Application arguments and environment exceed {} bytes!",
+                APP_ARGS_LEN
+            );
+            exit(1);
+        }
+    };
+    app_args.auxv = host_auxv();
+
+    let host_fs = config.root_dir().map(|root| match HostFs::new(root) {
+        Ok(host_fs) => host_fs,
+        Err(e) => {
+            eprintln!("Unable to export `{}` as root directory: {}", root, e);
+            exit(1);
+        }
+    });
+
+    let tracer = match config.trace_level {
+        TraceLevel::Off => None,
+        _ => match Tracer::create(&config.trace_file, config.trace_format) {
+            Ok(tracer) => Some(tracer),
+            Err(e) => {
+                eprintln!("Unable to create `{}`: {}", config.trace_file, e);
+                exit(1);
+            }
+        },
+    };
+
+    // The statistics need the totals of the kernel, even if nothing is traced.
+    let trace_level = match (config.trace_level, config.stats) {
+        (TraceLevel::Off, Some(_)) => TraceLevel::Summary,
+        (level, _) => level,
+    };
+
     eprintln!("Starting {} with {}", kernel_blob, elf_blob);
 
-    let mut kvm = kvmvm::KvmVm::vm_create_default(&kernel_blob, &elf_blob, 0).unwrap();
+    let mut kvm = kvmvm::KvmVm::vm_create_default(
+        kernel_blob,
+        elf_blob,
+        config.sysroot.as_ref().map(String::as_str),
+        config.mem_size,
+        &app_args,
+        &config.syscall_policy,
+        trace_level,
+        config.vcpus,
+        config.boot,
+    )
+    .unwrap();
+    kvm.host_fs = Mutex::new(host_fs);
+    kvm.tracer = Mutex::new(tracer);
+    kvm.stats = config.stats.map(|_| Stats::new());
+    kvm.crash_reporter.format = config.crash_format;
+    kvm.crash_reporter.file = config.crash_file.clone();
+
+    let vcpus = kvm.take_vcpus();
+    let kvm = Arc::new(kvm);
+    let config = Arc::new(config.clone());
+
+    // Every vCPU runs on its own thread. The application processors wait,
+    // until the guest starts them.
+    let mut threads = vcpus
+        .into_iter()
+        .enumerate()
+        .map(|(cpu, (vcpu, cpu_start))| {
+            let kvm = kvm.clone();
+            let config = config.clone();
+            thread::Builder::new()
+                .name(format!("vcpu{}", cpu))
+                .spawn(move || {
+                    if let Some(cpu_start) = cpu_start {
+                        if kvm.wait_for_start(&vcpu, cpu_start).is_err() {
+                            return;
+                        }
+                    }
+                    run_vcpu(&kvm, &config, cpu, &vcpu, start)
+                })
+                .expect("Hypervisor: Unable to spawn vCPU thread")
+        })
+        .collect::<Vec<_>>();
+
+    // The VM is done, when the first vCPU halts.
+    threads
+        .remove(0)
+        .join()
+        .expect("Hypervisor: vCPU thread panicked");
+    write_stats(&kvm, &config);
+    eprintln!("Hypervisor: Done");
+}
+
+/// Write the statistics of the VM, if requested
+fn write_stats(kvm: &kvmvm::KvmVm, config: &Config) {
+    let (stats, format) = match (kvm.stats.as_ref(), config.stats) {
+        (Some(stats), Some(format)) => (stats, format),
+        _ => return,
+    };
+    let out = stats.format(format);
+    match config.stats_file.as_ref() {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, out + "\n") {
+                eprintln!("Hypervisor: Unable to write `{}`: {}", path, e);
+            }
+        }
+        None => eprintln!("{}", out),
+    }
+}
+
+/// Stop the VM with exit `code`
+fn shutdown(kvm: &kvmvm::KvmVm, config: &Config, code: i32) -> ! {
+    write_stats(kvm, config);
+    exit(code)
+}
 
+/// The name of the kind of a `VcpuExit` for the statistics
+fn exit_kind(exit: &VcpuExit) -> &'static str {
+    match exit {
+        VcpuExit::IoIn(..) => "IoIn",
+        VcpuExit::IoOut(..) => "IoOut",
+        VcpuExit::MmioRead(..) => "MmioRead",
+        VcpuExit::MmioWrite(..) => "MmioWrite",
+        VcpuExit::Hlt => "Hlt",
+        VcpuExit::Shutdown => "Shutdown",
+        VcpuExit::FailEntry => "FailEntry",
+        VcpuExit::InternalError => "InternalError",
+        VcpuExit::Intr => "Intr",
+        _ => "Other",
+    }
+}
+
+/// Write a crash report with the state of `vcpu` for an unexpected VM exit
+fn report_unexpected(kvm: &kvmvm::KvmVm, cpu: usize, vcpu: &VcpuFd, reason: &str) {
+    let written = kvm
+        .vcpu_report(cpu, vcpu)
+        .map_err(|e| format!("{:?}", e))
+        .and_then(|report| {
+            kvm.crash_reporter
+                .write(&report, reason)
+                .map_err(|e| format!("{}", e))
+        });
+    if let Err(e) = written {
+        eprintln!("Hypervisor: {}, no crash report: {}", reason, e);
+    }
+}
+
+fn run_vcpu(kvm: &kvmvm::KvmVm, config: &Config, cpu: usize, vcpu: &VcpuFd, start: Instant) {
     loop {
-        let ret = kvm
-            .cpu_fd
-            .get(0)
-            .unwrap()
-            .run()
-            .expect("Hypervisor: VM run failed");
+        let ret = vcpu.run().expect("Hypervisor: VM run failed");
+        let handle_start = Instant::now();
+        let kind = exit_kind(&ret);
 
         match ret {
             VcpuExit::IoOut(port, data) => match port {
                 // Qemu exit simulation
-                PORT_QEMU_EXIT if data.eq(&[0x10, 0, 0, 0]) => {
+                EXIT_PORT => {
                     let elapsed = start.elapsed();
                     eprintln!("Hypervisor: Creating and running took {:?}", elapsed);
-                    std::process::exit(0);
-                }
-                PORT_QEMU_EXIT if data.eq(&[0x11, 0, 0, 0]) => {
-                    std::process::exit(1);
+                    let mut value = [0u8; 4];
+                    let len = data.len().min(4);
+                    value[..len].copy_from_slice(&data[..len]);
+                    match ExitCode::decode(u32::from_le_bytes(value)) {
+                        Some(code) => shutdown(kvm, config, exit_status(code)),
+                        None => {
+                            eprintln!("Hypervisor: invalid exit code {:#X?}", data);
+                            shutdown(kvm, config, 1);
+                        }
+                    }
                 }
+                // only needed with qemu, the exit code follows on `EXIT_PORT`
+                EXIT_RECORD_PORT => {}
                 SYSCALL_TRIGGER_PORT => {
-                    if let Err(e) = kvm.handle_syscall() {
-                        panic!("Handle syscall: {:#?}", e);
+                    if let Err(e) = kvm.handle_syscall(cpu, vcpu) {
+                        eprintln!("Hypervisor: Handle syscall: {:?}", e);
+                        shutdown(kvm, config, 1);
                     }
                 }
                 _ => {
-                    let regs = kvm.cpu_fd.get(0).unwrap().get_regs().unwrap();
-                    panic!(
-                        "Hypervisor: Unexpected IO port {:#X} {:#?}!\n{:#?}",
-                        port, data, regs
-                    )
+                    let reason = format!("unexpected IO port {:#X} {:#X?}", port, data);
+                    report_unexpected(kvm, cpu, vcpu, &reason);
+                    shutdown(kvm, config, 1);
                 }
             },
             VcpuExit::Hlt => {
                 let elapsed = start.elapsed();
                 eprintln!("Hypervisor: VcpuExit::Hlt");
                 eprintln!("Hypervisor: Creating and running took {:?}", elapsed);
-                break;
+                if let Some(stats) = kvm.stats.as_ref() {
+                    stats.exit(kind, handle_start.elapsed());
+                }
+                return;
             }
             exit_reason => {
-                let regs = kvm.cpu_fd.get(0).unwrap().get_regs().unwrap();
-                eprintln!(
-                    "Hypervisor: unexpected exit reason: {:?}\n{:#?}",
-                    exit_reason, regs
-                );
-                std::process::exit(1);
+                let reason = format!("unexpected exit reason {:?}", exit_reason);
+                report_unexpected(kvm, cpu, vcpu, &reason);
+                shutdown(kvm, config, 1);
             }
         }
+
+        if let Some(stats) = kvm.stats.as_ref() {
+            stats.exit(kind, handle_start.elapsed());
+        }
     }
-    eprintln!("Hypervisor: Done");
 }
@@ -0,0 +1,195 @@
+//! The PVH boot protocol
+//!
+//! With `--boot pvh`, vmrun enters the kernel like qemu does: in 32-bit protected mode at
+//! the `XEN_ELFNOTE_PHYS32_ENTRY` of the kernel ELF file, with an `hvm_start_info`
+//! holding an E820 memory map and a command line. The `BootInfo` is passed as the
+//! first module.
+
+use vmsyscall::memory_map::{MemoryMap, MemoryRegionType};
+use vmsyscall::pvh::{
+    HvmMemmapTableEntry, XEN_ELFNOTE_NAME, XEN_ELFNOTE_PHYS32_ENTRY, XEN_HVM_MEMMAP_TYPE_RAM,
+    XEN_HVM_MEMMAP_TYPE_RESERVED,
+};
+
+/// How vmrun enters the kernel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootProtocol {
+    /// In 64-bit mode at the ELF entry point with the `BootInfo` in `rdi`
+    Direct,
+    /// In 32-bit protected mode at the PVH entry point with the `hvm_start_info` in `ebx`
+    Pvh,
+}
+
+/// Size of the header of an ELF note
+const NOTE_HEADER_LEN: usize = 12;
+
+/// The little endian `u32` at `offset` of `data`
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(data.get(offset..offset.checked_add(4)?)?);
+    Some(u32::from_le_bytes(bytes))
+}
+
+/// `len` rounded up to the 4 byte alignment of the name and the descriptor of a note
+fn align4(len: usize) -> Option<usize> {
+    len.checked_add(3).map(|len| len & !3)
+}
+
+/// The 32-bit entry point in the `XEN_ELFNOTE_PHYS32_ENTRY` of the ELF `notes`
+///
+/// `notes` is the content of a `PT_NOTE` segment.
+pub fn phys32_entry(notes: &[u8]) -> Option<u32> {
+    let mut notes = notes;
+    while notes.len() >= NOTE_HEADER_LEN {
+        let namesz = u32_at(notes, 0)? as usize;
+        let descsz = u32_at(notes, 4)? as usize;
+        let note_type = u32_at(notes, 8)?;
+
+        let name_end = NOTE_HEADER_LEN.checked_add(namesz)?;
+        let desc_start = align4(name_end)?;
+        let desc_end = desc_start.checked_add(descsz)?;
+        let name = notes.get(NOTE_HEADER_LEN..name_end)?;
+        let desc = notes.get(desc_start..desc_end)?;
+
+        if note_type == XEN_ELFNOTE_PHYS32_ENTRY && name == XEN_ELFNOTE_NAME {
+            return u32_at(desc, 0);
+        }
+        notes = notes.get(align4(desc_end)?..)?;
+    }
+    None
+}
+
+/// The E820 memory map of the guest memory described by `memory_map`
+///
+/// Only the usable memory and the kernel are RAM, the kernel reserves its own image.
+/// Adjacent regions of the same type are merged.
+pub fn e820(memory_map: &MemoryMap) -> Vec<HvmMemmapTableEntry> {
+    let mut regions = memory_map
+        .iter()
+        .filter(|r| !r.range.is_empty())
+        .collect::<Vec<_>>();
+    regions.sort_by_key(|r| r.range.start_frame_number);
+
+    let mut entries: Vec<HvmMemmapTableEntry> = Vec::new();
+    for region in regions {
+        let entry_type = match region.region_type {
+            MemoryRegionType::Usable | MemoryRegionType::Kernel => XEN_HVM_MEMMAP_TYPE_RAM,
+            _ => XEN_HVM_MEMMAP_TYPE_RESERVED,
+        };
+        let addr = region.range.start_addr();
+        let size = region.range.end_addr() - addr;
+        match entries.last_mut() {
+            Some(last) if last.entry_type == entry_type && last.addr + last.size == addr => {
+                last.size += size
+            }
+            _ => entries.push(HvmMemmapTableEntry {
+                addr,
+                size,
+                entry_type,
+                reserved: 0,
+            }),
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmsyscall::memory_map::{FrameRange, MemoryRegion};
+
+    /// An ELF note of `note_type` with `name` and `desc`
+    fn note(note_type: u32, name: &[u8], desc: &[u8]) -> Vec<u8> {
+        let mut note = Vec::new();
+        note.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        note.extend_from_slice(&note_type.to_le_bytes());
+        note.extend_from_slice(name);
+        note.resize(align4(note.len()).unwrap(), 0);
+        note.extend_from_slice(desc);
+        note.resize(align4(note.len()).unwrap(), 0);
+        note
+    }
+
+    #[test]
+    fn phys32_entry_of_notes() {
+        // the notes of `pvh_notes.s`
+        let mut notes = note(
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            b"Xen\0",
+            &0x10_0020u32.to_le_bytes(),
+        );
+        notes.extend(note(2, b"Xen\0", &0x1000u32.to_le_bytes()));
+        assert_eq!(phys32_entry(&notes), Some(0x10_0020));
+
+        // other notes come first, the entry may be 64-bit
+        let mut notes = note(3, b"GNU\0", b"build-id");
+        notes.extend(note(
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            b"Xen\0",
+            &0x20_0000u64.to_le_bytes(),
+        ));
+        assert_eq!(phys32_entry(&notes), Some(0x20_0000));
+
+        // only the Xen notes count
+        let notes = note(
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            b"Foo\0",
+            &0x10_0020u32.to_le_bytes(),
+        );
+        assert_eq!(phys32_entry(&notes), None);
+        assert_eq!(phys32_entry(&[]), None);
+    }
+
+    #[test]
+    fn phys32_entry_of_truncated_notes() {
+        let notes = note(
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            b"Xen\0",
+            &0x10_0020u32.to_le_bytes(),
+        );
+        for len in 0..notes.len() {
+            assert_eq!(phys32_entry(&notes[..len]), None);
+        }
+        let mut notes = notes;
+        notes[4..8].copy_from_slice(&[0xff; 4]);
+        assert_eq!(phys32_entry(&notes), None);
+    }
+
+    #[test]
+    fn e820_of_memory_map() {
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(MemoryRegion {
+            range: FrameRange::new(0, 0x400_0000),
+            region_type: MemoryRegionType::Usable,
+        });
+        for (start, end, region_type) in [
+            (0, 0x1000, MemoryRegionType::FrameZero),
+            (0x1000, 0x2000, MemoryRegionType::InUse),
+            (0x10_0000, 0x10_3000, MemoryRegionType::Kernel),
+            (0x20_0000, 0x28_0000, MemoryRegionType::Kernel),
+            (0x40_0000, 0x41_0000, MemoryRegionType::App),
+        ]
+        .iter()
+        {
+            memory_map.mark_allocated_region(MemoryRegion {
+                range: FrameRange::new(*start, *end),
+                region_type: *region_type,
+            });
+        }
+
+        let entries = e820(&memory_map)
+            .iter()
+            .map(|e| (e.addr, e.addr + e.size, e.entry_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                (0, 0x2000, XEN_HVM_MEMMAP_TYPE_RESERVED),
+                (0x2000, 0x40_0000, XEN_HVM_MEMMAP_TYPE_RAM),
+                (0x40_0000, 0x41_0000, XEN_HVM_MEMMAP_TYPE_RESERVED),
+                (0x41_0000, 0x400_0000, XEN_HVM_MEMMAP_TYPE_RAM),
+            ]
+        );
+    }
+}
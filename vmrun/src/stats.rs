@@ -0,0 +1,294 @@
+//! Counters for the crossings of the hypervisor boundary
+//!
+//! vmrun counts every `VcpuExit` and every proxied `VmSyscall` with the time spent
+//! handling it. The kernel reports its totals per Linux syscall number, when the app
+//! exits. Everything is printed as a table or as JSON on shutdown.
+
+use crate::trace::syscall_name;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::Duration;
+use vmsyscall::trace::TraceSummary;
+
+/// Format of the statistics
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsFormat {
+    Table,
+    Json,
+}
+
+/// Number of events and the time spent handling them
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counter {
+    pub count: u64,
+    pub total: Duration,
+}
+
+impl Counter {
+    fn add(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+    }
+
+    fn average(&self) -> Duration {
+        if self.count == 0 {
+            Duration::default()
+        } else {
+            Duration::from_nanos((self.total.as_nanos() / self.count as u128) as u64)
+        }
+    }
+}
+
+/// The statistics of a VM, shared by all vCPU threads
+#[derive(Default)]
+pub struct Stats {
+    exits: Mutex<BTreeMap<String, Counter>>,
+    vm_syscalls: Mutex<BTreeMap<String, Counter>>,
+    guest_syscalls: Mutex<BTreeMap<u32, TraceSummary>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a `VcpuExit` of `kind`, which took `elapsed` to handle
+    pub fn exit(&self, kind: &str, elapsed: Duration) {
+        add(&self.exits, kind, elapsed)
+    }
+
+    /// Count a proxied `VmSyscall` `name`, which took `elapsed` to handle
+    pub fn vm_syscall(&self, name: &str, elapsed: Duration) {
+        add(&self.vm_syscalls, name, elapsed)
+    }
+
+    /// Add the totals per Linux syscall number reported by the kernel
+    pub fn guest_syscalls(&self, entries: &[TraceSummary]) {
+        let mut guest_syscalls = self.guest_syscalls.lock().unwrap();
+        for entry in entries {
+            let sum = guest_syscalls.entry(entry.nr).or_insert(TraceSummary {
+                nr: entry.nr,
+                ..TraceSummary::default()
+            });
+            sum.calls += entry.calls;
+            sum.errors += entry.errors;
+            sum.total_ns += entry.total_ns;
+        }
+    }
+
+    /// All statistics in `format`
+    pub fn format(&self, format: StatsFormat) -> String {
+        match format {
+            StatsFormat::Table => self.table(),
+            StatsFormat::Json => self.json(),
+        }
+    }
+
+    /// The statistics as human readable tables, most time consuming first
+    pub fn table(&self) -> String {
+        let mut out = String::new();
+        for (title, counters) in &[
+            ("VM exits", &self.exits),
+            ("VM syscalls", &self.vm_syscalls),
+        ] {
+            let counters = sorted(&counters.lock().unwrap());
+            let _ = writeln!(
+                out,
+                "{:<24} {:>10} {:>14} {:>12}",
+                title, "count", "total [us]", "avg [us]"
+            );
+            for (name, c) in counters {
+                let _ = writeln!(
+                    out,
+                    "  {:<22} {:>10} {:>14} {:>12}",
+                    name,
+                    c.count,
+                    c.total.as_micros(),
+                    c.average().as_micros()
+                );
+            }
+            out.push('\n');
+        }
+
+        let _ = writeln!(
+            out,
+            "{:<24} {:>10} {:>10} {:>14} {:>12}",
+            "Guest syscalls", "calls", "errors", "total [us]", "avg [us]"
+        );
+        for e in self.sorted_guest_syscalls() {
+            let name = match syscall_name(e.nr) {
+                Some(name) => format!("{} ({})", name, e.nr),
+                None => format!("{}", e.nr),
+            };
+            let _ = writeln!(
+                out,
+                "  {:<22} {:>10} {:>10} {:>14} {:>12}",
+                name,
+                e.calls,
+                e.errors,
+                e.total_ns / 1_000,
+                e.total_ns / 1_000 / e.calls.max(1)
+            );
+        }
+        out
+    }
+
+    /// The statistics as one JSON object
+    pub fn json(&self) -> String {
+        fn counters(map: &Mutex<BTreeMap<String, Counter>>) -> String {
+            sorted(&map.lock().unwrap())
+                .iter()
+                .map(|(name, c)| {
+                    format!(
+                        "{{\"name\":\"{}\",\"count\":{},\"total_ns\":{}}}",
+                        name,
+                        c.count,
+                        c.total.as_nanos()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+
+        let guest_syscalls = self
+            .sorted_guest_syscalls()
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"nr\":{},\"name\":{},\"calls\":{},\"errors\":{},\"total_ns\":{}}}",
+                    e.nr,
+                    syscall_name(e.nr)
+                        .map(|name| format!("\"{}\"", name))
+                        .unwrap_or_else(|| "null".into()),
+                    e.calls,
+                    e.errors,
+                    e.total_ns
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            "{{\"exits\":[{}],\"vm_syscalls\":[{}],\"guest_syscalls\":[{}]}}",
+            counters(&self.exits),
+            counters(&self.vm_syscalls),
+            guest_syscalls
+        )
+    }
+
+    fn sorted_guest_syscalls(&self) -> Vec<TraceSummary> {
+        let mut entries = self
+            .guest_syscalls
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| b.total_ns.cmp(&a.total_ns).then(a.nr.cmp(&b.nr)));
+        entries
+    }
+}
+
+fn add(map: &Mutex<BTreeMap<String, Counter>>, name: &str, elapsed: Duration) {
+    let mut map = map.lock().unwrap();
+    match map.get_mut(name) {
+        Some(counter) => counter.add(elapsed),
+        None => {
+            let mut counter = Counter::default();
+            counter.add(elapsed);
+            map.insert(name.to_string(), counter);
+        }
+    }
+}
+
+/// The counters with the most time spent first
+fn sorted(map: &BTreeMap<String, Counter>) -> Vec<(String, Counter)> {
+    let mut counters = map
+        .iter()
+        .map(|(name, c)| (name.clone(), *c))
+        .collect::<Vec<_>>();
+    counters.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(&b.0)));
+    counters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> Stats {
+        let stats = Stats::new();
+        stats.exit("IoOut", Duration::from_micros(3));
+        stats.exit("IoOut", Duration::from_micros(5));
+        stats.exit("Hlt", Duration::from_micros(1));
+        stats.vm_syscall("Write", Duration::from_micros(10));
+        stats.guest_syscalls(&[TraceSummary {
+            nr: 1,
+            calls: 2,
+            errors: 0,
+            total_ns: 4_000,
+        }]);
+        stats.guest_syscalls(&[
+            TraceSummary {
+                nr: 1,
+                calls: 1,
+                errors: 1,
+                total_ns: 2_000,
+            },
+            TraceSummary {
+                nr: 100_000,
+                calls: 1,
+                errors: 1,
+                total_ns: 1_000,
+            },
+        ]);
+        stats
+    }
+
+    #[test]
+    fn test_counters() {
+        let stats = stats();
+        let exits = sorted(&stats.exits.lock().unwrap());
+        assert_eq!(exits[0].0, "IoOut");
+        assert_eq!(exits[0].1.count, 2);
+        assert_eq!(exits[0].1.average(), Duration::from_micros(4));
+        assert_eq!(exits[1].0, "Hlt");
+
+        let guest = stats.sorted_guest_syscalls();
+        assert_eq!(guest.len(), 2);
+        assert_eq!(
+            guest[0],
+            TraceSummary {
+                nr: 1,
+                calls: 3,
+                errors: 1,
+                total_ns: 6_000
+            }
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let table = stats().table();
+        let lines = table.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("VM exits"));
+        assert!(lines[1].starts_with("  IoOut"));
+        assert!(lines[1].ends_with("  2              8            4"));
+        assert!(lines[5].starts_with("  Write"));
+        assert!(lines[8].starts_with("  write (1)"));
+        assert!(lines[8].ends_with("  3          1              6            2"));
+        assert!(lines[9].starts_with("  100000"));
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            stats().json(),
+            "{\"exits\":[{\"name\":\"IoOut\",\"count\":2,\"total_ns\":8000},\
+             {\"name\":\"Hlt\",\"count\":1,\"total_ns\":1000}],\
+             \"vm_syscalls\":[{\"name\":\"Write\",\"count\":1,\"total_ns\":10000}],\
+             \"guest_syscalls\":[{\"nr\":1,\"name\":\"write\",\"calls\":3,\"errors\":1,\"total_ns\":6000},\
+             {\"nr\":100000,\"name\":null,\"calls\":1,\"errors\":1,\"total_ns\":1000}]}"
+        );
+    }
+}
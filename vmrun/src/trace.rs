@@ -0,0 +1,686 @@
+//! Output of the syscall traces of the guest
+//!
+//! The kernel hands the [`TraceRecord`]s and [`TraceSummary`] entries over in batches.
+//! They are written strace-like or as JSON lines to a file of their own, so that they
+//! don't mix with the output of the app.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use vmsyscall::trace::{TraceRecord, TraceSummary, TRACE_NO_RETURN};
+
+/// Format of the trace file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    /// Like the output of `strace -f` and `strace -c`
+    Strace,
+    /// One JSON object per line
+    Json,
+}
+
+/// Writes the trace records of the guest
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    summary: Vec<TraceSummary>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>, format: TraceFormat) -> Self {
+        Tracer {
+            out,
+            format,
+            summary: Vec::new(),
+        }
+    }
+
+    /// Trace to the file `path`, which is truncated
+    pub fn create(path: &str, format: TraceFormat) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(Box::new(BufWriter::new(file)), format))
+    }
+
+    /// Write a batch of records
+    pub fn records(&mut self, records: &[TraceRecord]) -> io::Result<()> {
+        for rec in records {
+            let line = match self.format {
+                TraceFormat::Strace => format_strace(rec),
+                TraceFormat::Json => format_json(rec),
+            };
+            writeln!(self.out, "{}", line)?;
+        }
+        // the VM might be stopped with `exit()` at any time
+        self.out.flush()
+    }
+
+    /// Collect a batch of the totals and write them all with the `last` batch
+    pub fn summary(&mut self, entries: &[TraceSummary], last: bool) -> io::Result<()> {
+        self.summary.extend_from_slice(entries);
+        if !last {
+            return Ok(());
+        }
+
+        let mut summary = std::mem::take(&mut self.summary);
+        summary.sort_by(|a, b| b.total_ns.cmp(&a.total_ns).then(a.nr.cmp(&b.nr)));
+        let text = match self.format {
+            TraceFormat::Strace => format_strace_summary(&summary),
+            TraceFormat::Json => summary
+                .iter()
+                .map(|e| format_json_summary(e) + "\n")
+                .collect(),
+        };
+        self.out.write_all(text.as_bytes())?;
+        self.out.flush()
+    }
+}
+
+/// The name of the x86_64 Linux syscall `nr`
+pub fn syscall_name(nr: u32) -> Option<&'static str> {
+    let nr = nr as usize;
+    match nr {
+        0..=334 => SYSCALL_NAMES.get(nr).cloned(),
+        424..=450 => SYSCALL_NAMES_424.get(nr - 424).cloned(),
+        _ => None,
+    }
+}
+
+fn name(nr: u32) -> String {
+    match syscall_name(nr) {
+        Some(name) => name.to_string(),
+        None => format!("syscall_{}", nr),
+    }
+}
+
+fn is_error(ret: i64) -> bool {
+    (-4095..0).contains(&ret)
+}
+
+fn seconds(ns: u64) -> String {
+    format!("{}.{:06}", ns / 1_000_000_000, ns % 1_000_000_000 / 1_000)
+}
+
+/// `[tid 1] 0.001234 read(0x3, 0x7f0000001000, 0x100, 0x0, 0x0, 0x0) = 42 <0.000010>`
+pub fn format_strace(rec: &TraceRecord) -> String {
+    let mut line = format!(
+        "[tid {}] {} {}(",
+        rec.tid,
+        seconds(rec.start_ns),
+        name(rec.nr)
+    );
+    for (i, arg) in rec.args.iter().enumerate() {
+        if i != 0 {
+            line.push_str(", ");
+        }
+        let _ = write!(line, "{:#x}", arg);
+    }
+    line.push(')');
+
+    if rec.flags & TRACE_NO_RETURN != 0 {
+        line.push_str(" = ?");
+        return line;
+    }
+
+    if is_error(rec.ret) {
+        let _ = write!(
+            line,
+            " = -1 errno {} ({})",
+            -rec.ret,
+            io::Error::from_raw_os_error(-rec.ret as i32)
+        );
+    } else if rec.ret > 0xFFFF {
+        let _ = write!(line, " = {:#x}", rec.ret);
+    } else {
+        let _ = write!(line, " = {}", rec.ret);
+    }
+    let _ = write!(line, " <{}>", seconds(rec.duration_ns));
+    line
+}
+
+fn json_name(nr: u32) -> String {
+    match syscall_name(nr) {
+        Some(name) => format!("\"{}\"", name),
+        None => "null".to_string(),
+    }
+}
+
+/// The record as a JSON object
+pub fn format_json(rec: &TraceRecord) -> String {
+    let args = rec
+        .args
+        .iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let ret = if rec.flags & TRACE_NO_RETURN != 0 {
+        "null".to_string()
+    } else {
+        rec.ret.to_string()
+    };
+    format!(
+        "{{\"type\":\"syscall\",\"tid\":{},\"cpu\":{},\"nr\":{},\"name\":{},\"args\":[{}],\"ret\":{},\"start_ns\":{},\"duration_ns\":{}}}",
+        rec.tid,
+        rec.cpu,
+        rec.nr,
+        json_name(rec.nr),
+        args,
+        ret,
+        rec.start_ns,
+        rec.duration_ns
+    )
+}
+
+/// The totals as a JSON object
+pub fn format_json_summary(entry: &TraceSummary) -> String {
+    format!(
+        "{{\"type\":\"summary\",\"nr\":{},\"name\":{},\"calls\":{},\"errors\":{},\"total_ns\":{}}}",
+        entry.nr,
+        json_name(entry.nr),
+        entry.calls,
+        entry.errors,
+        entry.total_ns
+    )
+}
+
+/// The totals as a table like the one of `strace -c`
+pub fn format_strace_summary(summary: &[TraceSummary]) -> String {
+    let total_ns: u64 = summary.iter().map(|e| e.total_ns).sum();
+    let calls: u64 = summary.iter().map(|e| e.calls).sum();
+    let errors: u64 = summary.iter().map(|e| e.errors).sum();
+    let separator = "------ ----------- ----------- --------- --------- ----------------\n";
+
+    let mut table = String::from("% time     seconds  usecs/call     calls    errors syscall\n");
+    table.push_str(separator);
+    for e in summary {
+        let percent = if total_ns == 0 {
+            0.0
+        } else {
+            e.total_ns as f64 * 100.0 / total_ns as f64
+        };
+        let _ = writeln!(
+            table,
+            "{:6.2} {:>11} {:>11} {:>9} {:>9} {}",
+            percent,
+            seconds(e.total_ns),
+            e.total_ns / 1_000 / e.calls.max(1),
+            e.calls,
+            if e.errors == 0 {
+                String::new()
+            } else {
+                e.errors.to_string()
+            },
+            name(e.nr)
+        );
+    }
+    table.push_str(separator);
+    let _ = writeln!(
+        table,
+        "{:6.2} {:>11} {:>11} {:>9} {:>9} total",
+        100.0,
+        seconds(total_ns),
+        "",
+        calls,
+        errors
+    );
+    table
+}
+
+const SYSCALL_NAMES: &[&str] = &[
+    "read",
+    "write",
+    "open",
+    "close",
+    "stat",
+    "fstat",
+    "lstat",
+    "poll",
+    "lseek",
+    "mmap",
+    "mprotect",
+    "munmap",
+    "brk",
+    "rt_sigaction",
+    "rt_sigprocmask",
+    "rt_sigreturn",
+    "ioctl",
+    "pread64",
+    "pwrite64",
+    "readv",
+    "writev",
+    "access",
+    "pipe",
+    "select",
+    "sched_yield",
+    "mremap",
+    "msync",
+    "mincore",
+    "madvise",
+    "shmget",
+    "shmat",
+    "shmctl",
+    "dup",
+    "dup2",
+    "pause",
+    "nanosleep",
+    "getitimer",
+    "alarm",
+    "setitimer",
+    "getpid",
+    "sendfile",
+    "socket",
+    "connect",
+    "accept",
+    "sendto",
+    "recvfrom",
+    "sendmsg",
+    "recvmsg",
+    "shutdown",
+    "bind",
+    "listen",
+    "getsockname",
+    "getpeername",
+    "socketpair",
+    "setsockopt",
+    "getsockopt",
+    "clone",
+    "fork",
+    "vfork",
+    "execve",
+    "exit",
+    "wait4",
+    "kill",
+    "uname",
+    "semget",
+    "semop",
+    "semctl",
+    "shmdt",
+    "msgget",
+    "msgsnd",
+    "msgrcv",
+    "msgctl",
+    "fcntl",
+    "flock",
+    "fsync",
+    "fdatasync",
+    "truncate",
+    "ftruncate",
+    "getdents",
+    "getcwd",
+    "chdir",
+    "fchdir",
+    "rename",
+    "mkdir",
+    "rmdir",
+    "creat",
+    "link",
+    "unlink",
+    "symlink",
+    "readlink",
+    "chmod",
+    "fchmod",
+    "chown",
+    "fchown",
+    "lchown",
+    "umask",
+    "gettimeofday",
+    "getrlimit",
+    "getrusage",
+    "sysinfo",
+    "times",
+    "ptrace",
+    "getuid",
+    "syslog",
+    "getgid",
+    "setuid",
+    "setgid",
+    "geteuid",
+    "getegid",
+    "setpgid",
+    "getppid",
+    "getpgrp",
+    "setsid",
+    "setreuid",
+    "setregid",
+    "getgroups",
+    "setgroups",
+    "setresuid",
+    "getresuid",
+    "setresgid",
+    "getresgid",
+    "getpgid",
+    "setfsuid",
+    "setfsgid",
+    "getsid",
+    "capget",
+    "capset",
+    "rt_sigpending",
+    "rt_sigtimedwait",
+    "rt_sigqueueinfo",
+    "rt_sigsuspend",
+    "sigaltstack",
+    "utime",
+    "mknod",
+    "uselib",
+    "personality",
+    "ustat",
+    "statfs",
+    "fstatfs",
+    "sysfs",
+    "getpriority",
+    "setpriority",
+    "sched_setparam",
+    "sched_getparam",
+    "sched_setscheduler",
+    "sched_getscheduler",
+    "sched_get_priority_max",
+    "sched_get_priority_min",
+    "sched_rr_get_interval",
+    "mlock",
+    "munlock",
+    "mlockall",
+    "munlockall",
+    "vhangup",
+    "modify_ldt",
+    "pivot_root",
+    "_sysctl",
+    "prctl",
+    "arch_prctl",
+    "adjtimex",
+    "setrlimit",
+    "chroot",
+    "sync",
+    "acct",
+    "settimeofday",
+    "mount",
+    "umount2",
+    "swapon",
+    "swapoff",
+    "reboot",
+    "sethostname",
+    "setdomainname",
+    "iopl",
+    "ioperm",
+    "create_module",
+    "init_module",
+    "delete_module",
+    "get_kernel_syms",
+    "query_module",
+    "quotactl",
+    "nfsservctl",
+    "getpmsg",
+    "putpmsg",
+    "afs_syscall",
+    "tuxcall",
+    "security",
+    "gettid",
+    "readahead",
+    "setxattr",
+    "lsetxattr",
+    "fsetxattr",
+    "getxattr",
+    "lgetxattr",
+    "fgetxattr",
+    "listxattr",
+    "llistxattr",
+    "flistxattr",
+    "removexattr",
+    "lremovexattr",
+    "fremovexattr",
+    "tkill",
+    "time",
+    "futex",
+    "sched_setaffinity",
+    "sched_getaffinity",
+    "set_thread_area",
+    "io_setup",
+    "io_destroy",
+    "io_getevents",
+    "io_submit",
+    "io_cancel",
+    "get_thread_area",
+    "lookup_dcookie",
+    "epoll_create",
+    "epoll_ctl_old",
+    "epoll_wait_old",
+    "remap_file_pages",
+    "getdents64",
+    "set_tid_address",
+    "restart_syscall",
+    "semtimedop",
+    "fadvise64",
+    "timer_create",
+    "timer_settime",
+    "timer_gettime",
+    "timer_getoverrun",
+    "timer_delete",
+    "clock_settime",
+    "clock_gettime",
+    "clock_getres",
+    "clock_nanosleep",
+    "exit_group",
+    "epoll_wait",
+    "epoll_ctl",
+    "tgkill",
+    "utimes",
+    "vserver",
+    "mbind",
+    "set_mempolicy",
+    "get_mempolicy",
+    "mq_open",
+    "mq_unlink",
+    "mq_timedsend",
+    "mq_timedreceive",
+    "mq_notify",
+    "mq_getsetattr",
+    "kexec_load",
+    "waitid",
+    "add_key",
+    "request_key",
+    "keyctl",
+    "ioprio_set",
+    "ioprio_get",
+    "inotify_init",
+    "inotify_add_watch",
+    "inotify_rm_watch",
+    "migrate_pages",
+    "openat",
+    "mkdirat",
+    "mknodat",
+    "fchownat",
+    "futimesat",
+    "newfstatat",
+    "unlinkat",
+    "renameat",
+    "linkat",
+    "symlinkat",
+    "readlinkat",
+    "fchmodat",
+    "faccessat",
+    "pselect6",
+    "ppoll",
+    "unshare",
+    "set_robust_list",
+    "get_robust_list",
+    "splice",
+    "tee",
+    "sync_file_range",
+    "vmsplice",
+    "move_pages",
+    "utimensat",
+    "epoll_pwait",
+    "signalfd",
+    "timerfd_create",
+    "eventfd",
+    "fallocate",
+    "timerfd_settime",
+    "timerfd_gettime",
+    "accept4",
+    "signalfd4",
+    "eventfd2",
+    "epoll_create1",
+    "dup3",
+    "pipe2",
+    "inotify_init1",
+    "preadv",
+    "pwritev",
+    "rt_tgsigqueueinfo",
+    "perf_event_open",
+    "recvmmsg",
+    "fanotify_init",
+    "fanotify_mark",
+    "prlimit64",
+    "name_to_handle_at",
+    "open_by_handle_at",
+    "clock_adjtime",
+    "syncfs",
+    "sendmmsg",
+    "setns",
+    "getcpu",
+    "process_vm_readv",
+    "process_vm_writev",
+    "kcmp",
+    "finit_module",
+    "sched_setattr",
+    "sched_getattr",
+    "renameat2",
+    "seccomp",
+    "getrandom",
+    "memfd_create",
+    "kexec_file_load",
+    "bpf",
+    "execveat",
+    "userfaultfd",
+    "membarrier",
+    "mlock2",
+    "copy_file_range",
+    "preadv2",
+    "pwritev2",
+    "pkey_mprotect",
+    "pkey_alloc",
+    "pkey_free",
+    "statx",
+    "io_pgetevents",
+    "rseq",
+];
+
+const SYSCALL_NAMES_424: &[&str] = &[
+    "pidfd_send_signal",
+    "io_uring_setup",
+    "io_uring_enter",
+    "io_uring_register",
+    "open_tree",
+    "move_mount",
+    "fsopen",
+    "fsconfig",
+    "fsmount",
+    "fspick",
+    "pidfd_open",
+    "clone3",
+    "close_range",
+    "openat2",
+    "pidfd_getfd",
+    "faccessat2",
+    "process_madvise",
+    "epoll_pwait2",
+    "mount_setattr",
+    "quotactl_fd",
+    "landlock_create_ruleset",
+    "landlock_add_rule",
+    "landlock_restrict_self",
+    "memfd_secret",
+    "process_mrelease",
+    "futex_waitv",
+    "set_mempolicy_home_node",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> TraceRecord {
+        TraceRecord {
+            nr: 257,
+            tid: 1,
+            cpu: 0,
+            flags: 0,
+            args: [0xFFFF_FF9C, 0x1000, 0, 0, 0, 0],
+            ret: -2,
+            start_ns: 1_500_000_000,
+            duration_ns: 12_000,
+        }
+    }
+
+    #[test]
+    fn test_syscall_name() {
+        assert_eq!(syscall_name(0), Some("read"));
+        assert_eq!(syscall_name(231), Some("exit_group"));
+        assert_eq!(syscall_name(334), Some("rseq"));
+        assert_eq!(syscall_name(435), Some("clone3"));
+        assert_eq!(syscall_name(400), None);
+        assert_eq!(name(100_000), "syscall_100000");
+    }
+
+    #[test]
+    fn test_format_strace() {
+        assert_eq!(
+            format_strace(&record()),
+            "[tid 1] 1.500000 openat(0xffffff9c, 0x1000, 0x0, 0x0, 0x0, 0x0) \
+             = -1 errno 2 (No such file or directory (os error 2)) <0.000012>"
+        );
+
+        let mut rec = record();
+        rec.nr = 9;
+        rec.ret = 0x7F00_0000_0000;
+        assert!(format_strace(&rec).ends_with(" = 0x7f0000000000 <0.000012>"));
+
+        rec.nr = 231;
+        rec.flags = TRACE_NO_RETURN;
+        assert!(
+            format_strace(&rec).ends_with("exit_group(0xffffff9c, 0x1000, 0x0, 0x0, 0x0, 0x0) = ?")
+        );
+    }
+
+    #[test]
+    fn test_format_json() {
+        assert_eq!(
+            format_json(&record()),
+            "{\"type\":\"syscall\",\"tid\":1,\"cpu\":0,\"nr\":257,\"name\":\"openat\",\
+             \"args\":[4294967196,4096,0,0,0,0],\"ret\":-2,\"start_ns\":1500000000,\"duration_ns\":12000}"
+        );
+        let entry = TraceSummary {
+            nr: 100_000,
+            calls: 2,
+            errors: 1,
+            total_ns: 30,
+        };
+        assert_eq!(
+            format_json_summary(&entry),
+            "{\"type\":\"summary\",\"nr\":100000,\"name\":null,\"calls\":2,\"errors\":1,\"total_ns\":30}"
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let entries = [
+            TraceSummary {
+                nr: 0,
+                calls: 4,
+                errors: 0,
+                total_ns: 1_000,
+            },
+            TraceSummary {
+                nr: 1,
+                calls: 1,
+                errors: 1,
+                total_ns: 3_000,
+            },
+        ];
+        let table = format_strace_summary(&entries);
+        let lines = table.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[2].starts_with(" 25.00    0.000001           0         4"));
+        assert!(lines[2].ends_with(" read"));
+        assert!(lines[3].ends_with("         1 write"));
+        assert!(lines[5].starts_with("100.00    0.000004"));
+        assert!(lines[5].ends_with("        5         1 total"));
+    }
+}
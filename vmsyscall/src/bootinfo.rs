@@ -7,11 +7,29 @@
 //! https://github.com/rust-osdev/bootloader/blob/90f5b8910d146d6d489b70a6341d778253663cfa/src/bootinfo/mod.rs
 
 use crate::memory_map::MemoryMap;
+use crate::Error;
 use core::fmt;
 
 /// Hard coded trigger port
 pub const SYSCALL_TRIGGER_PORT: u16 = 0xFF;
 
+/// Maximum number of vCPUs
+pub const MAX_CPUS: usize = 64;
+
+/// Maximum size of all argument and environment strings, including the NUL terminators
+pub const APP_ARGS_LEN: usize = 2048;
+/// Magic number of the argument block ("ARGS")
+pub const APP_ARGS_MAGIC: u32 = 0x5347_5241;
+/// Version of the argument block layout
+pub const APP_ARGS_VERSION: u32 = 1;
+
+/// Maximum number of syscalls with their own entry in the [`SyscallPolicy`]
+pub const SYSCALL_POLICY_LEN: usize = 16;
+
+/// Maximum number of `PT_LOAD` segments in the [`AppSegments`],
+/// limited by the `BootInfo` fitting in one page
+pub const APP_SEGMENTS_LEN: usize = 5;
+
 /// This structure represents the information that the bootloader passes to the kernel.
 ///
 /// The information is passed as an argument to the entry point:
@@ -41,14 +59,408 @@ pub struct BootInfo {
     pub load_addr: *const u8,
     /// Elf number of program headers of the ring3 executable
     pub elf_phnum: usize,
+    /// The `PT_LOAD` segments of a fixed-address ring3 executable, which the kernel maps
+    /// with the permissions of their program headers
+    pub app_segments: AppSegments,
+    /// Guest physical address of the ELF file of a position independent ring3 executable,
+    /// which the kernel relocates and maps itself, or `0`
+    pub app_image: u64,
+    /// Size of the ELF file at `app_image`
+    pub app_image_len: u64,
+    /// Guest physical address of the ELF file of the dynamic linker named in the `PT_INTERP`
+    /// of a dynamically linked ring3 executable, or `0`
+    pub interp_image: u64,
+    /// Size of the ELF file at `interp_image`
+    pub interp_image_len: u64,
     /// Syscall trigger port
     pub syscall_trigger_port: u16,
+    /// Command line arguments and environment of the ring3 executable
+    pub app_args: AppArgs,
+    /// Number of vCPUs
+    pub nr_cpus: u32,
+    /// Guest physical address of the per CPU syscall pages
+    ///
+    /// CPU `n` uses the page at `syscall_pages + n * 4096`. If `0`, there is only
+    /// one CPU, which uses the page holding the `BootInfo`.
+    pub syscall_pages: u64,
+    /// Frequency of the TSC of the vCPUs in Hz or `0`, if unknown
+    pub tsc_hz: u64,
+    /// Host `CLOCK_REALTIME` in nanoseconds since the epoch, when the TSC was `0`
+    pub realtime_ns: u64,
+    /// What the kernel does on syscalls it does not implement
+    pub syscall_policy: SyscallPolicy,
+    /// The [`TraceLevel`](crate::trace::TraceLevel) of the syscalls of the app
+    pub trace_level: u32,
 }
 
 impl fmt::Debug for BootInfo {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("BootInfo")
             .field("memory_map", &self.memory_map)
+            .field("app_segments", &self.app_segments)
+            .field("nr_cpus", &self.nr_cpus)
+            .field("syscall_pages", &format_args!("{:#X}", self.syscall_pages))
+            .field("tsc_hz", &self.tsc_hz)
+            .field("realtime_ns", &self.realtime_ns)
+            .field("syscall_policy", &self.syscall_policy)
+            .field(
+                "trace_level",
+                &crate::trace::TraceLevel::from_u32(self.trace_level),
+            )
+            .finish()
+    }
+}
+
+/// Values for the auxiliary vector of the ring3 executable
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct AppAuxv {
+    /// Real user id
+    pub uid: u32,
+    /// Effective user id
+    pub euid: u32,
+    /// Real group id
+    pub gid: u32,
+    /// Effective group id
+    pub egid: u32,
+    /// Frequency of `times()`
+    pub clock_tick: u32,
+}
+
+impl AppAuxv {
+    /// The values used, if the hypervisor does not provide any
+    pub const fn new() -> Self {
+        AppAuxv {
+            uid: 1000,
+            euid: 1000,
+            gid: 1000,
+            egid: 1000,
+            clock_tick: 100,
+        }
+    }
+}
+
+impl Default for AppAuxv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The command line arguments, environment strings and auxiliary vector values
+/// of the ring3 executable.
+///
+/// All strings are stored NUL terminated in one buffer, the arguments first,
+/// followed by the environment.
+#[derive(Clone)]
+#[repr(C)]
+pub struct AppArgs {
+    magic: u32,
+    version: u32,
+    argc: u32,
+    envc: u32,
+    len: u32,
+    /// Values for the auxiliary vector
+    pub auxv: AppAuxv,
+    buf: [u8; APP_ARGS_LEN],
+}
+
+impl AppArgs {
+    /// Create an empty argument block
+    pub const fn new() -> Self {
+        AppArgs {
+            magic: APP_ARGS_MAGIC,
+            version: APP_ARGS_VERSION,
+            argc: 0,
+            envc: 0,
+            len: 0,
+            auxv: AppAuxv::new(),
+            buf: [0u8; APP_ARGS_LEN],
+        }
+    }
+
+    fn push(&mut self, s: &str) -> Result<(), Error> {
+        let start = self.len as usize;
+        let end = start + s.len() + 1;
+        if s.as_bytes().contains(&0) || end > APP_ARGS_LEN {
+            return Err(Error::SerializeError);
+        }
+        self.buf[start..end - 1].copy_from_slice(s.as_bytes());
+        self.buf[end - 1] = 0;
+        self.len = end as u32;
+        Ok(())
+    }
+
+    /// Append a command line argument.
+    ///
+    /// All arguments have to be pushed before the first environment string.
+    pub fn push_arg(&mut self, arg: &str) -> Result<(), Error> {
+        if self.envc != 0 {
+            return Err(Error::SerializeError);
+        }
+        self.push(arg)?;
+        self.argc += 1;
+        Ok(())
+    }
+
+    /// Append an environment string in the form `KEY=VALUE`
+    pub fn push_env(&mut self, env: &str) -> Result<(), Error> {
+        self.push(env)?;
+        self.envc += 1;
+        Ok(())
+    }
+
+    /// Check an argument block received from the other side.
+    ///
+    /// The block has to have the right magic and version, and the buffer has to contain
+    /// exactly `argc + envc` NUL terminated UTF-8 strings.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.magic != APP_ARGS_MAGIC || self.version != APP_ARGS_VERSION {
+            return Err(Error::DeSerializeError);
+        }
+
+        let len = self.len as usize;
+        if len > APP_ARGS_LEN || (len > 0 && self.buf[len - 1] != 0) {
+            return Err(Error::DeSerializeError);
+        }
+
+        let count = self.argc as usize + self.envc as usize;
+        if self.buf[..len].iter().filter(|&&b| b == 0).count() != count {
+            return Err(Error::DeSerializeError);
+        }
+
+        if core::str::from_utf8(&self.buf[..len]).is_err() {
+            return Err(Error::DeSerializeError);
+        }
+
+        Ok(())
+    }
+
+    fn strings(&self) -> impl Iterator<Item = &str> {
+        let len = core::cmp::min(self.len as usize, APP_ARGS_LEN);
+        self.buf[..len]
+            .split(|&b| b == 0)
+            .map(|s| core::str::from_utf8(s).unwrap_or(""))
+    }
+
+    /// Iterator over the command line arguments
+    pub fn argv(&self) -> impl Iterator<Item = &str> {
+        self.strings().take(self.argc as usize)
+    }
+
+    /// Iterator over the environment strings
+    pub fn envp(&self) -> impl Iterator<Item = &str> {
+        self.strings()
+            .skip(self.argc as usize)
+            .take(self.envc as usize)
+    }
+}
+
+impl Default for AppArgs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A `PT_LOAD` segment of the ring3 executable
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct AppSegment {
+    /// Virtual address of the segment
+    pub vaddr: u64,
+    /// Size of the segment in memory
+    pub memsz: u32,
+    /// The `p_flags` of the program header, a combination of `PF_R`, `PF_W` and `PF_X`
+    pub flags: u32,
+}
+
+impl AppSegment {
+    const fn empty() -> Self {
+        AppSegment {
+            vaddr: 0,
+            memsz: 0,
+            flags: 0,
+        }
+    }
+}
+
+/// The `PT_LOAD` segments of a fixed-address ring3 executable, which vmrun loaded.
+///
+/// Holds up to [`APP_SEGMENTS_LEN`] segments.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct AppSegments {
+    len: u32,
+    segments: [AppSegment; APP_SEGMENTS_LEN],
+}
+
+impl AppSegments {
+    /// No segments
+    pub const fn new() -> Self {
+        AppSegments {
+            len: 0,
+            segments: [AppSegment::empty(); APP_SEGMENTS_LEN],
+        }
+    }
+
+    /// Add a segment
+    pub fn push(&mut self, segment: AppSegment) -> Result<(), Error> {
+        let len = self.as_slice().len();
+        if len == APP_SEGMENTS_LEN {
+            return Err(Error::SerializeError);
+        }
+        self.segments[len] = segment;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// All segments
+    pub fn as_slice(&self) -> &[AppSegment] {
+        &self.segments[..core::cmp::min(self.len as usize, APP_SEGMENTS_LEN)]
+    }
+}
+
+impl Default for AppSegments {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for AppSegments {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+/// The action of the kernel on a syscall it does not implement
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyscallAction {
+    /// Return `-ENOSYS`
+    Enosys,
+    /// Return a fixed value, e.g. `0` to pretend success
+    Return(i64),
+    /// Stop the VM with a diagnostic
+    Abort,
+}
+
+const ACTION_ENOSYS: u32 = 0;
+const ACTION_RETURN: u32 = 1;
+const ACTION_ABORT: u32 = 2;
+
+impl SyscallAction {
+    fn encode(self) -> (u32, i64) {
+        match self {
+            SyscallAction::Enosys => (ACTION_ENOSYS, 0),
+            SyscallAction::Return(value) => (ACTION_RETURN, value),
+            SyscallAction::Abort => (ACTION_ABORT, 0),
+        }
+    }
+
+    /// Unknown actions of a newer hypervisor abort to be on the safe side
+    fn decode(action: u32, value: i64) -> Self {
+        match action {
+            ACTION_ENOSYS => SyscallAction::Enosys,
+            ACTION_RETURN => SyscallAction::Return(value),
+            _ => SyscallAction::Abort,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[repr(C)]
+struct SyscallPolicyEntry {
+    nr: u32,
+    action: u32,
+    value: i64,
+}
+
+impl SyscallPolicyEntry {
+    const fn empty() -> Self {
+        SyscallPolicyEntry {
+            nr: 0,
+            action: ACTION_ENOSYS,
+            value: 0,
+        }
+    }
+}
+
+/// The actions for syscalls the kernel does not implement.
+///
+/// Up to [`SYSCALL_POLICY_LEN`] syscall numbers have their own action,
+/// all others get the default action.
+#[derive(Clone, Copy, PartialEq)]
+#[repr(C)]
+pub struct SyscallPolicy {
+    default_action: u32,
+    len: u32,
+    default_value: i64,
+    entries: [SyscallPolicyEntry; SYSCALL_POLICY_LEN],
+}
+
+impl SyscallPolicy {
+    /// A policy returning `-ENOSYS` for all syscalls
+    pub const fn new() -> Self {
+        SyscallPolicy {
+            default_action: ACTION_ENOSYS,
+            len: 0,
+            default_value: 0,
+            entries: [SyscallPolicyEntry::empty(); SYSCALL_POLICY_LEN],
+        }
+    }
+
+    /// Set the action for all syscalls without their own entry
+    pub fn set_default(&mut self, action: SyscallAction) {
+        let (action, value) = action.encode();
+        self.default_action = action;
+        self.default_value = value;
+    }
+
+    /// Set the action for the syscall `nr`
+    pub fn set(&mut self, nr: u32, action: SyscallAction) -> Result<(), Error> {
+        let (action, value) = action.encode();
+        let len = self.entries().len();
+        let entry = match self.entries[..len].iter().position(|e| e.nr == nr) {
+            Some(i) => &mut self.entries[i],
+            None if len < SYSCALL_POLICY_LEN => {
+                self.len += 1;
+                &mut self.entries[len]
+            }
+            None => return Err(Error::SerializeError),
+        };
+        *entry = SyscallPolicyEntry { nr, action, value };
+        Ok(())
+    }
+
+    fn entries(&self) -> &[SyscallPolicyEntry] {
+        &self.entries[..core::cmp::min(self.len as usize, SYSCALL_POLICY_LEN)]
+    }
+
+    /// The action for the syscall `nr`
+    pub fn action(&self, nr: u32) -> SyscallAction {
+        match self.entries().iter().find(|e| e.nr == nr) {
+            Some(e) => SyscallAction::decode(e.action, e.value),
+            None => SyscallAction::decode(self.default_action, self.default_value),
+        }
+    }
+}
+
+impl Default for SyscallPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SyscallPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entry(
+                &"default",
+                &SyscallAction::decode(self.default_action, self.default_value),
+            )
+            .entries(
+                self.entries()
+                    .iter()
+                    .map(|e| (e.nr, SyscallAction::decode(e.action, e.value))),
+            )
             .finish()
     }
 }
@@ -62,3 +474,102 @@ fn check_bootinfo_size() {
     use crate::memory_map::PAGE_SIZE;
     assert!(core::mem::size_of::<BootInfo>() <= (PAGE_SIZE as _));
 }
+
+#[test]
+fn check_app_args() {
+    let mut args = AppArgs::new();
+    args.push_arg("/init").unwrap();
+    args.push_arg("--foo").unwrap();
+    args.push_env("LANG=C").unwrap();
+    assert_eq!(args.push_arg("late"), Err(Error::SerializeError));
+    assert!(args.argv().eq(["/init", "--foo"].iter().cloned()));
+    assert!(args.envp().eq(["LANG=C"].iter().cloned()));
+
+    let long = [b'x'; APP_ARGS_LEN];
+    let long = core::str::from_utf8(&long).unwrap();
+    assert_eq!(args.push_env(long), Err(Error::SerializeError));
+    assert_eq!(args.push_env("NUL=\\0"), Ok(()));
+    assert_eq!(args.push_env("NUL=\0"), Err(Error::SerializeError));
+}
+
+#[test]
+fn check_app_args_validate() {
+    let mut args = AppArgs::new();
+    assert_eq!(args.validate(), Ok(()));
+    args.push_arg("/init").unwrap();
+    args.push_env("LANG=C").unwrap();
+    assert_eq!(args.validate(), Ok(()));
+
+    let mut bad = args.clone();
+    bad.magic = 0;
+    assert_eq!(bad.validate(), Err(Error::DeSerializeError));
+
+    let mut bad = args.clone();
+    bad.version += 1;
+    assert_eq!(bad.validate(), Err(Error::DeSerializeError));
+
+    let mut bad = args.clone();
+    bad.len = APP_ARGS_LEN as u32 + 1;
+    assert_eq!(bad.validate(), Err(Error::DeSerializeError));
+
+    let mut bad = args.clone();
+    bad.len -= 1;
+    assert_eq!(bad.validate(), Err(Error::DeSerializeError));
+
+    let mut bad = args.clone();
+    bad.envc += 1;
+    assert_eq!(bad.validate(), Err(Error::DeSerializeError));
+
+    let mut bad = args;
+    bad.buf[0] = 0xFF;
+    assert_eq!(bad.validate(), Err(Error::DeSerializeError));
+}
+
+#[test]
+fn check_app_segments() {
+    let mut segments = AppSegments::new();
+    assert!(segments.as_slice().is_empty());
+
+    let text = AppSegment {
+        vaddr: 0x40_0000,
+        memsz: 0x1234,
+        flags: 5,
+    };
+    for _ in 0..APP_SEGMENTS_LEN {
+        segments.push(text).unwrap();
+    }
+    assert_eq!(segments.push(text), Err(Error::SerializeError));
+    assert_eq!(segments.as_slice().len(), APP_SEGMENTS_LEN);
+    assert_eq!(segments.as_slice()[0], text);
+
+    // a corrupted length never exceeds the array
+    segments.len = !0;
+    assert_eq!(segments.as_slice().len(), APP_SEGMENTS_LEN);
+}
+
+#[test]
+fn check_syscall_policy() {
+    let mut policy = SyscallPolicy::new();
+    assert_eq!(policy.action(0), SyscallAction::Enosys);
+
+    policy.set_default(SyscallAction::Abort);
+    policy.set(1, SyscallAction::Return(-1)).unwrap();
+    policy.set(2, SyscallAction::Return(0)).unwrap();
+    policy.set(1, SyscallAction::Enosys).unwrap();
+    assert_eq!(policy.action(0), SyscallAction::Abort);
+    assert_eq!(policy.action(1), SyscallAction::Enosys);
+    assert_eq!(policy.action(2), SyscallAction::Return(0));
+
+    for nr in 3..(SYSCALL_POLICY_LEN as u32 + 1) {
+        policy.set(nr, SyscallAction::Enosys).unwrap();
+    }
+    assert_eq!(
+        policy.set(1000, SyscallAction::Enosys),
+        Err(Error::SerializeError)
+    );
+    assert_eq!(policy.action(1000), SyscallAction::Abort);
+
+    let mut bad = policy;
+    bad.entries[0].action = 42;
+    assert_eq!(bad.action(1), SyscallAction::Abort);
+}
@@ -0,0 +1,135 @@
+//! Crash reports of fatal exceptions, which the kernel hands to the hypervisor
+//!
+//! The kernel fills in the CPU state and the return addresses found by walking the
+//! frame pointers. vmrun symbolizes the addresses with the ELF files it loaded.
+
+/// Maximum number of return addresses in a [`CrashReport`]
+pub const CRASH_FRAMES_LEN: usize = 32;
+
+/// The general purpose registers, `rip` and `rflags`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[allow(missing_docs)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl Registers {
+    /// The registers with their names in the order of a register dump
+    pub fn named(&self) -> [(&'static str, u64); 18] {
+        [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("rsp", self.rsp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+            ("rip", self.rip),
+            ("rflags", self.rflags),
+        ]
+    }
+}
+
+/// The segment selectors and the bases of `fs` and `gs`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[allow(missing_docs)]
+pub struct Segments {
+    pub cs: u16,
+    pub ss: u16,
+    pub ds: u16,
+    pub es: u16,
+    pub fs: u16,
+    pub gs: u16,
+    pub fs_base: u64,
+    pub gs_base: u64,
+}
+
+/// The state of a CPU at a fatal exception
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CrashReport {
+    /// The exception vector
+    pub vector: u32,
+    /// The CPU the exception happened on
+    pub cpu: u32,
+    /// Whether the exception happened in user mode
+    pub user: bool,
+    /// The error code of the exception, `0` if it has none
+    pub error_code: u64,
+    /// The faulting address of a page fault
+    pub cr2: u64,
+    /// The offset of the app's load address to its linked addresses
+    pub app_bias: u64,
+    /// The registers at the exception
+    pub regs: Registers,
+    /// The segments at the exception
+    pub segments: Segments,
+    /// Number of valid `frames`
+    pub frames_len: usize,
+    /// The return addresses of the stack walk, innermost first
+    pub frames: [u64; CRASH_FRAMES_LEN],
+}
+
+impl CrashReport {
+    /// The valid return addresses of the stack walk
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.frames_len.min(CRASH_FRAMES_LEN)]
+    }
+
+    /// The name of the exception
+    pub fn exception_name(&self) -> &'static str {
+        exception_name(self.vector)
+    }
+}
+
+/// The name of the exception `vector`
+pub fn exception_name(vector: u32) -> &'static str {
+    match vector {
+        0 => "divide error",
+        1 => "debug",
+        2 => "non-maskable interrupt",
+        3 => "breakpoint",
+        4 => "overflow",
+        5 => "bound range exceeded",
+        6 => "invalid opcode",
+        7 => "device not available",
+        8 => "double fault",
+        10 => "invalid TSS",
+        11 => "segment not present",
+        12 => "stack segment fault",
+        13 => "general protection fault",
+        14 => "page fault",
+        16 => "x87 floating point",
+        17 => "alignment check",
+        18 => "machine check",
+        19 => "SIMD floating point",
+        20 => "virtualization",
+        30 => "security exception",
+        _ => "unknown exception",
+    }
+}
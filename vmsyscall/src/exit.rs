@@ -0,0 +1,127 @@
+//! How the kernel tells the hypervisor, why the VM stops
+//!
+//! The kernel writes the encoded [`ExitCode`] as 32bit value to [`EXIT_PORT`], which
+//! is the `isa-debug-exit` device of qemu. Because qemu only keeps 7 bits of that value
+//! in its own exit code, the kernel writes the same value byte by byte to the debug
+//! console on [`EXIT_RECORD_PORT`] first, where vmrun picks it up after qemu exited.
+
+/// The port of the `isa-debug-exit` device
+pub const EXIT_PORT: u16 = 0xF4;
+/// The port of the `isa-debugcon` device, which records the exit code for qemu
+pub const EXIT_RECORD_PORT: u16 = 0xE9;
+
+/// Value of the kernel tests before the exit status was transported
+const LEGACY_SUCCESS: u32 = 0x10;
+/// Value of any failure before the exit status was transported
+const LEGACY_FAILED: u32 = 0x11;
+
+const KIND_EXIT: u32 = 1;
+const KIND_PANIC: u32 = 2;
+const KIND_EXCEPTION: u32 = 3;
+const KIND_SIGNAL: u32 = 4;
+
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGILL: u8 = 4;
+const SIGBUS: u8 = 7;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// The reason the VM stops
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitCode {
+    /// The app exited with the status
+    Exit(u8),
+    /// The kernel panicked
+    Panic,
+    /// A fatal CPU exception with the vector
+    Exception(u8),
+    /// The app was killed by the signal
+    Signal(u8),
+}
+
+impl ExitCode {
+    /// The value for [`EXIT_PORT`]
+    pub fn encode(self) -> u32 {
+        match self {
+            ExitCode::Exit(status) => KIND_EXIT << 8 | status as u32,
+            ExitCode::Panic => KIND_PANIC << 8,
+            ExitCode::Exception(vector) => KIND_EXCEPTION << 8 | vector as u32,
+            ExitCode::Signal(signo) => KIND_SIGNAL << 8 | signo as u32,
+        }
+    }
+
+    /// The exit code written to [`EXIT_PORT`], `None` for unknown values
+    pub fn decode(value: u32) -> Option<Self> {
+        match (value >> 8, value as u8) {
+            (0, _) if value == LEGACY_SUCCESS => Some(ExitCode::Exit(0)),
+            (0, _) if value == LEGACY_FAILED => Some(ExitCode::Exit(1)),
+            (KIND_EXIT, status) => Some(ExitCode::Exit(status)),
+            (KIND_PANIC, 0) => Some(ExitCode::Panic),
+            (KIND_EXCEPTION, vector) => Some(ExitCode::Exception(vector)),
+            (KIND_SIGNAL, signo) => Some(ExitCode::Signal(signo)),
+            _ => None,
+        }
+    }
+
+    /// The exit code of the hypervisor process
+    ///
+    /// The status of the app is passed on. A kernel panic looks like `abort()` and a
+    /// fatal exception like the signal Linux sends for it, i.e. `128 + signal`, as the
+    /// shell reports it for a native process.
+    pub fn process_exit_code(self) -> i32 {
+        match self {
+            ExitCode::Exit(status) => status as i32,
+            ExitCode::Panic => 128 + SIGABRT as i32,
+            ExitCode::Exception(vector) => 128 + exception_signal(vector) as i32,
+            ExitCode::Signal(signo) => 128 + signo as i32,
+        }
+    }
+}
+
+/// The signal Linux sends for the exception `vector`
+pub fn exception_signal(vector: u8) -> u8 {
+    match vector {
+        // #DE, #MF, #XM
+        0 | 16 | 19 => SIGFPE,
+        // #DB, #BP
+        1 | 3 => SIGTRAP,
+        // #UD
+        6 => SIGILL,
+        // #AC
+        17 => SIGBUS,
+        // #PF, #GP, #SS, #NP, ...
+        _ => SIGSEGV,
+    }
+}
+
+#[test]
+fn check_exit_code_roundtrip() {
+    for code in [
+        ExitCode::Exit(0),
+        ExitCode::Exit(77),
+        ExitCode::Exit(255),
+        ExitCode::Panic,
+        ExitCode::Exception(14),
+        ExitCode::Signal(11),
+    ]
+    .iter()
+    {
+        assert_eq!(ExitCode::decode(code.encode()), Some(*code));
+    }
+    assert_eq!(ExitCode::decode(LEGACY_SUCCESS), Some(ExitCode::Exit(0)));
+    assert_eq!(ExitCode::decode(LEGACY_FAILED), Some(ExitCode::Exit(1)));
+    assert_eq!(ExitCode::decode(0x12), None);
+    assert_eq!(ExitCode::decode(KIND_PANIC << 8 | 1), None);
+    assert_eq!(ExitCode::decode(5 << 8), None);
+}
+
+#[test]
+fn check_process_exit_code() {
+    assert_eq!(ExitCode::Exit(3).process_exit_code(), 3);
+    assert_eq!(ExitCode::Panic.process_exit_code(), 134);
+    assert_eq!(ExitCode::Exception(14).process_exit_code(), 139);
+    assert_eq!(ExitCode::Exception(6).process_exit_code(), 132);
+    assert_eq!(ExitCode::Exception(0).process_exit_code(), 136);
+    assert_eq!(ExitCode::Signal(11).process_exit_code(), 139);
+}
@@ -4,6 +4,8 @@
 //!
 //! Currently it uses a hard coded page and an I/O trigger.
 //! We might want to switch to MMIO.
+//!
+//! Requests and replies are encoded on the page with the format of the [`wire`] module.
 
 #![deny(missing_docs)]
 #![deny(clippy::all)]
@@ -11,9 +13,16 @@
 #![no_std]
 
 pub mod bootinfo;
+pub mod crash;
+pub mod exit;
 pub mod memory_map;
+pub mod pvh;
+pub mod trace;
+pub mod wire;
 
 use core::fmt::{Debug, Formatter};
+use crash::CrashReport;
+use trace::{TraceRecord, TraceSummary, TRACE_BATCH_LEN, TRACE_SUMMARY_BATCH_LEN};
 
 impl Debug for VmSyscall {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
@@ -25,6 +34,18 @@ impl Debug for VmSyscall {
             VmSyscall::Mremap { .. } => f.write_str("mremap(2)"),
             VmSyscall::Munmap { .. } => f.write_str("munmap(2)"),
             VmSyscall::Mprotect { .. } => f.write_str("mprotect(2)"),
+            VmSyscall::Openat { .. } => f.write_str("openat(2)"),
+            VmSyscall::Close { .. } => f.write_str("close(2)"),
+            VmSyscall::Pread { .. } => f.write_str("pread(2)"),
+            VmSyscall::Pwrite { .. } => f.write_str("pwrite(2)"),
+            VmSyscall::Lseek { .. } => f.write_str("lseek(2)"),
+            VmSyscall::Fstat { .. } => f.write_str("fstat(2)"),
+            VmSyscall::Getdents64 { .. } => f.write_str("getdents64(2)"),
+            VmSyscall::StartCpu { .. } => f.write_str("start_cpu"),
+            VmSyscall::GetRandom { .. } => f.write_str("getrandom(2)"),
+            VmSyscall::Trace { .. } => f.write_str("trace"),
+            VmSyscall::TraceSummary { .. } => f.write_str("trace_summary"),
+            VmSyscall::Crash { .. } => f.write_str("crash"),
         }
     }
 }
@@ -32,6 +53,15 @@ impl Debug for VmSyscall {
 /// maximum length of write(2) buffer
 pub const WRITE_BUF_LEN: usize = 4000;
 
+/// maximum length of read(2) buffer
+pub const READ_BUF_LEN: usize = 4000;
+
+/// maximum length of a path, including the NUL terminator
+pub const PATH_BUF_LEN: usize = 1024;
+
+/// `dirfd` value for paths relative to the current working directory
+pub const AT_FDCWD: i32 = -100;
+
 /// The syscalls for the Hypervisor <-> VM syscall proxy
 pub enum VmSyscall {
     /// ssize_t read(int fd, void *buf, size_t count);
@@ -97,14 +127,116 @@ pub enum VmSyscall {
         /// see mprotect(2)
         prot: i32,
     },
+    /// int openat(int dirfd, const char *pathname, int flags, mode_t mode);
+    Openat {
+        /// see openat(2)
+        dirfd: i32,
+        /// see openat(2), without the NUL terminator
+        path: [u8; PATH_BUF_LEN],
+        /// length of `path`
+        path_len: usize,
+        /// see openat(2)
+        flags: i32,
+        /// see openat(2)
+        mode: u32,
+    },
+    /// int close(int fd);
+    Close {
+        /// see close(2)
+        fd: i32,
+    },
+    /// ssize_t pread(int fd, void *buf, size_t count, off_t offset);
+    Pread {
+        /// see pread(2)
+        fd: i32,
+        /// see pread(2)
+        count: usize,
+        /// see pread(2), `None` reads from the current file offset like read(2)
+        offset: Option<i64>,
+    },
+    /// ssize_t pwrite(int fd, const void *buf, size_t count, off_t offset);
+    Pwrite {
+        /// see pwrite(2)
+        fd: i32,
+        /// see pwrite(2)
+        count: usize,
+        /// see pwrite(2), `None` writes at the current file offset like write(2)
+        offset: Option<i64>,
+        /// see pwrite(2)
+        data: [u8; WRITE_BUF_LEN],
+    },
+    /// off_t lseek(int fd, off_t offset, int whence);
+    Lseek {
+        /// see lseek(2)
+        fd: i32,
+        /// see lseek(2)
+        offset: i64,
+        /// see lseek(2)
+        whence: i32,
+    },
+    /// int fstat(int fd, struct stat *statbuf);
+    Fstat {
+        /// see fstat(2)
+        fd: i32,
+    },
+    /// ssize_t getdents64(int fd, void *dirp, size_t count);
+    Getdents64 {
+        /// see getdents64(2)
+        fd: i32,
+        /// see getdents64(2)
+        count: usize,
+    },
+    /// Start an application processor.
+    ///
+    /// The vCPU `cpu` starts in the same mode and address space as the calling vCPU,
+    /// with the instruction pointer at `entry`, the stack pointer at `stack` and `arg`
+    /// as its first argument.
+    StartCpu {
+        /// the vCPU to start
+        cpu: u32,
+        /// instruction pointer
+        entry: u64,
+        /// stack pointer
+        stack: u64,
+        /// first argument (`%rdi`)
+        arg: u64,
+    },
+    /// ssize_t getrandom(void *buf, size_t buflen, unsigned int flags);
+    ///
+    /// Entropy of the host for guests without RDRAND.
+    GetRandom {
+        /// see getrandom(2), at most `READ_BUF_LEN`
+        count: usize,
+    },
+    /// Syscall trace records of the app
+    Trace {
+        /// number of valid `records`
+        count: usize,
+        /// the records, oldest first
+        records: [TraceRecord; TRACE_BATCH_LEN],
+    },
+    /// The syscall totals of the app, sent when it exits
+    TraceSummary {
+        /// number of valid `entries`
+        count: usize,
+        /// whether this is the last batch
+        last: bool,
+        /// the totals
+        entries: [TraceSummary; TRACE_SUMMARY_BATCH_LEN],
+    },
+    /// The report of a fatal exception, sent before the kernel stops the VM
+    Crash {
+        /// the state of the crashed CPU
+        report: CrashReport,
+    },
     // Todo: extend with needed hypervisor proxy syscalls
 }
 
-/// The return value of the syscalls to be serialized/deserialized via serde
+/// The return value of the syscalls to be serialized/deserialized via the [`wire`] format
 /// for the Hypervisor <-> VM syscall proxy
 pub enum VmSyscallRet {
     /// ssize_t read(int fd, void *buf, size_t count);
-    Read(Result<(i32, [u8; 4000]), Error>),
+    Read(Result<(i32, [u8; READ_BUF_LEN]), Error>),
     /// ssize_t write(int fd, const void *buf, size_t count);
     Write(Result<i32, Error>),
     /// int madvise(void *addr, size_t length, int advice);
@@ -117,6 +249,72 @@ pub enum VmSyscallRet {
     Munmap(Result<i32, Error>),
     /// int mprotect(void *addr, size_t len, int prot);
     Mprotect(Result<i32, Error>),
+    /// int openat(int dirfd, const char *pathname, int flags, mode_t mode);
+    Openat(Result<i32, Error>),
+    /// int close(int fd);
+    Close(Result<i32, Error>),
+    /// ssize_t pread(int fd, void *buf, size_t count, off_t offset);
+    Pread(Result<(i32, [u8; READ_BUF_LEN]), Error>),
+    /// ssize_t pwrite(int fd, const void *buf, size_t count, off_t offset);
+    Pwrite(Result<i32, Error>),
+    /// off_t lseek(int fd, off_t offset, int whence);
+    Lseek(Result<i64, Error>),
+    /// int fstat(int fd, struct stat *statbuf);
+    Fstat(Result<Stat, Error>),
+    /// ssize_t getdents64(int fd, void *dirp, size_t count);
+    Getdents64(Result<(i32, [u8; READ_BUF_LEN]), Error>),
+    /// Start an application processor
+    StartCpu(Result<i32, Error>),
+    /// ssize_t getrandom(void *buf, size_t buflen, unsigned int flags);
+    GetRandom(Result<(i32, [u8; READ_BUF_LEN]), Error>),
+    /// number of trace records taken
+    Trace(Result<i32, Error>),
+    /// number of summary entries taken
+    TraceSummary(Result<i32, Error>),
+    /// `0` if the report was taken
+    Crash(Result<i32, Error>),
+}
+
+/// `struct stat` as used by the x86_64 Linux syscall ABI
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct Stat {
+    /// ID of device containing file
+    pub st_dev: u64,
+    /// Inode number
+    pub st_ino: u64,
+    /// Number of hard links
+    pub st_nlink: u64,
+    /// File type and mode
+    pub st_mode: u32,
+    /// User ID of owner
+    pub st_uid: u32,
+    /// Group ID of owner
+    pub st_gid: u32,
+    /// Padding
+    pub pad0: i32,
+    /// Device ID (if special file)
+    pub st_rdev: u64,
+    /// Total size, in bytes
+    pub st_size: i64,
+    /// Block size for filesystem I/O
+    pub st_blksize: i64,
+    /// Number of 512B blocks allocated
+    pub st_blocks: i64,
+    /// Time of last access, seconds
+    pub st_atime: i64,
+    /// Time of last access, nanoseconds
+    pub st_atime_nsec: i64,
+    /// Time of last modification, seconds
+    pub st_mtime: i64,
+    /// Time of last modification, nanoseconds
+    pub st_mtime_nsec: i64,
+    /// Time of last status change, seconds
+    pub st_ctime: i64,
+    /// Time of last status change, nanoseconds
+    pub st_ctime_nsec: i64,
+    /// Reserved
+    pub reserved: [i64; 3],
 }
 
 /// The error codes of the syscalls
@@ -130,3 +328,8 @@ pub enum Error {
     /// deserialize error
     DeSerializeError,
 }
+
+#[test]
+fn check_stat_size() {
+    assert_eq!(core::mem::size_of::<Stat>(), 144);
+}
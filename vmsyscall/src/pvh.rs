@@ -0,0 +1,166 @@
+//! The start info of the PVH boot protocol
+//!
+//! A PVH loader enters the kernel in 32-bit protected mode at the address in the
+//! `XEN_ELFNOTE_PHYS32_ENTRY` note of the kernel ELF file, with the physical address of
+//! the [`HvmStartInfo`] in `ebx`. Both qemu and vmrun boot the kernel this way.
+//!
+//! vmrun passes the [`BootInfo`](crate::bootinfo::BootInfo) as the first module. The
+//! memory map of the kernel is built from the E820 memory map in both cases.
+//!
+//! https://xenbits.xen.org/docs/unstable/misc/pvh.html
+
+use core::fmt;
+
+/// Type of the ELF note with the 32-bit physical entry point of the kernel
+pub const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+/// Name of the Xen ELF notes, including the NUL terminator
+pub const XEN_ELFNOTE_NAME: &[u8] = b"Xen\0";
+
+/// Magic value of the [`HvmStartInfo`] ("xEn3" with the 0x80 bit of the "E" set)
+pub const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+/// Version of the [`HvmStartInfo`] with a memory map
+pub const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+/// Memory map type of usable RAM
+pub const XEN_HVM_MEMMAP_TYPE_RAM: u32 = 1;
+/// Memory map type of reserved memory
+pub const XEN_HVM_MEMMAP_TYPE_RESERVED: u32 = 2;
+/// Memory map type of ACPI reclaimable memory
+pub const XEN_HVM_MEMMAP_TYPE_ACPI: u32 = 3;
+/// Memory map type of ACPI NVS memory
+pub const XEN_HVM_MEMMAP_TYPE_NVS: u32 = 4;
+/// Memory map type of unusable memory
+pub const XEN_HVM_MEMMAP_TYPE_UNUSABLE: u32 = 5;
+/// Memory map type of disabled memory
+pub const XEN_HVM_MEMMAP_TYPE_DISABLED: u32 = 6;
+/// Memory map type of persistent memory
+pub const XEN_HVM_MEMMAP_TYPE_PMEM: u32 = 7;
+
+/// The start info, whose physical address the loader passes in `ebx`
+///
+/// https://github.com/Xilinx/xen/blob/master/xen/include/public/arch-x86/hvm/start_info.h
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct HvmStartInfo {
+    /// Contains the magic value [`XEN_HVM_START_MAGIC_VALUE`]
+    pub magic: u32,
+    /// Version of this structure
+    pub version: u32,
+    /// SIF_xxx flags
+    pub flags: u32,
+    /// Number of modules passed to the kernel
+    pub nr_modules: u32,
+    /// Physical address of an array of [`HvmModlistEntry`]
+    pub modlist_paddr: u64,
+    /// Physical address of the NUL terminated command line
+    pub cmdline_paddr: u64,
+    /// Physical address of the RSDP ACPI data structure
+    pub rsdp_paddr: u64,
+    /// Physical address of an array of [`HvmMemmapTableEntry`], version 1 and newer
+    pub memmap_paddr: u64,
+    /// Number of entries in the memory map table, zero if there is no memory map
+    pub memmap_entries: u32,
+    /// Must be zero
+    pub reserved: u32,
+}
+
+/// A module passed to the kernel
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct HvmModlistEntry {
+    /// Physical address of the module
+    pub paddr: u64,
+    /// Size of the module in bytes
+    pub size: u64,
+    /// Physical address of the NUL terminated command line of the module
+    pub cmdline_paddr: u64,
+    /// Must be zero
+    pub reserved: u64,
+}
+
+/// An entry of the E820 style memory map
+#[derive(Clone, Copy, Default, PartialEq)]
+#[repr(C)]
+pub struct HvmMemmapTableEntry {
+    /// Base address of the memory region
+    pub addr: u64,
+    /// Size of the memory region in bytes
+    pub size: u64,
+    /// Mapping type, one of `XEN_HVM_MEMMAP_TYPE_*`
+    pub entry_type: u32,
+    /// Must be zero for version 1
+    pub reserved: u32,
+}
+
+impl HvmMemmapTableEntry {
+    /// The type of the memory region
+    pub fn get_type(&self) -> HvmMemmapTableEntryType {
+        match self.entry_type {
+            XEN_HVM_MEMMAP_TYPE_RAM => HvmMemmapTableEntryType::Ram,
+            XEN_HVM_MEMMAP_TYPE_RESERVED => HvmMemmapTableEntryType::Reserved,
+            XEN_HVM_MEMMAP_TYPE_ACPI => HvmMemmapTableEntryType::Acpi,
+            XEN_HVM_MEMMAP_TYPE_NVS => HvmMemmapTableEntryType::Nvs,
+            XEN_HVM_MEMMAP_TYPE_UNUSABLE => HvmMemmapTableEntryType::Unusable,
+            XEN_HVM_MEMMAP_TYPE_DISABLED => HvmMemmapTableEntryType::Disabled,
+            XEN_HVM_MEMMAP_TYPE_PMEM => HvmMemmapTableEntryType::Pmem,
+            _ => HvmMemmapTableEntryType::Unknown,
+        }
+    }
+}
+
+impl fmt::Debug for HvmMemmapTableEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HvmMemmapTableEntry({:#?} {:#x}..{:#x})",
+            self.get_type(),
+            self.addr,
+            self.addr + self.size
+        )
+    }
+}
+
+/// The types of the memory regions in the memory map
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HvmMemmapTableEntryType {
+    /// Usable RAM
+    Ram,
+    /// Reserved memory
+    Reserved,
+    /// ACPI reclaimable memory
+    Acpi,
+    /// ACPI NVS memory
+    Nvs,
+    /// Unusable memory
+    Unusable,
+    /// Disabled memory
+    Disabled,
+    /// Persistent memory
+    Pmem,
+    /// A type unknown to this version
+    Unknown,
+}
+
+#[test]
+fn check_hvm_start_info_layout() {
+    // the layout of start_info.h
+    assert_eq!(core::mem::size_of::<HvmStartInfo>(), 56);
+    assert_eq!(core::mem::size_of::<HvmModlistEntry>(), 32);
+    assert_eq!(core::mem::size_of::<HvmMemmapTableEntry>(), 24);
+}
+
+#[test]
+fn check_hvm_memmap_type() {
+    let entry = HvmMemmapTableEntry {
+        addr: 0x10_0000,
+        size: 0x1000,
+        entry_type: XEN_HVM_MEMMAP_TYPE_RAM,
+        reserved: 0,
+    };
+    assert_eq!(entry.get_type(), HvmMemmapTableEntryType::Ram);
+    let entry = HvmMemmapTableEntry {
+        entry_type: 42,
+        ..entry
+    };
+    assert_eq!(entry.get_type(), HvmMemmapTableEntryType::Unknown);
+}
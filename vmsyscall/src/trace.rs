@@ -0,0 +1,71 @@
+//! Syscall trace records, which the kernel hands to the hypervisor in batches
+//!
+//! With [`TraceLevel::Full`] every syscall of the app is recorded with its arguments,
+//! result and duration. With [`TraceLevel::Summary`] the kernel only counts the calls,
+//! errors and time per syscall and reports the table when the app exits.
+
+/// Maximum number of [`TraceRecord`]s in one [`VmSyscall::Trace`](crate::VmSyscall::Trace)
+pub const TRACE_BATCH_LEN: usize = 40;
+/// Maximum number of [`TraceSummary`] entries in one
+/// [`VmSyscall::TraceSummary`](crate::VmSyscall::TraceSummary)
+pub const TRACE_SUMMARY_BATCH_LEN: usize = 100;
+
+/// [`TraceRecord::flags`]: the syscall did not return, like `exit_group`
+pub const TRACE_NO_RETURN: u32 = 1 << 0;
+
+/// How much of the syscalls of the app is traced
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum TraceLevel {
+    /// No tracing
+    Off = 0,
+    /// Calls, errors and time per syscall, reported on exit
+    Summary = 1,
+    /// Every syscall, and the totals on exit
+    Full = 2,
+}
+
+impl TraceLevel {
+    /// The level of the `BootInfo`, unknown levels turn tracing off
+    pub fn from_u32(level: u32) -> Self {
+        match level {
+            1 => TraceLevel::Summary,
+            2 => TraceLevel::Full,
+            _ => TraceLevel::Off,
+        }
+    }
+}
+
+/// One syscall of the app
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TraceRecord {
+    /// The Linux syscall number
+    pub nr: u32,
+    /// The thread calling
+    pub tid: u32,
+    /// The CPU of the thread
+    pub cpu: u32,
+    /// `TRACE_*` flags
+    pub flags: u32,
+    /// The arguments in the order of the syscall ABI
+    pub args: [u64; 6],
+    /// The return value, negative values are `-errno`
+    pub ret: i64,
+    /// Start of the syscall in nanoseconds of `CLOCK_MONOTONIC` of the guest
+    pub start_ns: u64,
+    /// Time spent in the kernel in nanoseconds
+    pub duration_ns: u64,
+}
+
+/// The totals of one syscall number
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TraceSummary {
+    /// The Linux syscall number
+    pub nr: u32,
+    /// Number of calls
+    pub calls: u64,
+    /// Number of calls returning an error
+    pub errors: u64,
+    /// Time spent in the kernel in nanoseconds
+    pub total_ns: u64,
+}
@@ -0,0 +1,1252 @@
+//! Wire format of the syscall page
+//!
+//! Every message starts with a [`Header`], followed by `length` bytes of payload.
+//! All integers are encoded little endian, `usize` values are encoded as `u64`.
+//! Buffers are only encoded up to their used length.
+//!
+//! A `Result` is encoded as a `u8` tag (`0` for `Ok`, `1` for `Err`), followed by the
+//! value or the encoded [`Error`].
+
+use crate::crash::{CrashReport, Registers, Segments, CRASH_FRAMES_LEN};
+use crate::trace::{TraceRecord, TraceSummary, TRACE_BATCH_LEN, TRACE_SUMMARY_BATCH_LEN};
+use crate::{Error, Stat, VmSyscall, VmSyscallRet, PATH_BUF_LEN, READ_BUF_LEN, WRITE_BUF_LEN};
+use core::convert::TryFrom;
+
+/// Magic number of a syscall page message ("VMSC")
+pub const WIRE_MAGIC: u32 = 0x4353_4d56;
+/// Version of the wire format
+pub const WIRE_VERSION: u16 = 1;
+/// Length of the encoded [`Header`]
+pub const HEADER_LEN: usize = 16;
+/// Maximum length of a message including the header, which is the size of the syscall page
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+/// The numbers of the proxied syscalls on the wire
+///
+/// Zero is not used, so that an empty page is never a valid message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u16)]
+#[allow(missing_docs)]
+pub enum VmSyscallNr {
+    Read = 1,
+    Write = 2,
+    Madvise = 3,
+    Mmap = 4,
+    Mremap = 5,
+    Munmap = 6,
+    Mprotect = 7,
+    Openat = 8,
+    Close = 9,
+    Pread = 10,
+    Pwrite = 11,
+    Lseek = 12,
+    Fstat = 13,
+    Getdents64 = 14,
+    StartCpu = 15,
+    GetRandom = 16,
+    Trace = 17,
+    TraceSummary = 18,
+    Crash = 19,
+}
+
+impl TryFrom<u16> for VmSyscallNr {
+    type Error = Error;
+
+    fn try_from(nr: u16) -> Result<Self, Error> {
+        Ok(match nr {
+            1 => VmSyscallNr::Read,
+            2 => VmSyscallNr::Write,
+            3 => VmSyscallNr::Madvise,
+            4 => VmSyscallNr::Mmap,
+            5 => VmSyscallNr::Mremap,
+            6 => VmSyscallNr::Munmap,
+            7 => VmSyscallNr::Mprotect,
+            8 => VmSyscallNr::Openat,
+            9 => VmSyscallNr::Close,
+            10 => VmSyscallNr::Pread,
+            11 => VmSyscallNr::Pwrite,
+            12 => VmSyscallNr::Lseek,
+            13 => VmSyscallNr::Fstat,
+            14 => VmSyscallNr::Getdents64,
+            15 => VmSyscallNr::StartCpu,
+            16 => VmSyscallNr::GetRandom,
+            17 => VmSyscallNr::Trace,
+            18 => VmSyscallNr::TraceSummary,
+            19 => VmSyscallNr::Crash,
+            _ => return Err(Error::DeSerializeError),
+        })
+    }
+}
+
+/// The header of every message on the syscall page
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Header {
+    /// Always [`WIRE_MAGIC`]
+    pub magic: u32,
+    /// Always [`WIRE_VERSION`]
+    pub version: u16,
+    /// The syscall of the request or reply
+    pub nr: VmSyscallNr,
+    /// Length of the payload following the header
+    pub length: u32,
+    /// Chosen by the guest and copied to the reply by the host
+    pub request_id: u32,
+}
+
+impl Header {
+    fn encode(&self, w: &mut Writer) -> Result<(), Error> {
+        w.u32(self.magic)?;
+        w.u16(self.version)?;
+        w.u16(self.nr as u16)?;
+        w.u32(self.length)?;
+        w.u32(self.request_id)
+    }
+
+    /// Decode and validate the header at the start of `buf`
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader::new(buf);
+        let magic = r.u32()?;
+        let version = r.u16()?;
+        if magic != WIRE_MAGIC || version != WIRE_VERSION {
+            return Err(Error::DeSerializeError);
+        }
+        let nr = VmSyscallNr::try_from(r.u16()?)?;
+        let length = r.u32()?;
+        let request_id = r.u32()?;
+
+        if length as usize > buf.len().min(MAX_MESSAGE_LEN) - HEADER_LEN {
+            return Err(Error::DeSerializeError);
+        }
+
+        Ok(Header {
+            magic,
+            version,
+            nr,
+            length,
+            request_id,
+        })
+    }
+
+    fn payload<'a>(&self, buf: &'a [u8]) -> Reader<'a> {
+        Reader::new(&buf[HEADER_LEN..HEADER_LEN + self.length as usize])
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, b: &[u8]) -> Result<(), Error> {
+        let end = self.pos + b.len();
+        if end > self.buf.len() {
+            return Err(Error::SerializeError);
+        }
+        self.buf[self.pos..end].copy_from_slice(b);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, v: u8) -> Result<(), Error> {
+        self.bytes(&[v])
+    }
+
+    fn u16(&mut self, v: u16) -> Result<(), Error> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u32(&mut self, v: u32) -> Result<(), Error> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn i32(&mut self, v: i32) -> Result<(), Error> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> Result<(), Error> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn i64(&mut self, v: i64) -> Result<(), Error> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn usize(&mut self, v: usize) -> Result<(), Error> {
+        self.u64(v as u64)
+    }
+
+    fn opt_i64(&mut self, v: Option<i64>) -> Result<(), Error> {
+        match v {
+            None => self.u8(0),
+            Some(v) => {
+                self.u8(1)?;
+                self.i64(v)
+            }
+        }
+    }
+
+    fn error(&mut self, e: &Error) -> Result<(), Error> {
+        match e {
+            Error::Errno(errno) => {
+                self.u8(0)?;
+                self.i64(*errno)
+            }
+            Error::SerializeError => self.u8(1),
+            Error::DeSerializeError => self.u8(2),
+        }
+    }
+
+    fn result<T>(
+        &mut self,
+        res: &Result<T, Error>,
+        ok: impl FnOnce(&mut Self, &T) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        match res {
+            Ok(v) => {
+                self.u8(0)?;
+                ok(self, v)
+            }
+            Err(e) => {
+                self.u8(1)?;
+                self.error(e)
+            }
+        }
+    }
+
+    /// A buffer with `len` used bytes, prefixed with the length as `i32`
+    fn data(&mut self, len: i32, data: &[u8]) -> Result<(), Error> {
+        if len < 0 || len as usize > data.len() {
+            return Err(Error::SerializeError);
+        }
+        self.i32(len)?;
+        self.bytes(&data[..len as usize])
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos.checked_add(len).ok_or(Error::DeSerializeError)?;
+        if end > self.buf.len() {
+            return Err(Error::DeSerializeError);
+        }
+        let b = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(b)
+    }
+
+    fn array<T: Default + AsMut<[u8]>>(&mut self) -> Result<T, Error> {
+        let mut a = T::default();
+        let len = a.as_mut().len();
+        a.as_mut().copy_from_slice(self.bytes(len)?);
+        Ok(a)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, Error> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn i64(&mut self) -> Result<i64, Error> {
+        Ok(i64::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, Error> {
+        usize::try_from(self.u64()?).map_err(|_| Error::DeSerializeError)
+    }
+
+    fn opt_i64(&mut self) -> Result<Option<i64>, Error> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.i64()?)),
+            _ => Err(Error::DeSerializeError),
+        }
+    }
+
+    fn error(&mut self) -> Result<Error, Error> {
+        match self.u8()? {
+            0 => Ok(Error::Errno(self.i64()?)),
+            1 => Ok(Error::SerializeError),
+            2 => Ok(Error::DeSerializeError),
+            _ => Err(Error::DeSerializeError),
+        }
+    }
+
+    fn result<T>(
+        &mut self,
+        ok: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<Result<T, Error>, Error> {
+        match self.u8()? {
+            0 => Ok(Ok(ok(self)?)),
+            1 => Ok(Err(self.error()?)),
+            _ => Err(Error::DeSerializeError),
+        }
+    }
+
+    /// A buffer prefixed with its length as `i32`, copied to a `[u8; N]`
+    fn data<T: Default + AsMut<[u8]>>(&mut self) -> Result<(i32, T), Error> {
+        let len = self.i32()?;
+        let mut data = T::default();
+        if len < 0 || len as usize > data.as_mut().len() {
+            return Err(Error::DeSerializeError);
+        }
+        data.as_mut()[..len as usize].copy_from_slice(self.bytes(len as usize)?);
+        Ok((len, data))
+    }
+
+    /// All bytes of the payload have to be consumed
+    fn finish(&self) -> Result<(), Error> {
+        if self.pos != self.buf.len() {
+            return Err(Error::DeSerializeError);
+        }
+        Ok(())
+    }
+}
+
+/// Byte buffers bigger than 32 bytes don't implement `Default` and `AsMut<[u8]>`
+macro_rules! byte_buf {
+    ($name:ident, $len:expr) => {
+        struct $name([u8; $len]);
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name([0u8; $len])
+            }
+        }
+
+        impl AsMut<[u8]> for $name {
+            fn as_mut(&mut self) -> &mut [u8] {
+                &mut self.0
+            }
+        }
+    };
+}
+
+byte_buf!(ReadBuf, READ_BUF_LEN);
+byte_buf!(WriteBuf, WRITE_BUF_LEN);
+byte_buf!(PathBytes, PATH_BUF_LEN);
+
+fn encode_message(
+    nr: VmSyscallNr,
+    request_id: u32,
+    buf: &mut [u8],
+    payload: impl FnOnce(&mut Writer) -> Result<(), Error>,
+) -> Result<usize, Error> {
+    let len = buf.len().min(MAX_MESSAGE_LEN);
+    if len < HEADER_LEN {
+        return Err(Error::SerializeError);
+    }
+    let (header_buf, payload_buf) = buf[..len].split_at_mut(HEADER_LEN);
+
+    let mut w = Writer::new(payload_buf);
+    payload(&mut w)?;
+    let length = w.pos;
+
+    Header {
+        magic: WIRE_MAGIC,
+        version: WIRE_VERSION,
+        nr,
+        length: length as u32,
+        request_id,
+    }
+    .encode(&mut Writer::new(header_buf))?;
+
+    Ok(HEADER_LEN + length)
+}
+
+impl VmSyscall {
+    /// The wire number of the syscall
+    pub fn nr(&self) -> VmSyscallNr {
+        match self {
+            VmSyscall::Read { .. } => VmSyscallNr::Read,
+            VmSyscall::Write { .. } => VmSyscallNr::Write,
+            VmSyscall::Madvise { .. } => VmSyscallNr::Madvise,
+            VmSyscall::Mmap { .. } => VmSyscallNr::Mmap,
+            VmSyscall::Mremap { .. } => VmSyscallNr::Mremap,
+            VmSyscall::Munmap { .. } => VmSyscallNr::Munmap,
+            VmSyscall::Mprotect { .. } => VmSyscallNr::Mprotect,
+            VmSyscall::Openat { .. } => VmSyscallNr::Openat,
+            VmSyscall::Close { .. } => VmSyscallNr::Close,
+            VmSyscall::Pread { .. } => VmSyscallNr::Pread,
+            VmSyscall::Pwrite { .. } => VmSyscallNr::Pwrite,
+            VmSyscall::Lseek { .. } => VmSyscallNr::Lseek,
+            VmSyscall::Fstat { .. } => VmSyscallNr::Fstat,
+            VmSyscall::Getdents64 { .. } => VmSyscallNr::Getdents64,
+            VmSyscall::StartCpu { .. } => VmSyscallNr::StartCpu,
+            VmSyscall::GetRandom { .. } => VmSyscallNr::GetRandom,
+            VmSyscall::Trace { .. } => VmSyscallNr::Trace,
+            VmSyscall::TraceSummary { .. } => VmSyscallNr::TraceSummary,
+            VmSyscall::Crash { .. } => VmSyscallNr::Crash,
+        }
+    }
+
+    /// Encode the request into `buf` and return the length of the message
+    pub fn encode(&self, request_id: u32, buf: &mut [u8]) -> Result<usize, Error> {
+        encode_message(self.nr(), request_id, buf, |w| match self {
+            VmSyscall::Read { fd, count } => {
+                w.u32(*fd)?;
+                w.usize(*count)
+            }
+            VmSyscall::Write { fd, count, data } => {
+                w.u32(*fd)?;
+                w.data((*count).min(WRITE_BUF_LEN) as i32, data)
+            }
+            VmSyscall::Madvise {
+                addr,
+                length,
+                advice,
+            } => {
+                w.usize(*addr)?;
+                w.usize(*length)?;
+                w.i32(*advice)
+            }
+            VmSyscall::Mmap {
+                addr,
+                length,
+                prot,
+                flags,
+            } => {
+                w.usize(*addr)?;
+                w.usize(*length)?;
+                w.i32(*prot)?;
+                w.i32(*flags)
+            }
+            VmSyscall::Mremap {
+                old_address,
+                old_size,
+                new_size,
+                flags,
+            } => {
+                w.usize(*old_address)?;
+                w.usize(*old_size)?;
+                w.usize(*new_size)?;
+                w.i32(*flags)
+            }
+            VmSyscall::Munmap { addr, length } => {
+                w.usize(*addr)?;
+                w.usize(*length)
+            }
+            VmSyscall::Mprotect { addr, length, prot } => {
+                w.usize(*addr)?;
+                w.usize(*length)?;
+                w.i32(*prot)
+            }
+            VmSyscall::Openat {
+                dirfd,
+                path,
+                path_len,
+                flags,
+                mode,
+            } => {
+                w.i32(*dirfd)?;
+                w.data((*path_len).min(PATH_BUF_LEN) as i32, path)?;
+                w.i32(*flags)?;
+                w.u32(*mode)
+            }
+            VmSyscall::Close { fd } => w.i32(*fd),
+            VmSyscall::Pread { fd, count, offset } => {
+                w.i32(*fd)?;
+                w.usize(*count)?;
+                w.opt_i64(*offset)
+            }
+            VmSyscall::Pwrite {
+                fd,
+                count,
+                offset,
+                data,
+            } => {
+                w.i32(*fd)?;
+                w.opt_i64(*offset)?;
+                w.data((*count).min(WRITE_BUF_LEN) as i32, data)
+            }
+            VmSyscall::Lseek { fd, offset, whence } => {
+                w.i32(*fd)?;
+                w.i64(*offset)?;
+                w.i32(*whence)
+            }
+            VmSyscall::Fstat { fd } => w.i32(*fd),
+            VmSyscall::Getdents64 { fd, count } => {
+                w.i32(*fd)?;
+                w.usize(*count)
+            }
+            VmSyscall::StartCpu {
+                cpu,
+                entry,
+                stack,
+                arg,
+            } => {
+                w.u32(*cpu)?;
+                w.u64(*entry)?;
+                w.u64(*stack)?;
+                w.u64(*arg)
+            }
+            VmSyscall::GetRandom { count } => w.usize(*count),
+            VmSyscall::Trace { count, records } => {
+                let records = &records[..(*count).min(TRACE_BATCH_LEN)];
+                w.u32(records.len() as u32)?;
+                records
+                    .iter()
+                    .try_for_each(|rec| encode_trace_record(w, rec))
+            }
+            VmSyscall::TraceSummary {
+                count,
+                last,
+                entries,
+            } => {
+                let entries = &entries[..(*count).min(TRACE_SUMMARY_BATCH_LEN)];
+                w.u8(*last as u8)?;
+                w.u32(entries.len() as u32)?;
+                entries
+                    .iter()
+                    .try_for_each(|entry| encode_trace_summary(w, entry))
+            }
+            VmSyscall::Crash { report } => encode_crash_report(w, report),
+        })
+    }
+
+    /// Decode and validate a request, returning the request id and the request
+    pub fn decode(buf: &[u8]) -> Result<(u32, Self), Error> {
+        let header = Header::decode(buf)?;
+        let mut r = header.payload(buf);
+
+        let syscall = match header.nr {
+            VmSyscallNr::Read => VmSyscall::Read {
+                fd: r.u32()?,
+                count: r.usize()?,
+            },
+            VmSyscallNr::Write => {
+                let fd = r.u32()?;
+                let (count, data) = r.data::<WriteBuf>()?;
+                VmSyscall::Write {
+                    fd,
+                    count: count as usize,
+                    data: data.0,
+                }
+            }
+            VmSyscallNr::Madvise => VmSyscall::Madvise {
+                addr: r.usize()?,
+                length: r.usize()?,
+                advice: r.i32()?,
+            },
+            VmSyscallNr::Mmap => VmSyscall::Mmap {
+                addr: r.usize()?,
+                length: r.usize()?,
+                prot: r.i32()?,
+                flags: r.i32()?,
+            },
+            VmSyscallNr::Mremap => VmSyscall::Mremap {
+                old_address: r.usize()?,
+                old_size: r.usize()?,
+                new_size: r.usize()?,
+                flags: r.i32()?,
+            },
+            VmSyscallNr::Munmap => VmSyscall::Munmap {
+                addr: r.usize()?,
+                length: r.usize()?,
+            },
+            VmSyscallNr::Mprotect => VmSyscall::Mprotect {
+                addr: r.usize()?,
+                length: r.usize()?,
+                prot: r.i32()?,
+            },
+            VmSyscallNr::Openat => {
+                let dirfd = r.i32()?;
+                let (path_len, path) = r.data::<PathBytes>()?;
+                VmSyscall::Openat {
+                    dirfd,
+                    path: path.0,
+                    path_len: path_len as usize,
+                    flags: r.i32()?,
+                    mode: r.u32()?,
+                }
+            }
+            VmSyscallNr::Close => VmSyscall::Close { fd: r.i32()? },
+            VmSyscallNr::Pread => VmSyscall::Pread {
+                fd: r.i32()?,
+                count: r.usize()?,
+                offset: r.opt_i64()?,
+            },
+            VmSyscallNr::Pwrite => {
+                let fd = r.i32()?;
+                let offset = r.opt_i64()?;
+                let (count, data) = r.data::<WriteBuf>()?;
+                VmSyscall::Pwrite {
+                    fd,
+                    count: count as usize,
+                    offset,
+                    data: data.0,
+                }
+            }
+            VmSyscallNr::Lseek => VmSyscall::Lseek {
+                fd: r.i32()?,
+                offset: r.i64()?,
+                whence: r.i32()?,
+            },
+            VmSyscallNr::Fstat => VmSyscall::Fstat { fd: r.i32()? },
+            VmSyscallNr::Getdents64 => VmSyscall::Getdents64 {
+                fd: r.i32()?,
+                count: r.usize()?,
+            },
+            VmSyscallNr::StartCpu => VmSyscall::StartCpu {
+                cpu: r.u32()?,
+                entry: r.u64()?,
+                stack: r.u64()?,
+                arg: r.u64()?,
+            },
+            VmSyscallNr::GetRandom => VmSyscall::GetRandom { count: r.usize()? },
+            VmSyscallNr::Trace => {
+                let count = r.u32()? as usize;
+                if count > TRACE_BATCH_LEN {
+                    return Err(Error::DeSerializeError);
+                }
+                let mut records = [TraceRecord::default(); TRACE_BATCH_LEN];
+                for rec in records[..count].iter_mut() {
+                    *rec = decode_trace_record(&mut r)?;
+                }
+                VmSyscall::Trace { count, records }
+            }
+            VmSyscallNr::TraceSummary => {
+                let last = match r.u8()? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(Error::DeSerializeError),
+                };
+                let count = r.u32()? as usize;
+                if count > TRACE_SUMMARY_BATCH_LEN {
+                    return Err(Error::DeSerializeError);
+                }
+                let mut entries = [TraceSummary::default(); TRACE_SUMMARY_BATCH_LEN];
+                for entry in entries[..count].iter_mut() {
+                    *entry = decode_trace_summary(&mut r)?;
+                }
+                VmSyscall::TraceSummary {
+                    count,
+                    last,
+                    entries,
+                }
+            }
+            VmSyscallNr::Crash => VmSyscall::Crash {
+                report: decode_crash_report(&mut r)?,
+            },
+        };
+
+        r.finish()?;
+        Ok((header.request_id, syscall))
+    }
+}
+
+fn encode_stat(w: &mut Writer, st: &Stat) -> Result<(), Error> {
+    w.u64(st.st_dev)?;
+    w.u64(st.st_ino)?;
+    w.u64(st.st_nlink)?;
+    w.u32(st.st_mode)?;
+    w.u32(st.st_uid)?;
+    w.u32(st.st_gid)?;
+    w.u64(st.st_rdev)?;
+    w.i64(st.st_size)?;
+    w.i64(st.st_blksize)?;
+    w.i64(st.st_blocks)?;
+    w.i64(st.st_atime)?;
+    w.i64(st.st_atime_nsec)?;
+    w.i64(st.st_mtime)?;
+    w.i64(st.st_mtime_nsec)?;
+    w.i64(st.st_ctime)?;
+    w.i64(st.st_ctime_nsec)
+}
+
+fn decode_stat(r: &mut Reader) -> Result<Stat, Error> {
+    Ok(Stat {
+        st_dev: r.u64()?,
+        st_ino: r.u64()?,
+        st_nlink: r.u64()?,
+        st_mode: r.u32()?,
+        st_uid: r.u32()?,
+        st_gid: r.u32()?,
+        pad0: 0,
+        st_rdev: r.u64()?,
+        st_size: r.i64()?,
+        st_blksize: r.i64()?,
+        st_blocks: r.i64()?,
+        st_atime: r.i64()?,
+        st_atime_nsec: r.i64()?,
+        st_mtime: r.i64()?,
+        st_mtime_nsec: r.i64()?,
+        st_ctime: r.i64()?,
+        st_ctime_nsec: r.i64()?,
+        reserved: [0; 3],
+    })
+}
+
+fn encode_trace_record(w: &mut Writer, rec: &TraceRecord) -> Result<(), Error> {
+    w.u32(rec.nr)?;
+    w.u32(rec.tid)?;
+    w.u32(rec.cpu)?;
+    w.u32(rec.flags)?;
+    rec.args.iter().try_for_each(|arg| w.u64(*arg))?;
+    w.i64(rec.ret)?;
+    w.u64(rec.start_ns)?;
+    w.u64(rec.duration_ns)
+}
+
+fn decode_trace_record(r: &mut Reader) -> Result<TraceRecord, Error> {
+    let mut rec = TraceRecord {
+        nr: r.u32()?,
+        tid: r.u32()?,
+        cpu: r.u32()?,
+        flags: r.u32()?,
+        ..TraceRecord::default()
+    };
+    for arg in rec.args.iter_mut() {
+        *arg = r.u64()?;
+    }
+    rec.ret = r.i64()?;
+    rec.start_ns = r.u64()?;
+    rec.duration_ns = r.u64()?;
+    Ok(rec)
+}
+
+fn encode_trace_summary(w: &mut Writer, entry: &TraceSummary) -> Result<(), Error> {
+    w.u32(entry.nr)?;
+    w.u64(entry.calls)?;
+    w.u64(entry.errors)?;
+    w.u64(entry.total_ns)
+}
+
+fn decode_trace_summary(r: &mut Reader) -> Result<TraceSummary, Error> {
+    Ok(TraceSummary {
+        nr: r.u32()?,
+        calls: r.u64()?,
+        errors: r.u64()?,
+        total_ns: r.u64()?,
+    })
+}
+
+fn encode_crash_report(w: &mut Writer, report: &CrashReport) -> Result<(), Error> {
+    w.u32(report.vector)?;
+    w.u32(report.cpu)?;
+    w.u8(report.user as u8)?;
+    w.u64(report.error_code)?;
+    w.u64(report.cr2)?;
+    w.u64(report.app_bias)?;
+    report
+        .regs
+        .named()
+        .iter()
+        .try_for_each(|(_, v)| w.u64(*v))?;
+    let seg = &report.segments;
+    for sel in &[seg.cs, seg.ss, seg.ds, seg.es, seg.fs, seg.gs] {
+        w.u16(*sel)?;
+    }
+    w.u64(seg.fs_base)?;
+    w.u64(seg.gs_base)?;
+    let frames = report.frames();
+    w.u32(frames.len() as u32)?;
+    frames.iter().try_for_each(|frame| w.u64(*frame))
+}
+
+fn decode_crash_report(r: &mut Reader) -> Result<CrashReport, Error> {
+    let mut report = CrashReport {
+        vector: r.u32()?,
+        cpu: r.u32()?,
+        user: match r.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::DeSerializeError),
+        },
+        error_code: r.u64()?,
+        cr2: r.u64()?,
+        app_bias: r.u64()?,
+        ..CrashReport::default()
+    };
+    report.regs = Registers {
+        rax: r.u64()?,
+        rbx: r.u64()?,
+        rcx: r.u64()?,
+        rdx: r.u64()?,
+        rsi: r.u64()?,
+        rdi: r.u64()?,
+        rbp: r.u64()?,
+        rsp: r.u64()?,
+        r8: r.u64()?,
+        r9: r.u64()?,
+        r10: r.u64()?,
+        r11: r.u64()?,
+        r12: r.u64()?,
+        r13: r.u64()?,
+        r14: r.u64()?,
+        r15: r.u64()?,
+        rip: r.u64()?,
+        rflags: r.u64()?,
+    };
+    report.segments = Segments {
+        cs: r.u16()?,
+        ss: r.u16()?,
+        ds: r.u16()?,
+        es: r.u16()?,
+        fs: r.u16()?,
+        gs: r.u16()?,
+        fs_base: r.u64()?,
+        gs_base: r.u64()?,
+    };
+    let len = r.u32()? as usize;
+    if len > CRASH_FRAMES_LEN {
+        return Err(Error::DeSerializeError);
+    }
+    for frame in report.frames[..len].iter_mut() {
+        *frame = r.u64()?;
+    }
+    report.frames_len = len;
+    Ok(report)
+}
+
+impl VmSyscallRet {
+    /// The wire number of the syscall
+    pub fn nr(&self) -> VmSyscallNr {
+        match self {
+            VmSyscallRet::Read(_) => VmSyscallNr::Read,
+            VmSyscallRet::Write(_) => VmSyscallNr::Write,
+            VmSyscallRet::Madvise(_) => VmSyscallNr::Madvise,
+            VmSyscallRet::Mmap(_) => VmSyscallNr::Mmap,
+            VmSyscallRet::Mremap(_) => VmSyscallNr::Mremap,
+            VmSyscallRet::Munmap(_) => VmSyscallNr::Munmap,
+            VmSyscallRet::Mprotect(_) => VmSyscallNr::Mprotect,
+            VmSyscallRet::Openat(_) => VmSyscallNr::Openat,
+            VmSyscallRet::Close(_) => VmSyscallNr::Close,
+            VmSyscallRet::Pread(_) => VmSyscallNr::Pread,
+            VmSyscallRet::Pwrite(_) => VmSyscallNr::Pwrite,
+            VmSyscallRet::Lseek(_) => VmSyscallNr::Lseek,
+            VmSyscallRet::Fstat(_) => VmSyscallNr::Fstat,
+            VmSyscallRet::Getdents64(_) => VmSyscallNr::Getdents64,
+            VmSyscallRet::StartCpu(_) => VmSyscallNr::StartCpu,
+            VmSyscallRet::GetRandom(_) => VmSyscallNr::GetRandom,
+            VmSyscallRet::Trace(_) => VmSyscallNr::Trace,
+            VmSyscallRet::TraceSummary(_) => VmSyscallNr::TraceSummary,
+            VmSyscallRet::Crash(_) => VmSyscallNr::Crash,
+        }
+    }
+
+    /// The error reply for a request with the wire number `nr`
+    pub fn error(nr: VmSyscallNr, e: Error) -> Self {
+        match nr {
+            VmSyscallNr::Read => VmSyscallRet::Read(Err(e)),
+            VmSyscallNr::Write => VmSyscallRet::Write(Err(e)),
+            VmSyscallNr::Madvise => VmSyscallRet::Madvise(Err(e)),
+            VmSyscallNr::Mmap => VmSyscallRet::Mmap(Err(e)),
+            VmSyscallNr::Mremap => VmSyscallRet::Mremap(Err(e)),
+            VmSyscallNr::Munmap => VmSyscallRet::Munmap(Err(e)),
+            VmSyscallNr::Mprotect => VmSyscallRet::Mprotect(Err(e)),
+            VmSyscallNr::Openat => VmSyscallRet::Openat(Err(e)),
+            VmSyscallNr::Close => VmSyscallRet::Close(Err(e)),
+            VmSyscallNr::Pread => VmSyscallRet::Pread(Err(e)),
+            VmSyscallNr::Pwrite => VmSyscallRet::Pwrite(Err(e)),
+            VmSyscallNr::Lseek => VmSyscallRet::Lseek(Err(e)),
+            VmSyscallNr::Fstat => VmSyscallRet::Fstat(Err(e)),
+            VmSyscallNr::Getdents64 => VmSyscallRet::Getdents64(Err(e)),
+            VmSyscallNr::StartCpu => VmSyscallRet::StartCpu(Err(e)),
+            VmSyscallNr::GetRandom => VmSyscallRet::GetRandom(Err(e)),
+            VmSyscallNr::Trace => VmSyscallRet::Trace(Err(e)),
+            VmSyscallNr::TraceSummary => VmSyscallRet::TraceSummary(Err(e)),
+            VmSyscallNr::Crash => VmSyscallRet::Crash(Err(e)),
+        }
+    }
+
+    /// Encode the reply into `buf` and return the length of the message
+    pub fn encode(&self, request_id: u32, buf: &mut [u8]) -> Result<usize, Error> {
+        encode_message(self.nr(), request_id, buf, |w| match self {
+            VmSyscallRet::Read(res)
+            | VmSyscallRet::Pread(res)
+            | VmSyscallRet::Getdents64(res)
+            | VmSyscallRet::GetRandom(res) => w.result(res, |w, (n, data)| w.data(*n, data)),
+            VmSyscallRet::Write(res)
+            | VmSyscallRet::Madvise(res)
+            | VmSyscallRet::Munmap(res)
+            | VmSyscallRet::Mprotect(res)
+            | VmSyscallRet::Openat(res)
+            | VmSyscallRet::Close(res)
+            | VmSyscallRet::Pwrite(res)
+            | VmSyscallRet::StartCpu(res)
+            | VmSyscallRet::Trace(res)
+            | VmSyscallRet::TraceSummary(res)
+            | VmSyscallRet::Crash(res) => w.result(res, |w, v| w.i32(*v)),
+            VmSyscallRet::Mmap(res) | VmSyscallRet::Mremap(res) => {
+                w.result(res, |w, v| w.usize(*v))
+            }
+            VmSyscallRet::Lseek(res) => w.result(res, |w, v| w.i64(*v)),
+            VmSyscallRet::Fstat(res) => w.result(res, encode_stat),
+        })
+    }
+
+    /// Decode and validate a reply, returning the request id and the reply
+    pub fn decode(buf: &[u8]) -> Result<(u32, Self), Error> {
+        let header = Header::decode(buf)?;
+        let mut r = header.payload(buf);
+
+        fn data(r: &mut Reader) -> Result<(i32, [u8; READ_BUF_LEN]), Error> {
+            r.data::<ReadBuf>().map(|(n, data)| (n, data.0))
+        }
+
+        let ret = match header.nr {
+            VmSyscallNr::Read => VmSyscallRet::Read(r.result(data)?),
+            VmSyscallNr::Write => VmSyscallRet::Write(r.result(Reader::i32)?),
+            VmSyscallNr::Madvise => VmSyscallRet::Madvise(r.result(Reader::i32)?),
+            VmSyscallNr::Mmap => VmSyscallRet::Mmap(r.result(Reader::usize)?),
+            VmSyscallNr::Mremap => VmSyscallRet::Mremap(r.result(Reader::usize)?),
+            VmSyscallNr::Munmap => VmSyscallRet::Munmap(r.result(Reader::i32)?),
+            VmSyscallNr::Mprotect => VmSyscallRet::Mprotect(r.result(Reader::i32)?),
+            VmSyscallNr::Openat => VmSyscallRet::Openat(r.result(Reader::i32)?),
+            VmSyscallNr::Close => VmSyscallRet::Close(r.result(Reader::i32)?),
+            VmSyscallNr::Pread => VmSyscallRet::Pread(r.result(data)?),
+            VmSyscallNr::Pwrite => VmSyscallRet::Pwrite(r.result(Reader::i32)?),
+            VmSyscallNr::Lseek => VmSyscallRet::Lseek(r.result(Reader::i64)?),
+            VmSyscallNr::Fstat => VmSyscallRet::Fstat(r.result(decode_stat)?),
+            VmSyscallNr::Getdents64 => VmSyscallRet::Getdents64(r.result(data)?),
+            VmSyscallNr::StartCpu => VmSyscallRet::StartCpu(r.result(Reader::i32)?),
+            VmSyscallNr::GetRandom => VmSyscallRet::GetRandom(r.result(data)?),
+            VmSyscallNr::Trace => VmSyscallRet::Trace(r.result(Reader::i32)?),
+            VmSyscallNr::TraceSummary => VmSyscallRet::TraceSummary(r.result(Reader::i32)?),
+            VmSyscallNr::Crash => VmSyscallRet::Crash(r.result(Reader::i32)?),
+        };
+
+        r.finish()?;
+        Ok((header.request_id, ret))
+    }
+}
+
+#[test]
+fn check_request_roundtrip() {
+    let mut page = [0u8; MAX_MESSAGE_LEN];
+
+    let mut data = [0u8; WRITE_BUF_LEN];
+    data[..5].copy_from_slice(b"hello");
+    let len = VmSyscall::Pwrite {
+        fd: 3,
+        count: 5,
+        offset: Some(42),
+        data,
+    }
+    .encode(7, &mut page)
+    .unwrap();
+    assert_eq!(len, HEADER_LEN + 4 + 9 + 4 + 5);
+
+    match VmSyscall::decode(&page).unwrap() {
+        (
+            7,
+            VmSyscall::Pwrite {
+                fd: 3,
+                count: 5,
+                offset: Some(42),
+                data,
+            },
+        ) => assert_eq!(&data[..6], b"hello\0"),
+        _ => panic!("wrong request"),
+    }
+
+    let mut path = [0u8; PATH_BUF_LEN];
+    path[..4].copy_from_slice(b"/etc");
+    VmSyscall::Openat {
+        dirfd: -100,
+        path,
+        path_len: 4,
+        flags: 0o2,
+        mode: 0o644,
+    }
+    .encode(8, &mut page)
+    .unwrap();
+    match VmSyscall::decode(&page).unwrap() {
+        (
+            8,
+            VmSyscall::Openat {
+                dirfd: -100,
+                path,
+                path_len: 4,
+                flags: 0o2,
+                mode: 0o644,
+            },
+        ) => assert_eq!(&path[..4], b"/etc"),
+        _ => panic!("wrong request"),
+    }
+
+    VmSyscall::StartCpu {
+        cpu: 3,
+        entry: 0x8000_0020_0000,
+        stack: 0x7F48_4820_0000,
+        arg: 3,
+    }
+    .encode(9, &mut page)
+    .unwrap();
+    match VmSyscall::decode(&page).unwrap() {
+        (
+            9,
+            VmSyscall::StartCpu {
+                cpu: 3,
+                entry: 0x8000_0020_0000,
+                stack: 0x7F48_4820_0000,
+                arg: 3,
+            },
+        ) => {}
+        _ => panic!("wrong request"),
+    }
+}
+
+#[test]
+fn check_trace_roundtrip() {
+    let mut page = [0u8; MAX_MESSAGE_LEN];
+
+    // a full batch has to fit the syscall page
+    let rec = TraceRecord {
+        nr: 257,
+        tid: 2,
+        cpu: 1,
+        flags: 0,
+        args: [1, 2, 3, 4, 5, 6],
+        ret: -2,
+        start_ns: 1_000,
+        duration_ns: 20,
+    };
+    let mut records = [rec; TRACE_BATCH_LEN];
+    records[TRACE_BATCH_LEN - 1].nr = 0;
+    VmSyscall::Trace {
+        count: TRACE_BATCH_LEN,
+        records,
+    }
+    .encode(1, &mut page)
+    .unwrap();
+    match VmSyscall::decode(&page).unwrap() {
+        (1, VmSyscall::Trace { count, records: r }) => {
+            assert_eq!(count, TRACE_BATCH_LEN);
+            assert!(r.iter().eq(records.iter()));
+        }
+        _ => panic!("wrong request"),
+    }
+
+    let entry = TraceSummary {
+        nr: 0,
+        calls: 10,
+        errors: 1,
+        total_ns: 12_345,
+    };
+    VmSyscall::TraceSummary {
+        count: TRACE_SUMMARY_BATCH_LEN,
+        last: true,
+        entries: [entry; TRACE_SUMMARY_BATCH_LEN],
+    }
+    .encode(2, &mut page)
+    .unwrap();
+    match VmSyscall::decode(&page).unwrap() {
+        (
+            2,
+            VmSyscall::TraceSummary {
+                count: TRACE_SUMMARY_BATCH_LEN,
+                last: true,
+                entries,
+            },
+        ) => assert_eq!(entries[TRACE_SUMMARY_BATCH_LEN - 1], entry),
+        _ => panic!("wrong request"),
+    }
+
+    // a count beyond the batch is malformed
+    let len = VmSyscall::Trace { count: 1, records }
+        .encode(3, &mut page)
+        .unwrap();
+    page[HEADER_LEN] = TRACE_BATCH_LEN as u8 + 1;
+    assert_eq!(
+        VmSyscall::decode(&page[..len]).err(),
+        Some(Error::DeSerializeError)
+    );
+}
+
+#[test]
+fn check_crash_roundtrip() {
+    let mut page = [0u8; MAX_MESSAGE_LEN];
+
+    let mut report = CrashReport {
+        vector: 14,
+        cpu: 1,
+        user: true,
+        error_code: 0b110,
+        cr2: 0xdead_0000,
+        app_bias: 0x7f00_0000_0000,
+        frames_len: CRASH_FRAMES_LEN,
+        frames: [0x40_1000; CRASH_FRAMES_LEN],
+        ..CrashReport::default()
+    };
+    report.regs.rip = 0x40_1234;
+    report.regs.r15 = 15;
+    report.segments.cs = 0x23;
+    report.segments.fs_base = 0x7000;
+
+    let len = VmSyscall::Crash { report }.encode(4, &mut page).unwrap();
+    match VmSyscall::decode(&page[..len]).unwrap() {
+        (4, VmSyscall::Crash { report: r }) => assert_eq!(r, report),
+        _ => panic!("wrong request"),
+    }
+
+    // more frames than fit in the report are malformed
+    report.frames_len = 1;
+    let len = VmSyscall::Crash { report }.encode(5, &mut page).unwrap();
+    let frames_len = len - 8 - 4;
+    page[frames_len] = CRASH_FRAMES_LEN as u8 + 1;
+    assert_eq!(
+        VmSyscall::decode(&page[..len]).err(),
+        Some(Error::DeSerializeError)
+    );
+}
+
+#[test]
+fn check_reply_roundtrip() {
+    let mut page = [0u8; MAX_MESSAGE_LEN];
+
+    let mut data = [0u8; READ_BUF_LEN];
+    data[..3].copy_from_slice(b"abc");
+    VmSyscallRet::Read(Ok((3, data)))
+        .encode(1, &mut page)
+        .unwrap();
+    match VmSyscallRet::decode(&page).unwrap() {
+        (1, VmSyscallRet::Read(Ok((3, data)))) => assert_eq!(&data[..4], b"abc\0"),
+        _ => panic!("wrong reply"),
+    }
+
+    VmSyscallRet::error(VmSyscallNr::Fstat, Error::Errno(9))
+        .encode(2, &mut page)
+        .unwrap();
+    match VmSyscallRet::decode(&page).unwrap() {
+        (2, VmSyscallRet::Fstat(Err(Error::Errno(9)))) => {}
+        _ => panic!("wrong reply"),
+    }
+
+    let stat = Stat {
+        st_size: 1234,
+        st_mode: 0o100_644,
+        ..Default::default()
+    };
+    VmSyscallRet::Fstat(Ok(stat)).encode(3, &mut page).unwrap();
+    match VmSyscallRet::decode(&page).unwrap() {
+        (3, VmSyscallRet::Fstat(Ok(s))) => assert_eq!(s, stat),
+        _ => panic!("wrong reply"),
+    }
+
+    let mut data = [0u8; READ_BUF_LEN];
+    data[..4].copy_from_slice(&[1, 2, 3, 4]);
+    VmSyscallRet::GetRandom(Ok((4, data)))
+        .encode(4, &mut page)
+        .unwrap();
+    match VmSyscallRet::decode(&page).unwrap() {
+        (4, VmSyscallRet::GetRandom(Ok((4, data)))) => assert_eq!(&data[..5], &[1, 2, 3, 4, 0]),
+        _ => panic!("wrong reply"),
+    }
+}
+
+#[test]
+fn check_max_message_len() {
+    let mut page = [0u8; MAX_MESSAGE_LEN];
+    let len = VmSyscall::Pwrite {
+        fd: 3,
+        count: WRITE_BUF_LEN,
+        offset: Some(0),
+        data: [0xFF; WRITE_BUF_LEN],
+    }
+    .encode(0, &mut page)
+    .unwrap();
+    assert!(len <= MAX_MESSAGE_LEN);
+
+    let len = VmSyscallRet::Pread(Ok((READ_BUF_LEN as _, [0xFF; READ_BUF_LEN])))
+        .encode(0, &mut page)
+        .unwrap();
+    assert!(len <= MAX_MESSAGE_LEN);
+
+    // a too small buffer is an error and not a panic
+    let mut small = [0u8; 64];
+    assert_eq!(
+        VmSyscallRet::Pread(Ok((READ_BUF_LEN as _, [0xFF; READ_BUF_LEN]))).encode(0, &mut small),
+        Err(Error::SerializeError)
+    );
+}
+
+#[test]
+fn check_malformed_request() {
+    let mut page = [0u8; MAX_MESSAGE_LEN];
+
+    // empty page
+    assert_eq!(
+        VmSyscall::decode(&page).err(),
+        Some(Error::DeSerializeError)
+    );
+
+    let len = VmSyscall::Close { fd: 3 }.encode(1, &mut page).unwrap();
+    assert!(VmSyscall::decode(&page).is_ok());
+
+    // wrong magic
+    let mut bad = page;
+    bad[0] ^= 0xFF;
+    assert_eq!(VmSyscall::decode(&bad).err(), Some(Error::DeSerializeError));
+
+    // wrong version
+    let mut bad = page;
+    bad[4] ^= 0xFF;
+    assert_eq!(VmSyscall::decode(&bad).err(), Some(Error::DeSerializeError));
+
+    // unknown syscall
+    let mut bad = page;
+    bad[6..8].copy_from_slice(&0xFFFFu16.to_le_bytes());
+    assert_eq!(VmSyscall::decode(&bad).err(), Some(Error::DeSerializeError));
+
+    // length beyond the page
+    let mut bad = page;
+    bad[8..12].copy_from_slice(&(MAX_MESSAGE_LEN as u32).to_le_bytes());
+    assert_eq!(VmSyscall::decode(&bad).err(), Some(Error::DeSerializeError));
+
+    // trailing bytes
+    let mut bad = page;
+    bad[8..12].copy_from_slice(&((len - HEADER_LEN + 1) as u32).to_le_bytes());
+    assert_eq!(VmSyscall::decode(&bad).err(), Some(Error::DeSerializeError));
+
+    // truncated payload
+    let mut bad = page;
+    bad[8..12].copy_from_slice(&((len - HEADER_LEN - 1) as u32).to_le_bytes());
+    assert_eq!(VmSyscall::decode(&bad).err(), Some(Error::DeSerializeError));
+
+    // oversized buffer
+    let mut data = [0u8; WRITE_BUF_LEN];
+    data[0] = b'x';
+    VmSyscall::Write {
+        fd: 1,
+        count: 1,
+        data,
+    }
+    .encode(1, &mut page)
+    .unwrap();
+    let mut bad = page;
+    bad[HEADER_LEN + 4..HEADER_LEN + 8].copy_from_slice(&(WRITE_BUF_LEN as i32 + 1).to_le_bytes());
+    assert_eq!(VmSyscall::decode(&bad).err(), Some(Error::DeSerializeError));
+    let mut bad = page;
+    bad[HEADER_LEN + 4..HEADER_LEN + 8].copy_from_slice(&(-1i32).to_le_bytes());
+    assert_eq!(VmSyscall::decode(&bad).err(), Some(Error::DeSerializeError));
+}